@@ -0,0 +1,684 @@
+use crate::analyzer::CallGraph;
+use crate::ast::{
+    walk_expr, walk_exprs, walk_function_call, walk_set, Expr, ExprKind, Parameter, Span, Type,
+    Visitor,
+};
+use crate::diagnostics::{codes, Diagnostic, ToDiagnostic};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use thiserror::Error;
+
+/// Integers from `min` to `max` inclusive; a `None` end is unbounded
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Interval {
+    pub min: Option<i128>,
+    pub max: Option<i128>,
+}
+
+impl Interval {
+    pub fn new(min: Option<i128>, max: Option<i128>) -> Self {
+        Self { min, max }
+    }
+
+    pub fn exact(value: i128) -> Self {
+        Self::new(Some(value), Some(value))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        matches!((self.min, self.max), (Some(min), Some(max)) if min > max)
+    }
+
+    /// Smallest interval holding both
+    fn join(self, other: Self) -> Self {
+        Self::new(
+            self.min.zip(other.min).map(|(a, b)| a.min(b)),
+            self.max.zip(other.max).map(|(a, b)| a.max(b)),
+        )
+    }
+
+    fn add(self, other: Self) -> Self {
+        Self::new(
+            self.min.zip(other.min).and_then(|(a, b)| a.checked_add(b)),
+            self.max.zip(other.max).and_then(|(a, b)| a.checked_add(b)),
+        )
+    }
+
+    fn neg(self) -> Self {
+        Self::new(self.max.map(|m| -m), self.min.map(|m| -m))
+    }
+
+    fn mul(self, other: Self) -> Self {
+        let (Some(a), Some(b), Some(c), Some(d)) = (self.min, self.max, other.min, other.max)
+        else {
+            return Self::default();
+        };
+        let products = [
+            a.checked_mul(c),
+            a.checked_mul(d),
+            b.checked_mul(c),
+            b.checked_mul(d),
+        ];
+        let products: Option<Vec<i128>> = products.into_iter().collect();
+        match products {
+            Some(p) => Self::new(p.iter().min().copied(), p.iter().max().copied()),
+            None => Self::default(),
+        }
+    }
+
+    /// Truncating division of a non-negative dividend by a non-negative
+    /// divisor; dividing by zero has no result to bound
+    fn div(self, other: Self) -> Self {
+        match (self.min, other.min) {
+            (Some(a), Some(b)) if a >= 0 && b >= 0 => Self::new(
+                Some(other.max.map_or(0, |d| a / d.max(1))),
+                self.max.map(|m| m / b.max(1)),
+            ),
+            _ => Self::default(),
+        }
+    }
+
+    /// Remainder of a non-negative dividend by a non-negative divisor
+    fn rem(self, other: Self) -> Self {
+        match (self.min, other.min) {
+            (Some(a), Some(b)) if a >= 0 && b >= 0 => {
+                let below = other.max.map(|d| d - 1);
+                Self::new(
+                    Some(0),
+                    match (self.max, below) {
+                        (Some(m), Some(d)) => Some(m.min(d)),
+                        (max, None) | (None, max) => max,
+                    },
+                )
+            }
+            _ => Self::default(),
+        }
+    }
+}
+
+impl fmt::Display for Interval {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (self.min, self.max) {
+            (Some(min), Some(max)) if min == max => write!(f, "{}", min),
+            (Some(min), Some(max)) => write!(f, "{}..={}", min, max),
+            (Some(min), None) => write!(f, "{}..", min),
+            (None, Some(max)) => write!(f, "..={}", max),
+            (None, None) => write!(f, ".."),
+        }
+    }
+}
+
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum BoundsError {
+    #[error("{operation} index {index} is out of bounds for length {length} at {span}")]
+    OutOfBounds {
+        operation: &'static str,
+        index: Interval,
+        length: u64,
+        span: Span,
+    },
+
+    #[error("{operation} index {index} may be out of bounds at {span}")]
+    MayBeOutOfBounds {
+        operation: &'static str,
+        index: Interval,
+        /// `None` when the array's length is not known
+        length: Option<u64>,
+        /// Indices the access can take that are out of bounds
+        counterexample: Option<Interval>,
+        span: Span,
+    },
+}
+
+impl BoundsError {
+    pub fn span(&self) -> Span {
+        match self {
+            BoundsError::OutOfBounds { span, .. } | BoundsError::MayBeOutOfBounds { span, .. } => {
+                *span
+            }
+        }
+    }
+
+    /// An access that cannot be proven in range may still be, so it is
+    /// only a warning
+    pub fn is_warning(&self) -> bool {
+        matches!(self, BoundsError::MayBeOutOfBounds { .. })
+    }
+}
+
+impl ToDiagnostic for BoundsError {
+    fn to_diagnostic(&self) -> Diagnostic {
+        match self {
+            BoundsError::OutOfBounds {
+                operation,
+                index,
+                length,
+                span,
+            } => Diagnostic::error(
+                codes::INDEX_OUT_OF_BOUNDS,
+                format!("`{}` index is out of bounds", operation),
+                *span,
+            )
+            .with_label(format!("index {} is outside 0..{}", index, length)),
+            BoundsError::MayBeOutOfBounds {
+                operation,
+                index,
+                length,
+                counterexample,
+                span,
+            } => {
+                let label = match length {
+                    _ if *index == Interval::default() => "cannot bound this index".to_string(),
+                    Some(length) => format!("index in {}, for length {}", index, length),
+                    None => format!("index in {}, for an array of unknown length", index),
+                };
+                let diagnostic = Diagnostic::warning(
+                    codes::INDEX_MAY_BE_OUT_OF_BOUNDS,
+                    format!("`{}` index may be out of bounds", operation),
+                    *span,
+                )
+                .with_label(label);
+                match counterexample {
+                    Some(indices) => {
+                        diagnostic.with_note(format!("out of bounds for indices {}", indices))
+                    }
+                    None => diagnostic,
+                }
+            }
+        }
+    }
+}
+
+/// What is known about an integer value
+#[derive(Debug, Clone, Default)]
+struct Value {
+    range: Interval,
+    /// Array whose length the value is
+    length_of: Option<String>,
+    /// Array whose length the value is smaller than
+    below: Option<String>,
+}
+
+impl Value {
+    fn range(range: Interval) -> Self {
+        Self {
+            range,
+            ..Self::default()
+        }
+    }
+
+    /// Any value of `ty`
+    fn of_type(ty: Option<&Type>) -> Self {
+        match ty.and_then(Type::int_range) {
+            Some((min, max)) => Self::range(Interval::new(Some(min), Some(max))),
+            None => Self::default(),
+        }
+    }
+}
+
+/// Arguments a deploy-time function is called with, over all call sites
+#[derive(Debug, Clone)]
+struct Argument {
+    range: Interval,
+    /// Array length, when every call site passes the same one
+    length: Option<u64>,
+}
+
+/// Checks that every `array-get` and `array-set` in deploy-time code
+/// indexes within its array (memory safety).
+///
+/// Array lengths come from `array` and `array-init` forms and from array
+/// types. Index ranges come from constants, `bounded-for` ranges,
+/// arithmetic, `array-length` and integer types. An index is also in range
+/// when it is known to be below the length of the array it indexes, as a
+/// loop variable counting to `(array-length a)` is. Functions are checked
+/// callers first, so a parameter ranges over the arguments of every call.
+pub struct BoundsChecker {
+    /// Parameter count of every deploy-time function
+    functions: HashMap<String, usize>,
+    /// Arguments seen so far, for functions whose callers are known
+    arguments: HashMap<String, Vec<Argument>>,
+    values: HashMap<String, Value>,
+    lengths: HashMap<String, u64>,
+    /// Variables the current function assigns with `set`
+    assigned: HashSet<String>,
+    errors: Vec<BoundsError>,
+}
+
+impl BoundsChecker {
+    pub fn new() -> Self {
+        Self {
+            functions: HashMap::new(),
+            arguments: HashMap::new(),
+            values: HashMap::new(),
+            lengths: HashMap::new(),
+            assigned: HashSet::new(),
+            errors: Vec::new(),
+        }
+    }
+
+    pub fn check(mut self, exprs: &[Expr]) -> Vec<BoundsError> {
+        let mut functions = Vec::new();
+        collect_functions(exprs, &mut functions);
+        for function in &functions {
+            if let ExprKind::DefunDeploy { name, params, .. } = &function.kind {
+                self.functions.insert(name.clone(), params.len());
+            }
+        }
+
+        // Without an order, every parameter is checked as unknown
+        let order = CallGraph::build(exprs).topological_order();
+        if let Some(order) = &order {
+            functions.sort_by_key(|f| match &f.kind {
+                ExprKind::DefunDeploy { name, .. } => order.iter().position(|n| n == name),
+                _ => None,
+            });
+        } else {
+            self.functions.clear();
+        }
+        for function in functions {
+            self.visit_expr(function);
+        }
+
+        self.errors
+            .sort_by_key(|e| (e.span().file.0, e.span().start));
+        self.errors
+    }
+
+    fn value(&self, expr: &Expr) -> Value {
+        match &expr.kind {
+            ExprKind::Int(literal) => Value::range(Interval::exact(literal.value)),
+            ExprKind::Ident(name) => self.values.get(name).cloned().unwrap_or_default(),
+            ExprKind::ArrayLength(array) => Value {
+                range: match self.length(array) {
+                    Some(length) => Interval::exact(length as i128),
+                    None => Interval::new(Some(0), None),
+                },
+                length_of: name_of(array),
+                below: None,
+            },
+            ExprKind::FunctionCall { func, args } => {
+                let ExprKind::Ident(op) = &func.kind else {
+                    return Value::default();
+                };
+                match (op.as_str(), args.as_slice()) {
+                    ("-", [a]) => Value::range(self.value(a).range.neg()),
+                    (op, [a, b]) => self.arithmetic(op, self.value(a), self.value(b)),
+                    _ => Value::default(),
+                }
+            }
+            _ => Value::default(),
+        }
+    }
+
+    fn arithmetic(&self, op: &str, a: Value, b: Value) -> Value {
+        let non_negative = a.range.min.is_some_and(|m| m >= 0);
+        match op {
+            "+" => Value::range(a.range.add(b.range)),
+            // `(- (array-length a) 1)` is the last index of `a`
+            "-" => Value {
+                range: a.range.add(b.range.neg()),
+                length_of: None,
+                below: match b.range.min {
+                    Some(1..) => a.length_of.or(a.below),
+                    Some(0) => a.below,
+                    _ => None,
+                },
+            },
+            "*" => Value::range(a.range.mul(b.range)),
+            "/" => Value {
+                range: a.range.div(b.range),
+                length_of: None,
+                below: a.below.filter(|_| non_negative && b.range.min >= Some(0)),
+            },
+            "mod" => Value {
+                range: a.range.rem(b.range),
+                length_of: None,
+                below: b.length_of.or(b.below).filter(|_| non_negative),
+            },
+            _ => Value::default(),
+        }
+    }
+
+    fn length(&self, array: &Expr) -> Option<u64> {
+        let length = match &array.kind {
+            ExprKind::Ident(name) => self.lengths.get(name).copied(),
+            ExprKind::ArrayLiteral { size, .. } => Some(*size as u64),
+            ExprKind::ArrayInit { elements, .. } => Some(elements.len() as u64),
+            _ => None,
+        };
+        length.or(match &array.ty {
+            Some(Type::Array { size, .. }) => Some(*size as u64),
+            _ => None,
+        })
+    }
+
+    /// Bind `name`, dropping what was known about the name it shadows
+    fn bind(&mut self, name: &str, value: Value, length: Option<u64>) {
+        self.values.remove(name);
+        self.lengths.remove(name);
+        for known in self.values.values_mut() {
+            if known.length_of.as_deref() == Some(name) {
+                known.length_of = None;
+            }
+            if known.below.as_deref() == Some(name) {
+                known.below = None;
+            }
+        }
+        if !self.assigned.contains(name) {
+            self.values.insert(name.to_string(), value);
+        }
+        if let Some(length) = length {
+            self.lengths.insert(name.to_string(), length);
+        }
+    }
+
+    fn check_access(&mut self, operation: &'static str, array: &Expr, index: &Expr) {
+        let value = self.value(index);
+        let range = value.range;
+        let non_negative = range.min.is_some_and(|m| m >= 0);
+        if range.is_empty()
+            || (non_negative && value.below.is_some() && value.below == name_of(array))
+        {
+            return;
+        }
+
+        let length = self.length(array);
+        if let Some(length) = length {
+            let n = length as i128;
+            if non_negative && range.max.is_some_and(|m| m < n) {
+                return;
+            }
+            if range.min.is_some_and(|m| m >= n) || range.max.is_some_and(|m| m < 0) {
+                self.errors.push(BoundsError::OutOfBounds {
+                    operation,
+                    index: range,
+                    length,
+                    span: index.span,
+                });
+                return;
+            }
+        }
+        self.errors.push(BoundsError::MayBeOutOfBounds {
+            operation,
+            index: range,
+            length,
+            counterexample: counterexample(range, length),
+            span: index.span,
+        });
+    }
+
+    /// Check `walk` in a scope of its own
+    fn scoped(&mut self, walk: impl FnOnce(&mut Self)) {
+        let outer = (self.values.clone(), self.lengths.clone());
+        walk(self);
+        (self.values, self.lengths) = outer;
+    }
+}
+
+impl Default for BoundsChecker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Out-of-bounds part of `range`, preferring indices past the end
+fn counterexample(range: Interval, length: Option<u64>) -> Option<Interval> {
+    if let Some(length) = length {
+        let n = length as i128;
+        if range.max.is_none_or(|m| m >= n) {
+            return Some(Interval::new(
+                Some(range.min.map_or(n, |m| m.max(n))),
+                range.max,
+            ));
+        }
+    }
+    if range.min.is_none_or(|m| m < 0) {
+        return Some(Interval::new(
+            range.min,
+            Some(range.max.map_or(-1, |m| m.min(-1))),
+        ));
+    }
+    None
+}
+
+fn name_of(expr: &Expr) -> Option<String> {
+    match &expr.kind {
+        ExprKind::Ident(name) => Some(name.clone()),
+        _ => None,
+    }
+}
+
+fn collect_functions<'a>(exprs: &'a [Expr], functions: &mut Vec<&'a Expr>) {
+    for expr in exprs {
+        match &expr.kind {
+            ExprKind::DefunDeploy { .. } => functions.push(expr),
+            ExprKind::Program { forms, .. } => collect_functions(forms, functions),
+            _ => {}
+        }
+    }
+}
+
+/// Names assigned with `set`
+#[derive(Default)]
+struct Assigned(HashSet<String>);
+
+impl Visitor for Assigned {
+    fn visit_set(&mut self, var: &str, value: &Expr, _span: Span) {
+        self.0.insert(var.to_string());
+        walk_set(self, value);
+    }
+}
+
+impl Visitor for BoundsChecker {
+    fn visit_expr(&mut self, expr: &Expr) {
+        match &expr.kind {
+            ExprKind::ArrayGet { array, index } | ExprKind::ArraySet { array, index, .. } => {
+                self.check_access(expr.keyword().unwrap_or_default(), array, index);
+            }
+            _ => {}
+        }
+        walk_expr(self, expr)
+    }
+
+    fn visit_defun_deploy(
+        &mut self,
+        name: &str,
+        params: &[Parameter],
+        _return_type: Option<&Type>,
+        body: &[Expr],
+        _span: Span,
+    ) {
+        let mut assigned = Assigned::default();
+        walk_exprs(&mut assigned, body);
+        self.assigned = assigned.0;
+        self.values.clear();
+        self.lengths.clear();
+
+        let arguments = self.arguments.get(name).cloned();
+        for (i, param) in params.iter().enumerate() {
+            let ty = param.ty();
+            let mut value = Value::of_type(ty);
+            let mut length = match ty {
+                Some(Type::Array { size, .. }) => Some(*size as u64),
+                _ => None,
+            };
+            if let Some(argument) = arguments.as_ref().and_then(|a| a.get(i)) {
+                value.range = argument.range;
+                length = length.or(argument.length);
+            }
+            self.bind(&param.name, value, length);
+        }
+        walk_exprs(self, body);
+    }
+
+    // The values are bound in the outer scope
+    fn visit_let(&mut self, bindings: &[(String, Expr)], body: &[Expr], _span: Span) {
+        for (_, value) in bindings {
+            self.visit_expr(value);
+        }
+        let bound: Vec<(Value, Option<u64>)> = bindings
+            .iter()
+            .map(|(name, value)| {
+                let known = if self.assigned.contains(name) {
+                    Value::of_type(value.ty.as_ref())
+                } else {
+                    self.value(value)
+                };
+                (known, self.length(value))
+            })
+            .collect();
+        self.scoped(|checker| {
+            for ((name, _), (value, length)) in bindings.iter().zip(bound) {
+                checker.bind(name, value, length);
+            }
+            walk_exprs(checker, body);
+        });
+    }
+
+    fn visit_bounded_for(
+        &mut self,
+        var: &str,
+        start: &Expr,
+        end: &Expr,
+        body: &[Expr],
+        _span: Span,
+    ) {
+        self.visit_expr(start);
+        self.visit_expr(end);
+        let (first, last) = (self.value(start), self.value(end));
+        let value = Value {
+            range: Interval::new(first.range.min, last.range.max.map(|m| m - 1)),
+            length_of: None,
+            below: last.length_of.or(last.below),
+        };
+        self.scoped(|checker| {
+            checker.bind(var, value, None);
+            walk_exprs(checker, body);
+        });
+    }
+
+    fn visit_function_call(&mut self, func: &Expr, args: &[Expr], _span: Span) {
+        if let ExprKind::Ident(callee) = &func.kind {
+            if self.functions.get(callee) == Some(&args.len()) {
+                let passed: Vec<Argument> = args
+                    .iter()
+                    .map(|arg| Argument {
+                        range: self.value(arg).range,
+                        length: self.length(arg),
+                    })
+                    .collect();
+                let joined = match self.arguments.remove(callee) {
+                    Some(seen) => seen
+                        .into_iter()
+                        .zip(passed)
+                        .map(|(a, b)| Argument {
+                            range: a.range.join(b.range),
+                            length: a.length.filter(|_| a.length == b.length),
+                        })
+                        .collect(),
+                    None => passed,
+                };
+                self.arguments.insert(callee.clone(), joined);
+            }
+        }
+        walk_function_call(self, func, args)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analyzer::TypeInference;
+    use crate::parse_file;
+
+    fn check(source: &str) -> Vec<BoundsError> {
+        let mut exprs = parse_file(source).unwrap();
+        TypeInference::new(&exprs).infer(&mut exprs);
+        BoundsChecker::new().check(&exprs)
+    }
+
+    #[test]
+    fn test_example_accesses_are_in_bounds() {
+        let source = include_str!("../../../examples/temperature-monitor.obl");
+        assert_eq!(check(source), vec![]);
+    }
+
+    #[test]
+    fn test_indices_below_array_length() {
+        let errors = check(
+            r#"
+(defun-deploy sum (data) : int32
+  (let ((total 0)
+        (last (- (array-length data) 1)))
+    (bounded-for i 0 (array-length data)
+      (set total (+ total (array-get data i))))
+    (bounded-for i 0 10
+      (set total (+ total (array-get data (mod i (array-length data))))))
+    (+ total (array-get data last))))
+"#,
+        );
+        // Only the last index can be out of bounds, when `data` is empty
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].span().line, 9);
+        let BoundsError::MayBeOutOfBounds { counterexample, .. } = &errors[0] else {
+            panic!("expected a possible violation, found {:?}", errors[0]);
+        };
+        assert_eq!(counterexample, &Some(Interval::exact(-1)));
+    }
+
+    #[test]
+    fn test_provable_violation() {
+        let errors = check(
+            r#"
+(defun-deploy fill () : void
+  (let ((buffer (array int32 8)))
+    (bounded-for i 8 12
+      (array-set buffer i 0))))
+"#,
+        );
+        assert_eq!(
+            errors,
+            vec![BoundsError::OutOfBounds {
+                operation: "array-set",
+                index: Interval::new(Some(8), Some(11)),
+                length: 8,
+                span: errors[0].span(),
+            }]
+        );
+        let diagnostic = errors[0].to_diagnostic();
+        assert_eq!(diagnostic.code, codes::INDEX_OUT_OF_BOUNDS);
+        assert_eq!(diagnostic.primary.message, "index 8..=11 is outside 0..8");
+    }
+
+    #[test]
+    fn test_possible_violation_has_counterexample() {
+        let errors = check(
+            r#"
+(defun-deploy scale (count) : int32
+  (let ((table (array-init int32 1 2 4 8)))
+    (bounded-for i 0 count
+      (array-get table (* i 2)))))
+(defun-deploy main () : int32
+  (scale 2)
+  (scale 3))
+"#,
+        );
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].is_warning());
+        let BoundsError::MayBeOutOfBounds {
+            index,
+            counterexample,
+            ..
+        } = &errors[0]
+        else {
+            panic!("expected a possible violation, found {:?}", errors[0]);
+        };
+        // `count` is 2 or 3 at the call sites
+        assert_eq!(index.to_string(), "0..=4");
+        assert_eq!(counterexample, &Some(Interval::exact(4)));
+        assert_eq!(
+            errors[0].to_diagnostic().notes,
+            vec!["out of bounds for indices 4".to_string()]
+        );
+    }
+}
@@ -0,0 +1,689 @@
+use crate::analyzer::{CallGraph, CapabilityChecker};
+use crate::ast::{
+    walk_expr, walk_exprs, Expr, ExprKind, Parameter, ResourceType, Span, Type, Visitor,
+};
+use crate::diagnostics::{codes, Diagnostic, ToDiagnostic};
+use std::collections::{HashMap, HashSet};
+use std::mem;
+use thiserror::Error;
+
+/// Worst-case number of uses; `None` when it has no static bound
+pub type Uses = Option<u64>;
+
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum BudgetError {
+    #[error(
+        "Capability {capability} may use {uses} {unit}, over its budget of {budget} at {span}"
+    )]
+    OverBudget {
+        capability: String,
+        unit: &'static str,
+        uses: u64,
+        budget: u64,
+        span: Span,
+    },
+
+    #[error("Uses of capability {capability} have no static bound at {span}")]
+    Unbounded {
+        capability: String,
+        unit: &'static str,
+        budget: u64,
+        span: Span,
+    },
+}
+
+impl BudgetError {
+    pub fn span(&self) -> Span {
+        match self {
+            BudgetError::OverBudget { span, .. } | BudgetError::Unbounded { span, .. } => *span,
+        }
+    }
+}
+
+impl ToDiagnostic for BudgetError {
+    fn to_diagnostic(&self) -> Diagnostic {
+        match self {
+            BudgetError::OverBudget {
+                capability,
+                unit,
+                uses,
+                budget,
+                span,
+            } => Diagnostic::error(
+                codes::CAPABILITY_OVER_BUDGET,
+                format!("capability `{}` exceeds its budget", capability),
+                *span,
+            )
+            .with_label(format!(
+                "budget of {} {}, but up to {} are used",
+                budget, unit, uses
+            )),
+            BudgetError::Unbounded {
+                capability,
+                unit,
+                budget,
+                span,
+            } => Diagnostic::error(
+                codes::UNBOUNDED_CAPABILITY,
+                format!("cannot bound the uses of capability `{}`", capability),
+                *span,
+            )
+            .with_label(format!("budget of {} {}", budget, unit))
+            .with_note("loop bounds and data sizes must be known at compile time to count uses"),
+        }
+    }
+}
+
+/// What a use of `resource` is counted in
+pub fn budget_unit(resource: &ResourceType) -> &'static str {
+    match resource {
+        ResourceType::NetworkSend => "bytes",
+        _ => "operations",
+    }
+}
+
+/// Worst-case uses of the capability opened by one `with-capability`, each
+/// time the block runs
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScopeUsage {
+    pub function: String,
+    /// `None` when the capability's type is not known
+    pub resource: Option<ResourceType>,
+    pub uses: Uses,
+    /// The capability expression of the `with-capability`
+    pub span: Span,
+}
+
+/// Worst-case uses of a capability parameter over one call of its
+/// function, including the calls it is lent to
+#[derive(Debug, Clone, PartialEq)]
+pub struct CapabilityUsage {
+    pub function: String,
+    pub name: String,
+    pub resource: ResourceType,
+    pub uses: Uses,
+    pub budget: Option<u64>,
+    pub span: Span,
+}
+
+impl CapabilityUsage {
+    pub fn unit(&self) -> &'static str {
+        budget_unit(&self.resource)
+    }
+
+    /// Whether the uses provably fit the budget; `None` without a budget
+    pub fn within_budget(&self) -> Option<bool> {
+        Some(self.uses? <= self.budget?)
+    }
+}
+
+/// Worst-case I/O performed under each capability, checked against the
+/// budgets declared with `(capability res budget)` parameters.
+///
+/// Each I/O operation counts once, except `network-send`, which counts the
+/// bytes it sends. An operation is charged to the innermost enclosing
+/// `with-capability` for its resource, as `CapabilityChecker` would grant
+/// it. Loop bodies count once per iteration, and a call counts whatever
+/// the callee does with the capabilities lent to it. Callees are analyzed
+/// at each call site with the constant arguments they are passed, starting
+/// from the functions nothing calls; a function's counts are the worst
+/// over all of its call sites.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CapabilityBudgets {
+    pub scopes: Vec<ScopeUsage>,
+    pub capabilities: Vec<CapabilityUsage>,
+}
+
+impl CapabilityBudgets {
+    pub fn analyze(exprs: &[Expr]) -> Self {
+        let mut functions = Vec::new();
+        collect_functions(exprs, &mut functions);
+        let call_graph = CallGraph::build(exprs);
+
+        let mut summaries = Summaries {
+            functions: functions.iter().copied().collect(),
+            memo: HashMap::new(),
+            in_progress: HashSet::new(),
+            report: CapabilityBudgets::default(),
+        };
+        for (name, function) in &functions {
+            if call_graph.callers(name).is_empty() {
+                summaries.summarize(name, vec![None; function.params.len()]);
+            }
+        }
+
+        let mut report = summaries.report;
+        report.scopes.sort_by_key(|s| (s.span.file.0, s.span.start));
+        report
+            .capabilities
+            .sort_by_key(|c| (c.span.file.0, c.span.start));
+        report
+    }
+
+    /// Capabilities that exceed their budget, or cannot be shown not to
+    pub fn errors(&self) -> Vec<BudgetError> {
+        let mut errors = Vec::new();
+        for capability in &self.capabilities {
+            let Some(budget) = capability.budget else {
+                continue;
+            };
+            match capability.uses {
+                Some(uses) if uses > budget => errors.push(BudgetError::OverBudget {
+                    capability: capability.name.clone(),
+                    unit: capability.unit(),
+                    uses,
+                    budget,
+                    span: capability.span,
+                }),
+                Some(_) => {}
+                None => errors.push(BudgetError::Unbounded {
+                    capability: capability.name.clone(),
+                    unit: capability.unit(),
+                    budget,
+                    span: capability.span,
+                }),
+            }
+        }
+        errors
+    }
+}
+
+fn add(a: Uses, b: Uses) -> Uses {
+    Some(a?.saturating_add(b?))
+}
+
+fn max(a: Uses, b: Uses) -> Uses {
+    Some(a?.max(b?))
+}
+
+#[derive(Clone, Copy)]
+struct Function<'a> {
+    params: &'a [Parameter],
+    body: &'a [Expr],
+}
+
+fn collect_functions<'a>(exprs: &'a [Expr], functions: &mut Vec<(&'a str, Function<'a>)>) {
+    for expr in exprs {
+        match &expr.kind {
+            ExprKind::DefunDeploy {
+                name, params, body, ..
+            } => functions.push((name, Function { params, body })),
+            ExprKind::Program { forms, .. } => collect_functions(forms, functions),
+            _ => {}
+        }
+    }
+}
+
+/// Where uses are charged while a function is walked
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Target {
+    /// The `with-capability` whose capability expression is at this span
+    Scope(Span),
+    /// A capability parameter of the function
+    Param(String),
+}
+
+type Usage = HashMap<Target, Uses>;
+
+fn add_usage(usage: &mut Usage, other: Usage) {
+    for (target, uses) in other {
+        let total = usage.entry(target).or_insert(Some(0));
+        *total = add(*total, uses);
+    }
+}
+
+struct Summaries<'a> {
+    functions: HashMap<&'a str, Function<'a>>,
+    /// Uses of each parameter, by function and constant arguments
+    memo: HashMap<(String, Vec<Option<i128>>), Vec<Uses>>,
+    in_progress: HashSet<String>,
+    report: CapabilityBudgets,
+}
+
+impl<'a> Summaries<'a> {
+    /// Uses of each of `name`'s parameters by one call with `args`
+    fn summarize(&mut self, name: &str, args: Vec<Option<i128>>) -> Vec<Uses> {
+        let key = (name.to_string(), args);
+        if let Some(uses) = self.memo.get(&key) {
+            return uses.clone();
+        }
+        let Some(&Function { params, body }) = self.functions.get(name) else {
+            return Vec::new();
+        };
+        // Recursion is reported by the termination checker
+        if !self.in_progress.insert(name.to_string()) {
+            return vec![None; params.len()];
+        }
+
+        let mut visitor = UsageVisitor {
+            function: name.to_string(),
+            types: HashMap::new(),
+            consts: HashMap::new(),
+            capabilities: HashSet::new(),
+            open: Vec::new(),
+            usage: Usage::new(),
+            summaries: self,
+        };
+        for (param, arg) in params.iter().zip(&key.1) {
+            if let Some(ty) = param.ty() {
+                if matches!(ty, Type::Capability { .. }) {
+                    visitor.capabilities.insert(param.name.clone());
+                }
+                visitor.types.insert(param.name.clone(), ty.clone());
+            }
+            if let Some(arg) = arg {
+                visitor.consts.insert(param.name.clone(), *arg);
+            }
+        }
+        walk_exprs(&mut visitor, body);
+        let usage = visitor.usage;
+
+        let mut uses = Vec::new();
+        for param in params {
+            let param_uses = match param.ty() {
+                Some(Type::Capability { resource }) => {
+                    let param_uses = usage
+                        .get(&Target::Param(param.name.clone()))
+                        .copied()
+                        .unwrap_or(Some(0));
+                    self.record_capability(name, param, resource, param_uses);
+                    param_uses
+                }
+                _ => Some(0),
+            };
+            uses.push(param_uses);
+        }
+
+        self.in_progress.remove(name);
+        self.memo.insert(key, uses.clone());
+        uses
+    }
+
+    fn record_capability(
+        &mut self,
+        function: &str,
+        param: &Parameter,
+        resource: &ResourceType,
+        uses: Uses,
+    ) {
+        let capabilities = &mut self.report.capabilities;
+        match capabilities
+            .iter_mut()
+            .find(|c| c.function == function && c.name == param.name)
+        {
+            Some(capability) => capability.uses = max(capability.uses, uses),
+            None => capabilities.push(CapabilityUsage {
+                function: function.to_string(),
+                name: param.name.clone(),
+                resource: resource.clone(),
+                uses,
+                budget: param.budget,
+                span: param.span,
+            }),
+        }
+    }
+
+    fn record_scope(&mut self, scope: ScopeUsage) {
+        let scopes = &mut self.report.scopes;
+        match scopes.iter_mut().find(|s| s.span == scope.span) {
+            Some(existing) => existing.uses = max(existing.uses, scope.uses),
+            None => scopes.push(scope),
+        }
+    }
+}
+
+/// Counts the uses made by one function body, called with some arguments
+/// known to be constants
+struct UsageVisitor<'s, 'a> {
+    summaries: &'s mut Summaries<'a>,
+    function: String,
+    /// Variables with a known type
+    types: HashMap<String, Type>,
+    /// Variables with a known constant value
+    consts: HashMap<String, i128>,
+    /// Capability parameters in scope
+    capabilities: HashSet<String>,
+    /// Enclosing `with-capability` blocks, innermost last
+    open: Vec<(Span, Option<ResourceType>)>,
+    usage: Usage,
+}
+
+impl UsageVisitor<'_, '_> {
+    /// Uses charged by what `walk` visits, kept apart from the running total
+    fn measure(&mut self, walk: impl FnOnce(&mut Self)) -> Usage {
+        let outer = mem::take(&mut self.usage);
+        walk(self);
+        mem::replace(&mut self.usage, outer)
+    }
+
+    /// Check `walk` with `names` bound to values that are not tracked
+    fn shadowed<'n>(
+        &mut self,
+        names: impl IntoIterator<Item = &'n str>,
+        walk: impl FnOnce(&mut Self),
+    ) {
+        let outer = (
+            self.types.clone(),
+            self.consts.clone(),
+            self.capabilities.clone(),
+        );
+        for name in names {
+            self.types.remove(name);
+            self.consts.remove(name);
+            self.capabilities.remove(name);
+        }
+        walk(self);
+        (self.types, self.consts, self.capabilities) = outer;
+    }
+
+    fn constant(&self, expr: &Expr) -> Option<i128> {
+        match &expr.kind {
+            ExprKind::Int(literal) => Some(literal.value),
+            ExprKind::Ident(name) => self.consts.get(name).copied(),
+            _ => None,
+        }
+    }
+
+    /// Bytes `data` occupies, if its size is known
+    fn size(&self, data: &Expr) -> Uses {
+        match &data.kind {
+            ExprKind::Int(literal) => {
+                Some(literal.suffix.as_ref().unwrap_or(&Type::Int32).size_bytes())
+            }
+            ExprKind::String(s) => Some(s.len() as u64),
+            ExprKind::Ident(name) => self.types.get(name).map(Type::size_bytes),
+            ExprKind::ArrayLiteral { elem_type, size } => {
+                Some(elem_type.size_bytes().saturating_mul(*size as u64))
+            }
+            ExprKind::ArrayInit {
+                elem_type,
+                elements,
+            } => Some(elem_type.size_bytes().saturating_mul(elements.len() as u64)),
+            _ => data.ty.as_ref().map(Type::size_bytes),
+        }
+    }
+
+    /// Charge `uses` to the innermost block that grants `resource`
+    fn charge(&mut self, resource: &ResourceType, uses: Uses) {
+        let scope = self
+            .open
+            .iter()
+            .rev()
+            .find(|(_, r)| r.as_ref().is_none_or(|r| r == resource));
+        if let Some((span, _)) = scope {
+            add_usage(&mut self.usage, Usage::from([(Target::Scope(*span), uses)]));
+        }
+    }
+}
+
+impl Visitor for UsageVisitor<'_, '_> {
+    fn visit_expr(&mut self, expr: &Expr) {
+        walk_expr(self, expr);
+        if let Some((_, resource)) = CapabilityChecker::required_resource(expr) {
+            let uses = match &expr.kind {
+                ExprKind::NetworkSend { data, .. } => self.size(data),
+                _ => Some(1),
+            };
+            self.charge(&resource, uses);
+        }
+    }
+
+    fn visit_bounded_for(
+        &mut self,
+        var: &str,
+        start: &Expr,
+        end: &Expr,
+        body: &[Expr],
+        _span: Span,
+    ) {
+        self.visit_expr(start);
+        self.visit_expr(end);
+        let iterations = match (self.constant(start), self.constant(end)) {
+            (Some(start), Some(end)) => u64::try_from((end - start).max(0)).ok(),
+            _ => None,
+        };
+
+        let body = self.measure(|this| this.shadowed([var], |this| walk_exprs(this, body)));
+        let body = body
+            .into_iter()
+            .map(|(target, uses)| {
+                (
+                    target,
+                    uses.zip(iterations).map(|(u, n)| u.saturating_mul(n)),
+                )
+            })
+            .collect();
+        add_usage(&mut self.usage, body);
+    }
+
+    fn visit_if(&mut self, condition: &Expr, then_branch: &Expr, else_branch: &Expr, _span: Span) {
+        self.visit_expr(condition);
+        let mut branch = self.measure(|this| this.visit_expr(then_branch));
+        for (target, uses) in self.measure(|this| this.visit_expr(else_branch)) {
+            let other = branch.entry(target).or_insert(Some(0));
+            *other = max(*other, uses);
+        }
+        add_usage(&mut self.usage, branch);
+    }
+
+    // The values are bound in the outer scope
+    fn visit_let(&mut self, bindings: &[(String, Expr)], body: &[Expr], _span: Span) {
+        for (_, value) in bindings {
+            self.visit_expr(value);
+        }
+        let values: Vec<(&str, Option<i128>, Option<Type>)> = bindings
+            .iter()
+            .map(|(name, value)| (name.as_str(), self.constant(value), value.ty.clone()))
+            .collect();
+        let names = bindings.iter().map(|(name, _)| name.as_str());
+        self.shadowed(names, |this| {
+            for (name, constant, ty) in values {
+                if let Some(constant) = constant {
+                    this.consts.insert(name.to_string(), constant);
+                }
+                if let Some(ty) = ty {
+                    this.types.insert(name.to_string(), ty);
+                }
+            }
+            walk_exprs(this, body);
+        });
+    }
+
+    fn visit_with_capability(&mut self, capability: &Expr, body: &[Expr], _span: Span) {
+        self.visit_expr(capability);
+        let (resource, param) = match &capability.kind {
+            ExprKind::Ident(name) => {
+                let resource = match self.types.get(name) {
+                    Some(Type::Capability { resource }) => Some(resource.clone()),
+                    _ => None,
+                };
+                (resource, self.capabilities.get(name).cloned())
+            }
+            _ => (None, None),
+        };
+
+        self.open.push((capability.span, resource.clone()));
+        let mut usage = self.measure(|this| walk_exprs(this, body));
+        self.open.pop();
+
+        let uses = usage
+            .remove(&Target::Scope(capability.span))
+            .unwrap_or(Some(0));
+        self.summaries.record_scope(ScopeUsage {
+            function: self.function.clone(),
+            resource,
+            uses,
+            span: capability.span,
+        });
+        if let Some(param) = param {
+            add_usage(&mut usage, Usage::from([(Target::Param(param), uses)]));
+        }
+        add_usage(&mut self.usage, usage);
+    }
+
+    fn visit_function_call(&mut self, func: &Expr, args: &[Expr], _span: Span) {
+        walk_exprs(self, args);
+        let ExprKind::Ident(callee) = &func.kind else {
+            return;
+        };
+        if !self.summaries.functions.contains_key(callee.as_str()) {
+            return;
+        }
+
+        let constants = args.iter().map(|arg| self.constant(arg)).collect();
+        let summary = self.summaries.summarize(callee, constants);
+        for (arg, uses) in args.iter().zip(summary) {
+            if let ExprKind::Ident(name) = &arg.kind {
+                if self.capabilities.contains(name) {
+                    let lent = Usage::from([(Target::Param(name.clone()), uses)]);
+                    add_usage(&mut self.usage, lent);
+                }
+            }
+        }
+    }
+
+    // Compile-time code performs no I/O on the device
+    fn visit_defun_compile(
+        &mut self,
+        _name: &str,
+        _params: &[Parameter],
+        _return_type: Option<&Type>,
+        _body: &[Expr],
+        _span: Span,
+    ) {
+    }
+
+    fn visit_macro(&mut self, _name: &str, _params: &[Parameter], _body: &[Expr], _span: Span) {}
+
+    fn visit_eval_compile(&mut self, _expr: &Expr, _span: Span) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analyzer::TypeInference;
+    use crate::parse_file;
+
+    fn analyze(source: &str) -> CapabilityBudgets {
+        let mut exprs = parse_file(source).unwrap();
+        TypeInference::new(&exprs).infer(&mut exprs);
+        CapabilityBudgets::analyze(&exprs)
+    }
+
+    fn uses(report: &CapabilityBudgets) -> Vec<(&str, &str, Uses)> {
+        report
+            .capabilities
+            .iter()
+            .map(|c| (c.function.as_str(), c.name.as_str(), c.uses))
+            .collect()
+    }
+
+    #[test]
+    fn test_example_counts_through_loops_and_calls() {
+        let source = include_str!("../../../examples/temperature-monitor.obl");
+        let report = analyze(source);
+
+        // `samples` is only known where `main` passes 10, and `blink-status`
+        // blinks at most 3 times
+        assert_eq!(
+            uses(&report),
+            vec![
+                ("read-average-temp", "sensor-cap", Some(10)),
+                ("blink-status", "led-cap", Some(6)),
+                ("send-report", "network-cap", Some(24)),
+                ("main", "sensor-cap", Some(60)),
+                ("main", "network-cap", Some(24)),
+                ("main", "led-cap", Some(36)),
+            ]
+        );
+        let scopes: Vec<Uses> = report.scopes.iter().map(|s| s.uses).collect();
+        assert_eq!(scopes, vec![Some(1), Some(2), Some(24)]);
+        assert!(report.errors().is_empty());
+    }
+
+    #[test]
+    fn test_over_budget() {
+        let report = analyze(
+            r#"
+(defun-deploy blink ((led (capability gpio 10))) : void
+  (bounded-for i 0 4
+    (with-capability led
+      (gpio-set led 1)
+      (gpio-set led 0)))
+  (with-capability led
+    (if (gpio-get led) (gpio-set led 0) 0)))
+"#,
+        );
+        assert_eq!(uses(&report), vec![("blink", "led", Some(10))]);
+        assert!(report.errors().is_empty());
+
+        let report = analyze(
+            r#"
+(defun-deploy blink ((led (capability gpio 7))) : void
+  (bounded-for i 0 4
+    (with-capability led
+      (gpio-set led 1)
+      (gpio-set led 0))))
+"#,
+        );
+        let errors = report.errors();
+        assert_eq!(errors.len(), 1);
+        let diagnostic = errors[0].to_diagnostic();
+        assert_eq!(diagnostic.code, codes::CAPABILITY_OVER_BUDGET);
+        assert_eq!(
+            diagnostic.primary.message,
+            "budget of 7 operations, but up to 8 are used"
+        );
+    }
+
+    #[test]
+    fn test_unbounded_loop_under_budget() {
+        let report = analyze(
+            r#"
+(defun-deploy send ((link (capability network-send 64)) n) : void
+  (bounded-for i 0 n
+    (with-capability link
+      (network-send link 1))))
+"#,
+        );
+        assert_eq!(uses(&report), vec![("send", "link", None)]);
+        assert_eq!(report.scopes[0].uses, Some(4));
+        assert!(matches!(
+            report.errors()[..],
+            [BudgetError::Unbounded { budget: 64, .. }]
+        ));
+    }
+
+    #[test]
+    fn test_oversized_send_saturates() {
+        let report = analyze(
+            r#"
+(defun-deploy send ((link (capability network-send 64))) : void
+  (with-capability link
+    (network-send link (array (array uint64 18446744073709551615) 2))))
+"#,
+        );
+        assert_eq!(uses(&report), vec![("send", "link", Some(u64::MAX))]);
+        assert_eq!(report.errors().len(), 1);
+    }
+
+    #[test]
+    fn test_callee_summaries_use_constant_arguments() {
+        let report = analyze(
+            r#"
+(defun-deploy pulse ((led (capability gpio)) times) : void
+  (bounded-for i 0 times
+    (with-capability led (gpio-set led 1))))
+(defun-deploy main ((led (capability gpio 20))) : void
+  (pulse led 5)
+  (bounded-for i 0 3
+    (pulse led 5)))
+"#,
+        );
+        assert_eq!(
+            uses(&report),
+            vec![("pulse", "led", Some(5)), ("main", "led", Some(20))]
+        );
+        assert!(report.errors().is_empty());
+    }
+}
@@ -1,11 +1,15 @@
-use crate::ast::Expr;
+use crate::ast::{
+    walk_defun_deploy, walk_exprs, walk_function_call, Expr, ExprKind, Parameter, Span, Type,
+    Visitor,
+};
 use petgraph::graph::{DiGraph, NodeIndex};
-use petgraph::algo::is_cyclic_directed;
+use petgraph::algo::{is_cyclic_directed, tarjan_scc};
 use std::collections::HashMap;
 
 pub struct CallGraph {
     graph: DiGraph<String, ()>,
     node_map: HashMap<String, NodeIndex>,
+    definitions: HashMap<String, Span>,
 }
 
 impl CallGraph {
@@ -13,28 +17,27 @@ pub fn new() -> Self {
         Self {
             graph: DiGraph::new(),
             node_map: HashMap::new(),
+            definitions: HashMap::new(),
         }
     }
 
-    /// Build call graph from deploy functions
+    /// Build call graph from deploy functions, including those inside
+    /// `program` forms
     pub fn build(exprs: &[Expr]) -> Self {
+        let mut collector = CallCollector::default();
+        walk_exprs(&mut collector, exprs);
+
         let mut cg = Self::new();
 
-        // First pass: add all function nodes
-        for expr in exprs {
-            if let Expr::DefunDeploy { name, .. } = expr {
-                cg.add_function(name.clone());
-            }
+        // Add all function nodes before any edges, so calls to functions
+        // defined later in the file are kept
+        for (name, span) in collector.functions {
+            cg.add_function(name.clone());
+            cg.definitions.insert(name, span);
         }
 
-        // Second pass: add edges for function calls
-        for expr in exprs {
-            if let Expr::DefunDeploy { name, body, .. } = expr {
-                let calls = Self::extract_function_calls(body);
-                for called in calls {
-                    cg.add_call(name.clone(), called);
-                }
-            }
+        for (caller, callee) in collector.calls {
+            cg.add_call(caller, callee);
         }
 
         cg
@@ -47,69 +50,59 @@ fn add_function(&mut self, name: String) {
         }
     }
 
+    /// Calls to anything but a deploy function (builtins, compile-time
+    /// functions, undefined names) are not part of the graph; undefined
+    /// names are reported by `NameResolver`
     fn add_call(&mut self, caller: String, callee: String) {
-        self.add_function(callee.clone());
-
         if let (Some(&from), Some(&to)) = (self.node_map.get(&caller), self.node_map.get(&callee))
         {
             self.graph.add_edge(from, to, ());
         }
     }
 
-    fn extract_function_calls(exprs: &[Expr]) -> Vec<String> {
-        let mut calls = Vec::new();
-
-        for expr in exprs {
-            Self::collect_calls(expr, &mut calls);
-        }
-
-        calls
+    /// Check if the call graph has cycles (recursion)
+    pub fn has_cycles(&self) -> bool {
+        is_cyclic_directed(&self.graph)
     }
 
-    fn collect_calls(expr: &Expr, calls: &mut Vec<String>) {
-        match expr {
-            Expr::FunctionCall { func, args } => {
-                if let Expr::Ident(name) = func.as_ref() {
-                    calls.push(name.clone());
-                }
-                for arg in args {
-                    Self::collect_calls(arg, calls);
-                }
+    /// Functions that take part in a call cycle, in the order they were
+    /// added to the graph
+    pub fn recursive_functions(&self) -> Vec<String> {
+        let mut recursive = Vec::new();
+        for component in tarjan_scc(&self.graph) {
+            let self_loop = component.len() == 1
+                && self.graph.contains_edge(component[0], component[0]);
+            if component.len() > 1 || self_loop {
+                recursive.extend(component);
             }
-            Expr::BoundedFor { body, .. } => {
-                for e in body {
-                    Self::collect_calls(e, calls);
-                }
-            }
-            Expr::Let { bindings, body } => {
-                for (_, e) in bindings {
-                    Self::collect_calls(e, calls);
-                }
-                for e in body {
-                    Self::collect_calls(e, calls);
-                }
-            }
-            Expr::If {
-                condition,
-                then_branch,
-                else_branch,
-            } => {
-                Self::collect_calls(condition, calls);
-                Self::collect_calls(then_branch, calls);
-                Self::collect_calls(else_branch, calls);
-            }
-            Expr::WithCapability { body, .. } => {
-                for e in body {
-                    Self::collect_calls(e, calls);
-                }
-            }
-            _ => {}
         }
+        recursive.sort();
+        recursive
+            .into_iter()
+            .map(|idx| self.graph[idx].clone())
+            .collect()
     }
 
-    /// Check if the call graph has cycles (recursion)
-    pub fn has_cycles(&self) -> bool {
-        is_cyclic_directed(&self.graph)
+    /// Span of the `defun-deploy` that defines `name`
+    pub fn definition_span(&self, name: &str) -> Option<Span> {
+        self.definitions.get(name).copied()
+    }
+
+    /// Functions that call `name`, in the order they were added
+    pub fn callers(&self, name: &str) -> Vec<String> {
+        let Some(&idx) = self.node_map.get(name) else {
+            return Vec::new();
+        };
+        let mut callers: Vec<NodeIndex> = self
+            .graph
+            .neighbors_directed(idx, petgraph::Direction::Incoming)
+            .collect();
+        callers.sort();
+        callers.dedup();
+        callers
+            .into_iter()
+            .map(|idx| self.graph[idx].clone())
+            .collect()
     }
 
     /// Get topological order of functions (None if cyclic)
@@ -134,6 +127,50 @@ pub fn function_count(&self) -> usize {
     }
 }
 
+/// Collects deploy functions and the named calls made from their bodies
+#[derive(Default)]
+struct CallCollector {
+    functions: Vec<(String, Span)>,
+    calls: Vec<(String, String)>,
+    caller: Option<String>,
+}
+
+impl Visitor for CallCollector {
+    fn visit_defun_deploy(
+        &mut self,
+        name: &str,
+        params: &[Parameter],
+        _return_type: Option<&Type>,
+        body: &[Expr],
+        span: Span,
+    ) {
+        self.functions.push((name.to_string(), span));
+        let outer = self.caller.replace(name.to_string());
+        walk_defun_deploy(self, params, body);
+        self.caller = outer;
+    }
+
+    fn visit_function_call(&mut self, func: &Expr, args: &[Expr], _span: Span) {
+        if let (Some(caller), ExprKind::Ident(name)) = (&self.caller, &func.kind) {
+            self.calls.push((caller.clone(), name.clone()));
+        }
+        walk_function_call(self, func, args);
+    }
+
+    // Compile-time code is not part of the deployed call graph
+    fn visit_defun_compile(
+        &mut self,
+        _name: &str,
+        _params: &[Parameter],
+        _return_type: Option<&Type>,
+        _body: &[Expr],
+        _span: Span,
+    ) {
+    }
+
+    fn visit_macro(&mut self, _name: &str, _params: &[Parameter], _body: &[Expr], _span: Span) {}
+}
+
 impl Default for CallGraph {
     fn default() -> Self {
         Self::new()
@@ -143,57 +180,83 @@ fn default() -> Self {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::ast::types::Parameter;
 
     #[test]
     fn test_acyclic_call_graph() {
-        let exprs = vec![
-            Expr::DefunDeploy {
+        let exprs: Vec<Expr> = vec![
+            ExprKind::DefunDeploy {
                 name: "main".to_string(),
                 params: vec![],
                 return_type: None,
-                body: vec![Expr::FunctionCall {
-                    func: Box::new(Expr::Ident("helper".to_string())),
+                body: vec![ExprKind::FunctionCall {
+                    func: Box::new(ExprKind::Ident("helper".to_string()).into()),
                     args: vec![],
-                }],
-            },
-            Expr::DefunDeploy {
+                }
+                .into()],
+            }
+            .into(),
+            ExprKind::DefunDeploy {
                 name: "helper".to_string(),
                 params: vec![],
                 return_type: None,
-                body: vec![Expr::Int(42)],
-            },
+                body: vec![ExprKind::Int(42.into()).into()],
+            }
+            .into(),
         ];
 
         let cg = CallGraph::build(&exprs);
         assert!(!cg.has_cycles());
         assert_eq!(cg.function_count(), 2);
+        assert_eq!(cg.callers("helper"), vec!["main"]);
+        assert!(cg.callers("main").is_empty());
     }
 
     #[test]
     fn test_cyclic_call_graph() {
-        let exprs = vec![
-            Expr::DefunDeploy {
+        let exprs: Vec<Expr> = vec![
+            ExprKind::DefunDeploy {
                 name: "foo".to_string(),
                 params: vec![],
                 return_type: None,
-                body: vec![Expr::FunctionCall {
-                    func: Box::new(Expr::Ident("bar".to_string())),
+                body: vec![ExprKind::FunctionCall {
+                    func: Box::new(ExprKind::Ident("bar".to_string()).into()),
                     args: vec![],
-                }],
-            },
-            Expr::DefunDeploy {
+                }
+                .into()],
+            }
+            .into(),
+            ExprKind::DefunDeploy {
                 name: "bar".to_string(),
                 params: vec![],
                 return_type: None,
-                body: vec![Expr::FunctionCall {
-                    func: Box::new(Expr::Ident("foo".to_string())),
+                body: vec![ExprKind::FunctionCall {
+                    func: Box::new(ExprKind::Ident("foo".to_string()).into()),
                     args: vec![],
-                }],
-            },
+                }
+                .into()],
+            }
+            .into(),
         ];
 
         let cg = CallGraph::build(&exprs);
         assert!(cg.has_cycles());
     }
+
+    #[test]
+    fn test_calls_inside_program_and_nested_forms() {
+        let source = r#"(program p (resource-budget (time-ms 10))
+  (defun-deploy main (x)
+    (set x (helper (array-get buf (index))))
+    (gpio-set led (helper 1)))
+  (defun-deploy helper (n) (index))
+  (defun-deploy index () 0))"#;
+        let exprs = crate::parse_file(source).unwrap();
+
+        let cg = CallGraph::build(&exprs);
+        assert_eq!(cg.function_count(), 3);
+        assert_eq!(
+            cg.topological_order().unwrap(),
+            vec!["main", "helper", "index"]
+        );
+    }
 }
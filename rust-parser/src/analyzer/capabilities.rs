@@ -0,0 +1,403 @@
+use crate::ast::{
+    walk_bounded_for, walk_defun_deploy, walk_expr, walk_exprs, Expr, ExprKind, Parameter,
+    ResourceType, Span, Type, Visitor,
+};
+use crate::diagnostics::{codes, Diagnostic, ToDiagnostic};
+use std::collections::HashMap;
+use thiserror::Error;
+
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum CapabilityError {
+    #[error("{operation} outside of any with-capability block at {span}")]
+    OutsideScope {
+        operation: &'static str,
+        required: ResourceType,
+        span: Span,
+    },
+
+    #[error("{operation} needs a {required} capability, but {active} is active at {span}")]
+    WrongResource {
+        operation: &'static str,
+        required: ResourceType,
+        active: ResourceType,
+        span: Span,
+        /// The capability of the innermost enclosing `with-capability`
+        scope: Span,
+    },
+
+    #[error("with-capability expects a capability, found {found} at {span}")]
+    NotACapability { found: Type, span: Span },
+}
+
+impl CapabilityError {
+    pub fn span(&self) -> Span {
+        match self {
+            CapabilityError::OutsideScope { span, .. }
+            | CapabilityError::WrongResource { span, .. }
+            | CapabilityError::NotACapability { span, .. } => *span,
+        }
+    }
+}
+
+impl ToDiagnostic for CapabilityError {
+    fn to_diagnostic(&self) -> Diagnostic {
+        match self {
+            CapabilityError::OutsideScope {
+                operation,
+                required,
+                span,
+            } => Diagnostic::error(
+                codes::IO_OUTSIDE_CAPABILITY,
+                format!("`{}` outside of a capability scope", operation),
+                *span,
+            )
+            .with_label("no capability is active here")
+            .with_help(format!(
+                "wrap it in `with-capability` for a `(capability {})`",
+                required
+            )),
+            CapabilityError::WrongResource {
+                operation,
+                required,
+                active,
+                span,
+                scope,
+            } => Diagnostic::error(
+                codes::WRONG_CAPABILITY,
+                format!("`{}` needs a capability for `{}`", operation, required),
+                *span,
+            )
+            .with_label(format!("requires `(capability {})`", required))
+            .with_secondary(*scope, format!("this capability is for `{}`", active)),
+            CapabilityError::NotACapability { found, span } => Diagnostic::error(
+                codes::NOT_A_CAPABILITY,
+                "expected a capability",
+                *span,
+            )
+            .with_label(format!("this is `{}`", found)),
+        }
+    }
+}
+
+/// Capability granted by an enclosing `with-capability`
+struct Active {
+    /// `None` when the capability's type is not known
+    resource: Option<ResourceType>,
+    span: Span,
+}
+
+/// Variables with a known type
+type Scope = HashMap<String, Type>;
+
+/// Checks that every I/O operation in deploy-time code sits inside a
+/// `with-capability` block for the resource it uses.
+///
+/// Capabilities are tracked lexically within each function: a callee does
+/// not inherit its caller's scopes, so it has to open its own from the
+/// capability parameters it is passed. Like the type checker, this only
+/// reports what it knows; an operation under a capability of unknown type
+/// is accepted.
+pub struct CapabilityChecker {
+    /// Resources of the `defcap`s that declare one
+    defcaps: HashMap<String, ResourceType>,
+    scope: Scope,
+    active: Vec<Active>,
+    errors: Vec<CapabilityError>,
+}
+
+impl CapabilityChecker {
+    pub fn new() -> Self {
+        Self {
+            defcaps: HashMap::new(),
+            scope: Scope::new(),
+            active: Vec::new(),
+            errors: Vec::new(),
+        }
+    }
+
+    pub fn check(mut self, exprs: &[Expr]) -> Vec<CapabilityError> {
+        let programs = exprs.iter().flat_map(|expr| match &expr.kind {
+            ExprKind::Program { forms, .. } => forms.as_slice(),
+            _ => std::slice::from_ref(expr),
+        });
+        for form in programs {
+            if let ExprKind::DefCap {
+                name,
+                resource: Some(resource),
+                ..
+            } = &form.kind
+            {
+                self.defcaps.insert(name.clone(), resource.clone());
+            }
+        }
+        walk_exprs(&mut self, exprs);
+        self.errors
+    }
+
+    /// Resource an I/O operation needs, with the operation's keyword
+    pub fn required_resource(expr: &Expr) -> Option<(&'static str, ResourceType)> {
+        let resource = match &expr.kind {
+            ExprKind::GpioSet { .. } | ExprKind::GpioGet(_) => ResourceType::Gpio,
+            ExprKind::UartSend { .. } => ResourceType::UartTx,
+            ExprKind::UartRecv(_) => ResourceType::UartRx,
+            ExprKind::SensorRead(_) => ResourceType::SensorRead,
+            ExprKind::NetworkSend { .. } => ResourceType::NetworkSend,
+            ExprKind::NetworkRecv(_) => ResourceType::NetworkRecv,
+            _ => return None,
+        };
+        Some((expr.keyword()?, resource))
+    }
+
+    /// Resource of the capability `expr` evaluates to, if known
+    fn resource_of(&mut self, expr: &Expr) -> Option<ResourceType> {
+        let name = match &expr.kind {
+            ExprKind::Ident(name) => name,
+            ExprKind::FunctionCall { func, .. } => {
+                let ExprKind::Ident(callee) = &func.kind else {
+                    return None;
+                };
+                return self.defcaps.get(callee).cloned();
+            }
+            _ => return None,
+        };
+        match self.scope.get(name)? {
+            Type::Capability { resource } => Some(resource.clone()),
+            found => {
+                self.errors.push(CapabilityError::NotACapability {
+                    found: found.clone(),
+                    span: expr.span,
+                });
+                None
+            }
+        }
+    }
+
+    fn check_operation(&mut self, operation: &'static str, required: ResourceType, span: Span) {
+        let granted = self
+            .active
+            .iter()
+            .any(|a| a.resource.as_ref().is_none_or(|r| *r == required));
+        if granted {
+            return;
+        }
+        let error = match self.active.last() {
+            Some(Active {
+                resource: Some(active),
+                span: scope,
+            }) => CapabilityError::WrongResource {
+                operation,
+                required,
+                active: active.clone(),
+                span,
+                scope: *scope,
+            },
+            _ => CapabilityError::OutsideScope {
+                operation,
+                required,
+                span,
+            },
+        };
+        self.errors.push(error);
+    }
+}
+
+impl Default for CapabilityChecker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Visitor for CapabilityChecker {
+    fn visit_expr(&mut self, expr: &Expr) {
+        if let Some((operation, required)) = Self::required_resource(expr) {
+            self.check_operation(operation, required, expr.span);
+        }
+        walk_expr(self, expr)
+    }
+
+    fn visit_defun_deploy(
+        &mut self,
+        _name: &str,
+        params: &[Parameter],
+        _return_type: Option<&Type>,
+        body: &[Expr],
+        _span: Span,
+    ) {
+        let scope = params
+            .iter()
+            .filter_map(|p| Some((p.name.clone(), p.ty()?.clone())))
+            .collect();
+        let outer_scope = std::mem::replace(&mut self.scope, scope);
+        let outer_active = std::mem::take(&mut self.active);
+        walk_defun_deploy(self, params, body);
+        self.scope = outer_scope;
+        self.active = outer_active;
+    }
+
+    fn visit_with_capability(&mut self, capability: &Expr, body: &[Expr], _span: Span) {
+        self.visit_expr(capability);
+        let resource = self.resource_of(capability);
+        self.active.push(Active {
+            resource,
+            span: capability.span,
+        });
+        walk_exprs(self, body);
+        self.active.pop();
+    }
+
+    // The values are checked in the outer scope
+    fn visit_let(&mut self, bindings: &[(String, Expr)], body: &[Expr], _span: Span) {
+        for (_, value) in bindings {
+            self.visit_expr(value);
+        }
+        let outer = self.scope.clone();
+        for (name, value) in bindings {
+            match &value.ty {
+                Some(ty) => self.scope.insert(name.clone(), ty.clone()),
+                None => self.scope.remove(name),
+            };
+        }
+        walk_exprs(self, body);
+        self.scope = outer;
+    }
+
+    fn visit_bounded_for(
+        &mut self,
+        var: &str,
+        start: &Expr,
+        end: &Expr,
+        body: &[Expr],
+        _span: Span,
+    ) {
+        let outer = self.scope.clone();
+        self.scope.insert(var.to_string(), Type::Int32);
+        walk_bounded_for(self, start, end, body);
+        self.scope = outer;
+    }
+
+    // Compile-time code performs no I/O on the device
+    fn visit_defun_compile(
+        &mut self,
+        _name: &str,
+        _params: &[Parameter],
+        _return_type: Option<&Type>,
+        _body: &[Expr],
+        _span: Span,
+    ) {
+    }
+
+    fn visit_macro(&mut self, _name: &str, _params: &[Parameter], _body: &[Expr], _span: Span) {}
+
+    fn visit_eval_compile(&mut self, _expr: &Expr, _span: Span) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analyzer::TypeInference;
+    use crate::parse_file;
+
+    fn check(source: &str) -> Vec<CapabilityError> {
+        let mut exprs = parse_file(source).unwrap();
+        TypeInference::new(&exprs).infer(&mut exprs);
+        CapabilityChecker::new().check(&exprs)
+    }
+
+    #[test]
+    fn test_example_io_is_in_scope() {
+        let source = include_str!("../../../examples/temperature-monitor.obl");
+        assert_eq!(check(source), vec![]);
+    }
+
+    #[test]
+    fn test_io_outside_scope() {
+        let errors = check(
+            r#"
+(defun-deploy blink (led) : void
+  (gpio-set led 1)
+  (with-capability led
+    (gpio-set led 0)))
+"#,
+        );
+        assert_eq!(errors.len(), 1);
+        let CapabilityError::OutsideScope {
+            operation,
+            required,
+            span,
+        } = &errors[0]
+        else {
+            panic!("expected an out-of-scope error, found {:?}", errors[0]);
+        };
+        assert_eq!((*operation, required), ("gpio-set", &ResourceType::Gpio));
+        assert_eq!(span.line, 3);
+    }
+
+    #[test]
+    fn test_wrong_resource() {
+        let errors = check(
+            r#"
+(defun-deploy report ((sensor (capability sensor-read)) link) : void
+  (with-capability sensor
+    (network-send link (sensor-read sensor))))
+"#,
+        );
+        assert_eq!(errors.len(), 1);
+        let diagnostic = errors[0].to_diagnostic();
+        assert_eq!(diagnostic.code, codes::WRONG_CAPABILITY);
+        assert_eq!(diagnostic.primary.message, "requires `(capability network-send)`");
+        assert_eq!(diagnostic.secondary[0].message, "this capability is for `sensor-read`");
+        assert_eq!(diagnostic.secondary[0].span.line, 3);
+    }
+
+    #[test]
+    fn test_nested_scopes_and_callees() {
+        let errors = check(
+            r#"
+(defun-deploy relay (rx tx) : void
+  (with-capability rx
+    (with-capability tx
+      (uart-send tx (uart-recv rx))))
+  (log tx))
+(defun-deploy log (tx) : void
+  (uart-send tx 0))
+"#,
+        );
+        // The caller's scope does not extend into `log`
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].span().line, 8);
+    }
+
+    #[test]
+    fn test_defcap_call_grants_its_resource() {
+        let errors = check(
+            r#"
+(defcap led (pin) : (capability gpio) "Status LED")
+(defun-deploy blink ((pin int32) link) : void
+  (with-capability (led pin)
+    (gpio-set link 1)
+    (uart-send link 0)))
+"#,
+        );
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].to_diagnostic().code, codes::WRONG_CAPABILITY);
+        assert_eq!(errors[0].span().line, 6);
+    }
+
+    #[test]
+    fn test_not_a_capability() {
+        let errors = check(
+            r#"
+(defun-deploy f ((n int32) led) : void
+  (with-capability n
+    (gpio-set led 1)))
+"#,
+        );
+        assert_eq!(
+            errors,
+            vec![CapabilityError::NotACapability {
+                found: Type::Int32,
+                span: errors[0].span(),
+            }]
+        );
+    }
+}
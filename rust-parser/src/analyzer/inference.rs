@@ -0,0 +1,875 @@
+use crate::ast::{
+    walk_expr_mut, walk_exprs_mut, Expr, ExprKind, MutVisitor, Parameter, ResourceType, Span, Type,
+};
+use crate::diagnostics::{codes, Diagnostic, ToDiagnostic};
+use std::collections::HashMap;
+use thiserror::Error;
+
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum InferenceError {
+    #[error("Cannot infer the type of {name}, only that it is {known} at {span}")]
+    Ambiguous {
+        name: String,
+        /// What is known, with `_` for the undetermined parts
+        known: String,
+        span: Span,
+    },
+}
+
+impl InferenceError {
+    pub fn span(&self) -> Span {
+        match self {
+            InferenceError::Ambiguous { span, .. } => *span,
+        }
+    }
+}
+
+impl ToDiagnostic for InferenceError {
+    fn to_diagnostic(&self) -> Diagnostic {
+        match self {
+            InferenceError::Ambiguous { name, known, span } => Diagnostic::warning(
+                codes::AMBIGUOUS_TYPE,
+                format!("cannot infer the type of `{}`", name),
+                *span,
+            )
+            .with_label(format!("known to be `{}`", known))
+            .with_help("add a type annotation"),
+        }
+    }
+}
+
+/// A type variable of the solver
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct Var(usize);
+
+#[derive(Debug, Clone)]
+enum Term {
+    /// Not yet determined; unsuffixed literals carry the type they default to
+    Unknown {
+        default: Option<Type>,
+    },
+    /// Any type other than an array
+    Known(Type),
+    Array {
+        elem: Var,
+        size: Var,
+    },
+    /// The length of an array type
+    Size(usize),
+}
+
+/// Union-find over type terms, one per function
+#[derive(Default)]
+struct Solver {
+    terms: Vec<Term>,
+    parent: Vec<usize>,
+}
+
+impl Solver {
+    fn add(&mut self, term: Term) -> Var {
+        self.parent.push(self.terms.len());
+        self.terms.push(term);
+        Var(self.terms.len() - 1)
+    }
+
+    fn fresh(&mut self) -> Var {
+        self.add(Term::Unknown { default: None })
+    }
+
+    fn known(&mut self, ty: &Type) -> Var {
+        match ty {
+            Type::Array { elem_type, size } => {
+                let elem = self.known(elem_type);
+                let size = self.add(Term::Size(*size));
+                self.add(Term::Array { elem, size })
+            }
+            ty => self.add(Term::Known(ty.clone())),
+        }
+    }
+
+    /// An array type with fresh element type and size
+    fn array(&mut self) -> (Var, Var, Var) {
+        let elem = self.fresh();
+        let size = self.fresh();
+        (self.add(Term::Array { elem, size }), elem, size)
+    }
+
+    fn find(&mut self, var: Var) -> usize {
+        let mut root = var.0;
+        while self.parent[root] != root {
+            root = self.parent[root];
+        }
+        self.parent[var.0] = root;
+        root
+    }
+
+    /// Make `a` and `b` the same type. Conflicts are left for the type
+    /// checker to report, with whatever was inferred before them.
+    fn unify(&mut self, a: Var, b: Var) {
+        let (a, b) = (self.find(a), self.find(b));
+        if a == b {
+            return;
+        }
+        match (self.terms[a].clone(), self.terms[b].clone()) {
+            (Term::Unknown { default: first }, Term::Unknown { default: second }) => {
+                self.parent[a] = b;
+                self.terms[b] = Term::Unknown {
+                    default: second.or(first),
+                };
+            }
+            (Term::Unknown { default }, other) | (other, Term::Unknown { default }) => {
+                let accepts = match (&default, &other) {
+                    (None, _) => true,
+                    (Some(default), Term::Known(ty)) => {
+                        (default.is_integer() && ty.is_integer())
+                            || (default.is_float() && ty.is_float())
+                    }
+                    (Some(_), _) => false,
+                };
+                if accepts {
+                    let (unknown, known) = match self.terms[a] {
+                        Term::Unknown { .. } => (a, b),
+                        _ => (b, a),
+                    };
+                    self.parent[unknown] = known;
+                }
+            }
+            (Term::Array { elem, size }, Term::Array { elem: e, size: s }) => {
+                self.parent[a] = b;
+                self.unify(elem, e);
+                self.unify(size, s);
+            }
+            _ => {}
+        }
+    }
+
+    fn unify_known(&mut self, var: Var, ty: &Type) {
+        let known = self.known(ty);
+        self.unify(var, known);
+    }
+
+    /// The type `var` stands for, if fully determined. Literal defaults
+    /// only count when `defaults` is set.
+    fn resolve(&mut self, var: Var, defaults: bool) -> Option<Type> {
+        let root = self.find(var);
+        match self.terms[root].clone() {
+            Term::Unknown { default } => default.filter(|_| defaults),
+            Term::Known(ty) => Some(ty),
+            Term::Array { elem, size } => {
+                let root = self.find(size);
+                let Term::Size(size) = self.terms[root] else {
+                    return None;
+                };
+                Some(Type::Array {
+                    elem_type: Box::new(self.resolve(elem, defaults)?),
+                    size,
+                })
+            }
+            Term::Size(_) => None,
+        }
+    }
+
+    /// What is known about `var`, with `_` for the undetermined parts
+    fn describe(&mut self, var: Var) -> String {
+        let root = self.find(var);
+        match self.terms[root].clone() {
+            Term::Unknown { default: Some(ty) } | Term::Known(ty) => ty.to_string(),
+            Term::Unknown { default: None } => "_".to_string(),
+            Term::Array { elem, size } => {
+                format!("(array {} {})", self.describe(elem), self.describe(size))
+            }
+            Term::Size(size) => size.to_string(),
+        }
+    }
+}
+
+/// Parameter and return types of a deploy-time function, as declared or
+/// inferred so far
+#[derive(Clone)]
+struct Signature {
+    params: Vec<Option<Type>>,
+    return_type: Option<Type>,
+}
+
+/// Identifies a node of the tree being inferred. The tree is not modified
+/// until every function has been solved, so addresses are stable.
+type NodeId = *const Expr;
+
+/// What inference found for one function
+struct Solution {
+    params: Vec<Option<Type>>,
+    return_type: Option<Type>,
+    /// Types of the bindings of each `let`
+    lets: Vec<(NodeId, Vec<Option<Type>>)>,
+    /// Argument types seen at calls: callee, parameter index, type
+    seeds: Vec<(String, usize, Type)>,
+    ambiguous: Vec<InferenceError>,
+}
+
+/// Infers the types of unannotated parameters and `let` bindings of
+/// deploy-time functions.
+///
+/// Constraints come from annotations, literals, operator signatures, array
+/// operations and calls, and are solved per function. Solved signatures
+/// are shared: a callee's parameter types constrain the arguments passed to
+/// it, and an argument whose type is known seeds the callee's parameter
+/// when nothing inside the callee decides it. This repeats until no
+/// signature changes.
+///
+/// Array sizes have a fallback like literals do: an array indexed by a
+/// loop that runs over every index of another array is taken to have the
+/// same size, so copying a parameter into a local array sizes the
+/// parameter. Results are stored in `Parameter::inferred_type` and
+/// in the `ty` of each `let`-bound value.
+pub struct TypeInference {
+    signatures: HashMap<String, Signature>,
+}
+
+type Scope = HashMap<String, Var>;
+
+impl TypeInference {
+    pub fn new(exprs: &[Expr]) -> Self {
+        let mut signatures = HashMap::new();
+        for function in Self::functions(exprs) {
+            if let ExprKind::DefunDeploy {
+                name,
+                params,
+                return_type,
+                ..
+            } = &function.kind
+            {
+                let signature = Signature {
+                    params: params.iter().map(|p| p.type_annotation.clone()).collect(),
+                    return_type: return_type.clone(),
+                };
+                signatures.insert(name.clone(), signature);
+            }
+        }
+        Self { signatures }
+    }
+
+    /// Infer and record types, returning what could not be decided
+    pub fn infer(mut self, exprs: &mut [Expr]) -> Vec<InferenceError> {
+        // Literals fall back to their default type only once nothing else
+        // can decide, so that a later constraint is not preempted
+        let mut solutions = HashMap::new();
+        for defaults in [false, true] {
+            loop {
+                let mut changed = false;
+                let mut seeds = Vec::new();
+                for function in Self::functions(exprs) {
+                    let solution =
+                        FunctionInference::new(&self.signatures, defaults).solve(function);
+                    if let ExprKind::DefunDeploy { name, .. } = &function.kind {
+                        changed |= self.update(name, &solution.params, &solution.return_type);
+                    }
+                    seeds.extend(solution.seeds.iter().cloned());
+                    solutions.insert(function as NodeId, solution);
+                }
+                for (callee, index, ty) in seeds {
+                    changed |= self.seed(&callee, index, ty);
+                }
+                if !changed {
+                    break;
+                }
+            }
+        }
+
+        let mut errors = Vec::new();
+        let mut lets = HashMap::new();
+        for solution in solutions.values_mut() {
+            errors.append(&mut solution.ambiguous);
+            lets.extend(solution.lets.drain(..));
+        }
+        errors.sort_by_key(|e| (e.span().file.0, e.span().start));
+
+        let mut recorder = Recorder {
+            solutions: &solutions,
+            lets: &lets,
+        };
+        walk_exprs_mut(&mut recorder, exprs);
+        errors
+    }
+
+    /// Deploy-time functions, including those inside a program
+    fn functions(exprs: &[Expr]) -> Vec<&Expr> {
+        let mut functions = Vec::new();
+        for expr in exprs {
+            match &expr.kind {
+                ExprKind::DefunDeploy { .. } => functions.push(expr),
+                ExprKind::Program { forms, .. } => functions.extend(Self::functions(forms)),
+                _ => {}
+            }
+        }
+        functions
+    }
+
+    /// Fill in the undecided parts of `name`'s signature
+    fn update(&mut self, name: &str, params: &[Option<Type>], return_type: &Option<Type>) -> bool {
+        let Some(signature) = self.signatures.get_mut(name) else {
+            return false;
+        };
+        let mut changed = false;
+        for (slot, ty) in signature.params.iter_mut().zip(params) {
+            if slot.is_none() && ty.is_some() {
+                *slot = ty.clone();
+                changed = true;
+            }
+        }
+        if signature.return_type.is_none() && return_type.is_some() {
+            signature.return_type = return_type.clone();
+            changed = true;
+        }
+        changed
+    }
+
+    fn seed(&mut self, callee: &str, index: usize, ty: Type) -> bool {
+        match self
+            .signatures
+            .get_mut(callee)
+            .and_then(|s| s.params.get_mut(index))
+        {
+            Some(slot @ None) => {
+                *slot = Some(ty);
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Writes solved types back into the tree: unannotated parameters get
+/// `inferred_type`, `let` values get `ty`
+struct Recorder<'a> {
+    solutions: &'a HashMap<NodeId, Solution>,
+    lets: &'a HashMap<NodeId, Vec<Option<Type>>>,
+}
+
+impl MutVisitor for Recorder<'_> {
+    fn visit_expr_mut(&mut self, expr: &mut Expr) {
+        let id = expr as *const Expr;
+        if let Some(solution) = self.solutions.get(&id) {
+            if let ExprKind::DefunDeploy { params, .. } = &mut expr.kind {
+                for (param, ty) in params.iter_mut().zip(&solution.params) {
+                    if param.type_annotation.is_none() {
+                        param.inferred_type = ty.clone();
+                    }
+                }
+            }
+        }
+        if let (Some(types), ExprKind::Let { bindings, .. }) = (self.lets.get(&id), &mut expr.kind)
+        {
+            for ((_, value), ty) in bindings.iter_mut().zip(types) {
+                value.ty = ty.clone();
+            }
+        }
+        walk_expr_mut(self, expr);
+    }
+}
+
+/// A `let` binding's name, value span and type
+type Binding = (String, Span, Var);
+
+/// Constraint generation and solving for a single function
+struct FunctionInference<'a> {
+    signatures: &'a HashMap<String, Signature>,
+    /// Whether literals may fall back to their default type
+    defaults: bool,
+    solver: Solver,
+    /// Bindings of each `let`, with their names and spans
+    lets: Vec<(NodeId, Vec<Binding>)>,
+    /// Arguments passed to unannotated parameters of other functions
+    calls: Vec<(String, usize, Var)>,
+    /// Size of the array each `array-length` result measures
+    lengths: HashMap<Var, Var>,
+    /// Size of the array whose indices each `bounded-for` variable runs over
+    loops: HashMap<Var, Var>,
+    /// Sizes of arrays indexed in lockstep, equal unless decided otherwise
+    lockstep: Vec<(Var, Var)>,
+}
+
+impl<'a> FunctionInference<'a> {
+    fn new(signatures: &'a HashMap<String, Signature>, defaults: bool) -> Self {
+        Self {
+            signatures,
+            defaults,
+            solver: Solver::default(),
+            lets: Vec::new(),
+            calls: Vec::new(),
+            lengths: HashMap::new(),
+            loops: HashMap::new(),
+            lockstep: Vec::new(),
+        }
+    }
+
+    fn solve(mut self, function: &Expr) -> Solution {
+        let ExprKind::DefunDeploy {
+            name,
+            params,
+            return_type,
+            body,
+        } = &function.kind
+        else {
+            unreachable!("only deploy-time functions are inferred");
+        };
+
+        // Parameters start from their declared or previously inferred type
+        let signature = self.signatures.get(name).cloned();
+        let mut scope = Scope::new();
+        let mut param_vars = Vec::new();
+        for (i, param) in params.iter().enumerate() {
+            let known = signature
+                .as_ref()
+                .and_then(|s| s.params.get(i).cloned().flatten());
+            let var = match known.or_else(|| param.type_annotation.clone()) {
+                Some(ty) => self.solver.known(&ty),
+                None => self.solver.fresh(),
+            };
+            scope.insert(param.name.clone(), var);
+            param_vars.push(var);
+        }
+
+        let result = self.infer_body(body, &scope);
+        if let Some(ty) = return_type {
+            self.solver.unify_known(result, ty);
+        }
+        if self.defaults {
+            for (a, b) in std::mem::take(&mut self.lockstep) {
+                self.solver.unify(a, b);
+            }
+        }
+
+        let mut ambiguous = Vec::new();
+        let params = self.resolve_params(params, &param_vars, &mut ambiguous);
+        let return_type = self.solver.resolve(result, self.defaults);
+
+        let mut lets = Vec::new();
+        for (id, bindings) in std::mem::take(&mut self.lets) {
+            let types = bindings
+                .into_iter()
+                .map(|(name, span, var)| {
+                    let ty = self.solver.resolve(var, self.defaults);
+                    if ty.is_none() {
+                        ambiguous.push(self.ambiguous(name, span, var));
+                    }
+                    ty
+                })
+                .collect();
+            lets.push((id, types));
+        }
+
+        let seeds = std::mem::take(&mut self.calls)
+            .into_iter()
+            .filter_map(|(callee, i, var)| {
+                Some((callee, i, self.solver.resolve(var, self.defaults)?))
+            })
+            .collect();
+
+        Solution {
+            params,
+            return_type,
+            lets,
+            seeds,
+            ambiguous,
+        }
+    }
+
+    fn resolve_params(
+        &mut self,
+        params: &[Parameter],
+        vars: &[Var],
+        ambiguous: &mut Vec<InferenceError>,
+    ) -> Vec<Option<Type>> {
+        params
+            .iter()
+            .zip(vars)
+            .map(|(param, &var)| {
+                let ty = self.solver.resolve(var, self.defaults);
+                if ty.is_none() {
+                    ambiguous.push(self.ambiguous(param.name.clone(), param.span, var));
+                }
+                ty
+            })
+            .collect()
+    }
+
+    fn ambiguous(&mut self, name: String, span: Span, var: Var) -> InferenceError {
+        InferenceError::Ambiguous {
+            name,
+            known: self.solver.describe(var),
+            span,
+        }
+    }
+
+    fn infer_body(&mut self, body: &[Expr], scope: &Scope) -> Var {
+        let mut result = None;
+        for expr in body {
+            result = Some(self.infer(expr, scope));
+        }
+        result.unwrap_or_else(|| self.solver.known(&Type::Void))
+    }
+
+    fn infer(&mut self, expr: &Expr, scope: &Scope) -> Var {
+        match &expr.kind {
+            ExprKind::Int(literal) => match &literal.suffix {
+                Some(ty) => self.solver.known(ty),
+                None => self.solver.add(Term::Unknown {
+                    default: Some(Type::Int32),
+                }),
+            },
+            ExprKind::Float(_) => self.solver.add(Term::Unknown {
+                default: Some(Type::Float64),
+            }),
+            ExprKind::Bool(_) => self.solver.known(&Type::Bool),
+            ExprKind::String(_) => self.solver.known(&Type::String),
+
+            ExprKind::Ident(name) => match scope.get(name) {
+                Some(var) => *var,
+                None => self.function_type(name),
+            },
+
+            ExprKind::Let { bindings, body } => {
+                // Values are inferred in the outer scope, as in T-Let
+                let slot = self.lets.len();
+                self.lets.push((expr as NodeId, Vec::new()));
+                let mut inner = scope.clone();
+                let mut vars = Vec::new();
+                for (name, value) in bindings {
+                    let var = self.infer(value, scope);
+                    inner.insert(name.clone(), var);
+                    vars.push((name.clone(), value.span, var));
+                }
+                self.lets[slot].1 = vars;
+                self.infer_body(body, &inner)
+            }
+
+            ExprKind::Set { var, value } => {
+                let value = self.infer(value, scope);
+                if let Some(var) = scope.get(var) {
+                    self.solver.unify(*var, value);
+                }
+                self.solver.known(&Type::Void)
+            }
+
+            ExprKind::If {
+                condition,
+                then_branch,
+                else_branch,
+            } => {
+                let condition = self.infer(condition, scope);
+                self.solver.unify_known(condition, &Type::Bool);
+                let then_branch = self.infer(then_branch, scope);
+                let else_branch = self.infer(else_branch, scope);
+                self.solver.unify(then_branch, else_branch);
+                then_branch
+            }
+
+            ExprKind::BoundedFor {
+                var,
+                start,
+                end,
+                body,
+            } => {
+                let mut bounds = Vec::new();
+                for bound in [start, end] {
+                    let bound = self.infer(bound, scope);
+                    self.solver.unify_known(bound, &Type::Int32);
+                    bounds.push(bound);
+                }
+                let mut scope = scope.clone();
+                let index = self.solver.known(&Type::Int32);
+                scope.insert(var.clone(), index);
+                let from_zero = matches!(&start.kind, ExprKind::Int(literal) if literal.value == 0);
+                if let (true, Some(&size)) = (from_zero, self.lengths.get(&bounds[1])) {
+                    self.loops.insert(index, size);
+                }
+                self.infer_body(body, &scope);
+                self.solver.known(&Type::Void)
+            }
+
+            ExprKind::WithCapability { capability, body } => {
+                self.infer(capability, scope);
+                self.infer_body(body, scope)
+            }
+
+            ExprKind::FunctionCall { func, args } => self.infer_call(func, args, scope),
+
+            ExprKind::ArrayLiteral { elem_type, size } => self.solver.known(&Type::Array {
+                elem_type: Box::new(elem_type.clone()),
+                size: *size,
+            }),
+            ExprKind::ArrayInit {
+                elem_type,
+                elements,
+            } => {
+                for element in elements {
+                    let element = self.infer(element, scope);
+                    self.solver.unify_known(element, elem_type);
+                }
+                self.solver.known(&Type::Array {
+                    elem_type: Box::new(elem_type.clone()),
+                    size: elements.len(),
+                })
+            }
+            ExprKind::ArrayGet { array, index } => self.infer_array(array, index, scope),
+            ExprKind::ArraySet {
+                array,
+                index,
+                value,
+            } => {
+                let elem = self.infer_array(array, index, scope);
+                let value = self.infer(value, scope);
+                self.solver.unify(elem, value);
+                self.solver.known(&Type::Void)
+            }
+            ExprKind::ArrayLength(array) => {
+                let array = self.infer(array, scope);
+                let (expected, _, size) = self.solver.array();
+                self.solver.unify(array, expected);
+                let length = self.solver.known(&Type::Int32);
+                self.lengths.insert(length, size);
+                length
+            }
+
+            // Devices are used through the capability for their resource
+            ExprKind::GpioSet { device, value } => {
+                self.infer_device(device, ResourceType::Gpio, scope);
+                self.infer(value, scope);
+                self.solver.known(&Type::Void)
+            }
+            ExprKind::UartSend { device, data } => {
+                self.infer_device(device, ResourceType::UartTx, scope);
+                self.infer(data, scope);
+                self.solver.known(&Type::Void)
+            }
+            ExprKind::NetworkSend { device, data } => {
+                self.infer_device(device, ResourceType::NetworkSend, scope);
+                self.infer(data, scope);
+                self.solver.known(&Type::Void)
+            }
+            ExprKind::GpioGet(device) => {
+                self.infer_device(device, ResourceType::Gpio, scope);
+                self.solver.fresh()
+            }
+            ExprKind::UartRecv(device) => {
+                self.infer_device(device, ResourceType::UartRx, scope);
+                self.solver.fresh()
+            }
+            ExprKind::SensorRead(device) => {
+                self.infer_device(device, ResourceType::SensorRead, scope);
+                self.solver.fresh()
+            }
+            ExprKind::NetworkRecv(device) => {
+                self.infer_device(device, ResourceType::NetworkRecv, scope);
+                self.solver.fresh()
+            }
+            ExprKind::SleepMs(duration) => {
+                self.infer(duration, scope);
+                self.solver.known(&Type::Void)
+            }
+            ExprKind::Timestamp => self.solver.fresh(),
+
+            // Compile-time code and declarations are not inferred
+            ExprKind::DefunDeploy { .. }
+            | ExprKind::DefunCompile { .. }
+            | ExprKind::Macro { .. }
+            | ExprKind::EvalCompile(_)
+            | ExprKind::Include(_)
+            | ExprKind::For { .. }
+            | ExprKind::While { .. }
+            | ExprKind::ResourceBudget { .. }
+            | ExprKind::DefCap { .. }
+            | ExprKind::Program { .. } => self.solver.fresh(),
+        }
+    }
+
+    /// Element type of `array`, which is indexed by `index`
+    fn infer_array(&mut self, array: &Expr, index: &Expr, scope: &Scope) -> Var {
+        let array = self.infer(array, scope);
+        let (expected, elem, size) = self.solver.array();
+        self.solver.unify(array, expected);
+        let index = self.infer(index, scope);
+        self.solver.unify_known(index, &Type::Int32);
+        if let Some(&range) = self.loops.get(&index) {
+            self.lockstep.push((range, size));
+        }
+        elem
+    }
+
+    fn infer_device(&mut self, device: &Expr, resource: ResourceType, scope: &Scope) {
+        let device = self.infer(device, scope);
+        self.solver
+            .unify_known(device, &Type::Capability { resource });
+    }
+
+    /// Type of a function used as a value, if fully known
+    fn function_type(&mut self, name: &str) -> Var {
+        let ty = self.signatures.get(name).and_then(|signature| {
+            Some(Type::Function {
+                params: signature.params.iter().cloned().collect::<Option<_>>()?,
+                return_type: Box::new(signature.return_type.clone()?),
+            })
+        });
+        match ty {
+            Some(ty) => self.solver.known(&ty),
+            None => self.solver.fresh(),
+        }
+    }
+
+    fn infer_call(&mut self, func: &Expr, args: &[Expr], scope: &Scope) -> Var {
+        let args: Vec<Var> = args.iter().map(|arg| self.infer(arg, scope)).collect();
+
+        let name = match &func.kind {
+            ExprKind::Ident(name) if !scope.contains_key(name) => name,
+            _ => {
+                self.infer(func, scope);
+                return self.solver.fresh();
+            }
+        };
+
+        if let Some(signature) = self.signatures.get(name) {
+            if signature.params.len() == args.len() {
+                for (i, (&arg, param)) in args.iter().zip(&signature.params).enumerate() {
+                    match param {
+                        Some(ty) => self.solver.unify_known(arg, ty),
+                        None => self.calls.push((name.clone(), i, arg)),
+                    }
+                }
+            }
+            return match &signature.return_type {
+                Some(ty) => self.solver.known(ty),
+                None => self.solver.fresh(),
+            };
+        }
+
+        match name.as_str() {
+            // One type for the operands and the result
+            "+" | "-" | "*" | "/" | "mod" | "bitwise-and" | "bitwise-or" | "bitwise-xor"
+            | "bitwise-not" => self.unify_all(&args),
+            "<" | "<=" | ">" | ">=" | "=" | "!=" => {
+                self.unify_all(&args);
+                self.solver.known(&Type::Bool)
+            }
+            "and" | "or" | "not" => {
+                for &arg in &args {
+                    self.solver.unify_known(arg, &Type::Bool);
+                }
+                self.solver.known(&Type::Bool)
+            }
+            // The shift amount is independent of the value shifted
+            "bit-shift-left" | "bit-shift-right" => match args.first() {
+                Some(&value) => value,
+                None => self.solver.fresh(),
+            },
+            _ => self.solver.fresh(),
+        }
+    }
+
+    fn unify_all(&mut self, vars: &[Var]) -> Var {
+        let Some((&first, rest)) = vars.split_first() else {
+            return self.solver.fresh();
+        };
+        for &var in rest {
+            self.solver.unify(first, var);
+        }
+        first
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_file;
+
+    fn infer(source: &str) -> (Vec<Expr>, Vec<InferenceError>) {
+        let mut exprs = parse_file(source).unwrap();
+        let errors = TypeInference::new(&exprs).infer(&mut exprs);
+        (exprs, errors)
+    }
+
+    fn param_types(expr: &Expr) -> Vec<String> {
+        let ExprKind::DefunDeploy { params, .. } = &expr.kind else {
+            panic!("expected defun-deploy");
+        };
+        params
+            .iter()
+            .map(|p| match &p.inferred_type {
+                Some(ty) => ty.to_string(),
+                None => "_".to_string(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_params_and_lets_from_body() {
+        let source = r#"
+(defun-deploy f (buf n flag) : uint8
+  (let ((total 0u8)
+        (limit n))
+    (bounded-for i 0 limit
+      (set total (bitwise-xor total (array-get buf i))))
+    (if flag total 0)))
+"#;
+        let (exprs, errors) = infer(source);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(param_types(&exprs[0]), vec!["_", "int32", "bool"]);
+
+        let ExprKind::DefunDeploy { body, .. } = &exprs[0].kind else {
+            unreachable!();
+        };
+        let ExprKind::Let { bindings, .. } = &body[0].kind else {
+            panic!("expected let");
+        };
+        assert_eq!(bindings[0].1.ty, Some(Type::Uint8));
+        assert_eq!(bindings[1].1.ty, Some(Type::Int32));
+
+        let json = serde_json::to_string(&exprs[0]).unwrap();
+        assert!(json.contains(r#""inferred_type":"Bool""#), "{}", json);
+    }
+
+    #[test]
+    fn test_call_sites_seed_parameters() {
+        let source = r#"
+(defun-deploy main ((data (array uint8 4)))
+  (checksum data 2))
+(defun-deploy checksum (bytes count)
+  (let ((sum 0))
+    (bounded-for i 0 count
+      (set sum (+ sum (array-get bytes i))))
+    sum))
+(defun-deploy report (x) (checksum x 4))
+"#;
+        let (exprs, errors) = infer(source);
+        assert_eq!(errors, vec![]);
+        assert_eq!(param_types(&exprs[1]), vec!["(array uint8 4)", "int32"]);
+        assert_eq!(param_types(&exprs[2]), vec!["(array uint8 4)"]);
+    }
+
+    #[test]
+    fn test_lockstep_copy_sizes_parameter() {
+        let source = r#"
+(defun-deploy main (input) (copy input))
+(defun-deploy copy (src) : (array uint8 8)
+  (let ((dst (array uint8 8))
+        (len (array-length src)))
+    (bounded-for i 0 len
+      (array-set dst i (array-get src i)))
+    dst))
+"#;
+        let (exprs, errors) = infer(source);
+        assert_eq!(errors, vec![]);
+        assert_eq!(param_types(&exprs[0]), vec!["(array uint8 8)"]);
+        assert_eq!(param_types(&exprs[1]), vec!["(array uint8 8)"]);
+    }
+
+    #[test]
+    fn test_ambiguity_is_reported() {
+        let source = "(defun-deploy first (xs) (array-get xs 0))";
+        let (_, errors) = infer(source);
+        assert_eq!(errors.len(), 1);
+        let diagnostic = errors[0].to_diagnostic();
+        assert!(!diagnostic.is_error());
+        assert_eq!(diagnostic.code, codes::AMBIGUOUS_TYPE);
+        assert_eq!(diagnostic.primary.message, "known to be `(array _ _)`");
+    }
+}
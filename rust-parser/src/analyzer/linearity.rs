@@ -0,0 +1,416 @@
+use crate::ast::{
+    is_builtin, walk_expr, walk_exprs, walk_function_call, Expr, ExprKind, Parameter, Span, Type,
+    Visitor,
+};
+use crate::diagnostics::{codes, Diagnostic, ToDiagnostic};
+use std::collections::{HashMap, HashSet};
+use thiserror::Error;
+
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum LinearityError {
+    #[error("Capability {capability} is used again while still in use at {span}")]
+    Duplicated {
+        capability: String,
+        span: Span,
+        /// The use that is still in effect
+        first: Span,
+    },
+
+    #[error("Capability {capability} escapes through {form} at {span}")]
+    Leaked {
+        capability: String,
+        /// Keyword or function name of the form the capability escapes into
+        form: String,
+        span: Span,
+    },
+
+    #[error("Capability {capability} is never used at {span}")]
+    Unused { capability: String, span: Span },
+}
+
+impl LinearityError {
+    pub fn span(&self) -> Span {
+        match self {
+            LinearityError::Duplicated { span, .. }
+            | LinearityError::Leaked { span, .. }
+            | LinearityError::Unused { span, .. } => *span,
+        }
+    }
+}
+
+impl ToDiagnostic for LinearityError {
+    fn to_diagnostic(&self) -> Diagnostic {
+        match self {
+            LinearityError::Duplicated {
+                capability,
+                span,
+                first,
+            } => Diagnostic::error(
+                codes::DUPLICATED_CAPABILITY,
+                format!("capability `{}` is duplicated", capability),
+                *span,
+            )
+            .with_label("used again here")
+            .with_secondary(*first, "while still in use here")
+            .with_note("a capability can only be held in one place at a time"),
+            LinearityError::Leaked {
+                capability,
+                form,
+                span,
+            } => Diagnostic::error(
+                codes::LEAKED_CAPABILITY,
+                format!("capability `{}` escapes through `{}`", capability, form),
+                *span,
+            )
+            .with_label("used as a value here")
+            .with_note(
+                "a capability can only be opened, used as a device, or passed to a function",
+            ),
+            LinearityError::Unused { capability, span } => Diagnostic::error(
+                codes::UNUSED_CAPABILITY,
+                format!("capability `{}` is never used", capability),
+                *span,
+            )
+            .with_label("declared here")
+            .with_help("remove the parameter, or open it with `with-capability`"),
+        }
+    }
+}
+
+/// Checks the linear use of capability parameters (rule L-Capability).
+///
+/// A deploy-time function owns its capability parameters. It can open one
+/// with `with-capability`, use it as the device of an I/O operation, or
+/// lend it to another function for the duration of a call; the callee's
+/// parameter is checked by the same rules, so the capability always comes
+/// back. Anything else would copy it and is a leak. A capability lent twice
+/// at once, by passing it twice in one call, opening it again, or passing
+/// it on while it is open, is a duplicate. A capability the function never
+/// uses is dropped, which is reported too.
+pub struct LinearityChecker {
+    /// Capability parameters in scope
+    scope: HashSet<String>,
+    /// Capabilities opened by the enclosing `with-capability` blocks
+    open: Vec<(String, Span)>,
+    used: HashSet<String>,
+    /// Keyword or function name of the enclosing forms
+    forms: Vec<String>,
+    errors: Vec<LinearityError>,
+}
+
+impl LinearityChecker {
+    pub fn new() -> Self {
+        Self {
+            scope: HashSet::new(),
+            open: Vec::new(),
+            used: HashSet::new(),
+            forms: Vec::new(),
+            errors: Vec::new(),
+        }
+    }
+
+    pub fn check(mut self, exprs: &[Expr]) -> Vec<LinearityError> {
+        walk_exprs(&mut self, exprs);
+        self.errors
+    }
+
+    /// Name of the capability parameter `expr` refers to, marking it used
+    fn capability(&mut self, expr: &Expr) -> Option<String> {
+        match &expr.kind {
+            ExprKind::Ident(name) if self.scope.contains(name) => {
+                self.used.insert(name.clone());
+                Some(name.clone())
+            }
+            _ => None,
+        }
+    }
+
+    fn open_span(&self, name: &str) -> Option<Span> {
+        self.open
+            .iter()
+            .find(|(open, _)| open == name)
+            .map(|(_, span)| *span)
+    }
+
+    /// The device of an I/O operation only borrows its capability
+    fn device(&mut self, device: &Expr) {
+        if self.capability(device).is_none() {
+            self.visit_expr(device);
+        }
+    }
+
+    /// Check `body` with `names` no longer referring to capabilities
+    fn shadowed<'a>(
+        &mut self,
+        names: impl IntoIterator<Item = &'a str>,
+        body: impl FnOnce(&mut Self),
+    ) {
+        let outer = self.scope.clone();
+        for name in names {
+            self.scope.remove(name);
+        }
+        body(self);
+        self.scope = outer;
+    }
+}
+
+impl Default for LinearityChecker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Visitor for LinearityChecker {
+    fn visit_expr(&mut self, expr: &Expr) {
+        let form = match &expr.kind {
+            ExprKind::FunctionCall { func, .. } => match &func.kind {
+                ExprKind::Ident(name) => Some(name.clone()),
+                _ => Some("call".to_string()),
+            },
+            _ => expr.keyword().map(String::from),
+        };
+        match form {
+            Some(form) => {
+                self.forms.push(form);
+                walk_expr(self, expr);
+                self.forms.pop();
+            }
+            None => walk_expr(self, expr),
+        }
+    }
+
+    fn visit_ident(&mut self, name: &str, span: Span) {
+        if self.scope.contains(name) {
+            self.used.insert(name.to_string());
+            self.errors.push(LinearityError::Leaked {
+                capability: name.to_string(),
+                form: self.forms.last().cloned().unwrap_or_default(),
+                span,
+            });
+        }
+    }
+
+    fn visit_defun_deploy(
+        &mut self,
+        _name: &str,
+        params: &[Parameter],
+        _return_type: Option<&Type>,
+        body: &[Expr],
+        _span: Span,
+    ) {
+        let capabilities: Vec<&Parameter> = params
+            .iter()
+            .filter(|p| matches!(p.ty(), Some(Type::Capability { .. })))
+            .collect();
+        let scope = capabilities.iter().map(|p| p.name.clone()).collect();
+        let outer_scope = std::mem::replace(&mut self.scope, scope);
+        let outer_open = std::mem::take(&mut self.open);
+        let outer_used = std::mem::take(&mut self.used);
+
+        walk_exprs(self, body);
+        for param in capabilities {
+            if !self.used.contains(&param.name) {
+                self.errors.push(LinearityError::Unused {
+                    capability: param.name.clone(),
+                    span: param.span,
+                });
+            }
+        }
+
+        self.scope = outer_scope;
+        self.open = outer_open;
+        self.used = outer_used;
+    }
+
+    fn visit_with_capability(&mut self, capability: &Expr, body: &[Expr], _span: Span) {
+        let Some(name) = self.capability(capability) else {
+            self.visit_expr(capability);
+            walk_exprs(self, body);
+            return;
+        };
+        if let Some(first) = self.open_span(&name) {
+            self.errors.push(LinearityError::Duplicated {
+                capability: name.clone(),
+                span: capability.span,
+                first,
+            });
+        }
+        self.open.push((name, capability.span));
+        walk_exprs(self, body);
+        self.open.pop();
+    }
+
+    /// Arguments to a deploy-time function are lent to it; arguments to
+    /// builtin operators are values like any other
+    fn visit_function_call(&mut self, func: &Expr, args: &[Expr], _span: Span) {
+        let ExprKind::Ident(callee) = &func.kind else {
+            return walk_function_call(self, func, args);
+        };
+        if is_builtin(callee) {
+            return walk_function_call(self, func, args);
+        }
+
+        let mut lent: HashMap<String, Span> = HashMap::new();
+        for arg in args {
+            let Some(name) = self.capability(arg) else {
+                self.visit_expr(arg);
+                continue;
+            };
+            if let Some(first) = lent.get(&name).copied().or_else(|| self.open_span(&name)) {
+                self.errors.push(LinearityError::Duplicated {
+                    capability: name.clone(),
+                    span: arg.span,
+                    first,
+                });
+            }
+            lent.entry(name).or_insert(arg.span);
+        }
+    }
+
+    // The values are bound in the outer scope
+    fn visit_let(&mut self, bindings: &[(String, Expr)], body: &[Expr], _span: Span) {
+        for (_, value) in bindings {
+            self.visit_expr(value);
+        }
+        let names = bindings.iter().map(|(name, _)| name.as_str());
+        self.shadowed(names, |checker| walk_exprs(checker, body));
+    }
+
+    fn visit_bounded_for(
+        &mut self,
+        var: &str,
+        start: &Expr,
+        end: &Expr,
+        body: &[Expr],
+        _span: Span,
+    ) {
+        self.visit_expr(start);
+        self.visit_expr(end);
+        self.shadowed([var], |checker| walk_exprs(checker, body));
+    }
+
+    fn visit_gpio_set(&mut self, device: &Expr, value: &Expr, _span: Span) {
+        self.device(device);
+        self.visit_expr(value);
+    }
+
+    fn visit_gpio_get(&mut self, device: &Expr, _span: Span) {
+        self.device(device);
+    }
+
+    fn visit_uart_send(&mut self, device: &Expr, data: &Expr, _span: Span) {
+        self.device(device);
+        self.visit_expr(data);
+    }
+
+    fn visit_uart_recv(&mut self, device: &Expr, _span: Span) {
+        self.device(device);
+    }
+
+    fn visit_sensor_read(&mut self, device: &Expr, _span: Span) {
+        self.device(device);
+    }
+
+    fn visit_network_send(&mut self, device: &Expr, data: &Expr, _span: Span) {
+        self.device(device);
+        self.visit_expr(data);
+    }
+
+    fn visit_network_recv(&mut self, device: &Expr, _span: Span) {
+        self.device(device);
+    }
+
+    // Compile-time code cannot hold a deploy-time capability
+    fn visit_defun_compile(
+        &mut self,
+        _name: &str,
+        _params: &[Parameter],
+        _return_type: Option<&Type>,
+        _body: &[Expr],
+        _span: Span,
+    ) {
+    }
+
+    fn visit_macro(&mut self, _name: &str, _params: &[Parameter], _body: &[Expr], _span: Span) {}
+
+    fn visit_eval_compile(&mut self, _expr: &Expr, _span: Span) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analyzer::TypeInference;
+    use crate::parse_file;
+
+    fn check(source: &str) -> Vec<LinearityError> {
+        let mut exprs = parse_file(source).unwrap();
+        TypeInference::new(&exprs).infer(&mut exprs);
+        LinearityChecker::new().check(&exprs)
+    }
+
+    #[test]
+    fn test_example_is_linear() {
+        let source = include_str!("../../../examples/temperature-monitor.obl");
+        assert_eq!(check(source), vec![]);
+    }
+
+    #[test]
+    fn test_duplicated_capability() {
+        let errors = check(
+            r#"
+(defun-deploy mirror ((a (capability gpio)) (b (capability gpio))) : void
+  (with-capability a (gpio-set a 1))
+  (with-capability b (gpio-set b 1)))
+(defun-deploy main ((led (capability gpio))) : void
+  (mirror led led)
+  (with-capability led
+    (mirror led led)))
+"#,
+        );
+        let sites: Vec<(usize, usize, usize)> = errors
+            .iter()
+            .map(|e| match e {
+                LinearityError::Duplicated { span, first, .. } => {
+                    (span.line, span.column, first.line)
+                }
+                _ => panic!("expected a duplicate, found {:?}", e),
+            })
+            .collect();
+        // Passed twice, then passed while open and passed twice
+        assert_eq!(sites, vec![(6, 15, 6), (8, 13, 7), (8, 17, 8)]);
+    }
+
+    #[test]
+    fn test_leaked_capability() {
+        let errors = check(
+            r#"
+(defun-deploy keep ((led (capability gpio))) : void
+  (let ((copy led))
+    (with-capability copy (gpio-set copy 1))))
+"#,
+        );
+        assert_eq!(
+            errors,
+            vec![LinearityError::Leaked {
+                capability: "led".to_string(),
+                form: "let".to_string(),
+                span: errors[0].span(),
+            }]
+        );
+        assert_eq!(errors[0].span().line, 3);
+    }
+
+    #[test]
+    fn test_unused_capability() {
+        let errors = check(
+            r#"
+(defun-deploy idle ((led (capability gpio)) (count int32)) : int32
+  count)
+"#,
+        );
+        assert_eq!(errors.len(), 1);
+        let diagnostic = errors[0].to_diagnostic();
+        assert_eq!(diagnostic.code, codes::UNUSED_CAPABILITY);
+        assert_eq!((diagnostic.span().line, diagnostic.span().column), (2, 21));
+    }
+}
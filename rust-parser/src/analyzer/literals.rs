@@ -0,0 +1,331 @@
+use crate::ast::{Expr, ExprKind, IntLiteral, Parameter, Span, Type};
+use crate::diagnostics::{codes, Diagnostic, ToDiagnostic};
+use std::collections::HashMap;
+use thiserror::Error;
+
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum LiteralError {
+    #[error("Literal {literal} does not fit in {ty} at {span}")]
+    OutOfRange {
+        literal: String,
+        ty: Type,
+        span: Span,
+        /// Where the expected type comes from, if not a suffix
+        context: Option<Span>,
+    },
+}
+
+impl LiteralError {
+    pub fn span(&self) -> Span {
+        match self {
+            LiteralError::OutOfRange { span, .. } => *span,
+        }
+    }
+}
+
+impl ToDiagnostic for LiteralError {
+    fn to_diagnostic(&self) -> Diagnostic {
+        match self {
+            LiteralError::OutOfRange {
+                literal,
+                ty,
+                span,
+                context,
+            } => {
+                let (min, max) = ty.int_range().unwrap_or_default();
+                let diagnostic = Diagnostic::error(
+                    codes::LITERAL_OUT_OF_RANGE,
+                    format!("literal `{}` does not fit in `{}`", literal, ty),
+                    *span,
+                )
+                .with_label(format!("`{}` ranges from {} to {}", ty, min, max));
+                match context {
+                    Some(context) => {
+                        diagnostic.with_secondary(*context, format!("expected `{}` here", ty))
+                    }
+                    None => diagnostic,
+                }
+            }
+        }
+    }
+}
+
+/// Checks integer literals against the type their context expects: a
+/// suffix, a typed parameter, a declared return type, or the element type
+/// of an array being written.
+pub struct LiteralChecker {
+    /// Parameters of every named function, by name
+    signatures: HashMap<String, Vec<Parameter>>,
+    errors: Vec<LiteralError>,
+}
+
+/// Variables with a known type, and where that type was declared
+type Scope = HashMap<String, (Type, Span)>;
+
+impl LiteralChecker {
+    pub fn new(exprs: &[Expr]) -> Self {
+        let mut signatures = HashMap::new();
+        for expr in exprs {
+            Self::collect_signatures(expr, &mut signatures);
+        }
+        Self {
+            signatures,
+            errors: Vec::new(),
+        }
+    }
+
+    pub fn check(mut self, exprs: &[Expr]) -> Vec<LiteralError> {
+        for expr in exprs {
+            self.visit(expr, &Scope::new());
+        }
+        self.errors
+    }
+
+    fn collect_signatures(expr: &Expr, signatures: &mut HashMap<String, Vec<Parameter>>) {
+        match &expr.kind {
+            ExprKind::DefunDeploy { name, params, .. }
+            | ExprKind::DefunCompile { name, params, .. } => {
+                signatures.insert(name.clone(), params.clone());
+            }
+            ExprKind::Program { forms, .. } => {
+                for form in forms {
+                    Self::collect_signatures(form, signatures);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn visit(&mut self, expr: &Expr, scope: &Scope) {
+        match &expr.kind {
+            ExprKind::Int(literal) => self.check_literal(literal, expr.span, None),
+
+            ExprKind::DefunDeploy {
+                params,
+                return_type,
+                body,
+                ..
+            }
+            | ExprKind::DefunCompile {
+                params,
+                return_type,
+                body,
+                ..
+            } => {
+                let scope = Self::with_params(scope, params);
+                let Some((last, rest)) = body.split_last() else {
+                    return;
+                };
+                for expr in rest {
+                    self.visit(expr, &scope);
+                }
+                match return_type {
+                    Some(ty) => self.expect(last, ty, expr.span, &scope),
+                    None => self.visit(last, &scope),
+                }
+            }
+
+            ExprKind::Let { bindings, body } => {
+                let scope = self.bind(bindings, scope);
+                for expr in body {
+                    self.visit(expr, &scope);
+                }
+            }
+
+            ExprKind::FunctionCall { func, args } => {
+                let params = match &func.kind {
+                    ExprKind::Ident(name) if !scope.contains_key(name) => {
+                        self.signatures.get(name).cloned()
+                    }
+                    _ => None,
+                };
+                match params {
+                    Some(params) if params.len() == args.len() => {
+                        for (arg, param) in args.iter().zip(params) {
+                            match &param.type_annotation {
+                                Some(ty) => self.expect(arg, ty, param.span, scope),
+                                None => self.visit(arg, scope),
+                            }
+                        }
+                    }
+                    _ => {
+                        for arg in args {
+                            self.visit(arg, scope);
+                        }
+                    }
+                }
+            }
+
+            ExprKind::Set { var, value } => match scope.get(var) {
+                Some((ty, declared)) => self.expect(value, ty, *declared, scope),
+                None => self.visit(value, scope),
+            },
+
+            ExprKind::ArraySet {
+                array,
+                index,
+                value,
+            } => {
+                self.visit(array, scope);
+                self.visit(index, scope);
+                match Self::type_of(array, scope) {
+                    Some(Type::Array { elem_type, .. }) => {
+                        let declared = Self::declaration(array, scope).unwrap_or(array.span);
+                        self.expect(value, &elem_type, declared, scope)
+                    }
+                    _ => self.visit(value, scope),
+                }
+            }
+
+            _ => {
+                for child in expr.children() {
+                    self.visit(child, scope);
+                }
+            }
+        }
+    }
+
+    /// Visit `expr`, whose value must have type `ty` as declared at `context`
+    fn expect(&mut self, expr: &Expr, ty: &Type, context: Span, scope: &Scope) {
+        match &expr.kind {
+            ExprKind::Int(literal) => self.check_literal(literal, expr.span, Some((ty, context))),
+            ExprKind::If {
+                condition,
+                then_branch,
+                else_branch,
+            } => {
+                self.visit(condition, scope);
+                self.expect(then_branch, ty, context, scope);
+                self.expect(else_branch, ty, context, scope);
+            }
+            ExprKind::Let { bindings, body } => {
+                let scope = self.bind(bindings, scope);
+                let Some((last, rest)) = body.split_last() else {
+                    return;
+                };
+                for expr in rest {
+                    self.visit(expr, &scope);
+                }
+                self.expect(last, ty, context, &scope);
+            }
+            _ => self.visit(expr, scope),
+        }
+    }
+
+    /// Visit let bindings in order, recording the types evident from them
+    fn bind(&mut self, bindings: &[(String, Expr)], scope: &Scope) -> Scope {
+        let mut scope = scope.clone();
+        for (name, value) in bindings {
+            self.visit(value, &scope);
+            match Self::type_of(value, &scope) {
+                Some(ty) => scope.insert(name.clone(), (ty, value.span)),
+                None => scope.remove(name),
+            };
+        }
+        scope
+    }
+
+    fn check_literal(&mut self, literal: &IntLiteral, span: Span, context: Option<(&Type, Span)>) {
+        // A suffix overrides the context; mismatches between the two are a
+        // type error, not a range error
+        let (ty, context) = match (&literal.suffix, context) {
+            (Some(suffix), _) => (suffix, None),
+            (None, Some((ty, context))) => (ty, Some(context)),
+            (None, None) => return,
+        };
+
+        if !literal.fits(ty) {
+            self.errors.push(LiteralError::OutOfRange {
+                literal: literal.to_string(),
+                ty: ty.clone(),
+                span,
+                context,
+            });
+        }
+    }
+
+    fn with_params(scope: &Scope, params: &[Parameter]) -> Scope {
+        let mut scope = scope.clone();
+        for param in params {
+            match &param.type_annotation {
+                Some(ty) => scope.insert(param.name.clone(), (ty.clone(), param.span)),
+                None => scope.remove(&param.name),
+            };
+        }
+        scope
+    }
+
+    /// Type of `expr` when it is evident without inference
+    fn type_of(expr: &Expr, scope: &Scope) -> Option<Type> {
+        match &expr.kind {
+            ExprKind::Int(literal) => literal.suffix.clone(),
+            ExprKind::ArrayLiteral { elem_type, size } => Some(Type::Array {
+                elem_type: Box::new(elem_type.clone()),
+                size: *size,
+            }),
+            ExprKind::Ident(name) => scope.get(name).map(|(ty, _)| ty.clone()),
+            _ => None,
+        }
+    }
+
+    fn declaration(expr: &Expr, scope: &Scope) -> Option<Span> {
+        match &expr.kind {
+            ExprKind::Ident(name) => scope.get(name).map(|(_, span)| *span),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_file;
+
+    fn check(source: &str) -> Vec<LiteralError> {
+        let exprs = parse_file(source).unwrap();
+        LiteralChecker::new(&exprs).check(&exprs)
+    }
+
+    #[test]
+    fn test_suffix_range() {
+        let errors = check("(defun-deploy f () (g 255u8 256u8 -1i8))");
+        assert_eq!(errors.len(), 1);
+        assert!(matches!(
+            &errors[0],
+            LiteralError::OutOfRange { literal, ty: Type::Uint8, context: None, .. }
+                if literal == "256u8"
+        ));
+    }
+
+    #[test]
+    fn test_contextual_types() {
+        let source = r#"
+(defun-deploy put ((reg uint8) (mask uint16)) : uint8
+  (let ((buf (array uint8 4)))
+    (array-set buf 0 300)
+    (set reg 0x1FF)
+    (put 300 0xFFFF)
+    70000))
+"#;
+        let literals: Vec<String> = check(source)
+            .iter()
+            .map(|e| match e {
+                LiteralError::OutOfRange { literal, ty, .. } => format!("{} {}", literal, ty),
+            })
+            .collect();
+        assert_eq!(
+            literals,
+            vec!["300 uint8", "0x1FF uint8", "300 uint8", "70000 uint8"]
+        );
+    }
+
+    #[test]
+    fn test_diagnostic_points_at_declaration() {
+        let source = "(defun-deploy f ((x int8)) (set x 200))";
+        let diagnostic = check(source)[0].to_diagnostic();
+        assert_eq!(diagnostic.code, codes::LITERAL_OUT_OF_RANGE);
+        assert_eq!(diagnostic.span().column, 35);
+        assert_eq!(diagnostic.secondary[0].span.column, 18);
+        assert_eq!(diagnostic.primary.message, "`int8` ranges from -128 to 127");
+    }
+}
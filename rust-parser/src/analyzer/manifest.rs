@@ -0,0 +1,610 @@
+use crate::analyzer::{CapabilityChecker, SymbolKind, SymbolTable};
+use crate::ast::{
+    walk_bounded_for, walk_expr, walk_exprs, walk_function_call, Expr, ExprKind, Parameter,
+    ResourceType, Span, Type, Visitor,
+};
+use crate::diagnostics::{codes, Diagnostic, ToDiagnostic};
+use std::collections::HashMap;
+use thiserror::Error;
+
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum ManifestError {
+    #[error("No defcap declares a {resource} capability at {span}")]
+    Undeclared { resource: ResourceType, span: Span },
+
+    #[error("{resource} capability could be any of {} at {span}", candidates.join(", "))]
+    Ambiguous {
+        resource: ResourceType,
+        /// The `defcap`s that declare `resource`
+        candidates: Vec<String>,
+        span: Span,
+    },
+
+    #[error("Capability {name} is declared but never used at {span}")]
+    Unused { name: String, span: Span },
+}
+
+impl ManifestError {
+    pub fn span(&self) -> Span {
+        match self {
+            ManifestError::Undeclared { span, .. }
+            | ManifestError::Ambiguous { span, .. }
+            | ManifestError::Unused { span, .. } => *span,
+        }
+    }
+
+    /// An unused declaration grants nothing, so it is only a warning
+    pub fn is_warning(&self) -> bool {
+        matches!(self, ManifestError::Unused { .. })
+    }
+}
+
+impl ToDiagnostic for ManifestError {
+    fn to_diagnostic(&self) -> Diagnostic {
+        match self {
+            ManifestError::Undeclared { resource, span } => Diagnostic::error(
+                codes::UNDECLARED_CAPABILITY,
+                format!("no `defcap` declares a `{}` capability", resource),
+                *span,
+            )
+            .with_label(format!("needs a `(capability {})`", resource))
+            .with_help(format!(
+                "declare one with `(defcap name (...) : (capability {}) \"...\")`",
+                resource
+            )),
+            ManifestError::Ambiguous {
+                resource,
+                candidates,
+                span,
+            } => Diagnostic::error(
+                codes::AMBIGUOUS_CAPABILITY,
+                format!("several `defcap`s declare a `{}` capability", resource),
+                *span,
+            )
+            .with_label("cannot tell which one this is")
+            .with_note(format!(
+                "declared by {}",
+                candidates
+                    .iter()
+                    .map(|c| format!("`{}`", c))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )),
+            ManifestError::Unused { name, span } => Diagnostic::warning(
+                codes::UNUSED_DEFCAP,
+                format!("capability `{}` is never used", name),
+                *span,
+            )
+            .with_label("declared here"),
+        }
+    }
+}
+
+/// How a use site exercises a declared capability
+#[derive(Debug, Clone, PartialEq)]
+pub enum UseKind {
+    /// A capability parameter of a deploy-time function, by name
+    Parameter(String),
+    /// A `with-capability` block opening it
+    Scope,
+    /// A call to the `defcap`
+    Call,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CapabilityUse {
+    /// Deploy-time function the use is in
+    pub function: String,
+    pub kind: UseKind,
+    pub span: Span,
+}
+
+/// A `defcap` and the places the program uses it
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeclaredCapability {
+    pub name: String,
+    /// As annotated, or else inferred from the use sites; `None` when
+    /// neither decides it
+    pub resource: Option<ResourceType>,
+    pub description: String,
+    pub span: Span,
+    pub uses: Vec<CapabilityUse>,
+}
+
+/// The capabilities one program declares
+#[derive(Debug, Clone, PartialEq)]
+pub struct CapabilityManifest {
+    /// `None` for forms outside of any `program`
+    pub program: Option<String>,
+    pub capabilities: Vec<DeclaredCapability>,
+}
+
+impl CapabilityManifest {
+    pub fn get(&self, name: &str) -> Option<&DeclaredCapability> {
+        self.capabilities.iter().find(|c| c.name == name)
+    }
+}
+
+/// Capability manifests of every program, with each use site linked to
+/// the `defcap` it exercises.
+///
+/// A call to a `defcap` resolves through the symbol table. A capability
+/// parameter, and a `with-capability` on one, resolves by its resource to
+/// the one `defcap` of its program that declares that resource.
+///
+/// A `defcap` without a `: (capability res)` annotation takes the resource
+/// of the I/O operations its calls are passed to, or else the one resource
+/// left unclaimed when it is the program's only such `defcap`. Until its
+/// resource is known it may declare any capability the program uses, so
+/// those are not reported as undeclared.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CapabilityManifests {
+    pub manifests: Vec<CapabilityManifest>,
+    pub errors: Vec<ManifestError>,
+}
+
+impl CapabilityManifests {
+    /// Link the capabilities of `exprs`, given the symbols they resolve to
+    pub fn link(exprs: &[Expr], symbols: &SymbolTable) -> Self {
+        let mut linked = Self {
+            manifests: Vec::new(),
+            errors: Vec::new(),
+        };
+        let mut top_level = Vec::new();
+        for expr in exprs {
+            match &expr.kind {
+                ExprKind::Program { name, forms, .. } => {
+                    let forms: Vec<&Expr> = forms.iter().collect();
+                    linked.link_program(Some(name.clone()), &forms, symbols);
+                }
+                _ => top_level.push(expr),
+            }
+        }
+        if !top_level.is_empty() {
+            linked.link_program(None, &top_level, symbols);
+        }
+        linked
+    }
+
+    fn link_program(&mut self, program: Option<String>, forms: &[&Expr], symbols: &SymbolTable) {
+        let capabilities: Vec<DeclaredCapability> = forms
+            .iter()
+            .filter_map(|form| match &form.kind {
+                ExprKind::DefCap {
+                    name,
+                    resource,
+                    description,
+                    ..
+                } => Some(DeclaredCapability {
+                    name: name.clone(),
+                    resource: resource.clone(),
+                    description: description.clone(),
+                    span: form.span,
+                    uses: Vec::new(),
+                }),
+                _ => None,
+            })
+            .collect();
+
+        let mut linker = Linker {
+            symbols,
+            capabilities,
+            function: String::new(),
+            scope: HashMap::new(),
+            pending: Vec::new(),
+            errors: Vec::new(),
+        };
+        for form in forms {
+            linker.visit_expr(form);
+        }
+        let unclaimed = linker.resolve_pending();
+        for capability in &mut linker.capabilities {
+            capability
+                .uses
+                .sort_by_key(|u| (u.span.file.0, u.span.start));
+        }
+
+        self.errors.extend(linker.errors);
+        for capability in &linker.capabilities {
+            // An unannotated `defcap` may be what the unclaimed uses are of
+            let maybe_used = capability.resource.is_none() && unclaimed;
+            if capability.uses.is_empty() && !maybe_used {
+                self.errors.push(ManifestError::Unused {
+                    name: capability.name.clone(),
+                    span: capability.span,
+                });
+            }
+        }
+        // A file of plain functions has no manifest to speak of
+        if program.is_some() || !linker.capabilities.is_empty() {
+            self.manifests.push(CapabilityManifest {
+                program,
+                capabilities: linker.capabilities,
+            });
+        }
+    }
+
+    pub fn get(&self, program: &str) -> Option<&CapabilityManifest> {
+        self.manifests
+            .iter()
+            .find(|m| m.program.as_deref() == Some(program))
+    }
+}
+
+/// A use of a capability by its resource, linked once every `defcap`'s
+/// resource is known
+struct PendingUse {
+    resource: ResourceType,
+    function: String,
+    kind: UseKind,
+    span: Span,
+}
+
+/// Records the use sites within one program
+struct Linker<'a> {
+    symbols: &'a SymbolTable,
+    capabilities: Vec<DeclaredCapability>,
+    function: String,
+    /// Variables with a known type
+    scope: HashMap<String, Type>,
+    pending: Vec<PendingUse>,
+    errors: Vec<ManifestError>,
+}
+
+impl Linker<'_> {
+    /// Declarations of `resource`, by index
+    fn declaring(&self, resource: &ResourceType) -> Vec<usize> {
+        (0..self.capabilities.len())
+            .filter(|&i| self.capabilities[i].resource.as_ref() == Some(resource))
+            .collect()
+    }
+
+    /// Declaration `func` refers to, when it is a `defcap`
+    fn called(&self, func: &Expr) -> Option<usize> {
+        let symbol = self.symbols.resolve(func.span)?;
+        if symbol.kind != SymbolKind::Capability {
+            return None;
+        }
+        self.capabilities.iter().position(|c| c.name == symbol.name)
+    }
+
+    fn record(&mut self, index: usize, kind: UseKind, span: Span) {
+        self.capabilities[index].uses.push(CapabilityUse {
+            function: self.function.clone(),
+            kind,
+            span,
+        });
+    }
+
+    fn defer(&mut self, resource: &ResourceType, kind: UseKind, span: Span) {
+        self.pending.push(PendingUse {
+            resource: resource.clone(),
+            function: self.function.clone(),
+            kind,
+            span,
+        });
+    }
+
+    /// Link the uses made by resource. Returns whether some were left
+    /// unlinked because an unannotated `defcap` may declare them.
+    fn resolve_pending(&mut self) -> bool {
+        let mut unclaimed = Vec::new();
+        for pending in std::mem::take(&mut self.pending) {
+            match self.declaring(&pending.resource).as_slice() {
+                [] => unclaimed.push(pending),
+                &[index] => self.link(index, pending),
+                candidates => {
+                    // Scopes are only reported through their parameter
+                    if matches!(pending.kind, UseKind::Parameter(_)) {
+                        let candidates = candidates
+                            .iter()
+                            .map(|&i| self.capabilities[i].name.clone())
+                            .collect();
+                        self.errors.push(ManifestError::Ambiguous {
+                            resource: pending.resource,
+                            candidates,
+                            span: pending.span,
+                        });
+                    }
+                }
+            }
+        }
+
+        let unannotated: Vec<usize> = (0..self.capabilities.len())
+            .filter(|&i| self.capabilities[i].resource.is_none())
+            .collect();
+        let mut resources: Vec<&ResourceType> = Vec::new();
+        for pending in &unclaimed {
+            if !resources.contains(&&pending.resource) {
+                resources.push(&pending.resource);
+            }
+        }
+        match (unannotated.as_slice(), resources.as_slice()) {
+            (_, []) => false,
+            ([], _) => {
+                for pending in unclaimed {
+                    if matches!(pending.kind, UseKind::Parameter(_)) {
+                        self.errors.push(ManifestError::Undeclared {
+                            resource: pending.resource,
+                            span: pending.span,
+                        });
+                    }
+                }
+                false
+            }
+            (&[index], [resource]) => {
+                self.capabilities[index].resource = Some((*resource).clone());
+                for pending in unclaimed {
+                    self.link(index, pending);
+                }
+                false
+            }
+            _ => true,
+        }
+    }
+
+    fn link(&mut self, index: usize, pending: PendingUse) {
+        self.capabilities[index].uses.push(CapabilityUse {
+            function: pending.function,
+            kind: pending.kind,
+            span: pending.span,
+        });
+    }
+
+    fn link_param(&mut self, param: &Parameter) {
+        if let Some(Type::Capability { resource }) = param.ty() {
+            self.defer(resource, UseKind::Parameter(param.name.clone()), param.span);
+        }
+    }
+}
+
+impl Visitor for Linker<'_> {
+    fn visit_expr(&mut self, expr: &Expr) {
+        // A `defcap` passed to an I/O operation grants what it requires
+        if let Some((_, required)) = CapabilityChecker::required_resource(expr) {
+            let device = expr.children()[0];
+            if let ExprKind::FunctionCall { func, .. } = &device.kind {
+                if let Some(index) = self.called(func) {
+                    self.capabilities[index].resource.get_or_insert(required);
+                }
+            }
+        }
+        walk_expr(self, expr);
+    }
+
+    fn visit_defun_deploy(
+        &mut self,
+        name: &str,
+        params: &[Parameter],
+        _return_type: Option<&Type>,
+        body: &[Expr],
+        _span: Span,
+    ) {
+        let outer_function = std::mem::replace(&mut self.function, name.to_string());
+        for param in params {
+            self.link_param(param);
+        }
+        let scope = params
+            .iter()
+            .filter_map(|p| Some((p.name.clone(), p.ty()?.clone())))
+            .collect();
+        let outer_scope = std::mem::replace(&mut self.scope, scope);
+        walk_exprs(self, body);
+        self.scope = outer_scope;
+        self.function = outer_function;
+    }
+
+    // Undeclared resources are reported at the parameter, not again here
+    fn visit_with_capability(&mut self, capability: &Expr, body: &[Expr], _span: Span) {
+        match &capability.kind {
+            ExprKind::FunctionCall { func, args } => match self.called(func) {
+                Some(index) => {
+                    self.record(index, UseKind::Scope, capability.span);
+                    walk_exprs(self, args);
+                }
+                None => self.visit_expr(capability),
+            },
+            ExprKind::Ident(name) => {
+                if let Some(Type::Capability { resource }) = self.scope.get(name).cloned() {
+                    self.defer(&resource, UseKind::Scope, capability.span);
+                }
+            }
+            _ => self.visit_expr(capability),
+        }
+        walk_exprs(self, body);
+    }
+
+    fn visit_function_call(&mut self, func: &Expr, args: &[Expr], span: Span) {
+        if let Some(index) = self.called(func) {
+            self.record(index, UseKind::Call, span);
+        }
+        walk_function_call(self, func, args);
+    }
+
+    // The values are bound in the outer scope
+    fn visit_let(&mut self, bindings: &[(String, Expr)], body: &[Expr], _span: Span) {
+        for (_, value) in bindings {
+            self.visit_expr(value);
+        }
+        let outer = self.scope.clone();
+        for (name, value) in bindings {
+            match &value.ty {
+                Some(ty) => self.scope.insert(name.clone(), ty.clone()),
+                None => self.scope.remove(name),
+            };
+        }
+        walk_exprs(self, body);
+        self.scope = outer;
+    }
+
+    fn visit_bounded_for(
+        &mut self,
+        var: &str,
+        start: &Expr,
+        end: &Expr,
+        body: &[Expr],
+        _span: Span,
+    ) {
+        let outer = self.scope.clone();
+        self.scope.insert(var.to_string(), Type::Int32);
+        walk_bounded_for(self, start, end, body);
+        self.scope = outer;
+    }
+
+    // Compile-time code cannot hold a deploy-time capability
+    fn visit_defun_compile(
+        &mut self,
+        _name: &str,
+        _params: &[Parameter],
+        _return_type: Option<&Type>,
+        _body: &[Expr],
+        _span: Span,
+    ) {
+    }
+
+    fn visit_macro(&mut self, _name: &str, _params: &[Parameter], _body: &[Expr], _span: Span) {}
+
+    fn visit_eval_compile(&mut self, _expr: &Expr, _span: Span) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analyzer::{NameResolver, Resolution, TypeInference};
+    use crate::parse_file;
+
+    fn link(source: &str) -> CapabilityManifests {
+        let mut exprs = parse_file(source).unwrap();
+        let Resolution { symbols, .. } = NameResolver::new(&exprs).resolve(&exprs);
+        TypeInference::new(&exprs).infer(&mut exprs);
+        CapabilityManifests::link(&exprs, &symbols)
+    }
+
+    #[test]
+    fn test_example_manifest() {
+        let source = include_str!("../../../examples/temperature-monitor.obl");
+        let annotated = source
+            .replace(
+                "(device) \"Temperature",
+                "(device) : (capability sensor-read) \"Temperature",
+            )
+            .replace(
+                "(device) \"Network",
+                "(device) : (capability network-send) \"Network",
+            )
+            .replace("(pin) \"Status", "(pin) : (capability gpio) \"Status");
+        let linked = link(&annotated);
+        assert_eq!(linked.errors, vec![]);
+
+        let manifest = linked.get("temp-monitor").unwrap();
+        let sensor = manifest.get("temp-sensor").unwrap();
+        assert_eq!(sensor.resource, Some(ResourceType::SensorRead));
+        let sites: Vec<(&str, &UseKind)> = sensor
+            .uses
+            .iter()
+            .map(|u| (u.function.as_str(), &u.kind))
+            .collect();
+        assert_eq!(
+            sites,
+            vec![
+                (
+                    "read-average-temp",
+                    &UseKind::Parameter("sensor-cap".to_string())
+                ),
+                ("read-average-temp", &UseKind::Scope),
+                ("main", &UseKind::Parameter("sensor-cap".to_string())),
+            ]
+        );
+        assert_eq!(manifest.get("status-led").unwrap().uses.len(), 3);
+    }
+
+    #[test]
+    fn test_unannotated_defcaps() {
+        // The example as written, with no resource on its `defcap`s
+        let linked = link(include_str!("../../../examples/temperature-monitor.obl"));
+        assert_eq!(linked.errors, vec![]);
+        let manifest = linked.get("temp-monitor").unwrap();
+        assert_eq!(manifest.capabilities.len(), 3);
+        assert!(manifest.capabilities.iter().all(|c| c.resource.is_none()));
+
+        let linked = link(
+            r#"
+(defcap led (pin) "Status LED")
+(defcap radio (port) "Radio")
+(defun-deploy blink ((pin int32)) : void
+  (with-capability (led pin)
+    (gpio-set (led pin) 1)))
+(defun-deploy send ((tx (capability uart-tx))) : void
+  (with-capability tx (uart-send tx 0)))
+"#,
+        );
+        assert_eq!(linked.errors, vec![]);
+        let manifest = &linked.manifests[0];
+        assert_eq!(
+            manifest.get("led").unwrap().resource,
+            Some(ResourceType::Gpio)
+        );
+        let radio = manifest.get("radio").unwrap();
+        assert_eq!(radio.resource, Some(ResourceType::UartTx));
+        assert_eq!(radio.uses.len(), 2);
+    }
+
+    #[test]
+    fn test_defcap_calls_resolve_through_symbols() {
+        let linked = link(
+            r#"
+(defcap led (pin) : (capability gpio) "Status LED")
+(defun-deploy blink ((pin int32)) : void
+  (with-capability (led pin)
+    (gpio-set (led pin) 1)))
+"#,
+        );
+        assert_eq!(linked.errors, vec![]);
+        let uses = &linked.manifests[0].get("led").unwrap().uses;
+        let kinds: Vec<&UseKind> = uses.iter().map(|u| &u.kind).collect();
+        assert_eq!(kinds, vec![&UseKind::Scope, &UseKind::Call]);
+    }
+
+    #[test]
+    fn test_undeclared_and_unused() {
+        let linked = link(
+            r#"
+(program relay
+  (resource-budget (time-ms 100))
+  (defcap tx (port) : (capability uart-tx) "Transmit")
+  (defun-deploy echo ((rx (capability uart-rx))) : int32
+    (with-capability rx (uart-recv rx))))
+"#,
+        );
+        let diagnostics: Vec<Diagnostic> =
+            linked.errors.iter().map(|e| e.to_diagnostic()).collect();
+        assert_eq!(diagnostics.len(), 2);
+        assert_eq!(diagnostics[0].code, codes::UNDECLARED_CAPABILITY);
+        assert_eq!(diagnostics[0].span().line, 5);
+        assert_eq!(diagnostics[1].code, codes::UNUSED_DEFCAP);
+        assert!(linked.errors[1].is_warning());
+        assert_eq!(linked.get("relay").unwrap().capabilities.len(), 1);
+    }
+
+    #[test]
+    fn test_ambiguous_resource() {
+        let linked = link(
+            r#"
+(defcap red (pin) : (capability gpio) "Red LED")
+(defcap green (pin) : (capability gpio) "Green LED")
+(defun-deploy blink ((led (capability gpio))) : void
+  (with-capability led (gpio-set led 1)))
+"#,
+        );
+        assert_eq!(
+            linked.errors[0],
+            ManifestError::Ambiguous {
+                resource: ResourceType::Gpio,
+                candidates: vec!["red".to_string(), "green".to_string()],
+                span: linked.errors[0].span(),
+            }
+        );
+        // Neither declaration is used
+        assert_eq!(linked.errors.len(), 3);
+    }
+}
@@ -1,7 +1,25 @@
+pub mod bounds;
+pub mod budgets;
 pub mod call_graph;
+pub mod capabilities;
+pub mod inference;
+pub mod linearity;
+pub mod literals;
+pub mod manifest;
+pub mod resolver;
 pub mod resources;
 pub mod termination;
+pub mod typecheck;
 
+pub use bounds::*;
+pub use budgets::*;
 pub use call_graph::*;
+pub use capabilities::*;
+pub use inference::*;
+pub use linearity::*;
+pub use literals::*;
+pub use manifest::*;
+pub use resolver::*;
 pub use resources::*;
 pub use termination::*;
+pub use typecheck::*;
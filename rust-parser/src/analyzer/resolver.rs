@@ -0,0 +1,684 @@
+use crate::ast::{builtin_arity, Expr, ExprKind, Parameter, ResourceType, Span};
+use crate::diagnostics::{codes, Diagnostic, ToDiagnostic};
+use std::collections::HashMap;
+use std::fmt;
+use thiserror::Error;
+
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum ResolveError {
+    #[error("Undefined variable {name} at {span}")]
+    UndefinedVariable { name: String, span: Span },
+
+    #[error("Undefined function {name} at {span}")]
+    UndefinedFunction { name: String, span: Span },
+
+    #[error("{name} takes {expected} argument(s) but {found} were given at {span}")]
+    Arity {
+        name: String,
+        expected: String,
+        found: usize,
+        span: Span,
+        /// Where `name` is defined, unless it is a builtin
+        definition: Option<Span>,
+    },
+
+    #[error("{name} shadows an earlier binding at {span}")]
+    Shadowed {
+        name: String,
+        span: Span,
+        previous: Span,
+    },
+
+    #[error("{name} is defined more than once at {span}")]
+    Duplicate {
+        name: String,
+        span: Span,
+        previous: Span,
+    },
+}
+
+impl ResolveError {
+    pub fn span(&self) -> Span {
+        match self {
+            ResolveError::UndefinedVariable { span, .. }
+            | ResolveError::UndefinedFunction { span, .. }
+            | ResolveError::Arity { span, .. }
+            | ResolveError::Shadowed { span, .. }
+            | ResolveError::Duplicate { span, .. } => *span,
+        }
+    }
+
+    /// Shadowing is legal, so it is only reported as a warning
+    pub fn is_warning(&self) -> bool {
+        matches!(self, ResolveError::Shadowed { .. })
+    }
+}
+
+impl ToDiagnostic for ResolveError {
+    fn to_diagnostic(&self) -> Diagnostic {
+        match self {
+            ResolveError::UndefinedVariable { name, span } => Diagnostic::error(
+                codes::UNDEFINED_VARIABLE,
+                format!("cannot find variable `{}` in this scope", name),
+                *span,
+            )
+            .with_label("not found in this scope"),
+            ResolveError::UndefinedFunction { name, span } => Diagnostic::error(
+                codes::UNDEFINED_FUNCTION,
+                format!("cannot find function `{}`", name),
+                *span,
+            )
+            .with_label("not defined"),
+            ResolveError::Arity {
+                name,
+                expected,
+                found,
+                span,
+                definition,
+            } => {
+                let diagnostic = Diagnostic::error(
+                    codes::ARITY_MISMATCH,
+                    format!(
+                        "`{}` takes {} argument(s) but {} were given",
+                        name, expected, found
+                    ),
+                    *span,
+                )
+                .with_label(format!("expected {} argument(s)", expected));
+                match definition {
+                    Some(definition) => {
+                        diagnostic.with_secondary(*definition, format!("`{}` defined here", name))
+                    }
+                    None => diagnostic,
+                }
+            }
+            ResolveError::Shadowed {
+                name,
+                span,
+                previous,
+            } => Diagnostic::warning(
+                codes::SHADOWED_NAME,
+                format!("`{}` shadows an earlier binding", name),
+                *span,
+            )
+            .with_label("this binding hides the earlier one")
+            .with_secondary(*previous, "previously bound here"),
+            ResolveError::Duplicate {
+                name,
+                span,
+                previous,
+            } => Diagnostic::error(
+                codes::DUPLICATE_DEFINITION,
+                format!("`{}` is defined more than once", name),
+                *span,
+            )
+            .with_label("redefined here")
+            .with_secondary(*previous, "first defined here"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SymbolId(pub u32);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ScopeId(pub u32);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SymbolKind {
+    DeployFunction,
+    CompileFunction,
+    Macro,
+    Capability,
+    Parameter,
+    Local,
+    LoopVariable,
+}
+
+impl SymbolKind {
+    pub fn is_function(self) -> bool {
+        matches!(
+            self,
+            SymbolKind::DeployFunction | SymbolKind::CompileFunction | SymbolKind::Macro
+        )
+    }
+}
+
+impl fmt::Display for SymbolKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            SymbolKind::DeployFunction => "defun-deploy",
+            SymbolKind::CompileFunction => "defun-compile",
+            SymbolKind::Macro => "macro",
+            SymbolKind::Capability => "defcap",
+            SymbolKind::Parameter => "parameter",
+            SymbolKind::Local => "let",
+            SymbolKind::LoopVariable => "loop variable",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// A named definition
+#[derive(Debug, Clone, PartialEq)]
+pub struct Symbol {
+    pub name: String,
+    pub kind: SymbolKind,
+    /// Where the name is bound
+    pub span: Span,
+    pub scope: ScopeId,
+    /// Parameter count of functions, macros and capabilities
+    pub arity: Option<usize>,
+    /// Resource a capability grants, if it declares one
+    pub resource: Option<ResourceType>,
+}
+
+/// A region of code in which bindings are visible
+#[derive(Debug, Clone, PartialEq)]
+pub struct Scope {
+    /// `None` only for the global scope
+    pub parent: Option<ScopeId>,
+    pub span: Span,
+    pub symbols: Vec<SymbolId>,
+}
+
+/// Every definition in a program, the scopes they live in, and what each
+/// identifier refers to
+#[derive(Debug, Clone, PartialEq)]
+pub struct SymbolTable {
+    symbols: Vec<Symbol>,
+    scopes: Vec<Scope>,
+    /// Use sites, by the span of the identifier, `set` or call head
+    references: HashMap<Span, SymbolId>,
+}
+
+impl SymbolTable {
+    pub const GLOBAL: ScopeId = ScopeId(0);
+
+    pub fn new() -> Self {
+        Self {
+            symbols: Vec::new(),
+            scopes: vec![Scope {
+                parent: None,
+                span: Span::dummy(),
+                symbols: Vec::new(),
+            }],
+            references: HashMap::new(),
+        }
+    }
+
+    pub fn get(&self, id: SymbolId) -> &Symbol {
+        &self.symbols[id.0 as usize]
+    }
+
+    pub fn symbols(&self) -> &[Symbol] {
+        &self.symbols
+    }
+
+    pub fn scope(&self, id: ScopeId) -> &Scope {
+        &self.scopes[id.0 as usize]
+    }
+
+    pub fn scopes(&self) -> &[Scope] {
+        &self.scopes
+    }
+
+    /// The function, macro or capability named `name`
+    pub fn global(&self, name: &str) -> Option<&Symbol> {
+        self.scope(Self::GLOBAL)
+            .symbols
+            .iter()
+            .map(|&id| self.get(id))
+            .find(|s| s.name == name)
+    }
+
+    /// The symbol the identifier at `span` refers to
+    pub fn resolve(&self, span: Span) -> Option<&Symbol> {
+        self.references.get(&span).map(|&id| self.get(id))
+    }
+
+    /// Use sites of `id`, in source order
+    pub fn references_to(&self, id: SymbolId) -> Vec<Span> {
+        let mut spans: Vec<Span> = self
+            .references
+            .iter()
+            .filter(|(_, &target)| target == id)
+            .map(|(&span, _)| span)
+            .collect();
+        spans.sort_by_key(|s| (s.file.0, s.start));
+        spans
+    }
+
+    /// Number of scopes between `scope` and the global scope
+    pub fn depth(&self, scope: ScopeId) -> usize {
+        let mut depth = 0;
+        let mut current = self.scope(scope).parent;
+        while let Some(parent) = current {
+            depth += 1;
+            current = self.scope(parent).parent;
+        }
+        depth
+    }
+
+    fn add_scope(&mut self, parent: ScopeId, span: Span) -> ScopeId {
+        self.scopes.push(Scope {
+            parent: Some(parent),
+            span,
+            symbols: Vec::new(),
+        });
+        ScopeId(self.scopes.len() as u32 - 1)
+    }
+
+    fn add_symbol(&mut self, symbol: Symbol) -> SymbolId {
+        let id = SymbolId(self.symbols.len() as u32);
+        self.scopes[symbol.scope.0 as usize].symbols.push(id);
+        self.symbols.push(symbol);
+        id
+    }
+}
+
+impl Default for SymbolTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Symbol table and problems found while building it
+#[derive(Debug, Clone, Default)]
+pub struct Resolution {
+    pub symbols: SymbolTable,
+    pub errors: Vec<ResolveError>,
+}
+
+/// Binds every identifier, `set` target and call to its definition.
+///
+/// Functions, macros and capabilities live in one global scope. Function
+/// parameters, `let` bindings and loop variables each open a lexical scope;
+/// `let` values are resolved outside the scope they bind, as in T-Let.
+/// Macro bodies are only resolved once expanded.
+pub struct NameResolver {
+    table: SymbolTable,
+    errors: Vec<ResolveError>,
+    globals: HashMap<String, SymbolId>,
+    /// Open local scopes, innermost last
+    scopes: Vec<(ScopeId, HashMap<String, SymbolId>)>,
+}
+
+impl NameResolver {
+    /// Declare the functions, macros and capabilities defined at the top
+    /// level of `exprs` and inside `program` forms
+    pub fn new(exprs: &[Expr]) -> Self {
+        let mut resolver = Self {
+            table: SymbolTable::new(),
+            errors: Vec::new(),
+            globals: HashMap::new(),
+            scopes: Vec::new(),
+        };
+        for expr in exprs {
+            resolver.declare_global(expr);
+        }
+        resolver
+    }
+
+    pub fn resolve(mut self, exprs: &[Expr]) -> Resolution {
+        for expr in exprs {
+            self.visit(expr);
+        }
+        Resolution {
+            symbols: self.table,
+            errors: self.errors,
+        }
+    }
+
+    fn declare_global(&mut self, expr: &Expr) {
+        let (name, kind, params, resource) = match &expr.kind {
+            ExprKind::DefunDeploy { name, params, .. } => {
+                (name, SymbolKind::DeployFunction, params, None)
+            }
+            ExprKind::DefunCompile { name, params, .. } => {
+                (name, SymbolKind::CompileFunction, params, None)
+            }
+            ExprKind::Macro { name, params, .. } => (name, SymbolKind::Macro, params, None),
+            ExprKind::DefCap {
+                name,
+                params,
+                resource,
+                ..
+            } => (name, SymbolKind::Capability, params, resource.clone()),
+            ExprKind::Program { forms, .. } => {
+                for form in forms {
+                    self.declare_global(form);
+                }
+                return;
+            }
+            _ => return,
+        };
+
+        if let Some(&previous) = self.globals.get(name) {
+            self.errors.push(ResolveError::Duplicate {
+                name: name.clone(),
+                span: expr.span,
+                previous: self.table.get(previous).span,
+            });
+            return;
+        }
+        let id = self.table.add_symbol(Symbol {
+            name: name.clone(),
+            kind,
+            span: expr.span,
+            scope: SymbolTable::GLOBAL,
+            arity: Some(params.len()),
+            resource,
+        });
+        self.globals.insert(name.clone(), id);
+    }
+
+    fn visit(&mut self, expr: &Expr) {
+        match &expr.kind {
+            ExprKind::Ident(name) => match self.lookup(name) {
+                Some(id) => self.refer(expr.span, id),
+                None => self.errors.push(ResolveError::UndefinedVariable {
+                    name: name.clone(),
+                    span: expr.span,
+                }),
+            },
+
+            ExprKind::DefunDeploy { params, body, .. }
+            | ExprKind::DefunCompile { params, body, .. } => {
+                self.enter(expr.span);
+                self.declare_params(params);
+                self.visit_all(body);
+                self.exit();
+            }
+
+            // Templates: their names only mean something once expanded
+            ExprKind::Macro { .. } | ExprKind::DefCap { .. } => {}
+
+            ExprKind::Let { bindings, body } => {
+                for (_, value) in bindings {
+                    self.visit(value);
+                }
+                self.enter(expr.span);
+                for (name, value) in bindings {
+                    self.declare(name, SymbolKind::Local, value.span);
+                }
+                self.visit_all(body);
+                self.exit();
+            }
+
+            ExprKind::BoundedFor {
+                var,
+                start,
+                end,
+                body,
+            } => {
+                self.visit(start);
+                self.visit(end);
+                self.enter(expr.span);
+                self.declare(var, SymbolKind::LoopVariable, expr.span);
+                self.visit_all(body);
+                self.exit();
+            }
+
+            ExprKind::For {
+                var,
+                iterable,
+                body,
+            } => {
+                self.visit(iterable);
+                self.enter(expr.span);
+                self.declare(var, SymbolKind::LoopVariable, expr.span);
+                self.visit_all(body);
+                self.exit();
+            }
+
+            ExprKind::Set { var, value } => {
+                match self.lookup_local(var) {
+                    Some(id) => self.refer(expr.span, id),
+                    None => self.errors.push(ResolveError::UndefinedVariable {
+                        name: var.clone(),
+                        span: expr.span,
+                    }),
+                }
+                self.visit(value);
+            }
+
+            ExprKind::FunctionCall { func, args } => {
+                match &func.kind {
+                    ExprKind::Ident(name) => self.visit_call(name, func.span, args.len()),
+                    _ => self.visit(func),
+                }
+                self.visit_all(args);
+            }
+
+            _ => {
+                for child in expr.children() {
+                    self.visit(child);
+                }
+            }
+        }
+    }
+
+    fn visit_all(&mut self, exprs: &[Expr]) {
+        for expr in exprs {
+            self.visit(expr);
+        }
+    }
+
+    fn visit_call(&mut self, name: &str, span: Span, found: usize) {
+        // A local of function type
+        if let Some(id) = self.lookup_local(name) {
+            return self.refer(span, id);
+        }
+
+        if let Some(&id) = self.globals.get(name) {
+            self.refer(span, id);
+            let symbol = self.table.get(id);
+            if let Some(arity) = symbol.arity.filter(|&n| n != found) {
+                self.errors.push(ResolveError::Arity {
+                    name: name.to_string(),
+                    expected: arity.to_string(),
+                    found,
+                    span,
+                    definition: Some(symbol.span),
+                });
+            }
+            return;
+        }
+
+        match builtin_arity(name) {
+            Some(arity) if !arity.contains(&found) => {
+                let expected = if arity.start() == arity.end() {
+                    arity.start().to_string()
+                } else {
+                    format!("{} or {}", arity.start(), arity.end())
+                };
+                self.errors.push(ResolveError::Arity {
+                    name: name.to_string(),
+                    expected,
+                    found,
+                    span,
+                    definition: None,
+                });
+            }
+            Some(_) => {}
+            None => self.errors.push(ResolveError::UndefinedFunction {
+                name: name.to_string(),
+                span,
+            }),
+        }
+    }
+
+    fn declare_params(&mut self, params: &[Parameter]) {
+        for param in params {
+            self.declare(&param.name, SymbolKind::Parameter, param.span);
+        }
+    }
+
+    /// Bind `name` in the innermost scope
+    fn declare(&mut self, name: &str, kind: SymbolKind, span: Span) {
+        let (scope, names) = self.scopes.last().expect("declared inside a scope");
+        if let Some(&previous) = names.get(name) {
+            self.errors.push(ResolveError::Duplicate {
+                name: name.to_string(),
+                span,
+                previous: self.table.get(previous).span,
+            });
+            return;
+        }
+        if let Some(previous) = self.lookup(name) {
+            self.errors.push(ResolveError::Shadowed {
+                name: name.to_string(),
+                span,
+                previous: self.table.get(previous).span,
+            });
+        }
+
+        let id = self.table.add_symbol(Symbol {
+            name: name.to_string(),
+            kind,
+            span,
+            scope: *scope,
+            arity: None,
+            resource: None,
+        });
+        self.scopes
+            .last_mut()
+            .unwrap()
+            .1
+            .insert(name.to_string(), id);
+    }
+
+    fn lookup(&self, name: &str) -> Option<SymbolId> {
+        self.lookup_local(name)
+            .or_else(|| self.globals.get(name).copied())
+    }
+
+    fn lookup_local(&self, name: &str) -> Option<SymbolId> {
+        self.scopes
+            .iter()
+            .rev()
+            .find_map(|(_, names)| names.get(name).copied())
+    }
+
+    fn refer(&mut self, span: Span, id: SymbolId) {
+        self.table.references.insert(span, id);
+    }
+
+    fn enter(&mut self, span: Span) {
+        let parent = self
+            .scopes
+            .last()
+            .map_or(SymbolTable::GLOBAL, |(id, _)| *id);
+        let id = self.table.add_scope(parent, span);
+        self.scopes.push((id, HashMap::new()));
+    }
+
+    fn exit(&mut self) {
+        self.scopes.pop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_file;
+
+    fn resolve(source: &str) -> Resolution {
+        let exprs = parse_file(source).unwrap();
+        NameResolver::new(&exprs).resolve(&exprs)
+    }
+
+    fn codes(resolution: &Resolution) -> Vec<String> {
+        resolution
+            .errors
+            .iter()
+            .map(|e| {
+                let diagnostic = e.to_diagnostic();
+                format!("{} {}", diagnostic.code, diagnostic.span())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_scopes_and_references() {
+        let source = r#"
+(defun-deploy sum (n) : int32
+  (let ((total 0))
+    (bounded-for i 0 n
+      (set total (+ total (helper i))))
+    total))
+(defun-deploy helper (x) x)
+"#;
+        let resolution = resolve(source);
+        assert!(resolution.errors.is_empty(), "{:?}", resolution.errors);
+
+        let table = &resolution.symbols;
+        let helper = table.global("helper").unwrap();
+        assert_eq!(helper.kind, SymbolKind::DeployFunction);
+        assert_eq!(helper.arity, Some(1));
+
+        let kinds: Vec<(&str, SymbolKind, usize)> = table
+            .symbols()
+            .iter()
+            .map(|s| (s.name.as_str(), s.kind, table.depth(s.scope)))
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![
+                ("sum", SymbolKind::DeployFunction, 0),
+                ("helper", SymbolKind::DeployFunction, 0),
+                ("n", SymbolKind::Parameter, 1),
+                ("total", SymbolKind::Local, 2),
+                ("i", SymbolKind::LoopVariable, 3),
+                ("x", SymbolKind::Parameter, 1),
+            ]
+        );
+
+        // `total` is read twice and assigned once
+        let total = SymbolId(3);
+        let uses: Vec<usize> = table.references_to(total).iter().map(|s| s.line).collect();
+        assert_eq!(uses, vec![5, 5, 6]);
+    }
+
+    #[test]
+    fn test_undefined_names_and_arity() {
+        let source = r#"
+(defun-deploy f (a)
+  (set b a)
+  (g c)
+  (f 1 2)
+  (not 1 2))
+"#;
+        assert_eq!(
+            codes(&resolve(source)),
+            vec![
+                "OBL0501 3:3",
+                "OBL0502 4:4",
+                "OBL0501 4:6",
+                "OBL0503 5:4",
+                "OBL0503 6:4",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_shadowing_and_duplicates() {
+        let source = r#"
+(defun-deploy f (x)
+  (let ((x 1) (y 2) (y 3))
+    (bounded-for y 0 x y)))
+(defcap f () "again")
+"#;
+        let resolution = resolve(source);
+        assert_eq!(
+            codes(&resolution),
+            vec!["OBL0505 5:1", "OBL0504 3:12", "OBL0505 3:24", "OBL0504 4:5"]
+        );
+        assert!(resolution.errors[1].is_warning());
+
+        let diagnostic = resolution.errors[1].to_diagnostic();
+        assert!(!diagnostic.is_error());
+        assert_eq!(diagnostic.secondary[0].span.line, 2);
+    }
+}
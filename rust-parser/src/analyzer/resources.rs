@@ -1,5 +1,10 @@
-use crate::ast::{Expr, ResourceKind, ResourceSpec};
+use crate::ast::{
+    walk_array_get, walk_array_set, walk_exprs, walk_gpio_get, walk_gpio_set, walk_network_recv,
+    walk_network_send, walk_sensor_read, walk_uart_recv, walk_uart_send, Expr, ExprKind,
+    IntLiteral, Parameter, ResourceKind, ResourceSpec, Span, Type, Visitor,
+};
 use std::collections::HashMap;
+use std::mem;
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct ResourceBounds {
@@ -7,6 +12,9 @@ pub struct ResourceBounds {
     pub memory_bytes: u64,
     pub network_bytes: u64,
     pub storage_bytes: u64,
+    /// A loop's trip count is not a constant, so its body is counted once
+    /// and the totals bound nothing
+    pub unbounded: bool,
 }
 
 impl ResourceBounds {
@@ -16,14 +24,17 @@ pub fn new() -> Self {
             memory_bytes: 0,
             network_bytes: 0,
             storage_bytes: 0,
+            unbounded: false,
         }
     }
 
+    // Totals saturate, so a bound too large to count exceeds every budget
     pub fn add(&mut self, other: &ResourceBounds) {
-        self.time_ms += other.time_ms;
-        self.memory_bytes += other.memory_bytes;
-        self.network_bytes += other.network_bytes;
-        self.storage_bytes += other.storage_bytes;
+        self.time_ms = self.time_ms.saturating_add(other.time_ms);
+        self.memory_bytes = self.memory_bytes.saturating_add(other.memory_bytes);
+        self.network_bytes = self.network_bytes.saturating_add(other.network_bytes);
+        self.storage_bytes = self.storage_bytes.saturating_add(other.storage_bytes);
+        self.unbounded |= other.unbounded;
     }
 
     pub fn max(&mut self, other: &ResourceBounds) {
@@ -31,17 +42,19 @@ pub fn max(&mut self, other: &ResourceBounds) {
         self.memory_bytes = self.memory_bytes.max(other.memory_bytes);
         self.network_bytes = self.network_bytes.max(other.network_bytes);
         self.storage_bytes = self.storage_bytes.max(other.storage_bytes);
+        self.unbounded |= other.unbounded;
     }
 
     pub fn multiply(&mut self, factor: u64) {
-        self.time_ms *= factor;
-        self.memory_bytes *= factor;
-        self.network_bytes *= factor;
-        self.storage_bytes *= factor;
+        self.time_ms = self.time_ms.saturating_mul(factor);
+        self.memory_bytes = self.memory_bytes.saturating_mul(factor);
+        self.network_bytes = self.network_bytes.saturating_mul(factor);
+        self.storage_bytes = self.storage_bytes.saturating_mul(factor);
     }
 
     pub fn fits_within(&self, budget: &ResourceBounds) -> bool {
-        self.time_ms <= budget.time_ms
+        !self.unbounded
+            && self.time_ms <= budget.time_ms
             && self.memory_bytes <= budget.memory_bytes
             && self.network_bytes <= budget.network_bytes
             && self.storage_bytes <= budget.storage_bytes
@@ -80,175 +93,24 @@ pub fn new() -> Self {
 
     /// Analyze resource usage of an expression (WCET)
     pub fn analyze(&self, expr: &Expr) -> ResourceBounds {
-        match expr {
-            // Literals: minimal cost
-            Expr::Int(_) | Expr::Float(_) | Expr::Bool(_) | Expr::String(_) | Expr::Ident(_) => {
-                let mut bounds = ResourceBounds::new();
-                bounds.time_ms = 1;
-                bounds
-            }
-
-            // Bounded for loop: multiply body cost by iterations
-            Expr::BoundedFor {
-                var: _,
-                start,
-                end,
-                body,
-            } => {
-                let iterations = self.eval_const_diff(start, end).unwrap_or(100);
-
-                let mut body_bounds = ResourceBounds::new();
-                for expr in body {
-                    let expr_bounds = self.analyze(expr);
-                    body_bounds.add(&expr_bounds);
-                }
-
-                body_bounds.multiply(iterations);
-                body_bounds
-            }
-
-            // Let binding: sum of bindings + body
-            Expr::Let { bindings, body } => {
-                let mut bounds = ResourceBounds::new();
-
-                for (_, expr) in bindings {
-                    let expr_bounds = self.analyze(expr);
-                    bounds.add(&expr_bounds);
-                }
-
-                for expr in body {
-                    let expr_bounds = self.analyze(expr);
-                    bounds.add(&expr_bounds);
-                }
-
-                bounds
-            }
-
-            // If: condition + max of branches
-            Expr::If {
-                condition,
-                then_branch,
-                else_branch,
-            } => {
-                let mut bounds = self.analyze(condition);
-                let then_bounds = self.analyze(then_branch);
-                let else_bounds = self.analyze(else_branch);
-
-                bounds.max(&then_bounds);
-                bounds.max(&else_bounds);
-                bounds
-            }
-
-            // Function call: analyze arguments + call cost
-            Expr::FunctionCall { func, args } => {
-                let mut bounds = ResourceBounds::new();
-                bounds.time_ms = 10; // Base call overhead
-
-                for arg in args {
-                    let arg_bounds = self.analyze(arg);
-                    bounds.add(&arg_bounds);
-                }
-
-                // If we know the function, add its cost
-                // For now, use heuristic
-                if let Expr::Ident(name) = func.as_ref() {
-                    if let Some(cost) = self.costs.get(name) {
-                        bounds.time_ms += cost;
-                    }
-                }
-
-                bounds
-            }
-
-            // I/O operations
-            Expr::GpioSet { .. } | Expr::GpioGet(_) => {
-                let mut bounds = ResourceBounds::new();
-                bounds.time_ms = *self.costs.get("gpio").unwrap_or(&100);
-                bounds
-            }
-
-            Expr::SensorRead(_) => {
-                let mut bounds = ResourceBounds::new();
-                bounds.time_ms = *self.costs.get("sensor").unwrap_or(&500);
-                bounds
-            }
-
-            Expr::NetworkSend { data, .. } => {
-                let mut bounds = ResourceBounds::new();
-                bounds.time_ms = *self.costs.get("network").unwrap_or(&1000);
-                // Estimate network bytes (would need better analysis)
-                bounds.network_bytes = 256;
-                bounds
-            }
-
-            Expr::SleepMs(ms_expr) => {
-                let mut bounds = ResourceBounds::new();
-                if let Expr::Int(ms) = ms_expr.as_ref() {
-                    bounds.time_ms = *ms as u64;
-                } else {
-                    bounds.time_ms = 1000; // Conservative estimate
-                }
-                bounds
-            }
-
-            // Array operations
-            Expr::ArrayGet { array, index } => {
-                let mut bounds = self.analyze(array);
-                bounds.add(&self.analyze(index));
-                bounds.time_ms += self.costs.get("array-access").unwrap_or(&1);
-                bounds
-            }
-
-            Expr::ArraySet {
-                array,
-                index,
-                value,
-            } => {
-                let mut bounds = self.analyze(array);
-                bounds.add(&self.analyze(index));
-                bounds.add(&self.analyze(value));
-                bounds.time_ms += self.costs.get("array-access").unwrap_or(&1);
-                bounds
-            }
-
-            Expr::ArrayLiteral { elem_type, size } => {
-                let mut bounds = ResourceBounds::new();
-                // Memory for array
-                bounds.memory_bytes = (*size as u64) * 8; // Assume 8 bytes per element
-                bounds
-            }
-
-            // Capability: analyze body
-            Expr::WithCapability { body, .. } => {
-                let mut bounds = ResourceBounds::new();
-                for expr in body {
-                    bounds.add(&self.analyze(expr));
-                }
-                bounds
-            }
-
-            // DefunDeploy: analyze body
-            Expr::DefunDeploy { body, .. } => {
-                let mut bounds = ResourceBounds::new();
-                for expr in body {
-                    bounds.add(&self.analyze(expr));
-                }
-                bounds
-            }
+        let mut visitor = CostVisitor {
+            analyzer: self,
+            bounds: ResourceBounds::new(),
+        };
+        visitor.visit_expr(expr);
+        visitor.bounds
+    }
 
-            _ => ResourceBounds::new(),
-        }
+    fn cost(&self, operation: &str, default: u64) -> u64 {
+        *self.costs.get(operation).unwrap_or(&default)
     }
 
     /// Try to evaluate constant integer difference (for loop bounds)
     fn eval_const_diff(&self, start: &Expr, end: &Expr) -> Option<u64> {
-        match (start, end) {
-            (Expr::Int(s), Expr::Int(e)) => {
-                if e >= s {
-                    Some((e - s) as u64)
-                } else {
-                    Some(0)
-                }
+        match (&start.kind, &end.kind) {
+            (ExprKind::Int(s), ExprKind::Int(e)) => {
+                let diff = e.value.saturating_sub(s.value).max(0);
+                Some(u64::try_from(diff).unwrap_or(u64::MAX))
             }
             _ => None,
         }
@@ -257,11 +119,11 @@ fn eval_const_diff(&self, start: &Expr, end: &Expr) -> Option<u64> {
     /// Extract resource budget from program
     pub fn extract_budget(exprs: &[Expr]) -> Option<ResourceBounds> {
         for expr in exprs {
-            if let Expr::Program { budget, .. } = expr {
-                if let Expr::ResourceBudget { specs } = budget.as_ref() {
+            if let ExprKind::Program { budget, .. } = &expr.kind {
+                if let ExprKind::ResourceBudget { specs } = &budget.kind {
                     return Some(Self::specs_to_bounds(specs));
                 }
-            } else if let Expr::ResourceBudget { specs } = expr {
+            } else if let ExprKind::ResourceBudget { specs } = &expr.kind {
                 return Some(Self::specs_to_bounds(specs));
             }
         }
@@ -284,8 +146,274 @@ fn specs_to_bounds(specs: &[ResourceSpec]) -> ResourceBounds {
     }
 }
 
+/// Sums the worst-case cost of every deploy-time node it visits
+struct CostVisitor<'a> {
+    analyzer: &'a ResourceAnalyzer,
+    bounds: ResourceBounds,
+}
+
+impl CostVisitor<'_> {
+    /// Cost of what `walk` visits, kept apart from the running total
+    fn measure(&mut self, walk: impl FnOnce(&mut Self)) -> ResourceBounds {
+        let outer = mem::take(&mut self.bounds);
+        walk(self);
+        mem::replace(&mut self.bounds, outer)
+    }
+
+    fn charge(&mut self, operation: &str, default: u64) {
+        let cost = self.analyzer.cost(operation, default);
+        self.bounds.time_ms = self.bounds.time_ms.saturating_add(cost);
+    }
+}
+
+impl Visitor for CostVisitor<'_> {
+    // Literals: minimal cost
+    fn visit_int(&mut self, _literal: &IntLiteral, _span: Span) {
+        self.bounds.time_ms = self.bounds.time_ms.saturating_add(1);
+    }
+
+    fn visit_float(&mut self, _value: f64, _span: Span) {
+        self.bounds.time_ms = self.bounds.time_ms.saturating_add(1);
+    }
+
+    fn visit_bool(&mut self, _value: bool, _span: Span) {
+        self.bounds.time_ms = self.bounds.time_ms.saturating_add(1);
+    }
+
+    fn visit_string(&mut self, _value: &str, _span: Span) {
+        self.bounds.time_ms = self.bounds.time_ms.saturating_add(1);
+    }
+
+    fn visit_ident(&mut self, _name: &str, _span: Span) {
+        self.bounds.time_ms = self.bounds.time_ms.saturating_add(1);
+    }
+
+    // Bounded for loop: multiply body cost by iterations, when they are known
+    fn visit_bounded_for(
+        &mut self,
+        _var: &str,
+        start: &Expr,
+        end: &Expr,
+        body: &[Expr],
+        _span: Span,
+    ) {
+        let mut body_bounds = self.measure(|this| walk_exprs(this, body));
+        match self.analyzer.eval_const_diff(start, end) {
+            Some(iterations) => body_bounds.multiply(iterations),
+            None => body_bounds.unbounded = true,
+        }
+        self.bounds.add(&body_bounds);
+    }
+
+    // If: condition + max of branches
+    fn visit_if(&mut self, condition: &Expr, then_branch: &Expr, else_branch: &Expr, _span: Span) {
+        self.visit_expr(condition);
+        let mut branch = self.measure(|this| this.visit_expr(then_branch));
+        branch.max(&self.measure(|this| this.visit_expr(else_branch)));
+        self.bounds.add(&branch);
+    }
+
+    // Function call: arguments + call overhead + known operation cost
+    fn visit_function_call(&mut self, func: &Expr, args: &[Expr], _span: Span) {
+        self.bounds.time_ms = self.bounds.time_ms.saturating_add(10);
+        walk_exprs(self, args);
+        if let ExprKind::Ident(name) = &func.kind {
+            if let Some(cost) = self.analyzer.costs.get(name) {
+                self.bounds.time_ms = self.bounds.time_ms.saturating_add(*cost);
+            }
+        }
+    }
+
+    // I/O operations
+    fn visit_gpio_set(&mut self, device: &Expr, value: &Expr, _span: Span) {
+        walk_gpio_set(self, device, value);
+        self.charge("gpio", 100);
+    }
+
+    fn visit_gpio_get(&mut self, device: &Expr, _span: Span) {
+        walk_gpio_get(self, device);
+        self.charge("gpio", 100);
+    }
+
+    fn visit_uart_send(&mut self, device: &Expr, data: &Expr, _span: Span) {
+        walk_uart_send(self, device, data);
+        self.charge("uart", 200);
+    }
+
+    fn visit_uart_recv(&mut self, device: &Expr, _span: Span) {
+        walk_uart_recv(self, device);
+        self.charge("uart", 200);
+    }
+
+    fn visit_sensor_read(&mut self, device: &Expr, _span: Span) {
+        walk_sensor_read(self, device);
+        self.charge("sensor", 500);
+    }
+
+    fn visit_network_send(&mut self, device: &Expr, data: &Expr, _span: Span) {
+        walk_network_send(self, device, data);
+        self.charge("network", 1000);
+        // Estimate network bytes (would need better analysis)
+        self.bounds.network_bytes = self.bounds.network_bytes.saturating_add(256);
+    }
+
+    fn visit_network_recv(&mut self, device: &Expr, _span: Span) {
+        walk_network_recv(self, device);
+        self.charge("network", 1000);
+    }
+
+    fn visit_sleep_ms(&mut self, duration: &Expr, _span: Span) {
+        let ms = match &duration.kind {
+            // A negative duration does not sleep
+            ExprKind::Int(ms) => u64::try_from(ms.value.max(0)).unwrap_or(u64::MAX),
+            _ => 1000, // Conservative estimate
+        };
+        self.bounds.time_ms = self.bounds.time_ms.saturating_add(ms);
+    }
+
+    // Array operations
+    fn visit_array_get(&mut self, array: &Expr, index: &Expr, _span: Span) {
+        walk_array_get(self, array, index);
+        self.charge("array-access", 1);
+    }
+
+    fn visit_array_set(&mut self, array: &Expr, index: &Expr, value: &Expr, _span: Span) {
+        walk_array_set(self, array, index, value);
+        self.charge("array-access", 1);
+    }
+
+    fn visit_array_literal(&mut self, elem_type: &Type, size: usize, _span: Span) {
+        let bytes = elem_type.size_bytes().saturating_mul(size as u64);
+        self.bounds.memory_bytes = self.bounds.memory_bytes.saturating_add(bytes);
+    }
+
+    fn visit_array_init(&mut self, elem_type: &Type, elements: &[Expr], _span: Span) {
+        walk_exprs(self, elements);
+        let bytes = elem_type.size_bytes().saturating_mul(elements.len() as u64);
+        self.bounds.memory_bytes = self.bounds.memory_bytes.saturating_add(bytes);
+    }
+
+    // Compile-time code costs nothing at deploy time
+    fn visit_defun_compile(
+        &mut self,
+        _name: &str,
+        _params: &[Parameter],
+        _return_type: Option<&Type>,
+        _body: &[Expr],
+        _span: Span,
+    ) {
+    }
+
+    fn visit_macro(&mut self, _name: &str, _params: &[Parameter], _body: &[Expr], _span: Span) {}
+
+    fn visit_eval_compile(&mut self, _expr: &Expr, _span: Span) {}
+}
+
 impl Default for ResourceAnalyzer {
     fn default() -> Self {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_array_memory_uses_element_size() {
+        let analyzer = ResourceAnalyzer::new();
+        let bytes = |elem_type: Type, size: usize| {
+            let expr = ExprKind::ArrayLiteral { elem_type, size }.into();
+            analyzer.analyze(&expr).memory_bytes
+        };
+
+        assert_eq!(bytes(Type::Uint8, 128), 128);
+        assert_eq!(bytes(Type::Int16, 4), 8);
+        assert_eq!(bytes(Type::Uint64, 4), 32);
+    }
+
+    #[test]
+    fn test_oversized_array_exceeds_every_budget() {
+        let analyzer = ResourceAnalyzer::new();
+        let huge = Type::Array {
+            elem_type: Box::new(Type::Array {
+                elem_type: Box::new(Type::Uint64),
+                size: usize::MAX,
+            }),
+            size: usize::MAX,
+        };
+        assert_eq!(huge.size_bytes(), u64::MAX);
+
+        let expr = ExprKind::ArrayLiteral {
+            elem_type: huge,
+            size: 2,
+        }
+        .into();
+        let bounds = analyzer.analyze(&expr);
+        assert_eq!(bounds.memory_bytes, u64::MAX);
+        let budget = ResourceBounds {
+            memory_bytes: u64::MAX - 1,
+            ..ResourceBounds::new()
+        };
+        assert!(!bounds.fits_within(&budget));
+    }
+
+    #[test]
+    fn test_wide_literals_do_not_wrap() {
+        let analyzer = ResourceAnalyzer::new();
+        let cost = |source: &str| analyzer.analyze(&crate::parse_file(source).unwrap()[0]);
+
+        assert_eq!(cost("(sleep-ms -5)").time_ms, 0);
+        assert_eq!(cost("(sleep-ms 0xFFFF_FFFF_FFFF_FFFF)").time_ms, u64::MAX);
+        let bounds = cost("(bounded-for i -1 0xFFFF_FFFF_FFFF_FFFF (sleep-ms 1))");
+        assert_eq!(bounds.time_ms, u64::MAX);
+    }
+
+    #[test]
+    fn test_totals_saturate() {
+        let analyzer = ResourceAnalyzer::new();
+        let exprs = crate::parse_file(
+            "(bounded-for i 0 0xFFFF_FFFF_FFFF_FFFF (network-send n (sensor-read s)))",
+        )
+        .unwrap();
+        let bounds = analyzer.analyze(&exprs[0]);
+        assert_eq!(bounds.time_ms, u64::MAX);
+        assert_eq!(bounds.network_bytes, u64::MAX);
+
+        let mut total = bounds.clone();
+        total.add(&bounds);
+        assert_eq!(total, bounds);
+    }
+
+    #[test]
+    fn test_variable_trip_count_is_unbounded() {
+        let analyzer = ResourceAnalyzer::new();
+        let cost = |source: &str| analyzer.analyze(&crate::parse_file(source).unwrap()[0]);
+
+        let bounds = cost("(bounded-for i 0 4 (sleep-ms 5))");
+        assert_eq!(bounds.time_ms, 20);
+        assert!(!bounds.unbounded);
+
+        // The body is counted once, and no budget is met
+        let bounds = cost("(bounded-for i 0 n (sleep-ms 5))");
+        assert_eq!(bounds.time_ms, 5);
+        assert!(bounds.unbounded);
+        let budget = ResourceBounds {
+            time_ms: u64::MAX,
+            ..ResourceBounds::new()
+        };
+        assert!(!bounds.fits_within(&budget));
+
+        let bounds = cost("(if c (bounded-for i 0 (array-length xs) 1) 0)");
+        assert!(bounds.unbounded);
+    }
+
+    #[test]
+    fn test_if_costs_condition_plus_worst_branch() {
+        let analyzer = ResourceAnalyzer::new();
+        let exprs = crate::parse_file("(if c (sleep-ms 5) (let ((x 1)) (sensor-read s)))").unwrap();
+
+        // Condition (1) + the else branch: binding (1) + sensor (500) + device (1)
+        assert_eq!(analyzer.analyze(&exprs[0]).time_ms, 503);
+    }
+}
@@ -1,22 +1,70 @@
-use crate::ast::Expr;
+use crate::ast::{
+    walk_bounded_for, walk_expr, walk_exprs, Expr, ExprKind, Parameter, Span, Type, Visitor,
+};
 use crate::analyzer::call_graph::CallGraph;
+use crate::diagnostics::{codes, Diagnostic, ToDiagnostic};
 use thiserror::Error;
 
 #[derive(Error, Debug)]
 pub enum TerminationError {
-    #[error("Recursion detected in deploy-time code")]
-    Recursion,
+    #[error("Recursion detected in deploy-time code: {function} at {span}")]
+    Recursion { function: String, span: Span },
 
-    #[error("Unbounded loop found in deploy-time code: {0}")]
-    UnboundedLoop(String),
+    #[error("Unbounded loop found in deploy-time code: {construct} at {span}")]
+    UnboundedLoop { construct: String, span: Span },
 
-    #[error("Cannot prove loop bounds are finite: {0}")]
-    UnknownBounds(String),
+    #[error("Cannot prove loop bounds are finite: {context} at {span}")]
+    UnknownBounds { context: String, span: Span },
 
     #[error("Infinite resource budget (deployment must have finite resources)")]
     InfiniteResources,
 }
 
+impl TerminationError {
+    /// Source location of the offending construct, if it has one
+    pub fn span(&self) -> Option<Span> {
+        match self {
+            TerminationError::Recursion { span, .. }
+            | TerminationError::UnboundedLoop { span, .. }
+            | TerminationError::UnknownBounds { span, .. } => Some(*span),
+            TerminationError::InfiniteResources => None,
+        }
+    }
+}
+
+impl ToDiagnostic for TerminationError {
+    fn to_diagnostic(&self) -> Diagnostic {
+        match self {
+            TerminationError::Recursion { function, span } => Diagnostic::error(
+                codes::RECURSION,
+                format!("function `{}` is recursive", function),
+                *span,
+            )
+            .with_label("part of a call cycle")
+            .with_note("deploy-time functions must form an acyclic call graph")
+            .with_help("rewrite the recursion as a `bounded-for` loop"),
+            TerminationError::UnboundedLoop { construct, span } => Diagnostic::error(
+                codes::UNBOUNDED_LOOP,
+                "unbounded loop in deploy-time code",
+                *span,
+            )
+            .with_label(format!("{} has no static bound", construct))
+            .with_help("use `bounded-for` with a static iteration count"),
+            TerminationError::UnknownBounds { context, span } => Diagnostic::error(
+                codes::UNKNOWN_BOUNDS,
+                "cannot prove loop bounds are finite",
+                *span,
+            )
+            .with_label(format!("bounds of `{}` are not integer expressions", context)),
+            TerminationError::InfiniteResources => Diagnostic::error(
+                codes::INFINITE_RESOURCES,
+                "deployment must have finite resources",
+                Span::dummy(),
+            ),
+        }
+    }
+}
+
 pub struct TerminationChecker {
     call_graph: CallGraph,
 }
@@ -30,107 +78,43 @@ pub fn new(exprs: &[Expr]) -> Self {
     /// Check that all deploy-time code provably terminates
     pub fn check_terminates(&self, exprs: &[Expr]) -> Result<(), TerminationError> {
         // Check 1: Call graph must be acyclic (no recursion)
-        if self.call_graph.has_cycles() {
-            return Err(TerminationError::Recursion);
+        if let Some(function) = self.call_graph.recursive_functions().into_iter().next() {
+            let span = self
+                .call_graph
+                .definition_span(&function)
+                .unwrap_or_default();
+            return Err(TerminationError::Recursion { function, span });
         }
 
         // Check 2: All loops must be bounded
-        for expr in exprs {
-            self.check_loops(expr)?;
-        }
-
-        Ok(())
-    }
-
-    /// Recursively check that all loops are bounded
-    fn check_loops(&self, expr: &Expr) -> Result<(), TerminationError> {
-        match expr {
-            // While loops are unbounded - not allowed in deploy
-            Expr::While { .. } => Err(TerminationError::UnboundedLoop(
-                "while loop".to_string(),
-            )),
-
-            // For loops are unbounded - not allowed in deploy
-            Expr::For { .. } => Err(TerminationError::UnboundedLoop(
-                "for loop".to_string(),
-            )),
-
-            // Bounded-for is OK if bounds are static
-            Expr::BoundedFor {
-                var,
-                start,
-                end,
-                body,
-            } => {
-                // Check that bounds are computable
-                if !self.are_bounds_finite(start, end) {
-                    return Err(TerminationError::UnknownBounds(format!(
-                        "bounded-for {}",
-                        var
-                    )));
-                }
-
-                // Recursively check body
-                for e in body {
-                    self.check_loops(e)?;
-                }
-
-                Ok(())
-            }
-
-            // Recursively check compound expressions
-            Expr::DefunDeploy { body, .. } => {
-                for e in body {
-                    self.check_loops(e)?;
-                }
-                Ok(())
-            }
-
-            Expr::Let { bindings, body } => {
-                for (_, e) in bindings {
-                    self.check_loops(e)?;
-                }
-                for e in body {
-                    self.check_loops(e)?;
-                }
-                Ok(())
-            }
-
-            Expr::If {
-                condition,
-                then_branch,
-                else_branch,
-            } => {
-                self.check_loops(condition)?;
-                self.check_loops(then_branch)?;
-                self.check_loops(else_branch)?;
-                Ok(())
-            }
-
-            Expr::WithCapability { body, .. } => {
-                for e in body {
-                    self.check_loops(e)?;
-                }
-                Ok(())
-            }
-
-            Expr::FunctionCall { func, args } => {
-                self.check_loops(func)?;
-                for arg in args {
-                    self.check_loops(arg)?;
-                }
-                Ok(())
-            }
+        let mut loops = LoopChecker {
+            checker: self,
+            error: None,
+        };
+        walk_exprs(&mut loops, exprs);
 
-            _ => Ok(()),
+        match loops.error {
+            Some(error) => Err(error),
+            None => Ok(()),
         }
     }
 
     /// Check if loop bounds are finite and computable
     fn are_bounds_finite(&self, start: &Expr, end: &Expr) -> bool {
-        // For now, accept only integer literals or simple expressions
-        // A more sophisticated analysis would handle variables, etc.
-        matches!(start, Expr::Int(_)) && matches!(end, Expr::Int(_))
+        // Bounds are evaluated once on loop entry, so any integer-valued
+        // expression built from literals, variables and calls is finite
+        Self::is_integer_bound(start) && Self::is_integer_bound(end)
+    }
+
+    fn is_integer_bound(expr: &Expr) -> bool {
+        match &expr.kind {
+            ExprKind::Int(_) | ExprKind::Ident(_) => true,
+            ExprKind::ArrayLength(_) | ExprKind::ArrayGet { .. } => true,
+            ExprKind::FunctionCall { func, args } => {
+                matches!(func.kind, ExprKind::Ident(_)) && args.iter().all(Self::is_integer_bound)
+            }
+            _ => false,
+        }
     }
 
     /// Get the topological order of functions (for verification)
@@ -139,12 +123,12 @@ pub fn function_order(&self) -> Option<Vec<String>> {
     }
 
     /// Compute a termination ranking function for a loop
-    /// Returns the maximum iterations or None if unbounded
+    /// Returns the maximum iterations, or None if unbounded or too many to count
     pub fn loop_ranking_function(&self, start: &Expr, end: &Expr) -> Option<u64> {
-        match (start, end) {
-            (Expr::Int(s), Expr::Int(e)) => {
-                if e >= s {
-                    Some((e - s) as u64)
+        match (&start.kind, &end.kind) {
+            (ExprKind::Int(s), ExprKind::Int(e)) => {
+                if e.value >= s.value {
+                    u64::try_from(e.value - s.value).ok()
                 } else {
                     Some(0)
                 }
@@ -154,51 +138,212 @@ pub fn loop_ranking_function(&self, start: &Expr, end: &Expr) -> Option<u64> {
     }
 }
 
+/// Finds the first loop in deploy-time code that is not provably bounded
+struct LoopChecker<'a> {
+    checker: &'a TerminationChecker,
+    error: Option<TerminationError>,
+}
+
+impl LoopChecker<'_> {
+    fn report(&mut self, error: TerminationError) {
+        self.error.get_or_insert(error);
+    }
+}
+
+impl Visitor for LoopChecker<'_> {
+    fn visit_expr(&mut self, expr: &Expr) {
+        if self.error.is_none() {
+            walk_expr(self, expr);
+        }
+    }
+
+    // While and for loops are unbounded - not allowed in deploy
+    fn visit_while(&mut self, _condition: &Expr, _body: &[Expr], span: Span) {
+        self.report(TerminationError::UnboundedLoop {
+            construct: "while loop".to_string(),
+            span,
+        });
+    }
+
+    fn visit_for(&mut self, _var: &str, _iterable: &Expr, _body: &[Expr], span: Span) {
+        self.report(TerminationError::UnboundedLoop {
+            construct: "for loop".to_string(),
+            span,
+        });
+    }
+
+    // Bounded-for is OK if bounds are static
+    fn visit_bounded_for(
+        &mut self,
+        var: &str,
+        start: &Expr,
+        end: &Expr,
+        body: &[Expr],
+        span: Span,
+    ) {
+        if !self.checker.are_bounds_finite(start, end) {
+            self.report(TerminationError::UnknownBounds {
+                context: format!("bounded-for {}", var),
+                span,
+            });
+            return;
+        }
+        walk_bounded_for(self, start, end, body);
+    }
+
+    // Compile-time code is bounded by the evaluator's fuel instead
+    fn visit_defun_compile(
+        &mut self,
+        _name: &str,
+        _params: &[Parameter],
+        _return_type: Option<&Type>,
+        _body: &[Expr],
+        _span: Span,
+    ) {
+    }
+
+    fn visit_macro(&mut self, _name: &str, _params: &[Parameter], _body: &[Expr], _span: Span) {}
+
+    fn visit_eval_compile(&mut self, _expr: &Expr, _span: Span) {}
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
     #[test]
     fn test_bounded_for_valid() {
-        let expr = Expr::BoundedFor {
+        let expr: Expr = ExprKind::BoundedFor {
             var: "i".to_string(),
-            start: Box::new(Expr::Int(0)),
-            end: Box::new(Expr::Int(10)),
-            body: vec![Expr::Int(1)],
-        };
+            start: Box::new(ExprKind::Int(0.into()).into()),
+            end: Box::new(ExprKind::Int(10.into()).into()),
+            body: vec![ExprKind::Int(1.into()).into()],
+        }
+        .into();
 
-        let exprs = vec![expr];
+        let exprs: Vec<Expr> = vec![expr];
         let checker = TerminationChecker::new(&exprs);
         assert!(checker.check_terminates(&exprs).is_ok());
     }
 
     #[test]
-    fn test_while_invalid() {
-        let expr = Expr::While {
-            condition: Box::new(Expr::Bool(true)),
-            body: vec![Expr::Int(1)],
+    fn test_bound_forms() {
+        let check = |bound: &str| {
+            let source = format!(
+                "(defun-deploy half (x) (/ x 2))\n(defun-deploy f (n xs) (bounded-for i 0 {} 1))",
+                bound
+            );
+            let exprs = crate::parse_file(&source).unwrap();
+            TerminationChecker::new(&exprs).check_terminates(&exprs)
         };
 
-        let exprs = vec![expr];
+        for bound in [
+            "n",
+            "(array-length xs)",
+            "(array-get xs 0)",
+            "(half n)",
+            "(min (array-length xs) (half 8))",
+        ] {
+            assert!(check(bound).is_ok(), "{}", bound);
+        }
+        for bound in ["1.5", "\"n\"", "(if true 1 2)", "(half \"n\")"] {
+            assert!(
+                matches!(check(bound), Err(TerminationError::UnknownBounds { .. })),
+                "{}",
+                bound
+            );
+        }
+    }
+
+    #[test]
+    fn test_ranking_function_fits_u64() {
+        let exprs = crate::parse_file("(defun-deploy f () 0)").unwrap();
+        let checker = TerminationChecker::new(&exprs);
+        let rank = |start: &str, end: &str| {
+            let start = &crate::parse_file(start).unwrap()[0];
+            let end = &crate::parse_file(end).unwrap()[0];
+            checker.loop_ranking_function(start, end)
+        };
+
+        assert_eq!(rank("2", "10"), Some(8));
+        assert_eq!(rank("10", "2"), Some(0));
+        assert_eq!(rank("0", "0xFFFF_FFFF_FFFF_FFFF"), Some(u64::MAX));
+        assert_eq!(rank("-1", "0xFFFF_FFFF_FFFF_FFFF"), None);
+        assert_eq!(rank("0", "n"), None);
+    }
+
+    #[test]
+    fn test_while_invalid() {
+        let expr: Expr = ExprKind::While {
+            condition: Box::new(ExprKind::Bool(true).into()),
+            body: vec![ExprKind::Int(1.into()).into()],
+        }
+        .into();
+
+        let exprs: Vec<Expr> = vec![expr];
         let checker = TerminationChecker::new(&exprs);
         assert!(checker.check_terminates(&exprs).is_err());
     }
 
     #[test]
     fn test_recursion_detection() {
-        let exprs = vec![
-            Expr::DefunDeploy {
+        let exprs: Vec<Expr> = vec![
+            ExprKind::DefunDeploy {
                 name: "foo".to_string(),
                 params: vec![],
                 return_type: None,
-                body: vec![Expr::FunctionCall {
-                    func: Box::new(Expr::Ident("foo".to_string())),
+                body: vec![ExprKind::FunctionCall {
+                    func: Box::new(ExprKind::Ident("foo".to_string()).into()),
                     args: vec![],
-                }],
-            },
+                }
+                .into()],
+            }
+            .into(),
         ];
 
         let checker = TerminationChecker::new(&exprs);
         assert!(checker.check_terminates(&exprs).is_err());
     }
+
+    #[test]
+    fn test_error_points_at_loop() {
+        let source = "(defun-deploy f (n)\n  (bounded-for i 0 \"n\"\n    (sleep-ms n)))";
+        let exprs = crate::parse_file(source).unwrap();
+        let checker = TerminationChecker::new(&exprs);
+
+        let err = checker.check_terminates(&exprs).unwrap_err();
+        assert_eq!(err.span(), Some(Span::new(22, 60, 2, 3)));
+        assert!(err.to_string().ends_with("at 2:3"));
+    }
+
+    #[test]
+    fn test_recursion_points_at_function() {
+        let source = "(defun-deploy helper () 1)\n(defun-deploy loop ()\n  (loop))";
+        let exprs = crate::parse_file(source).unwrap();
+        let checker = TerminationChecker::new(&exprs);
+
+        match checker.check_terminates(&exprs) {
+            Err(TerminationError::Recursion { function, span }) => {
+                assert_eq!(function, "loop");
+                assert_eq!(span.line, 2);
+            }
+            other => panic!("expected recursion error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_loops_checked_inside_program_and_nested_forms() {
+        let source = "(program p (resource-budget (time-ms 10))\n  (defun-deploy f (x)\n    (set x (if x (while x 1) 0))))";
+        let exprs = crate::parse_file(source).unwrap();
+        let checker = TerminationChecker::new(&exprs);
+
+        let err = checker.check_terminates(&exprs).unwrap_err();
+        assert!(matches!(err, TerminationError::UnboundedLoop { .. }));
+        assert_eq!(err.span().unwrap().line, 3);
+
+        // Compile-time loops are left to the evaluator's fuel limit
+        let exprs = crate::parse_file("(defun-compile g () (while true 1))").unwrap();
+        let checker = TerminationChecker::new(&exprs);
+        assert!(checker.check_terminates(&exprs).is_ok());
+    }
 }
@@ -0,0 +1,706 @@
+use crate::ast::{Expr, ExprKind, Parameter, Span, Type};
+use crate::diagnostics::{codes, Diagnostic, ToDiagnostic};
+use std::collections::HashMap;
+use thiserror::Error;
+
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum TypeError {
+    #[error("Type mismatch: expected {expected}, found {found} at {span}")]
+    Mismatch {
+        expected: Type,
+        found: Type,
+        span: Span,
+        /// Where the expected type comes from, if written in the source
+        context: Option<Span>,
+    },
+
+    #[error("Expected an array, found {found} at {span}")]
+    NotAnArray { found: Type, span: Span },
+
+    #[error("Operator {operator} cannot be applied to {found} at {span}")]
+    InvalidOperand {
+        operator: String,
+        found: Type,
+        span: Span,
+    },
+}
+
+impl TypeError {
+    pub fn span(&self) -> Span {
+        match self {
+            TypeError::Mismatch { span, .. }
+            | TypeError::NotAnArray { span, .. }
+            | TypeError::InvalidOperand { span, .. } => *span,
+        }
+    }
+}
+
+impl ToDiagnostic for TypeError {
+    fn to_diagnostic(&self) -> Diagnostic {
+        match self {
+            TypeError::Mismatch {
+                expected,
+                found,
+                span,
+                context,
+            } => {
+                let diagnostic = Diagnostic::error(codes::TYPE_MISMATCH, "mismatched types", *span)
+                    .with_label(format!("expected `{}`, found `{}`", expected, found));
+                match context {
+                    Some(context) => diagnostic.with_secondary(
+                        *context,
+                        format!("expected `{}` because of this", expected),
+                    ),
+                    None => diagnostic,
+                }
+            }
+            TypeError::NotAnArray { found, span } => {
+                Diagnostic::error(codes::NOT_AN_ARRAY, "expected an array", *span)
+                    .with_label(format!("this is `{}`", found))
+            }
+            TypeError::InvalidOperand {
+                operator,
+                found,
+                span,
+            } => Diagnostic::error(
+                codes::INVALID_OPERAND,
+                format!("`{}` cannot be applied to `{}`", operator, found),
+                *span,
+            )
+            .with_label(format!("this is `{}`", found)),
+        }
+    }
+}
+
+/// Declared signature of a named function
+struct Signature {
+    params: Vec<Parameter>,
+    return_type: Option<Type>,
+}
+
+impl Signature {
+    /// The function's type, if every part of it is annotated
+    fn to_type(&self) -> Option<Type> {
+        let params = self
+            .params
+            .iter()
+            .map(|p| p.ty().cloned())
+            .collect::<Option<Vec<_>>>()?;
+        Some(Type::Function {
+            params,
+            return_type: Box::new(self.return_type.clone()?),
+        })
+    }
+}
+
+/// Variables in scope, their type if known, and where they were declared
+type Scope = HashMap<String, (Option<Type>, Span)>;
+
+/// A builtin operator and the operand types it accepts
+type Operator<'a> = (&'a str, fn(&Type) -> bool);
+
+/// Checks deploy-time functions against the typing rules of the semantics
+/// document.
+///
+/// Types are only checked where they are known: an unannotated parameter,
+/// or anything computed from one, has no type and is never reported.
+/// Unsuffixed literals take on the type their context expects, leaving the
+/// range check to `LiteralChecker`. Compile-time code is not checked; the
+/// evaluator rejects ill-typed values when it runs.
+pub struct TypeChecker {
+    signatures: HashMap<String, Signature>,
+    errors: Vec<TypeError>,
+}
+
+impl TypeChecker {
+    pub fn new(exprs: &[Expr]) -> Self {
+        let mut signatures = HashMap::new();
+        for expr in exprs {
+            Self::collect_signatures(expr, &mut signatures);
+        }
+        Self {
+            signatures,
+            errors: Vec::new(),
+        }
+    }
+
+    pub fn check(mut self, exprs: &[Expr]) -> Result<(), Vec<TypeError>> {
+        for expr in exprs {
+            self.synth(expr, &Scope::new());
+        }
+        if self.errors.is_empty() {
+            Ok(())
+        } else {
+            Err(self.errors)
+        }
+    }
+
+    fn collect_signatures(expr: &Expr, signatures: &mut HashMap<String, Signature>) {
+        match &expr.kind {
+            ExprKind::DefunDeploy {
+                name,
+                params,
+                return_type,
+                ..
+            }
+            | ExprKind::DefunCompile {
+                name,
+                params,
+                return_type,
+                ..
+            } => {
+                let signature = Signature {
+                    params: params.clone(),
+                    return_type: return_type.clone(),
+                };
+                signatures.insert(name.clone(), signature);
+            }
+            ExprKind::Program { forms, .. } => {
+                for form in forms {
+                    Self::collect_signatures(form, signatures);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Type of `expr`, if it can be determined
+    fn synth(&mut self, expr: &Expr, scope: &Scope) -> Option<Type> {
+        match &expr.kind {
+            // T-Int, T-Bool
+            ExprKind::Int(literal) => Some(literal.suffix.clone().unwrap_or(Type::Int32)),
+            ExprKind::Float(_) => Some(Type::Float64),
+            ExprKind::Bool(_) => Some(Type::Bool),
+            ExprKind::String(_) => Some(Type::String),
+
+            // T-Var
+            ExprKind::Ident(name) => match scope.get(name) {
+                Some((ty, _)) => ty.clone(),
+                None => self.signatures.get(name).and_then(Signature::to_type),
+            },
+
+            // T-Defun-Deploy
+            ExprKind::DefunDeploy {
+                name,
+                params,
+                return_type,
+                body,
+            } => {
+                let mut scope = scope.clone();
+                for param in params {
+                    scope.insert(
+                        param.name.clone(),
+                        (param.ty().cloned(), param.span),
+                    );
+                }
+                if let Some((last, rest)) = body.split_last() {
+                    for expr in rest {
+                        self.synth(expr, &scope);
+                    }
+                    match return_type {
+                        Some(ty) => self.check_expr(last, ty, Some(expr.span), &scope),
+                        None => {
+                            self.synth(last, &scope);
+                        }
+                    }
+                }
+                self.signatures.get(name).and_then(Signature::to_type)
+            }
+
+            // T-Bounded-For
+            ExprKind::BoundedFor {
+                var,
+                start,
+                end,
+                body,
+            } => {
+                self.check_expr(start, &Type::Int32, None, scope);
+                self.check_expr(end, &Type::Int32, None, scope);
+                let mut scope = scope.clone();
+                scope.insert(var.clone(), (Some(Type::Int32), expr.span));
+                for expr in body {
+                    self.synth(expr, &scope);
+                }
+                Some(Type::Void)
+            }
+
+            // T-With-Capability
+            ExprKind::WithCapability { capability, body } => {
+                self.synth(capability, scope);
+                self.synth_body(body, scope)
+            }
+
+            // T-Let: the values are typed in the outer scope
+            ExprKind::Let { bindings, body } => {
+                let scope = self.bind(bindings, scope);
+                self.synth_body(body, &scope)
+            }
+
+            ExprKind::Set { var, value } => {
+                match scope.get(var) {
+                    Some((Some(ty), declared)) => {
+                        self.check_expr(value, ty, Some(*declared), scope)
+                    }
+                    _ => {
+                        self.synth(value, scope);
+                    }
+                }
+                Some(Type::Void)
+            }
+
+            // T-If
+            ExprKind::If {
+                condition,
+                then_branch,
+                else_branch,
+            } => {
+                self.check_expr(condition, &Type::Bool, None, scope);
+                self.unify(&[then_branch, else_branch], scope, None)
+            }
+
+            // T-App
+            ExprKind::FunctionCall { func, args } => self.synth_call(func, args, scope),
+
+            ExprKind::ArrayLiteral { elem_type, size } => Some(Type::Array {
+                elem_type: Box::new(elem_type.clone()),
+                size: *size,
+            }),
+            ExprKind::ArrayInit {
+                elem_type,
+                elements,
+            } => {
+                for element in elements {
+                    self.check_expr(element, elem_type, None, scope);
+                }
+                Some(Type::Array {
+                    elem_type: Box::new(elem_type.clone()),
+                    size: elements.len(),
+                })
+            }
+
+            // T-Array-Get
+            ExprKind::ArrayGet { array, index } => {
+                let elem_type = self.synth_elem(array, scope);
+                self.check_expr(index, &Type::Int32, None, scope);
+                elem_type
+            }
+            ExprKind::ArraySet {
+                array,
+                index,
+                value,
+            } => {
+                let elem_type = self.synth_elem(array, scope);
+                self.check_expr(index, &Type::Int32, None, scope);
+                match elem_type {
+                    Some(ty) => self.check_expr(value, &ty, None, scope),
+                    None => {
+                        self.synth(value, scope);
+                    }
+                }
+                Some(Type::Void)
+            }
+            ExprKind::ArrayLength(array) => {
+                self.synth_elem(array, scope);
+                Some(Type::Int32)
+            }
+
+            // The semantics leave device reads untyped, so only the effects
+            // are known
+            ExprKind::GpioSet { .. }
+            | ExprKind::UartSend { .. }
+            | ExprKind::NetworkSend { .. }
+            | ExprKind::SleepMs(_) => {
+                for child in expr.children() {
+                    self.synth(child, scope);
+                }
+                Some(Type::Void)
+            }
+            ExprKind::GpioGet(device)
+            | ExprKind::UartRecv(device)
+            | ExprKind::SensorRead(device)
+            | ExprKind::NetworkRecv(device) => {
+                self.synth(device, scope);
+                None
+            }
+            ExprKind::Timestamp => None,
+
+            ExprKind::Program { forms, .. } => {
+                for form in forms {
+                    self.synth(form, scope);
+                }
+                None
+            }
+
+            // Compile-time code and declarations
+            ExprKind::DefunCompile { .. }
+            | ExprKind::Macro { .. }
+            | ExprKind::EvalCompile(_)
+            | ExprKind::Include(_)
+            | ExprKind::For { .. }
+            | ExprKind::While { .. }
+            | ExprKind::ResourceBudget { .. }
+            | ExprKind::DefCap { .. } => None,
+        }
+    }
+
+    /// Check that `expr` has type `expected`, as required at `context`
+    fn check_expr(&mut self, expr: &Expr, expected: &Type, context: Option<Span>, scope: &Scope) {
+        if Self::adapts_to(expr, expected) {
+            return;
+        }
+        match &expr.kind {
+            ExprKind::If {
+                condition,
+                then_branch,
+                else_branch,
+            } => {
+                self.check_expr(condition, &Type::Bool, None, scope);
+                self.check_expr(then_branch, expected, context, scope);
+                self.check_expr(else_branch, expected, context, scope);
+            }
+            ExprKind::Let { bindings, body } => {
+                let scope = self.bind(bindings, scope);
+                self.check_body(body, expected, context, &scope);
+            }
+            ExprKind::WithCapability { capability, body } => {
+                self.synth(capability, scope);
+                self.check_body(body, expected, context, scope);
+            }
+            _ => {
+                if let Some(found) = self.synth(expr, scope) {
+                    self.expect(expected, found, expr.span, context);
+                }
+            }
+        }
+    }
+
+    fn expect(&mut self, expected: &Type, found: Type, span: Span, context: Option<Span>) {
+        if *expected != found {
+            self.errors.push(TypeError::Mismatch {
+                expected: expected.clone(),
+                found,
+                span,
+                context,
+            });
+        }
+    }
+
+    fn synth_body(&mut self, body: &[Expr], scope: &Scope) -> Option<Type> {
+        let (last, rest) = body.split_last()?;
+        for expr in rest {
+            self.synth(expr, scope);
+        }
+        self.synth(last, scope)
+    }
+
+    fn check_body(&mut self, body: &[Expr], expected: &Type, context: Option<Span>, scope: &Scope) {
+        let Some((last, rest)) = body.split_last() else {
+            return;
+        };
+        for expr in rest {
+            self.synth(expr, scope);
+        }
+        self.check_expr(last, expected, context, scope);
+    }
+
+    /// Type the values of a parallel `let`, returning the scope of its body.
+    /// An inferred binding type takes precedence over the value's own.
+    fn bind(&mut self, bindings: &[(String, Expr)], scope: &Scope) -> Scope {
+        let mut inner = scope.clone();
+        for (name, value) in bindings {
+            let ty = match &value.ty {
+                Some(ty) => {
+                    self.check_expr(value, ty, None, scope);
+                    Some(ty.clone())
+                }
+                None => self.synth(value, scope),
+            };
+            inner.insert(name.clone(), (ty, value.span));
+        }
+        inner
+    }
+
+    /// Element type of `array`, reporting it if it is known not to be one
+    fn synth_elem(&mut self, array: &Expr, scope: &Scope) -> Option<Type> {
+        match self.synth(array, scope)? {
+            Type::Array { elem_type, .. } => Some(*elem_type),
+            found => {
+                self.errors.push(TypeError::NotAnArray {
+                    found,
+                    span: array.span,
+                });
+                None
+            }
+        }
+    }
+
+    /// Common type of `exprs`, which must all agree. Literals follow the
+    /// first operand with a type of its own. An `operator` may reject the
+    /// common type, which is then reported once rather than per operand.
+    fn unify(
+        &mut self,
+        exprs: &[&Expr],
+        scope: &Scope,
+        operator: Option<Operator>,
+    ) -> Option<Type> {
+        let types: Vec<Option<Type>> = exprs
+            .iter()
+            .map(|expr| match Self::literal_type(expr) {
+                Some(_) => None,
+                None => self.synth(expr, scope),
+            })
+            .collect();
+
+        let (ty, context) = match types.iter().position(Option::is_some) {
+            Some(i) => (types[i].clone()?, exprs[i].span),
+            None => {
+                let literal = exprs.iter().find_map(|expr| Self::literal_type(expr))?;
+                (literal, exprs[0].span)
+            }
+        };
+
+        if let Some((operator, accepts)) = operator {
+            if !accepts(&ty) {
+                self.errors.push(TypeError::InvalidOperand {
+                    operator: operator.to_string(),
+                    found: ty,
+                    span: context,
+                });
+                return None;
+            }
+        }
+
+        for (expr, found) in exprs.iter().zip(types) {
+            match found {
+                Some(found) => self.expect(&ty, found, expr.span, Some(context)),
+                None => match Self::literal_type(expr) {
+                    Some(literal) if !Self::adapts_to(expr, &ty) => {
+                        self.expect(&ty, literal, expr.span, Some(context))
+                    }
+                    _ => {}
+                },
+            }
+        }
+        Some(ty)
+    }
+
+    fn synth_call(&mut self, func: &Expr, args: &[Expr], scope: &Scope) -> Option<Type> {
+        if let ExprKind::Ident(name) = &func.kind {
+            if !scope.contains_key(name) {
+                if let Some(signature) = self.signatures.get(name) {
+                    let params = signature.params.clone();
+                    let return_type = signature.return_type.clone();
+                    self.check_args(args, &params, scope);
+                    return return_type;
+                }
+                if let Some(ty) = self.synth_builtin(name, args, scope) {
+                    return ty;
+                }
+            }
+        }
+
+        match self.synth(func, scope) {
+            Some(Type::Function {
+                params,
+                return_type,
+            }) => {
+                let params: Vec<Parameter> = params
+                    .into_iter()
+                    .map(|ty| Parameter::new(String::new(), Some(ty)).with_span(func.span))
+                    .collect();
+                self.check_args(args, &params, scope);
+                Some(*return_type)
+            }
+            _ => {
+                for arg in args {
+                    self.synth(arg, scope);
+                }
+                None
+            }
+        }
+    }
+
+    fn check_args(&mut self, args: &[Expr], params: &[Parameter], scope: &Scope) {
+        if args.len() != params.len() {
+            // Arity is reported by the resolver
+            for arg in args {
+                self.synth(arg, scope);
+            }
+            return;
+        }
+        for (arg, param) in args.iter().zip(params) {
+            match param.ty() {
+                Some(ty) => self.check_expr(arg, ty, Some(param.span), scope),
+                None => {
+                    self.synth(arg, scope);
+                }
+            }
+        }
+    }
+
+    /// Result type of a builtin operator, or `None` if `name` is not one
+    fn synth_builtin(&mut self, name: &str, args: &[Expr], scope: &Scope) -> Option<Option<Type>> {
+        let operands: Vec<&Expr> = args.iter().collect();
+        let numeric: fn(&Type) -> bool = |ty| ty.is_integer() || ty.is_float();
+        let ty = match name {
+            "+" | "-" | "*" | "/" | "mod" => self.unify(&operands, scope, Some((name, numeric))),
+            "<" | "<=" | ">" | ">=" => {
+                self.unify(&operands, scope, Some((name, numeric)));
+                Some(Type::Bool)
+            }
+            "=" | "!=" => {
+                self.unify(&operands, scope, None);
+                Some(Type::Bool)
+            }
+            "and" | "or" | "not" => {
+                for arg in args {
+                    self.check_expr(arg, &Type::Bool, None, scope);
+                }
+                Some(Type::Bool)
+            }
+            "bitwise-and" | "bitwise-or" | "bitwise-xor" | "bitwise-not" => {
+                self.unify(&operands, scope, Some((name, Type::is_integer)))
+            }
+            "bit-shift-left" | "bit-shift-right" => {
+                let (value, amount) = operands.split_first()?;
+                let ty = self.unify(&[value], scope, Some((name, Type::is_integer)));
+                self.unify(amount, scope, Some((name, Type::is_integer)));
+                ty
+            }
+            _ => return None,
+        };
+        Some(ty)
+    }
+
+    /// Default type of an unsuffixed numeric literal
+    fn literal_type(expr: &Expr) -> Option<Type> {
+        match &expr.kind {
+            ExprKind::Int(literal) if literal.suffix.is_none() => Some(Type::Int32),
+            ExprKind::Float(_) => Some(Type::Float64),
+            _ => None,
+        }
+    }
+
+    /// Whether `expr` is an unsuffixed literal that takes on type `ty`
+    fn adapts_to(expr: &Expr, ty: &Type) -> bool {
+        match &expr.kind {
+            ExprKind::Int(literal) => literal.suffix.is_none() && ty.is_integer(),
+            ExprKind::Float(_) => ty.is_float(),
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_file;
+
+    fn check(source: &str) -> Vec<TypeError> {
+        let exprs = parse_file(source).unwrap();
+        TypeChecker::new(&exprs)
+            .check(&exprs)
+            .err()
+            .unwrap_or_default()
+    }
+
+    fn mismatches(source: &str) -> Vec<String> {
+        check(source)
+            .iter()
+            .map(|e| match e {
+                TypeError::Mismatch {
+                    expected, found, ..
+                } => format!("{} {}", expected, found),
+                other => panic!("unexpected {:?}", other),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_examples_type_check() {
+        for source in [
+            include_str!("../../../examples/crypto-xor.obl"),
+            include_str!("../../../examples/temperature-monitor.obl"),
+        ] {
+            assert_eq!(check(source), vec![]);
+        }
+    }
+
+    #[test]
+    fn test_return_type_and_application() {
+        let source = r#"
+(defun-deploy scale ((x uint8) (factor uint8)) : uint8
+  (* x factor))
+(defun-deploy wrong ((flag bool)) : int32
+  (scale flag 2)
+  (if flag 1u16 (scale 1 2)))
+(defun-deploy sum ((xs (array int32 4))) : int32
+  (let ((total 0))
+    (bounded-for i 0 4
+      (set total (+ total (array-get xs i))))
+    total))
+"#;
+        assert_eq!(
+            mismatches(source),
+            vec!["uint8 bool", "int32 uint16", "int32 uint8"]
+        );
+    }
+
+    #[test]
+    fn test_rule_premises() {
+        let source = r#"
+(defun-deploy f ((n int64) (x bool) (buf (array uint8 4)))
+  (bounded-for i 0 n
+    (if 1 (array-get buf x) (array-get x 0))))
+"#;
+        let errors = check(source);
+        let expected: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+        assert_eq!(errors.len(), 4, "{:?}", expected);
+        assert!(matches!(
+            &errors[0],
+            TypeError::Mismatch {
+                expected: Type::Int32,
+                found: Type::Int64,
+                ..
+            }
+        ));
+        assert!(matches!(
+            &errors[1],
+            TypeError::Mismatch {
+                expected: Type::Bool,
+                found: Type::Int32,
+                ..
+            }
+        ));
+        assert!(matches!(
+            &errors[2],
+            TypeError::Mismatch {
+                expected: Type::Int32,
+                found: Type::Bool,
+                ..
+            }
+        ));
+        assert!(matches!(
+            &errors[3],
+            TypeError::NotAnArray {
+                found: Type::Bool,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn test_diagnostic_names_both_types() {
+        let source = "(defun-deploy f ((flag bool)) : uint8\n  (+ flag 1))";
+        let errors = check(source);
+        assert_eq!(errors.len(), 1);
+        let diagnostic = errors[0].to_diagnostic();
+        assert_eq!(diagnostic.code, codes::INVALID_OPERAND);
+
+        let diagnostic = check("(defun-deploy f () : uint8\n  true)")[0].to_diagnostic();
+        assert_eq!(diagnostic.code, codes::TYPE_MISMATCH);
+        assert_eq!(diagnostic.primary.message, "expected `uint8`, found `bool`");
+        assert_eq!((diagnostic.span().line, diagnostic.span().column), (2, 3));
+        assert_eq!(diagnostic.secondary[0].span.line, 1);
+    }
+}
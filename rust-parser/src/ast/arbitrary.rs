@@ -0,0 +1,361 @@
+//! Proptest strategies for well-formed syntax trees.
+//!
+//! A tree is well-formed when printing it gives source that parses back to
+//! the same tree: identifiers are never keywords, a `program` holds a
+//! resource budget and only definitions, and types are limited to those the
+//! grammar can spell, which leaves out function types. Downstream crates get
+//! these with the `arbitrary` feature.
+
+use super::expr::{Expr, ExprKind, ResourceKind, ResourceSpec};
+use super::literal::{IntLiteral, Radix};
+use super::types::{Parameter, ResourceType, Type};
+use proptest::collection::vec;
+use proptest::prelude::*;
+
+/// Words the grammar reads as the start of a special form
+const RESERVED: &[&str] = &[
+    "defun-deploy",
+    "defun-compile",
+    "bounded-for",
+    "with-capability",
+    "macro",
+    "eval-compile",
+    "include",
+    "for",
+    "while",
+    "let",
+    "set",
+    "if",
+    "array-get",
+    "array-set",
+    "array-length",
+    "array-init",
+    "array",
+    "gpio-set",
+    "gpio-get",
+    "uart-send",
+    "uart-recv",
+    "sensor-read",
+    "network-send",
+    "network-recv",
+    "sleep-ms",
+    "timestamp",
+    "defcap",
+    "program",
+    "true",
+    "false",
+];
+
+/// Identifiers, including operator names like `+` and `<=`
+pub fn arb_ident() -> impl Strategy<Value = String> {
+    prop_oneof![
+        4 => "[a-z][a-z0-9_-]{0,7}".prop_filter("reserved word", |s| {
+            !RESERVED.contains(&s.as_str())
+        }),
+        1 => prop::sample::select(vec!["+", "-", "*", "/", "<", "<=", "=", "!=", "not?"])
+            .prop_map(String::from),
+    ]
+}
+
+/// String contents, with characters that need escaping or look like syntax
+pub fn arb_string() -> impl Strategy<Value = String> {
+    r#"[a-zA-Z0-9 "\\\n\r\t;()é]{0,12}"#
+}
+
+pub fn arb_int_literal() -> impl Strategy<Value = IntLiteral> {
+    let value = prop_oneof![
+        any::<i64>().prop_map(i128::from),
+        any::<u64>().prop_map(i128::from),
+        (-300i128..300),
+    ];
+    let radix = prop_oneof![Just(Radix::Decimal), Just(Radix::Hex), Just(Radix::Binary)];
+    let suffix = prop::option::weighted(
+        0.2,
+        prop::sample::select(vec![
+            Type::Int8,
+            Type::Int16,
+            Type::Int32,
+            Type::Int64,
+            Type::Uint8,
+            Type::Uint16,
+            Type::Uint32,
+            Type::Uint64,
+        ]),
+    );
+
+    (value, radix, suffix).prop_map(|(value, radix, suffix)| IntLiteral {
+        value,
+        radix,
+        suffix,
+    })
+}
+
+pub fn arb_resource() -> impl Strategy<Value = ResourceType> {
+    prop::sample::select(vec![
+        ResourceType::UartTx,
+        ResourceType::UartRx,
+        ResourceType::Gpio,
+        ResourceType::I2c,
+        ResourceType::Spi,
+        ResourceType::SensorRead,
+        ResourceType::NetworkSend,
+        ResourceType::NetworkRecv,
+    ])
+}
+
+/// Types with source syntax: everything but function types
+pub fn arb_type() -> impl Strategy<Value = Type> {
+    let leaf = prop_oneof![
+        8 => prop::sample::select(vec![
+            Type::Int8,
+            Type::Int16,
+            Type::Int32,
+            Type::Int64,
+            Type::Uint8,
+            Type::Uint16,
+            Type::Uint32,
+            Type::Uint64,
+            Type::Float32,
+            Type::Float64,
+            Type::Bool,
+            Type::String,
+            Type::Void,
+        ]),
+        1 => arb_resource().prop_map(|resource| Type::Capability { resource }),
+    ];
+
+    leaf.prop_recursive(2, 4, 1, |inner| {
+        (inner, 0..1024usize).prop_map(|(elem_type, size)| Type::Array {
+            elem_type: Box::new(elem_type),
+            size,
+        })
+    })
+}
+
+/// Parameters; capability parameters sometimes have a budget
+pub fn arb_parameter() -> impl Strategy<Value = Parameter> {
+    (arb_ident(), prop::option::of(arb_type()), prop::option::of(any::<u64>())).prop_map(
+        |(name, type_annotation, budget)| {
+            let budgeted = matches!(type_annotation, Some(Type::Capability { .. }));
+            let param = Parameter::new(name, type_annotation);
+            match budget {
+                Some(budget) if budgeted => param.with_budget(budget),
+                _ => param,
+            }
+        },
+    )
+}
+
+/// A `resource-budget`, which only appears as a program's budget
+pub fn arb_budget() -> impl Strategy<Value = Expr> {
+    let kind = prop_oneof![
+        Just(ResourceKind::TimeMs),
+        Just(ResourceKind::MemoryBytes),
+        Just(ResourceKind::NetworkBytes),
+        Just(ResourceKind::StorageBytes),
+    ];
+    vec((kind, any::<u64>()), 0..4).prop_map(|specs| {
+        let specs = specs
+            .into_iter()
+            .map(|(kind, amount)| ResourceSpec::new(kind, amount))
+            .collect();
+        Expr::from(ExprKind::ResourceBudget { specs })
+    })
+}
+
+/// Any well-formed expression, up to a few levels deep
+pub fn arb_expr() -> impl Strategy<Value = Expr> {
+    let leaf = prop_oneof![
+        arb_int_literal().prop_map(ExprKind::Int),
+        any::<f64>()
+            .prop_filter("finite", |f| f.is_finite())
+            .prop_map(ExprKind::Float),
+        any::<bool>().prop_map(ExprKind::Bool),
+        arb_string().prop_map(ExprKind::String),
+        arb_ident().prop_map(ExprKind::Ident),
+        arb_string().prop_map(ExprKind::Include),
+        Just(ExprKind::Timestamp),
+        (arb_type(), 0..1024usize)
+            .prop_map(|(elem_type, size)| ExprKind::ArrayLiteral { elem_type, size }),
+    ];
+
+    leaf.prop_map(Expr::from).prop_recursive(4, 48, 4, |inner| {
+        let operand = || inner.clone().prop_map(Box::new);
+        let body = || vec(inner.clone(), 0..3);
+        let params = || vec(arb_parameter(), 0..3);
+
+        let definition = prop_oneof![
+            (arb_ident(), params(), prop::option::of(arb_type()), body()).prop_map(
+                |(name, params, return_type, body)| ExprKind::DefunDeploy {
+                    name,
+                    params,
+                    return_type,
+                    body,
+                }
+            ),
+            (arb_ident(), params(), prop::option::of(arb_type()), body()).prop_map(
+                |(name, params, return_type, body)| ExprKind::DefunCompile {
+                    name,
+                    params,
+                    return_type,
+                    body,
+                }
+            ),
+            (arb_ident(), params(), body()).prop_map(|(name, params, body)| ExprKind::Macro {
+                name,
+                params,
+                body
+            }),
+            (
+                arb_ident(),
+                params(),
+                prop::option::of(arb_resource()),
+                arb_string()
+            )
+                .prop_map(|(name, params, resource, description)| ExprKind::DefCap {
+                    name,
+                    params,
+                    resource,
+                    description,
+                }),
+        ];
+
+        prop_oneof![
+            definition.clone(),
+            (arb_ident(), operand(), operand(), body()).prop_map(|(var, start, end, body)| {
+                ExprKind::BoundedFor {
+                    var,
+                    start,
+                    end,
+                    body,
+                }
+            }),
+            (operand(), body())
+                .prop_map(|(capability, body)| ExprKind::WithCapability { capability, body }),
+            operand().prop_map(ExprKind::EvalCompile),
+            (arb_ident(), operand(), body()).prop_map(|(var, iterable, body)| ExprKind::For {
+                var,
+                iterable,
+                body
+            }),
+            (operand(), body()).prop_map(|(condition, body)| ExprKind::While { condition, body }),
+            (vec((arb_ident(), inner.clone()), 0..3), body())
+                .prop_map(|(bindings, body)| ExprKind::Let { bindings, body }),
+            (arb_ident(), operand()).prop_map(|(var, value)| ExprKind::Set { var, value }),
+            (operand(), operand(), operand()).prop_map(|(condition, then_branch, else_branch)| {
+                ExprKind::If {
+                    condition,
+                    then_branch,
+                    else_branch,
+                }
+            }),
+            (operand(), body()).prop_map(|(func, args)| ExprKind::FunctionCall { func, args }),
+            (arb_type(), body()).prop_map(|(elem_type, elements)| ExprKind::ArrayInit {
+                elem_type,
+                elements
+            }),
+            (operand(), operand()).prop_map(|(array, index)| ExprKind::ArrayGet { array, index }),
+            (operand(), operand(), operand()).prop_map(|(array, index, value)| {
+                ExprKind::ArraySet {
+                    array,
+                    index,
+                    value,
+                }
+            }),
+            operand().prop_map(ExprKind::ArrayLength),
+            (operand(), operand()).prop_map(|(device, value)| ExprKind::GpioSet { device, value }),
+            operand().prop_map(ExprKind::GpioGet),
+            (operand(), operand()).prop_map(|(device, data)| ExprKind::UartSend { device, data }),
+            operand().prop_map(ExprKind::UartRecv),
+            operand().prop_map(ExprKind::SensorRead),
+            (operand(), operand())
+                .prop_map(|(device, data)| ExprKind::NetworkSend { device, data }),
+            operand().prop_map(ExprKind::NetworkRecv),
+            operand().prop_map(ExprKind::SleepMs),
+            (
+                arb_ident(),
+                arb_budget(),
+                vec(definition.prop_map(Expr::from), 0..3)
+            )
+                .prop_map(|(name, budget, forms)| {
+                    ExprKind::Program {
+                        name,
+                        budget: Box::new(budget),
+                        forms,
+                    }
+                }),
+        ]
+        .prop_map(Expr::from)
+    })
+}
+
+impl Arbitrary for Expr {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Expr>;
+
+    fn arbitrary_with(_: ()) -> Self::Strategy {
+        arb_expr().boxed()
+    }
+}
+
+impl Arbitrary for Type {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Type>;
+
+    fn arbitrary_with(_: ()) -> Self::Strategy {
+        arb_type().boxed()
+    }
+}
+
+impl Arbitrary for Parameter {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Parameter>;
+
+    fn arbitrary_with(_: ()) -> Self::Strategy {
+        arb_parameter().boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::PrettyPrinter;
+    use crate::parse_file;
+    use proptest::strategy::ValueTree;
+    use proptest::test_runner::TestRunner;
+    use std::collections::HashSet;
+    use std::mem::{discriminant, Discriminant};
+
+    proptest! {
+        #[test]
+        fn test_print_then_parse_is_identity(expr in any::<Expr>()) {
+            let printed = PrettyPrinter::print(&expr);
+            prop_assert_eq!(parse_file(&printed), Ok(vec![expr.clone()]), "{}", printed);
+
+            // Narrow enough that nearly every list breaks
+            let narrow = PrettyPrinter::with_width(10).print_expr(&expr);
+            prop_assert_eq!(parse_file(&narrow), Ok(vec![expr]), "{}", narrow);
+        }
+    }
+
+    #[test]
+    fn test_strategy_reaches_every_variant() {
+        fn collect(expr: &Expr, seen: &mut HashSet<Discriminant<ExprKind>>) {
+            seen.insert(discriminant(&expr.kind));
+            for child in expr.children() {
+                collect(child, seen);
+            }
+        }
+
+        let mut runner = TestRunner::deterministic();
+        let strategy = arb_expr();
+        let mut seen = HashSet::new();
+        for _ in 0..2000 {
+            let expr = strategy.new_tree(&mut runner).unwrap().current();
+            collect(&expr, &mut seen);
+        }
+
+        // Every `ExprKind` variant, so a new one has to be added here too
+        assert_eq!(seen.len(), 35);
+    }
+}
@@ -0,0 +1,1181 @@
+//! Compact binary encoding of syntax trees for work unit payloads.
+//!
+//! Layout, all integers LEB128 varints unless noted:
+//!
+//! ```text
+//! "OBLB"  version:u8  flags:u8
+//! string count, then each string as length + UTF-8 bytes
+//! form count, then each form as a node
+//! CRC-32 of everything before it: u32, little endian
+//! ```
+//!
+//! Every string in the tree (identifiers, literals, paths) is stored once in
+//! the table and referred to by index. A node is a tag byte naming its
+//! variant followed by its fields in declaration order; the tags are part of
+//! the format, so new variants get new tags and old tags are never reused.
+//! With the `SPANS` flag each node and parameter ends with its span.
+//!
+//! Versions, each read by every later build:
+//!
+//! 1. The first layout.
+//! 2. A parameter has an optional capability budget after its inferred
+//!    type.
+//! 3. A `defcap` has an optional resource type after its parameters.
+//!
+//! Decoding checks the checksum first and then everything a corrupt or
+//! hostile payload could get wrong: lengths past the end of the input,
+//! unknown tags, string indices outside the table, nesting deeper than
+//! `MAX_DEPTH`, and programs whose budget or forms the grammar would not
+//! accept.
+
+use super::expr::{Expr, ExprKind, ResourceKind, ResourceSpec};
+use super::literal::{IntLiteral, Radix};
+use super::span::{ExpansionId, FileId, Span};
+use super::types::{Parameter, ResourceType, Type};
+use std::collections::HashMap;
+use thiserror::Error;
+
+pub const BINARY_MAGIC: &[u8; 4] = b"OBLB";
+
+/// Version written by `BinaryEncoder`; bumped on any incompatible change
+pub const BINARY_VERSION: u8 = 3;
+
+/// Header flag: nodes and parameters carry spans
+const SPANS: u8 = 1;
+
+/// Deepest nesting a payload may have; keeps decoding off the end of the
+/// stack
+pub const MAX_DEPTH: usize = 128;
+
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum BinaryError {
+    #[error("Not an Oblibeny binary AST (bad magic number)")]
+    BadMagic,
+
+    #[error("Unsupported binary AST version {found} (this build reads 1 to {BINARY_VERSION})")]
+    UnsupportedVersion { found: u8 },
+
+    #[error("Checksum mismatch: payload says {expected:08x}, content hashes to {found:08x}")]
+    ChecksumMismatch { expected: u32, found: u32 },
+
+    #[error("Unexpected end of payload at byte {offset}")]
+    Truncated { offset: usize },
+
+    #[error("Invalid {what} tag {tag} at byte {offset}")]
+    InvalidTag {
+        what: &'static str,
+        tag: u8,
+        offset: usize,
+    },
+
+    #[error("Malformed payload at byte {offset}: {message}")]
+    Malformed { message: String, offset: usize },
+}
+
+type Result<T> = std::result::Result<T, BinaryError>;
+
+/// Writes trees in the binary format
+pub struct BinaryEncoder {
+    spans: bool,
+}
+
+impl Default for BinaryEncoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl BinaryEncoder {
+    pub fn new() -> Self {
+        Self { spans: true }
+    }
+
+    /// Leave spans out, for payloads nobody reads diagnostics from
+    pub fn without_spans(mut self) -> Self {
+        self.spans = false;
+        self
+    }
+
+    pub fn encode(&self, exprs: &[Expr]) -> Vec<u8> {
+        let mut writer = Writer {
+            body: Vec::new(),
+            strings: Vec::new(),
+            interned: HashMap::new(),
+            spans: self.spans,
+        };
+        writer.varint(exprs.len() as u128);
+        for expr in exprs {
+            writer.node(expr);
+        }
+
+        let mut out = BINARY_MAGIC.to_vec();
+        out.push(BINARY_VERSION);
+        out.push(if self.spans { SPANS } else { 0 });
+        write_varint(&mut out, writer.strings.len() as u128);
+        for s in &writer.strings {
+            write_varint(&mut out, s.len() as u128);
+            out.extend_from_slice(s.as_bytes());
+        }
+        out.extend_from_slice(&writer.body);
+        let checksum = crc32(&out);
+        out.extend_from_slice(&checksum.to_le_bytes());
+        out
+    }
+}
+
+pub fn encode_binary(exprs: &[Expr]) -> Vec<u8> {
+    BinaryEncoder::new().encode(exprs)
+}
+
+pub fn decode_binary(bytes: &[u8]) -> Result<Vec<Expr>> {
+    if bytes.len() < BINARY_MAGIC.len() || &bytes[..4] != BINARY_MAGIC {
+        return Err(BinaryError::BadMagic);
+    }
+    if bytes.len() < 10 {
+        return Err(BinaryError::Truncated {
+            offset: bytes.len(),
+        });
+    }
+    if !(1..=BINARY_VERSION).contains(&bytes[4]) {
+        return Err(BinaryError::UnsupportedVersion { found: bytes[4] });
+    }
+
+    let (content, checksum) = bytes.split_at(bytes.len() - 4);
+    let expected = u32::from_le_bytes(checksum.try_into().unwrap());
+    let found = crc32(content);
+    if expected != found {
+        return Err(BinaryError::ChecksumMismatch { expected, found });
+    }
+
+    let mut reader = Reader {
+        bytes: content,
+        pos: 6,
+        version: content[4],
+        strings: Vec::new(),
+        spans: content[5] & SPANS != 0,
+        depth: 0,
+    };
+    if content[5] & !SPANS != 0 {
+        return Err(reader.malformed(format!("unknown flags {:#04x}", content[5])));
+    }
+
+    let count = reader.count()?;
+    for _ in 0..count {
+        let len = reader.count()?;
+        let offset = reader.pos;
+        let bytes = reader.take(len)?;
+        let s = std::str::from_utf8(bytes).map_err(|_| BinaryError::Malformed {
+            message: "string is not valid UTF-8".to_string(),
+            offset,
+        })?;
+        reader.strings.push(s.to_string());
+    }
+
+    let forms = reader.nodes()?;
+    if reader.pos != content.len() {
+        return Err(reader.malformed("trailing bytes after the last form"));
+    }
+    Ok(forms)
+}
+
+struct Writer {
+    body: Vec<u8>,
+    strings: Vec<String>,
+    interned: HashMap<String, usize>,
+    spans: bool,
+}
+
+impl Writer {
+    fn byte(&mut self, byte: u8) {
+        self.body.push(byte);
+    }
+
+    fn varint(&mut self, value: u128) {
+        write_varint(&mut self.body, value);
+    }
+
+    fn string(&mut self, s: &str) {
+        let index = match self.interned.get(s) {
+            Some(&index) => index,
+            None => {
+                self.strings.push(s.to_string());
+                self.interned.insert(s.to_string(), self.strings.len() - 1);
+                self.strings.len() - 1
+            }
+        };
+        self.varint(index as u128);
+    }
+
+    fn node(&mut self, expr: &Expr) {
+        match &expr.kind {
+            ExprKind::Int(n) => {
+                self.byte(0);
+                // Zigzag, so small negative numbers stay small
+                self.varint(((n.value << 1) ^ (n.value >> 127)) as u128);
+                self.byte(match n.radix {
+                    Radix::Decimal => 0,
+                    Radix::Hex => 1,
+                    Radix::Binary => 2,
+                });
+                self.optional_ty(n.suffix.as_ref());
+            }
+            ExprKind::Float(f) => {
+                self.byte(1);
+                self.body.extend_from_slice(&f.to_bits().to_le_bytes());
+            }
+            ExprKind::Bool(b) => {
+                self.byte(2);
+                self.byte(*b as u8);
+            }
+            ExprKind::String(s) => {
+                self.byte(3);
+                self.string(s);
+            }
+            ExprKind::Ident(name) => {
+                self.byte(4);
+                self.string(name);
+            }
+            ExprKind::DefunDeploy {
+                name,
+                params,
+                return_type,
+                body,
+            } => {
+                self.byte(5);
+                self.function(name, params, return_type.as_ref(), body);
+            }
+            ExprKind::BoundedFor {
+                var,
+                start,
+                end,
+                body,
+            } => {
+                self.byte(6);
+                self.string(var);
+                self.node(start);
+                self.node(end);
+                self.nodes(body);
+            }
+            ExprKind::WithCapability { capability, body } => {
+                self.byte(7);
+                self.node(capability);
+                self.nodes(body);
+            }
+            ExprKind::DefunCompile {
+                name,
+                params,
+                return_type,
+                body,
+            } => {
+                self.byte(8);
+                self.function(name, params, return_type.as_ref(), body);
+            }
+            ExprKind::Macro { name, params, body } => {
+                self.byte(9);
+                self.string(name);
+                self.params(params);
+                self.nodes(body);
+            }
+            ExprKind::EvalCompile(inner) => {
+                self.byte(10);
+                self.node(inner);
+            }
+            ExprKind::Include(path) => {
+                self.byte(11);
+                self.string(path);
+            }
+            ExprKind::For {
+                var,
+                iterable,
+                body,
+            } => {
+                self.byte(12);
+                self.string(var);
+                self.node(iterable);
+                self.nodes(body);
+            }
+            ExprKind::While { condition, body } => {
+                self.byte(13);
+                self.node(condition);
+                self.nodes(body);
+            }
+            ExprKind::Let { bindings, body } => {
+                self.byte(14);
+                self.varint(bindings.len() as u128);
+                for (name, value) in bindings {
+                    self.string(name);
+                    self.node(value);
+                }
+                self.nodes(body);
+            }
+            ExprKind::Set { var, value } => {
+                self.byte(15);
+                self.string(var);
+                self.node(value);
+            }
+            ExprKind::If {
+                condition,
+                then_branch,
+                else_branch,
+            } => {
+                self.byte(16);
+                self.node(condition);
+                self.node(then_branch);
+                self.node(else_branch);
+            }
+            ExprKind::FunctionCall { func, args } => {
+                self.byte(17);
+                self.node(func);
+                self.nodes(args);
+            }
+            ExprKind::ArrayLiteral { elem_type, size } => {
+                self.byte(18);
+                self.ty(elem_type);
+                self.varint(*size as u128);
+            }
+            ExprKind::ArrayInit {
+                elem_type,
+                elements,
+            } => {
+                self.byte(19);
+                self.ty(elem_type);
+                self.nodes(elements);
+            }
+            ExprKind::ArrayGet { array, index } => {
+                self.byte(20);
+                self.node(array);
+                self.node(index);
+            }
+            ExprKind::ArraySet {
+                array,
+                index,
+                value,
+            } => {
+                self.byte(21);
+                self.node(array);
+                self.node(index);
+                self.node(value);
+            }
+            ExprKind::ArrayLength(array) => {
+                self.byte(22);
+                self.node(array);
+            }
+            ExprKind::GpioSet { device, value } => {
+                self.byte(23);
+                self.node(device);
+                self.node(value);
+            }
+            ExprKind::GpioGet(device) => {
+                self.byte(24);
+                self.node(device);
+            }
+            ExprKind::UartSend { device, data } => {
+                self.byte(25);
+                self.node(device);
+                self.node(data);
+            }
+            ExprKind::UartRecv(device) => {
+                self.byte(26);
+                self.node(device);
+            }
+            ExprKind::SensorRead(device) => {
+                self.byte(27);
+                self.node(device);
+            }
+            ExprKind::NetworkSend { device, data } => {
+                self.byte(28);
+                self.node(device);
+                self.node(data);
+            }
+            ExprKind::NetworkRecv(device) => {
+                self.byte(29);
+                self.node(device);
+            }
+            ExprKind::SleepMs(duration) => {
+                self.byte(30);
+                self.node(duration);
+            }
+            ExprKind::Timestamp => self.byte(31),
+            ExprKind::ResourceBudget { specs } => {
+                self.byte(32);
+                self.varint(specs.len() as u128);
+                for spec in specs {
+                    self.byte(match spec.kind {
+                        ResourceKind::TimeMs => 0,
+                        ResourceKind::MemoryBytes => 1,
+                        ResourceKind::NetworkBytes => 2,
+                        ResourceKind::StorageBytes => 3,
+                    });
+                    self.varint(spec.amount as u128);
+                }
+            }
+            ExprKind::DefCap {
+                name,
+                params,
+                resource,
+                description,
+            } => {
+                self.byte(33);
+                self.string(name);
+                self.params(params);
+                self.optional_resource(resource.as_ref());
+                self.string(description);
+            }
+            ExprKind::Program {
+                name,
+                budget,
+                forms,
+            } => {
+                self.byte(34);
+                self.string(name);
+                self.node(budget);
+                self.nodes(forms);
+            }
+        }
+
+        self.optional_ty(expr.ty.as_ref());
+        if self.spans {
+            self.span(expr.span);
+        }
+    }
+
+    fn nodes(&mut self, exprs: &[Expr]) {
+        self.varint(exprs.len() as u128);
+        for expr in exprs {
+            self.node(expr);
+        }
+    }
+
+    fn function(
+        &mut self,
+        name: &str,
+        params: &[Parameter],
+        return_type: Option<&Type>,
+        body: &[Expr],
+    ) {
+        self.string(name);
+        self.params(params);
+        self.optional_ty(return_type);
+        self.nodes(body);
+    }
+
+    fn params(&mut self, params: &[Parameter]) {
+        self.varint(params.len() as u128);
+        for param in params {
+            self.string(&param.name);
+            self.optional_ty(param.type_annotation.as_ref());
+            self.optional_ty(param.inferred_type.as_ref());
+            match param.budget {
+                Some(budget) => {
+                    self.byte(1);
+                    self.varint(budget as u128);
+                }
+                None => self.byte(0),
+            }
+            if self.spans {
+                self.span(param.span);
+            }
+        }
+    }
+
+    fn ty(&mut self, ty: &Type) {
+        let tag = match ty {
+            Type::Int8 => 0,
+            Type::Int16 => 1,
+            Type::Int32 => 2,
+            Type::Int64 => 3,
+            Type::Uint8 => 4,
+            Type::Uint16 => 5,
+            Type::Uint32 => 6,
+            Type::Uint64 => 7,
+            Type::Float32 => 8,
+            Type::Float64 => 9,
+            Type::Bool => 10,
+            Type::String => 11,
+            Type::Void => 12,
+            Type::Array { elem_type, size } => {
+                self.byte(13);
+                self.ty(elem_type);
+                self.varint(*size as u128);
+                return;
+            }
+            Type::Capability { resource } => {
+                self.byte(14);
+                self.resource(resource);
+                return;
+            }
+            Type::Function {
+                params,
+                return_type,
+            } => {
+                self.byte(15);
+                self.varint(params.len() as u128);
+                for param in params {
+                    self.ty(param);
+                }
+                self.ty(return_type);
+                return;
+            }
+        };
+        self.byte(tag);
+    }
+
+    fn optional_ty(&mut self, ty: Option<&Type>) {
+        match ty {
+            Some(ty) => {
+                self.byte(1);
+                self.ty(ty);
+            }
+            None => self.byte(0),
+        }
+    }
+
+    fn optional_resource(&mut self, resource: Option<&ResourceType>) {
+        match resource {
+            Some(resource) => {
+                self.byte(1);
+                self.resource(resource);
+            }
+            None => self.byte(0),
+        }
+    }
+
+    fn resource(&mut self, resource: &ResourceType) {
+        self.byte(match resource {
+            ResourceType::UartTx => 0,
+            ResourceType::UartRx => 1,
+            ResourceType::Gpio => 2,
+            ResourceType::I2c => 3,
+            ResourceType::Spi => 4,
+            ResourceType::SensorRead => 5,
+            ResourceType::NetworkSend => 6,
+            ResourceType::NetworkRecv => 7,
+        });
+    }
+
+    fn span(&mut self, span: Span) {
+        for value in [span.start, span.end, span.line, span.column] {
+            self.varint(value as u128);
+        }
+        self.varint(span.file.0 as u128);
+        // 0 for none, so the common case stays one byte
+        self.varint(span.expansion.map_or(0, |e| e.0 as u128 + 1));
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    version: u8,
+    strings: Vec<String>,
+    spans: bool,
+    depth: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn malformed(&self, message: impl Into<String>) -> BinaryError {
+        BinaryError::Malformed {
+            message: message.into(),
+            offset: self.pos,
+        }
+    }
+
+    fn byte(&mut self) -> Result<u8> {
+        let byte = *self
+            .bytes
+            .get(self.pos)
+            .ok_or(BinaryError::Truncated { offset: self.pos })?;
+        self.pos += 1;
+        Ok(byte)
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.bytes.len() - self.pos < len {
+            return Err(BinaryError::Truncated {
+                offset: self.bytes.len(),
+            });
+        }
+        self.pos += len;
+        Ok(&self.bytes[self.pos - len..self.pos])
+    }
+
+    fn varint(&mut self) -> Result<u128> {
+        let start = self.pos;
+        let mut value = 0u128;
+        for shift in (0..128).step_by(7) {
+            let byte = self.byte()?;
+            value |= ((byte & 0x7f) as u128) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(BinaryError::Malformed {
+            message: "integer too long".to_string(),
+            offset: start,
+        })
+    }
+
+    fn integer<T: TryFrom<u128>>(&mut self, what: &str) -> Result<T> {
+        let offset = self.pos;
+        T::try_from(self.varint()?).map_err(|_| BinaryError::Malformed {
+            message: format!("{} out of range", what),
+            offset,
+        })
+    }
+
+    /// Number of items that follow; every item takes at least a byte, so a
+    /// count larger than what is left is corrupt and never allocated
+    fn count(&mut self) -> Result<usize> {
+        let offset = self.pos;
+        let count: usize = self.integer("count")?;
+        if count > self.bytes.len() - self.pos {
+            return Err(BinaryError::Truncated { offset });
+        }
+        Ok(count)
+    }
+
+    fn tag(&mut self, what: &'static str, max: u8) -> Result<u8> {
+        let offset = self.pos;
+        let tag = self.byte()?;
+        if tag > max {
+            return Err(BinaryError::InvalidTag { what, tag, offset });
+        }
+        Ok(tag)
+    }
+
+    fn string(&mut self) -> Result<String> {
+        let offset = self.pos;
+        let index: usize = self.integer("string index")?;
+        self.strings
+            .get(index)
+            .cloned()
+            .ok_or(BinaryError::Malformed {
+                message: format!("string index {} outside the table", index),
+                offset,
+            })
+    }
+
+    fn boxed(&mut self) -> Result<Box<Expr>> {
+        self.node().map(Box::new)
+    }
+
+    fn nodes(&mut self) -> Result<Vec<Expr>> {
+        let count = self.count()?;
+        (0..count).map(|_| self.node()).collect()
+    }
+
+    fn node(&mut self) -> Result<Expr> {
+        if self.depth == MAX_DEPTH {
+            return Err(self.malformed(format!("nesting deeper than {}", MAX_DEPTH)));
+        }
+        self.depth += 1;
+
+        let offset = self.pos;
+        let kind = match self.tag("node", 34)? {
+            0 => {
+                let zigzag = self.varint()?;
+                let value = (zigzag >> 1) as i128 ^ -((zigzag & 1) as i128);
+                if value < i64::MIN as i128 || value > u64::MAX as i128 {
+                    return Err(self.malformed("integer literal out of range"));
+                }
+                let radix = match self.tag("radix", 2)? {
+                    0 => Radix::Decimal,
+                    1 => Radix::Hex,
+                    _ => Radix::Binary,
+                };
+                ExprKind::Int(IntLiteral {
+                    value,
+                    radix,
+                    suffix: self.optional_ty()?,
+                })
+            }
+            1 => {
+                let bits = self.take(8)?;
+                ExprKind::Float(f64::from_bits(u64::from_le_bytes(bits.try_into().unwrap())))
+            }
+            2 => ExprKind::Bool(self.tag("bool", 1)? == 1),
+            3 => ExprKind::String(self.string()?),
+            4 => ExprKind::Ident(self.string()?),
+            5 => ExprKind::DefunDeploy {
+                name: self.string()?,
+                params: self.params()?,
+                return_type: self.optional_ty()?,
+                body: self.nodes()?,
+            },
+            6 => ExprKind::BoundedFor {
+                var: self.string()?,
+                start: self.boxed()?,
+                end: self.boxed()?,
+                body: self.nodes()?,
+            },
+            7 => ExprKind::WithCapability {
+                capability: self.boxed()?,
+                body: self.nodes()?,
+            },
+            8 => ExprKind::DefunCompile {
+                name: self.string()?,
+                params: self.params()?,
+                return_type: self.optional_ty()?,
+                body: self.nodes()?,
+            },
+            9 => ExprKind::Macro {
+                name: self.string()?,
+                params: self.params()?,
+                body: self.nodes()?,
+            },
+            10 => ExprKind::EvalCompile(self.boxed()?),
+            11 => ExprKind::Include(self.string()?),
+            12 => ExprKind::For {
+                var: self.string()?,
+                iterable: self.boxed()?,
+                body: self.nodes()?,
+            },
+            13 => ExprKind::While {
+                condition: self.boxed()?,
+                body: self.nodes()?,
+            },
+            14 => {
+                let count = self.count()?;
+                let bindings = (0..count)
+                    .map(|_| Ok((self.string()?, self.node()?)))
+                    .collect::<Result<_>>()?;
+                ExprKind::Let {
+                    bindings,
+                    body: self.nodes()?,
+                }
+            }
+            15 => ExprKind::Set {
+                var: self.string()?,
+                value: self.boxed()?,
+            },
+            16 => ExprKind::If {
+                condition: self.boxed()?,
+                then_branch: self.boxed()?,
+                else_branch: self.boxed()?,
+            },
+            17 => ExprKind::FunctionCall {
+                func: self.boxed()?,
+                args: self.nodes()?,
+            },
+            18 => ExprKind::ArrayLiteral {
+                elem_type: self.ty()?,
+                size: self.integer("array size")?,
+            },
+            19 => ExprKind::ArrayInit {
+                elem_type: self.ty()?,
+                elements: self.nodes()?,
+            },
+            20 => ExprKind::ArrayGet {
+                array: self.boxed()?,
+                index: self.boxed()?,
+            },
+            21 => ExprKind::ArraySet {
+                array: self.boxed()?,
+                index: self.boxed()?,
+                value: self.boxed()?,
+            },
+            22 => ExprKind::ArrayLength(self.boxed()?),
+            23 => ExprKind::GpioSet {
+                device: self.boxed()?,
+                value: self.boxed()?,
+            },
+            24 => ExprKind::GpioGet(self.boxed()?),
+            25 => ExprKind::UartSend {
+                device: self.boxed()?,
+                data: self.boxed()?,
+            },
+            26 => ExprKind::UartRecv(self.boxed()?),
+            27 => ExprKind::SensorRead(self.boxed()?),
+            28 => ExprKind::NetworkSend {
+                device: self.boxed()?,
+                data: self.boxed()?,
+            },
+            29 => ExprKind::NetworkRecv(self.boxed()?),
+            30 => ExprKind::SleepMs(self.boxed()?),
+            31 => ExprKind::Timestamp,
+            32 => {
+                let count = self.count()?;
+                let specs = (0..count)
+                    .map(|_| {
+                        let kind = match self.tag("resource kind", 3)? {
+                            0 => ResourceKind::TimeMs,
+                            1 => ResourceKind::MemoryBytes,
+                            2 => ResourceKind::NetworkBytes,
+                            _ => ResourceKind::StorageBytes,
+                        };
+                        Ok(ResourceSpec::new(kind, self.integer("resource amount")?))
+                    })
+                    .collect::<Result<_>>()?;
+                ExprKind::ResourceBudget { specs }
+            }
+            33 => ExprKind::DefCap {
+                name: self.string()?,
+                params: self.params()?,
+                resource: if self.version >= 3 {
+                    self.optional_resource()?
+                } else {
+                    None
+                },
+                description: self.string()?,
+            },
+            _ => {
+                let name = self.string()?;
+                let budget = self.boxed()?;
+                if !matches!(budget.kind, ExprKind::ResourceBudget { .. }) {
+                    return Err(BinaryError::Malformed {
+                        message: "program budget is not a resource-budget".to_string(),
+                        offset,
+                    });
+                }
+                let forms = self.nodes()?;
+                if let Some(form) = forms.iter().find(|form| !is_definition(form)) {
+                    return Err(BinaryError::Malformed {
+                        message: format!(
+                            "program form `{}` is not a definition",
+                            form.keyword().unwrap_or("expression")
+                        ),
+                        offset,
+                    });
+                }
+                ExprKind::Program {
+                    name,
+                    budget,
+                    forms,
+                }
+            }
+        };
+
+        let ty = self.optional_ty()?;
+        let span = if self.spans {
+            self.span()?
+        } else {
+            Span::dummy()
+        };
+        self.depth -= 1;
+        Ok(Expr { kind, span, ty })
+    }
+
+    fn params(&mut self) -> Result<Vec<Parameter>> {
+        let count = self.count()?;
+        (0..count)
+            .map(|_| {
+                let mut param = Parameter::new(self.string()?, self.optional_ty()?);
+                param.inferred_type = self.optional_ty()?;
+                if self.version >= 2 {
+                    param.budget = match self.tag("option", 1)? {
+                        0 => None,
+                        _ => Some(self.integer("budget")?),
+                    };
+                }
+                if self.spans {
+                    param.span = self.span()?;
+                }
+                Ok(param)
+            })
+            .collect()
+    }
+
+    fn ty(&mut self) -> Result<Type> {
+        if self.depth == MAX_DEPTH {
+            return Err(self.malformed(format!("nesting deeper than {}", MAX_DEPTH)));
+        }
+        self.depth += 1;
+
+        let ty = match self.tag("type", 15)? {
+            0 => Type::Int8,
+            1 => Type::Int16,
+            2 => Type::Int32,
+            3 => Type::Int64,
+            4 => Type::Uint8,
+            5 => Type::Uint16,
+            6 => Type::Uint32,
+            7 => Type::Uint64,
+            8 => Type::Float32,
+            9 => Type::Float64,
+            10 => Type::Bool,
+            11 => Type::String,
+            12 => Type::Void,
+            13 => Type::Array {
+                elem_type: Box::new(self.ty()?),
+                size: self.integer("array size")?,
+            },
+            14 => Type::Capability {
+                resource: self.resource()?,
+            },
+            _ => {
+                let count = self.count()?;
+                let params = (0..count).map(|_| self.ty()).collect::<Result<_>>()?;
+                Type::Function {
+                    params,
+                    return_type: Box::new(self.ty()?),
+                }
+            }
+        };
+
+        self.depth -= 1;
+        Ok(ty)
+    }
+
+    fn optional_ty(&mut self) -> Result<Option<Type>> {
+        match self.tag("option", 1)? {
+            0 => Ok(None),
+            _ => self.ty().map(Some),
+        }
+    }
+
+    fn optional_resource(&mut self) -> Result<Option<ResourceType>> {
+        match self.tag("option", 1)? {
+            0 => Ok(None),
+            _ => self.resource().map(Some),
+        }
+    }
+
+    fn resource(&mut self) -> Result<ResourceType> {
+        Ok(match self.tag("resource type", 7)? {
+            0 => ResourceType::UartTx,
+            1 => ResourceType::UartRx,
+            2 => ResourceType::Gpio,
+            3 => ResourceType::I2c,
+            4 => ResourceType::Spi,
+            5 => ResourceType::SensorRead,
+            6 => ResourceType::NetworkSend,
+            _ => ResourceType::NetworkRecv,
+        })
+    }
+
+    fn span(&mut self) -> Result<Span> {
+        let mut span = Span::new(
+            self.integer("span")?,
+            self.integer("span")?,
+            self.integer("span")?,
+            self.integer("span")?,
+        );
+        span.file = FileId(self.integer("file id")?);
+        let expansion: u32 = self.integer("expansion id")?;
+        span.expansion = expansion.checked_sub(1).map(ExpansionId);
+        Ok(span)
+    }
+}
+
+/// Forms the grammar allows directly inside a `program`
+fn is_definition(expr: &Expr) -> bool {
+    matches!(
+        expr.kind,
+        ExprKind::DefunDeploy { .. }
+            | ExprKind::DefunCompile { .. }
+            | ExprKind::Macro { .. }
+            | ExprKind::DefCap { .. }
+    )
+}
+
+fn write_varint(out: &mut Vec<u8>, mut value: u128) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+/// CRC-32 (IEEE 802.3), as used by zip and PNG
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xEDB8_8320 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::arb_expr;
+    use crate::parse_file;
+    use proptest::prelude::*;
+
+    const MONITOR: &str = include_str!("../../../examples/temperature-monitor.obl");
+
+    /// Replace the checksum of a hand-edited payload
+    fn reseal(mut bytes: Vec<u8>) -> Vec<u8> {
+        let len = bytes.len() - 4;
+        let checksum = crc32(&bytes[..len]);
+        bytes[len..].copy_from_slice(&checksum.to_le_bytes());
+        bytes
+    }
+
+    #[test]
+    fn test_round_trips_with_and_without_spans() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+
+        let exprs = parse_file(MONITOR).unwrap();
+        let bytes = encode_binary(&exprs);
+        let back = decode_binary(&bytes).unwrap();
+        assert_eq!(back, exprs);
+        assert_eq!(back[0].span, exprs[0].span);
+
+        let stripped = BinaryEncoder::new().without_spans().encode(&exprs);
+        assert!(stripped.len() < bytes.len());
+        assert!(stripped.len() < MONITOR.len() / 2);
+        let back = decode_binary(&stripped).unwrap();
+        assert_eq!(back, exprs);
+        assert!(back[0].span.is_dummy());
+
+        // Interned: a capability name is stored once however often it is used
+        let name = b"led-cap";
+        let stored = stripped.windows(name.len()).filter(|w| w == name).count();
+        assert_eq!(stored, 1);
+        assert!(MONITOR.matches("led-cap").count() > 1);
+    }
+
+    proptest! {
+        #[test]
+        fn test_decode_inverts_encode(expr in arb_expr()) {
+            let bytes = encode_binary(std::slice::from_ref(&expr));
+            prop_assert_eq!(decode_binary(&bytes), Ok(vec![expr]));
+        }
+    }
+
+    #[test]
+    fn test_golden_bytes() {
+        let source = "(defcap led () : (capability gpio) \"d\")\n\
+                      (defun-deploy f ((led (capability gpio 3))) led)";
+        let exprs = parse_file(source).unwrap();
+        #[rustfmt::skip]
+        let v3: &[u8] = &[
+            b'O', b'B', b'L', b'B', 3, 0,
+            3, 3, b'l', b'e', b'd', 1, b'd', 1, b'f',
+            2,
+            // defcap led, no parameters, resource gpio, description, no type
+            33, 0, 0, 1, 2, 1, 0,
+            // defun-deploy f, one parameter: led, (capability gpio),
+            // no inferred type, budget 3
+            5, 2, 1, 0, 1, 14, 2, 0, 1, 3,
+            // no return type, body `led`, node types
+            0, 1, 4, 0, 0, 0,
+            0xa1, 0x18, 0xcc, 0x29,
+        ];
+        assert_eq!(BinaryEncoder::new().without_spans().encode(&exprs), v3);
+        assert_eq!(decode_binary(v3).unwrap(), exprs);
+
+        // Each older version lacks a field: version 2 the defcap resource,
+        // version 1 also the parameter budget
+        let older = |version: u8, cut: std::ops::Range<usize>, bytes: &[u8]| {
+            let mut bytes = bytes[..bytes.len() - 4].to_vec();
+            bytes[4] = version;
+            bytes.drain(cut);
+            bytes.extend_from_slice(&[0; 4]);
+            reseal(bytes)
+        };
+        let v2 = older(2, 19..21, v3);
+        let decoded = decode_binary(&v2).unwrap();
+        assert!(matches!(
+            decoded[0].kind,
+            ExprKind::DefCap { resource: None, .. }
+        ));
+        assert_eq!(decoded[1], exprs[1]);
+
+        let v1 = older(1, 29..31, &v2);
+        let decoded = decode_binary(&v1).unwrap();
+        let ExprKind::DefunDeploy { params, .. } = &decoded[1].kind else {
+            panic!("expected defun-deploy, found {:?}", decoded[1].kind);
+        };
+        assert_eq!(params[0].budget, None);
+        assert_eq!(
+            params[0].type_annotation,
+            Some(Type::Capability {
+                resource: ResourceType::Gpio
+            })
+        );
+    }
+
+    #[test]
+    fn test_detects_corruption() {
+        let bytes = BinaryEncoder::new()
+            .without_spans()
+            .encode(&parse_file(MONITOR).unwrap());
+
+        let mut flipped = bytes.clone();
+        flipped[bytes.len() / 2] ^= 0x10;
+        assert!(matches!(
+            decode_binary(&flipped),
+            Err(BinaryError::ChecksumMismatch { .. })
+        ));
+
+        assert_eq!(decode_binary(b"(program"), Err(BinaryError::BadMagic));
+        let mut newer = bytes.clone();
+        newer[4] = BINARY_VERSION + 1;
+        assert_eq!(
+            decode_binary(&newer),
+            Err(BinaryError::UnsupportedVersion {
+                found: BINARY_VERSION + 1
+            })
+        );
+
+        // Cut short but correctly sealed
+        let mut truncated = bytes[..bytes.len() - 10].to_vec();
+        truncated.extend_from_slice(&[0; 4]);
+        let truncated = reseal(truncated);
+        assert!(matches!(
+            decode_binary(&truncated),
+            Err(BinaryError::Truncated { .. })
+        ));
+    }
+
+    #[test]
+    fn test_validates_structure() {
+        let header = |strings: &[&str]| {
+            let mut bytes = b"OBLB\x01\x00".to_vec();
+            write_varint(&mut bytes, strings.len() as u128);
+            for s in strings {
+                write_varint(&mut bytes, s.len() as u128);
+                bytes.extend_from_slice(s.as_bytes());
+            }
+            bytes
+        };
+        let payload = |strings: &[&str], body: &[u8]| {
+            let mut bytes = header(strings);
+            bytes.extend_from_slice(body);
+            bytes.extend_from_slice(&[0; 4]);
+            decode_binary(&reseal(bytes))
+        };
+
+        // One form: tag 99
+        assert_eq!(
+            payload(&[], &[1, 99]),
+            Err(BinaryError::InvalidTag {
+                what: "node",
+                tag: 99,
+                offset: 8
+            })
+        );
+        // An identifier naming string 3 of 1
+        assert!(matches!(
+            payload(&["x"], &[1, 4, 3, 0]),
+            Err(BinaryError::Malformed { message, .. }) if message.contains("outside the table")
+        ));
+        // A program whose budget is an identifier
+        assert!(matches!(
+            payload(&["p"], &[1, 34, 0, 4, 0, 0, 0, 0]),
+            Err(BinaryError::Malformed { message, .. }) if message.contains("budget")
+        ));
+        // A million forms promised by a few bytes
+        assert!(matches!(
+            payload(&[], &[0xC0, 0x84, 0x3D]),
+            Err(BinaryError::Truncated { .. })
+        ));
+
+        // Eval-compile nested past the limit
+        let mut deep = vec![1];
+        deep.extend(std::iter::repeat_n(10, MAX_DEPTH + 1));
+        assert!(matches!(
+            payload(&[], &deep),
+            Err(BinaryError::Malformed { message, .. }) if message.contains("nesting")
+        ));
+    }
+}
@@ -0,0 +1,38 @@
+use std::ops::RangeInclusive;
+
+/// Operators predefined in every program, with the number of arguments
+/// each accepts
+const BUILTINS: &[(&str, usize, usize)] = &[
+    ("+", 2, 2),
+    ("-", 1, 2),
+    ("*", 2, 2),
+    ("/", 2, 2),
+    ("mod", 2, 2),
+    ("=", 2, 2),
+    ("!=", 2, 2),
+    ("<", 2, 2),
+    ("<=", 2, 2),
+    (">", 2, 2),
+    (">=", 2, 2),
+    ("and", 2, 2),
+    ("or", 2, 2),
+    ("not", 1, 1),
+    ("bitwise-and", 2, 2),
+    ("bitwise-or", 2, 2),
+    ("bitwise-xor", 2, 2),
+    ("bitwise-not", 1, 1),
+    ("bit-shift-left", 2, 2),
+    ("bit-shift-right", 2, 2),
+];
+
+pub fn is_builtin(name: &str) -> bool {
+    builtin_arity(name).is_some()
+}
+
+/// Argument counts the builtin `name` accepts
+pub fn builtin_arity(name: &str) -> Option<RangeInclusive<usize>> {
+    BUILTINS
+        .iter()
+        .find(|(builtin, _, _)| *builtin == name)
+        .map(|(_, min, max)| *min..=*max)
+}
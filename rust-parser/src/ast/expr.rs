@@ -1,11 +1,74 @@
+use super::literal::IntLiteral;
+use super::pretty_print::PrettyPrinter;
+use super::span::Span;
 use super::types::{Parameter, ResourceType, Type};
 use serde::{Deserialize, Serialize};
 use std::fmt;
 
+/// An AST node: the expression itself plus where it came from.
+///
+/// Equality compares only `kind`, so a tree produced by the parser is equal
+/// to the same tree built by hand or re-parsed from printed output.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(from = "ExprRepr")]
+pub struct Expr {
+    pub kind: ExprKind,
+    pub span: Span,
+    /// Type found by `TypeInference`; set on `let`-bound values
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ty: Option<Type>,
+}
+
+/// Shapes an `Expr` is deserialized from: a node, or the bare kind written
+/// before nodes carried spans
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ExprRepr {
+    Node {
+        kind: ExprKind,
+        #[serde(default)]
+        span: Span,
+        #[serde(default)]
+        ty: Option<Type>,
+    },
+    Bare(ExprKind),
+}
+
+impl From<ExprRepr> for Expr {
+    fn from(repr: ExprRepr) -> Self {
+        match repr {
+            ExprRepr::Node { kind, span, ty } => Self { kind, span, ty },
+            ExprRepr::Bare(kind) => Self::from(kind),
+        }
+    }
+}
+
+impl Expr {
+    pub fn new(kind: ExprKind, span: Span) -> Self {
+        Self {
+            kind,
+            span,
+            ty: None,
+        }
+    }
+}
+
+impl From<ExprKind> for Expr {
+    fn from(kind: ExprKind) -> Self {
+        Self::new(kind, Span::dummy())
+    }
+}
+
+impl PartialEq for Expr {
+    fn eq(&self, other: &Self) -> bool {
+        self.kind == other.kind
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
-pub enum Expr {
+pub enum ExprKind {
     // Literals
-    Int(i64),
+    Int(IntLiteral),
     Float(f64),
     Bool(bool),
     String(String),
@@ -77,6 +140,11 @@ pub enum Expr {
         elem_type: Type,
         size: usize,
     },
+    /// `(array-init T e...)`: an array holding the given elements
+    ArrayInit {
+        elem_type: Type,
+        elements: Vec<Expr>,
+    },
     ArrayGet {
         array: Box<Expr>,
         index: Box<Expr>,
@@ -115,6 +183,9 @@ pub enum Expr {
     DefCap {
         name: String,
         params: Vec<Parameter>,
+        /// Resource the capability grants, from its `: (capability res)`
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        resource: Option<ResourceType>,
         description: String,
     },
 
@@ -134,6 +205,17 @@ pub enum ResourceKind {
     StorageBytes,
 }
 
+impl fmt::Display for ResourceKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ResourceKind::TimeMs => write!(f, "time-ms"),
+            ResourceKind::MemoryBytes => write!(f, "memory-bytes"),
+            ResourceKind::NetworkBytes => write!(f, "network-bytes"),
+            ResourceKind::StorageBytes => write!(f, "storage-bytes"),
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct ResourceSpec {
     pub kind: ResourceKind,
@@ -156,17 +238,17 @@ pub enum Phase {
 impl Expr {
     /// Determine the phase of an expression
     pub fn phase(&self) -> Phase {
-        match self {
+        match &self.kind {
             // Compile-time only
-            Expr::DefunCompile { .. }
-            | Expr::Macro { .. }
-            | Expr::EvalCompile(_)
-            | Expr::Include(_)
-            | Expr::For { .. }
-            | Expr::While { .. } => Phase::Compile,
+            ExprKind::DefunCompile { .. }
+            | ExprKind::Macro { .. }
+            | ExprKind::EvalCompile(_)
+            | ExprKind::Include(_)
+            | ExprKind::For { .. }
+            | ExprKind::While { .. } => Phase::Compile,
 
             // Deploy-time
-            Expr::DefunDeploy { body, .. } => {
+            ExprKind::DefunDeploy { body, .. } => {
                 // Check that body contains no compile-only constructs
                 for expr in body {
                     if expr.phase() == Phase::Compile {
@@ -175,7 +257,7 @@ pub fn phase(&self) -> Phase {
                 }
                 Phase::Deploy
             }
-            Expr::BoundedFor { body, .. } => {
+            ExprKind::BoundedFor { body, .. } => {
                 for expr in body {
                     if expr.phase() == Phase::Compile {
                         return Phase::Mixed;
@@ -185,14 +267,14 @@ pub fn phase(&self) -> Phase {
             }
 
             // Literals and identifiers are phase-neutral
-            Expr::Int(_)
-            | Expr::Float(_)
-            | Expr::Bool(_)
-            | Expr::String(_)
-            | Expr::Ident(_) => Phase::Deploy, // Default to deploy
+            ExprKind::Int(_)
+            | ExprKind::Float(_)
+            | ExprKind::Bool(_)
+            | ExprKind::String(_)
+            | ExprKind::Ident(_) => Phase::Deploy, // Default to deploy
 
             // Recursively check compound expressions
-            Expr::Let { body, .. } => {
+            ExprKind::Let { body, .. } => {
                 let phases: Vec<_> = body.iter().map(|e| e.phase()).collect();
                 if phases.contains(&Phase::Compile) {
                     Phase::Compile
@@ -203,16 +285,12 @@ pub fn phase(&self) -> Phase {
                 }
             }
 
-            Expr::If {
+            ExprKind::If {
                 condition,
                 then_branch,
                 else_branch,
             } => {
-                let phases = vec![
-                    condition.phase(),
-                    then_branch.phase(),
-                    else_branch.phase(),
-                ];
+                let phases = [condition.phase(), then_branch.phase(), else_branch.phase()];
                 if phases.contains(&Phase::Mixed) {
                     Phase::Mixed
                 } else if phases.contains(&Phase::Compile) {
@@ -222,7 +300,7 @@ pub fn phase(&self) -> Phase {
                 }
             }
 
-            Expr::FunctionCall { func, args } => {
+            ExprKind::FunctionCall { func, args } => {
                 let mut phase = func.phase();
                 for arg in args {
                     let arg_phase = arg.phase();
@@ -244,72 +322,220 @@ pub fn phase(&self) -> Phase {
     /// Check if expression is compile-only
     pub fn is_compile_only(&self) -> bool {
         matches!(
-            self,
-            Expr::DefunCompile { .. }
-                | Expr::Macro { .. }
-                | Expr::EvalCompile(_)
-                | Expr::Include(_)
-                | Expr::For { .. }
-                | Expr::While { .. }
+            self.kind,
+            ExprKind::DefunCompile { .. }
+                | ExprKind::Macro { .. }
+                | ExprKind::EvalCompile(_)
+                | ExprKind::Include(_)
+                | ExprKind::For { .. }
+                | ExprKind::While { .. }
         )
     }
 
-    /// Check if expression is deploy-time safe
-    pub fn is_deploy_safe(&self) -> bool {
-        self.phase() != Phase::Compile && self.phase() != Phase::Mixed
+    /// Direct subexpressions, in source order
+    pub fn children(&self) -> Vec<&Expr> {
+        match &self.kind {
+            ExprKind::Int(_)
+            | ExprKind::Float(_)
+            | ExprKind::Bool(_)
+            | ExprKind::String(_)
+            | ExprKind::Ident(_)
+            | ExprKind::Include(_)
+            | ExprKind::ArrayLiteral { .. }
+            | ExprKind::Timestamp
+            | ExprKind::ResourceBudget { .. }
+            | ExprKind::DefCap { .. } => vec![],
+            ExprKind::DefunDeploy { body, .. }
+            | ExprKind::DefunCompile { body, .. }
+            | ExprKind::Macro { body, .. }
+            | ExprKind::ArrayInit {
+                elements: body, ..
+            } => body.iter().collect(),
+            ExprKind::BoundedFor {
+                start, end, body, ..
+            } => [start.as_ref(), end.as_ref()]
+                .into_iter()
+                .chain(body)
+                .collect(),
+            ExprKind::WithCapability {
+                capability: head,
+                body,
+            }
+            | ExprKind::For {
+                iterable: head,
+                body,
+                ..
+            }
+            | ExprKind::While {
+                condition: head,
+                body,
+            } => std::iter::once(head.as_ref()).chain(body).collect(),
+            ExprKind::Let { bindings, body } => bindings
+                .iter()
+                .map(|(_, value)| value)
+                .chain(body)
+                .collect(),
+            ExprKind::FunctionCall { func, args } => {
+                std::iter::once(func.as_ref()).chain(args).collect()
+            }
+            ExprKind::If {
+                condition,
+                then_branch,
+                else_branch,
+            } => vec![condition, then_branch, else_branch],
+            ExprKind::ArraySet {
+                array,
+                index,
+                value,
+            } => vec![array, index, value],
+            ExprKind::ArrayGet { array: a, index: b }
+            | ExprKind::GpioSet {
+                device: a,
+                value: b,
+            }
+            | ExprKind::UartSend { device: a, data: b }
+            | ExprKind::NetworkSend { device: a, data: b } => vec![a, b],
+            ExprKind::Set { value: e, .. }
+            | ExprKind::EvalCompile(e)
+            | ExprKind::ArrayLength(e)
+            | ExprKind::GpioGet(e)
+            | ExprKind::UartRecv(e)
+            | ExprKind::SensorRead(e)
+            | ExprKind::NetworkRecv(e)
+            | ExprKind::SleepMs(e) => vec![e],
+            ExprKind::Program { budget, forms, .. } => {
+                std::iter::once(budget.as_ref()).chain(forms).collect()
+            }
+        }
     }
-}
 
-impl fmt::Display for Expr {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match self {
-            Expr::Int(n) => write!(f, "{}", n),
-            Expr::Float(n) => write!(f, "{}", n),
-            Expr::Bool(b) => write!(f, "{}", b),
-            Expr::String(s) => write!(f, "\"{}\"", s),
-            Expr::Ident(i) => write!(f, "{}", i),
-            Expr::DefunDeploy {
-                name,
-                params,
-                return_type,
+    /// Mutable access to the direct subexpressions, in source order
+    pub fn children_mut(&mut self) -> Vec<&mut Expr> {
+        match &mut self.kind {
+            ExprKind::Int(_)
+            | ExprKind::Float(_)
+            | ExprKind::Bool(_)
+            | ExprKind::String(_)
+            | ExprKind::Ident(_)
+            | ExprKind::Include(_)
+            | ExprKind::ArrayLiteral { .. }
+            | ExprKind::Timestamp
+            | ExprKind::ResourceBudget { .. }
+            | ExprKind::DefCap { .. } => vec![],
+            ExprKind::DefunDeploy { body, .. }
+            | ExprKind::DefunCompile { body, .. }
+            | ExprKind::Macro { body, .. }
+            | ExprKind::ArrayInit {
+                elements: body, ..
+            } => body.iter_mut().collect(),
+            ExprKind::BoundedFor {
+                start, end, body, ..
+            } => [start.as_mut(), end.as_mut()]
+                .into_iter()
+                .chain(body)
+                .collect(),
+            ExprKind::WithCapability {
+                capability: head,
                 body,
-            } => {
-                write!(f, "(defun-deploy {} (", name)?;
-                for (i, param) in params.iter().enumerate() {
-                    if i > 0 {
-                        write!(f, " ")?;
-                    }
-                    write!(f, "{}", param)?;
-                }
-                write!(f, ")")?;
-                if let Some(ty) = return_type {
-                    write!(f, " : {}", ty)?;
-                }
-                for expr in body {
-                    write!(f, "\n  {}", expr)?;
-                }
-                write!(f, ")")
             }
-            Expr::BoundedFor {
-                var,
-                start,
-                end,
+            | ExprKind::For {
+                iterable: head,
                 body,
-            } => {
-                write!(f, "(bounded-for {} {} {}", var, start, end)?;
-                for expr in body {
-                    write!(f, "\n  {}", expr)?;
-                }
-                write!(f, ")")
+                ..
             }
-            Expr::FunctionCall { func, args } => {
-                write!(f, "({}", func)?;
-                for arg in args {
-                    write!(f, " {}", arg)?;
-                }
-                write!(f, ")")
+            | ExprKind::While {
+                condition: head,
+                body,
+            } => std::iter::once(head.as_mut()).chain(body).collect(),
+            ExprKind::Let { bindings, body } => bindings
+                .iter_mut()
+                .map(|(_, value)| value)
+                .chain(body)
+                .collect(),
+            ExprKind::FunctionCall { func, args } => {
+                std::iter::once(func.as_mut()).chain(args).collect()
+            }
+            ExprKind::If {
+                condition,
+                then_branch,
+                else_branch,
+            } => vec![condition, then_branch, else_branch],
+            ExprKind::ArraySet {
+                array,
+                index,
+                value,
+            } => vec![array, index, value],
+            ExprKind::ArrayGet { array: a, index: b }
+            | ExprKind::GpioSet {
+                device: a,
+                value: b,
+            }
+            | ExprKind::UartSend { device: a, data: b }
+            | ExprKind::NetworkSend { device: a, data: b } => vec![a, b],
+            ExprKind::Set { value: e, .. }
+            | ExprKind::EvalCompile(e)
+            | ExprKind::ArrayLength(e)
+            | ExprKind::GpioGet(e)
+            | ExprKind::UartRecv(e)
+            | ExprKind::SensorRead(e)
+            | ExprKind::NetworkRecv(e)
+            | ExprKind::SleepMs(e) => vec![e],
+            ExprKind::Program { budget, forms, .. } => {
+                std::iter::once(budget.as_mut()).chain(forms).collect()
             }
-            _ => write!(f, "<expr>"),
         }
     }
+
+    /// Keyword that introduces this form, if it is a special form
+    pub fn keyword(&self) -> Option<&'static str> {
+        let keyword = match &self.kind {
+            ExprKind::DefunDeploy { .. } => "defun-deploy",
+            ExprKind::BoundedFor { .. } => "bounded-for",
+            ExprKind::WithCapability { .. } => "with-capability",
+            ExprKind::DefunCompile { .. } => "defun-compile",
+            ExprKind::Macro { .. } => "macro",
+            ExprKind::EvalCompile(_) => "eval-compile",
+            ExprKind::Include(_) => "include",
+            ExprKind::For { .. } => "for",
+            ExprKind::While { .. } => "while",
+            ExprKind::Let { .. } => "let",
+            ExprKind::Set { .. } => "set",
+            ExprKind::If { .. } => "if",
+            ExprKind::ArrayLiteral { .. } => "array",
+            ExprKind::ArrayInit { .. } => "array-init",
+            ExprKind::ArrayGet { .. } => "array-get",
+            ExprKind::ArraySet { .. } => "array-set",
+            ExprKind::ArrayLength(_) => "array-length",
+            ExprKind::GpioSet { .. } => "gpio-set",
+            ExprKind::GpioGet(_) => "gpio-get",
+            ExprKind::UartSend { .. } => "uart-send",
+            ExprKind::UartRecv(_) => "uart-recv",
+            ExprKind::SensorRead(_) => "sensor-read",
+            ExprKind::NetworkSend { .. } => "network-send",
+            ExprKind::NetworkRecv(_) => "network-recv",
+            ExprKind::SleepMs(_) => "sleep-ms",
+            ExprKind::Timestamp => "timestamp",
+            ExprKind::ResourceBudget { .. } => "resource-budget",
+            ExprKind::DefCap { .. } => "defcap",
+            ExprKind::Program { .. } => "program",
+            ExprKind::Int(_)
+            | ExprKind::Float(_)
+            | ExprKind::Bool(_)
+            | ExprKind::String(_)
+            | ExprKind::Ident(_)
+            | ExprKind::FunctionCall { .. } => return None,
+        };
+        Some(keyword)
+    }
+
+    /// Check if expression is deploy-time safe
+    pub fn is_deploy_safe(&self) -> bool {
+        self.phase() != Phase::Compile && self.phase() != Phase::Mixed
+    }
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", PrettyPrinter::print(self))
+    }
 }
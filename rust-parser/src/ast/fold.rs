@@ -0,0 +1,705 @@
+//! Rewriting of the AST by value.
+//!
+//! `Fold` has one hook per `ExprKind` variant. A hook receives the node's
+//! fields and returns the `ExprKind` to put in its place, which need not be
+//! the same variant; each default rebuilds the node after folding its
+//! children with the matching `fold_*` function. The node's span and
+//! recorded type are carried over by `fold_expr`, so diagnostics from later
+//! passes still point at the source that was rewritten.
+//!
+//! A `Pass` rewrites a whole program, and every `Fold` is one. Passes are
+//! chained with a `Pipeline`.
+
+use super::expr::{Expr, ExprKind, ResourceSpec};
+use super::literal::IntLiteral;
+use super::span::Span;
+use super::types::{Parameter, ResourceType, Type};
+
+pub trait Fold: Sized {
+    fn fold_expr(&mut self, expr: Expr) -> Expr {
+        fold_expr(self, expr)
+    }
+
+    fn fold_param(&mut self, param: Parameter) -> Parameter {
+        param
+    }
+
+    // Literals
+
+    fn fold_int(&mut self, literal: IntLiteral, _span: Span) -> ExprKind {
+        ExprKind::Int(literal)
+    }
+
+    fn fold_float(&mut self, value: f64, _span: Span) -> ExprKind {
+        ExprKind::Float(value)
+    }
+
+    fn fold_bool(&mut self, value: bool, _span: Span) -> ExprKind {
+        ExprKind::Bool(value)
+    }
+
+    fn fold_string(&mut self, value: String, _span: Span) -> ExprKind {
+        ExprKind::String(value)
+    }
+
+    fn fold_ident(&mut self, name: String, _span: Span) -> ExprKind {
+        ExprKind::Ident(name)
+    }
+
+    // Deploy-time constructs
+
+    fn fold_defun_deploy(
+        &mut self,
+        name: String,
+        params: Vec<Parameter>,
+        return_type: Option<Type>,
+        body: Vec<Expr>,
+        _span: Span,
+    ) -> ExprKind {
+        fold_defun_deploy(self, name, params, return_type, body)
+    }
+
+    fn fold_bounded_for(
+        &mut self,
+        var: String,
+        start: Box<Expr>,
+        end: Box<Expr>,
+        body: Vec<Expr>,
+        _span: Span,
+    ) -> ExprKind {
+        fold_bounded_for(self, var, start, end, body)
+    }
+
+    fn fold_with_capability(
+        &mut self,
+        capability: Box<Expr>,
+        body: Vec<Expr>,
+        _span: Span,
+    ) -> ExprKind {
+        fold_with_capability(self, capability, body)
+    }
+
+    // Compile-time constructs
+
+    fn fold_defun_compile(
+        &mut self,
+        name: String,
+        params: Vec<Parameter>,
+        return_type: Option<Type>,
+        body: Vec<Expr>,
+        _span: Span,
+    ) -> ExprKind {
+        fold_defun_compile(self, name, params, return_type, body)
+    }
+
+    fn fold_macro(
+        &mut self,
+        name: String,
+        params: Vec<Parameter>,
+        body: Vec<Expr>,
+        _span: Span,
+    ) -> ExprKind {
+        fold_macro(self, name, params, body)
+    }
+
+    fn fold_eval_compile(&mut self, expr: Box<Expr>, _span: Span) -> ExprKind {
+        fold_eval_compile(self, expr)
+    }
+
+    fn fold_include(&mut self, path: String, _span: Span) -> ExprKind {
+        ExprKind::Include(path)
+    }
+
+    fn fold_for(
+        &mut self,
+        var: String,
+        iterable: Box<Expr>,
+        body: Vec<Expr>,
+        _span: Span,
+    ) -> ExprKind {
+        fold_for(self, var, iterable, body)
+    }
+
+    fn fold_while(&mut self, condition: Box<Expr>, body: Vec<Expr>, _span: Span) -> ExprKind {
+        fold_while(self, condition, body)
+    }
+
+    // Common constructs
+
+    fn fold_let(
+        &mut self,
+        bindings: Vec<(String, Expr)>,
+        body: Vec<Expr>,
+        _span: Span,
+    ) -> ExprKind {
+        fold_let(self, bindings, body)
+    }
+
+    fn fold_set(&mut self, var: String, value: Box<Expr>, _span: Span) -> ExprKind {
+        fold_set(self, var, value)
+    }
+
+    fn fold_if(
+        &mut self,
+        condition: Box<Expr>,
+        then_branch: Box<Expr>,
+        else_branch: Box<Expr>,
+        _span: Span,
+    ) -> ExprKind {
+        fold_if(self, condition, then_branch, else_branch)
+    }
+
+    fn fold_function_call(&mut self, func: Box<Expr>, args: Vec<Expr>, _span: Span) -> ExprKind {
+        fold_function_call(self, func, args)
+    }
+
+    // Array operations
+
+    fn fold_array_literal(&mut self, elem_type: Type, size: usize, _span: Span) -> ExprKind {
+        ExprKind::ArrayLiteral { elem_type, size }
+    }
+
+    fn fold_array_init(&mut self, elem_type: Type, elements: Vec<Expr>, _span: Span) -> ExprKind {
+        fold_array_init(self, elem_type, elements)
+    }
+
+    fn fold_array_get(&mut self, array: Box<Expr>, index: Box<Expr>, _span: Span) -> ExprKind {
+        fold_array_get(self, array, index)
+    }
+
+    fn fold_array_set(
+        &mut self,
+        array: Box<Expr>,
+        index: Box<Expr>,
+        value: Box<Expr>,
+        _span: Span,
+    ) -> ExprKind {
+        fold_array_set(self, array, index, value)
+    }
+
+    fn fold_array_length(&mut self, array: Box<Expr>, _span: Span) -> ExprKind {
+        ExprKind::ArrayLength(fold_box(self, array))
+    }
+
+    // I/O operations
+
+    fn fold_gpio_set(&mut self, device: Box<Expr>, value: Box<Expr>, _span: Span) -> ExprKind {
+        fold_gpio_set(self, device, value)
+    }
+
+    fn fold_gpio_get(&mut self, device: Box<Expr>, _span: Span) -> ExprKind {
+        ExprKind::GpioGet(fold_box(self, device))
+    }
+
+    fn fold_uart_send(&mut self, device: Box<Expr>, data: Box<Expr>, _span: Span) -> ExprKind {
+        fold_uart_send(self, device, data)
+    }
+
+    fn fold_uart_recv(&mut self, device: Box<Expr>, _span: Span) -> ExprKind {
+        ExprKind::UartRecv(fold_box(self, device))
+    }
+
+    fn fold_sensor_read(&mut self, device: Box<Expr>, _span: Span) -> ExprKind {
+        ExprKind::SensorRead(fold_box(self, device))
+    }
+
+    fn fold_network_send(&mut self, device: Box<Expr>, data: Box<Expr>, _span: Span) -> ExprKind {
+        fold_network_send(self, device, data)
+    }
+
+    fn fold_network_recv(&mut self, device: Box<Expr>, _span: Span) -> ExprKind {
+        ExprKind::NetworkRecv(fold_box(self, device))
+    }
+
+    fn fold_sleep_ms(&mut self, duration: Box<Expr>, _span: Span) -> ExprKind {
+        ExprKind::SleepMs(fold_box(self, duration))
+    }
+
+    fn fold_timestamp(&mut self, _span: Span) -> ExprKind {
+        ExprKind::Timestamp
+    }
+
+    // Resource management
+
+    fn fold_resource_budget(&mut self, specs: Vec<ResourceSpec>, _span: Span) -> ExprKind {
+        ExprKind::ResourceBudget { specs }
+    }
+
+    fn fold_defcap(
+        &mut self,
+        name: String,
+        params: Vec<Parameter>,
+        resource: Option<ResourceType>,
+        description: String,
+        _span: Span,
+    ) -> ExprKind {
+        fold_defcap(self, name, params, resource, description)
+    }
+
+    // Program structure
+
+    fn fold_program(
+        &mut self,
+        name: String,
+        budget: Box<Expr>,
+        forms: Vec<Expr>,
+        _span: Span,
+    ) -> ExprKind {
+        fold_program(self, name, budget, forms)
+    }
+}
+
+/// Fold `expr`'s kind with the folder's hook for it, keeping the span and
+/// recorded type
+pub fn fold_expr<F: Fold>(folder: &mut F, expr: Expr) -> Expr {
+    let Expr { kind, span, ty } = expr;
+    let kind = match kind {
+        ExprKind::Int(literal) => folder.fold_int(literal, span),
+        ExprKind::Float(value) => folder.fold_float(value, span),
+        ExprKind::Bool(value) => folder.fold_bool(value, span),
+        ExprKind::String(value) => folder.fold_string(value, span),
+        ExprKind::Ident(name) => folder.fold_ident(name, span),
+        ExprKind::DefunDeploy {
+            name,
+            params,
+            return_type,
+            body,
+        } => folder.fold_defun_deploy(name, params, return_type, body, span),
+        ExprKind::BoundedFor {
+            var,
+            start,
+            end,
+            body,
+        } => folder.fold_bounded_for(var, start, end, body, span),
+        ExprKind::WithCapability { capability, body } => {
+            folder.fold_with_capability(capability, body, span)
+        }
+        ExprKind::DefunCompile {
+            name,
+            params,
+            return_type,
+            body,
+        } => folder.fold_defun_compile(name, params, return_type, body, span),
+        ExprKind::Macro { name, params, body } => folder.fold_macro(name, params, body, span),
+        ExprKind::EvalCompile(expr) => folder.fold_eval_compile(expr, span),
+        ExprKind::Include(path) => folder.fold_include(path, span),
+        ExprKind::For {
+            var,
+            iterable,
+            body,
+        } => folder.fold_for(var, iterable, body, span),
+        ExprKind::While { condition, body } => folder.fold_while(condition, body, span),
+        ExprKind::Let { bindings, body } => folder.fold_let(bindings, body, span),
+        ExprKind::Set { var, value } => folder.fold_set(var, value, span),
+        ExprKind::If {
+            condition,
+            then_branch,
+            else_branch,
+        } => folder.fold_if(condition, then_branch, else_branch, span),
+        ExprKind::FunctionCall { func, args } => folder.fold_function_call(func, args, span),
+        ExprKind::ArrayLiteral { elem_type, size } => {
+            folder.fold_array_literal(elem_type, size, span)
+        }
+        ExprKind::ArrayInit {
+            elem_type,
+            elements,
+        } => folder.fold_array_init(elem_type, elements, span),
+        ExprKind::ArrayGet { array, index } => folder.fold_array_get(array, index, span),
+        ExprKind::ArraySet {
+            array,
+            index,
+            value,
+        } => folder.fold_array_set(array, index, value, span),
+        ExprKind::ArrayLength(array) => folder.fold_array_length(array, span),
+        ExprKind::GpioSet { device, value } => folder.fold_gpio_set(device, value, span),
+        ExprKind::GpioGet(device) => folder.fold_gpio_get(device, span),
+        ExprKind::UartSend { device, data } => folder.fold_uart_send(device, data, span),
+        ExprKind::UartRecv(device) => folder.fold_uart_recv(device, span),
+        ExprKind::SensorRead(device) => folder.fold_sensor_read(device, span),
+        ExprKind::NetworkSend { device, data } => folder.fold_network_send(device, data, span),
+        ExprKind::NetworkRecv(device) => folder.fold_network_recv(device, span),
+        ExprKind::SleepMs(duration) => folder.fold_sleep_ms(duration, span),
+        ExprKind::Timestamp => folder.fold_timestamp(span),
+        ExprKind::ResourceBudget { specs } => folder.fold_resource_budget(specs, span),
+        ExprKind::DefCap {
+            name,
+            params,
+            resource,
+            description,
+        } => folder.fold_defcap(name, params, resource, description, span),
+        ExprKind::Program {
+            name,
+            budget,
+            forms,
+        } => folder.fold_program(name, budget, forms, span),
+    };
+    Expr { kind, span, ty }
+}
+
+/// Fold a boxed child, reusing its allocation
+pub fn fold_box<F: Fold>(folder: &mut F, mut expr: Box<Expr>) -> Box<Expr> {
+    *expr = folder.fold_expr(*expr);
+    expr
+}
+
+pub fn fold_exprs<F: Fold>(folder: &mut F, exprs: Vec<Expr>) -> Vec<Expr> {
+    exprs.into_iter().map(|e| folder.fold_expr(e)).collect()
+}
+
+pub fn fold_params<F: Fold>(folder: &mut F, params: Vec<Parameter>) -> Vec<Parameter> {
+    params.into_iter().map(|p| folder.fold_param(p)).collect()
+}
+
+pub fn fold_defun_deploy<F: Fold>(
+    folder: &mut F,
+    name: String,
+    params: Vec<Parameter>,
+    return_type: Option<Type>,
+    body: Vec<Expr>,
+) -> ExprKind {
+    ExprKind::DefunDeploy {
+        name,
+        params: fold_params(folder, params),
+        return_type,
+        body: fold_exprs(folder, body),
+    }
+}
+
+pub fn fold_bounded_for<F: Fold>(
+    folder: &mut F,
+    var: String,
+    start: Box<Expr>,
+    end: Box<Expr>,
+    body: Vec<Expr>,
+) -> ExprKind {
+    ExprKind::BoundedFor {
+        var,
+        start: fold_box(folder, start),
+        end: fold_box(folder, end),
+        body: fold_exprs(folder, body),
+    }
+}
+
+pub fn fold_with_capability<F: Fold>(
+    folder: &mut F,
+    capability: Box<Expr>,
+    body: Vec<Expr>,
+) -> ExprKind {
+    ExprKind::WithCapability {
+        capability: fold_box(folder, capability),
+        body: fold_exprs(folder, body),
+    }
+}
+
+pub fn fold_defun_compile<F: Fold>(
+    folder: &mut F,
+    name: String,
+    params: Vec<Parameter>,
+    return_type: Option<Type>,
+    body: Vec<Expr>,
+) -> ExprKind {
+    ExprKind::DefunCompile {
+        name,
+        params: fold_params(folder, params),
+        return_type,
+        body: fold_exprs(folder, body),
+    }
+}
+
+pub fn fold_macro<F: Fold>(
+    folder: &mut F,
+    name: String,
+    params: Vec<Parameter>,
+    body: Vec<Expr>,
+) -> ExprKind {
+    ExprKind::Macro {
+        name,
+        params: fold_params(folder, params),
+        body: fold_exprs(folder, body),
+    }
+}
+
+pub fn fold_eval_compile<F: Fold>(folder: &mut F, expr: Box<Expr>) -> ExprKind {
+    ExprKind::EvalCompile(fold_box(folder, expr))
+}
+
+pub fn fold_for<F: Fold>(
+    folder: &mut F,
+    var: String,
+    iterable: Box<Expr>,
+    body: Vec<Expr>,
+) -> ExprKind {
+    ExprKind::For {
+        var,
+        iterable: fold_box(folder, iterable),
+        body: fold_exprs(folder, body),
+    }
+}
+
+pub fn fold_while<F: Fold>(folder: &mut F, condition: Box<Expr>, body: Vec<Expr>) -> ExprKind {
+    ExprKind::While {
+        condition: fold_box(folder, condition),
+        body: fold_exprs(folder, body),
+    }
+}
+
+pub fn fold_let<F: Fold>(
+    folder: &mut F,
+    bindings: Vec<(String, Expr)>,
+    body: Vec<Expr>,
+) -> ExprKind {
+    ExprKind::Let {
+        bindings: bindings
+            .into_iter()
+            .map(|(name, value)| (name, folder.fold_expr(value)))
+            .collect(),
+        body: fold_exprs(folder, body),
+    }
+}
+
+pub fn fold_set<F: Fold>(folder: &mut F, var: String, value: Box<Expr>) -> ExprKind {
+    ExprKind::Set {
+        var,
+        value: fold_box(folder, value),
+    }
+}
+
+pub fn fold_if<F: Fold>(
+    folder: &mut F,
+    condition: Box<Expr>,
+    then_branch: Box<Expr>,
+    else_branch: Box<Expr>,
+) -> ExprKind {
+    ExprKind::If {
+        condition: fold_box(folder, condition),
+        then_branch: fold_box(folder, then_branch),
+        else_branch: fold_box(folder, else_branch),
+    }
+}
+
+pub fn fold_function_call<F: Fold>(folder: &mut F, func: Box<Expr>, args: Vec<Expr>) -> ExprKind {
+    ExprKind::FunctionCall {
+        func: fold_box(folder, func),
+        args: fold_exprs(folder, args),
+    }
+}
+
+pub fn fold_array_init<F: Fold>(folder: &mut F, elem_type: Type, elements: Vec<Expr>) -> ExprKind {
+    ExprKind::ArrayInit {
+        elem_type,
+        elements: fold_exprs(folder, elements),
+    }
+}
+
+pub fn fold_array_get<F: Fold>(folder: &mut F, array: Box<Expr>, index: Box<Expr>) -> ExprKind {
+    ExprKind::ArrayGet {
+        array: fold_box(folder, array),
+        index: fold_box(folder, index),
+    }
+}
+
+pub fn fold_array_set<F: Fold>(
+    folder: &mut F,
+    array: Box<Expr>,
+    index: Box<Expr>,
+    value: Box<Expr>,
+) -> ExprKind {
+    ExprKind::ArraySet {
+        array: fold_box(folder, array),
+        index: fold_box(folder, index),
+        value: fold_box(folder, value),
+    }
+}
+
+pub fn fold_gpio_set<F: Fold>(folder: &mut F, device: Box<Expr>, value: Box<Expr>) -> ExprKind {
+    ExprKind::GpioSet {
+        device: fold_box(folder, device),
+        value: fold_box(folder, value),
+    }
+}
+
+pub fn fold_uart_send<F: Fold>(folder: &mut F, device: Box<Expr>, data: Box<Expr>) -> ExprKind {
+    ExprKind::UartSend {
+        device: fold_box(folder, device),
+        data: fold_box(folder, data),
+    }
+}
+
+pub fn fold_network_send<F: Fold>(folder: &mut F, device: Box<Expr>, data: Box<Expr>) -> ExprKind {
+    ExprKind::NetworkSend {
+        device: fold_box(folder, device),
+        data: fold_box(folder, data),
+    }
+}
+
+pub fn fold_defcap<F: Fold>(
+    folder: &mut F,
+    name: String,
+    params: Vec<Parameter>,
+    resource: Option<ResourceType>,
+    description: String,
+) -> ExprKind {
+    ExprKind::DefCap {
+        name,
+        params: fold_params(folder, params),
+        resource,
+        description,
+    }
+}
+
+pub fn fold_program<F: Fold>(
+    folder: &mut F,
+    name: String,
+    budget: Box<Expr>,
+    forms: Vec<Expr>,
+) -> ExprKind {
+    ExprKind::Program {
+        name,
+        budget: fold_box(folder, budget),
+        forms: fold_exprs(folder, forms),
+    }
+}
+
+/// A whole-program transformation
+pub trait Pass {
+    fn run(&mut self, exprs: Vec<Expr>) -> Vec<Expr>;
+}
+
+impl<F: Fold> Pass for F {
+    fn run(&mut self, exprs: Vec<Expr>) -> Vec<Expr> {
+        fold_exprs(self, exprs)
+    }
+}
+
+/// Passes run one after another, each seeing the output of the last
+#[derive(Default)]
+pub struct Pipeline {
+    passes: Vec<Box<dyn Pass>>,
+}
+
+impl Pipeline {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Append `pass` to the end of the pipeline
+    pub fn then(mut self, pass: impl Pass + 'static) -> Self {
+        self.passes.push(Box::new(pass));
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.passes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.passes.is_empty()
+    }
+
+    pub fn run(&mut self, exprs: Vec<Expr>) -> Vec<Expr> {
+        self.passes
+            .iter_mut()
+            .fold(exprs, |exprs, pass| pass.run(exprs))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_file;
+
+    /// Folds `(+ a b)` on integer literals
+    struct AddLiterals;
+
+    impl Fold for AddLiterals {
+        fn fold_function_call(
+            &mut self,
+            func: Box<Expr>,
+            args: Vec<Expr>,
+            _span: Span,
+        ) -> ExprKind {
+            let args = fold_exprs(self, args);
+            if let (ExprKind::Ident(op), [a, b]) = (&func.kind, args.as_slice()) {
+                if let (ExprKind::Int(a), ExprKind::Int(b)) = (&a.kind, &b.kind) {
+                    if op == "+" {
+                        return ExprKind::Int(IntLiteral::new(a.value + b.value));
+                    }
+                }
+            }
+            ExprKind::FunctionCall { func, args }
+        }
+    }
+
+    /// Replaces `(if true a b)` with `a` and `(if false a b)` with `b`
+    struct PruneIf;
+
+    impl Fold for PruneIf {
+        fn fold_if(
+            &mut self,
+            condition: Box<Expr>,
+            then_branch: Box<Expr>,
+            else_branch: Box<Expr>,
+            _span: Span,
+        ) -> ExprKind {
+            match condition.kind {
+                ExprKind::Bool(true) => self.fold_expr(*then_branch).kind,
+                ExprKind::Bool(false) => self.fold_expr(*else_branch).kind,
+                _ => fold_if(self, condition, then_branch, else_branch),
+            }
+        }
+    }
+
+    struct Identity;
+
+    impl Fold for Identity {}
+
+    #[test]
+    fn test_identity_fold_preserves_tree_and_spans() {
+        let source = "(program p (resource-budget (time-ms 10))\n  (defun-deploy f (x)\n    (let ((y (array-get buf x)))\n      (bounded-for i 0 y (gpio-set led (if y 1 0))))))";
+        let exprs = parse_file(source).unwrap();
+        let folded = Identity.run(exprs.clone());
+
+        assert_eq!(folded, exprs);
+        let spans = |e: &[Expr]| -> Vec<Span> {
+            let mut spans = Vec::new();
+            let mut stack: Vec<&Expr> = e.iter().collect();
+            while let Some(expr) = stack.pop() {
+                spans.push(expr.span);
+                stack.extend(expr.children());
+            }
+            spans
+        };
+        assert_eq!(spans(&folded), spans(&exprs));
+    }
+
+    #[test]
+    fn test_fold_replaces_variant_and_keeps_span() {
+        let exprs = parse_file("(sleep-ms (+ 1 (+ 2 3)))").unwrap();
+        let ExprKind::SleepMs(arg) = &exprs[0].kind else {
+            panic!("expected sleep-ms");
+        };
+        let call_span = arg.span;
+
+        let folded = AddLiterals.run(exprs);
+        let ExprKind::SleepMs(arg) = &folded[0].kind else {
+            panic!("expected sleep-ms");
+        };
+        assert_eq!(arg.kind, ExprKind::Int(IntLiteral::new(6)));
+        assert_eq!(arg.span, call_span);
+    }
+
+    #[test]
+    fn test_pipeline_runs_passes_in_order() {
+        let exprs = parse_file("(if true (+ 1 2) 0)").unwrap();
+
+        let mut pipeline = Pipeline::new().then(AddLiterals).then(PruneIf);
+        assert_eq!(pipeline.len(), 2);
+        let folded = pipeline.run(exprs.clone());
+        assert_eq!(folded[0].kind, ExprKind::Int(IntLiteral::new(3)));
+        assert_eq!(folded[0].span, exprs[0].span);
+
+        // Pruning first leaves the call unfolded
+        let folded = Pipeline::new().then(PruneIf).run(exprs);
+        assert!(matches!(folded[0].kind, ExprKind::FunctionCall { .. }));
+    }
+}
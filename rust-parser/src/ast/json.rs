@@ -0,0 +1,1278 @@
+//! Versioned JSON interchange format for syntax trees.
+//!
+//! The derived serde encoding of `Expr` follows the Rust type names, so it
+//! changes whenever a variant does. Other components (the coordinator, the
+//! dashboard) read ASTs in this format instead:
+//!
+//! ```json
+//! {
+//!   "format": "oblibeny-ast",
+//!   "version": 3,
+//!   "forms": [
+//!     {"kind": "call", "function": {"kind": "ident", "name": "+"},
+//!      "args": [{"kind": "int", "value": 1}, {"kind": "int", "value": 2}]}
+//!   ]
+//! }
+//! ```
+//!
+//! Every node is an object with a `kind`, its fields, an optional `span` and,
+//! after type inference, an optional `type`. `ast_json_schema` generates the
+//! JSON Schema from the same field table the tests check the writer against;
+//! `grammar/ast-json.md` describes the format. Readers accept all earlier
+//! versions; version 0 is the unversioned serde encoding, an array of nodes.
+
+use super::expr::{Expr, ExprKind, ResourceKind, ResourceSpec};
+use super::literal::{IntLiteral, Radix};
+use super::span::{ExpansionId, FileId, Span};
+use super::types::{Parameter, ResourceType, Type};
+use serde_json::{json, Map, Value};
+use thiserror::Error;
+
+/// Value of a document's `format` field
+pub const AST_FORMAT: &str = "oblibeny-ast";
+
+/// Version written by `ast_to_json`; bumped on any incompatible change
+pub const AST_FORMAT_VERSION: u64 = 3;
+
+#[derive(Error, Debug)]
+pub enum AstJsonError {
+    #[error("Invalid JSON: {0}")]
+    Json(#[from] serde_json::Error),
+
+    #[error("Not an Oblibeny AST document (expected \"format\": \"{AST_FORMAT}\")")]
+    NotAnAst,
+
+    #[error(
+        "Unsupported AST format version {found} (this build reads up to {AST_FORMAT_VERSION})"
+    )]
+    UnsupportedVersion { found: u64 },
+
+    #[error("Invalid AST at {path}: {message}")]
+    Invalid { path: String, message: String },
+}
+
+type Result<T> = std::result::Result<T, AstJsonError>;
+
+/// What a node field holds
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Field {
+    Node,
+    Nodes,
+    Text,
+    Integer,
+    Number,
+    Boolean,
+    Radix,
+    Type,
+    Resource,
+    Params,
+    Bindings,
+    Limits,
+}
+
+struct FieldSpec {
+    name: &'static str,
+    field: Field,
+    required: bool,
+}
+
+const fn req(name: &'static str, field: Field) -> FieldSpec {
+    FieldSpec {
+        name,
+        field,
+        required: true,
+    }
+}
+
+const fn opt(name: &'static str, field: Field) -> FieldSpec {
+    FieldSpec {
+        name,
+        field,
+        required: false,
+    }
+}
+
+/// Every node kind of the current version and its fields, besides `kind`,
+/// `span` and `type`
+const NODE_KINDS: &[(&str, &[FieldSpec])] = &[
+    (
+        "int",
+        &[
+            req("value", Field::Integer),
+            opt("radix", Field::Radix),
+            opt("suffix", Field::Type),
+        ],
+    ),
+    ("float", &[req("value", Field::Number)]),
+    ("bool", &[req("value", Field::Boolean)]),
+    ("string", &[req("value", Field::Text)]),
+    ("ident", &[req("name", Field::Text)]),
+    (
+        "defun-deploy",
+        &[
+            req("name", Field::Text),
+            req("params", Field::Params),
+            opt("return_type", Field::Type),
+            req("body", Field::Nodes),
+        ],
+    ),
+    (
+        "defun-compile",
+        &[
+            req("name", Field::Text),
+            req("params", Field::Params),
+            opt("return_type", Field::Type),
+            req("body", Field::Nodes),
+        ],
+    ),
+    (
+        "macro",
+        &[
+            req("name", Field::Text),
+            req("params", Field::Params),
+            req("body", Field::Nodes),
+        ],
+    ),
+    (
+        "bounded-for",
+        &[
+            req("var", Field::Text),
+            req("start", Field::Node),
+            req("end", Field::Node),
+            req("body", Field::Nodes),
+        ],
+    ),
+    (
+        "with-capability",
+        &[req("capability", Field::Node), req("body", Field::Nodes)],
+    ),
+    ("eval-compile", &[req("expr", Field::Node)]),
+    ("include", &[req("path", Field::Text)]),
+    (
+        "for",
+        &[
+            req("var", Field::Text),
+            req("iterable", Field::Node),
+            req("body", Field::Nodes),
+        ],
+    ),
+    (
+        "while",
+        &[req("condition", Field::Node), req("body", Field::Nodes)],
+    ),
+    (
+        "let",
+        &[req("bindings", Field::Bindings), req("body", Field::Nodes)],
+    ),
+    ("set", &[req("var", Field::Text), req("value", Field::Node)]),
+    (
+        "if",
+        &[
+            req("condition", Field::Node),
+            req("then", Field::Node),
+            req("else", Field::Node),
+        ],
+    ),
+    (
+        "call",
+        &[req("function", Field::Node), req("args", Field::Nodes)],
+    ),
+    (
+        "array",
+        &[
+            req("element_type", Field::Type),
+            req("size", Field::Integer),
+        ],
+    ),
+    (
+        "array-init",
+        &[
+            req("element_type", Field::Type),
+            req("elements", Field::Nodes),
+        ],
+    ),
+    (
+        "array-get",
+        &[req("array", Field::Node), req("index", Field::Node)],
+    ),
+    (
+        "array-set",
+        &[
+            req("array", Field::Node),
+            req("index", Field::Node),
+            req("value", Field::Node),
+        ],
+    ),
+    ("array-length", &[req("array", Field::Node)]),
+    (
+        "gpio-set",
+        &[req("device", Field::Node), req("value", Field::Node)],
+    ),
+    ("gpio-get", &[req("device", Field::Node)]),
+    (
+        "uart-send",
+        &[req("device", Field::Node), req("data", Field::Node)],
+    ),
+    ("uart-recv", &[req("device", Field::Node)]),
+    ("sensor-read", &[req("device", Field::Node)]),
+    (
+        "network-send",
+        &[req("device", Field::Node), req("data", Field::Node)],
+    ),
+    ("network-recv", &[req("device", Field::Node)]),
+    ("sleep-ms", &[req("duration", Field::Node)]),
+    ("timestamp", &[]),
+    ("resource-budget", &[req("limits", Field::Limits)]),
+    (
+        "defcap",
+        &[
+            req("name", Field::Text),
+            req("params", Field::Params),
+            opt("resource", Field::Resource),
+            req("description", Field::Text),
+        ],
+    ),
+    (
+        "program",
+        &[
+            req("name", Field::Text),
+            req("budget", Field::Node),
+            req("forms", Field::Nodes),
+        ],
+    ),
+];
+
+const SIMPLE_TYPES: &[Type] = &[
+    Type::Int8,
+    Type::Int16,
+    Type::Int32,
+    Type::Int64,
+    Type::Uint8,
+    Type::Uint16,
+    Type::Uint32,
+    Type::Uint64,
+    Type::Float32,
+    Type::Float64,
+    Type::Bool,
+    Type::String,
+    Type::Void,
+];
+
+const RESOURCE_TYPES: &[ResourceType] = &[
+    ResourceType::UartTx,
+    ResourceType::UartRx,
+    ResourceType::Gpio,
+    ResourceType::I2c,
+    ResourceType::Spi,
+    ResourceType::SensorRead,
+    ResourceType::NetworkSend,
+    ResourceType::NetworkRecv,
+];
+
+const RESOURCE_KINDS: &[ResourceKind] = &[
+    ResourceKind::TimeMs,
+    ResourceKind::MemoryBytes,
+    ResourceKind::NetworkBytes,
+    ResourceKind::StorageBytes,
+];
+
+const RADIXES: &[(Radix, &str)] = &[
+    (Radix::Decimal, "decimal"),
+    (Radix::Hex, "hex"),
+    (Radix::Binary, "binary"),
+];
+
+/// The current-version document for `exprs`
+pub fn ast_to_json(exprs: &[Expr]) -> Value {
+    json!({
+        "format": AST_FORMAT,
+        "version": AST_FORMAT_VERSION,
+        "forms": exprs.iter().map(write_node).collect::<Vec<_>>(),
+    })
+}
+
+/// Read a document of any version
+pub fn ast_from_json(text: &str) -> Result<Vec<Expr>> {
+    ast_from_value(serde_json::from_str(text)?)
+}
+
+pub fn ast_from_value(value: Value) -> Result<Vec<Expr>> {
+    let document = match value {
+        Value::Array(_) => return Ok(serde_json::from_value(value)?),
+        Value::Object(document) => document,
+        _ => return Err(AstJsonError::NotAnAst),
+    };
+    if document.get("format").and_then(Value::as_str) != Some(AST_FORMAT) {
+        return Err(AstJsonError::NotAnAst);
+    }
+
+    let reader = Reader {
+        object: &document,
+        path: String::new(),
+    };
+    match reader.uint("version")? {
+        // Versions 2 and 3 only added fields, which older documents lack
+        1..=3 => reader.nodes("forms"),
+        found => Err(AstJsonError::UnsupportedVersion { found }),
+    }
+}
+
+fn write_node(expr: &Expr) -> Value {
+    let node = |expr: &Expr| write_node(expr);
+    let nodes = |exprs: &[Expr]| Value::Array(exprs.iter().map(write_node).collect());
+
+    let fields: Vec<(&str, Value)> = match &expr.kind {
+        ExprKind::Int(n) => {
+            let mut fields = vec![("value", json!(n.value))];
+            if n.radix != Radix::Decimal {
+                fields.push(("radix", json!(radix_name(n.radix))));
+            }
+            if let Some(suffix) = &n.suffix {
+                fields.push(("suffix", write_type(suffix)));
+            }
+            fields
+        }
+        ExprKind::Float(f) => vec![("value", json!(f))],
+        ExprKind::Bool(b) => vec![("value", json!(b))],
+        ExprKind::String(s) => vec![("value", json!(s))],
+        ExprKind::Ident(name) => vec![("name", json!(name))],
+        ExprKind::DefunDeploy {
+            name,
+            params,
+            return_type,
+            body,
+        }
+        | ExprKind::DefunCompile {
+            name,
+            params,
+            return_type,
+            body,
+        } => {
+            let mut fields = vec![("name", json!(name)), ("params", write_params(params))];
+            if let Some(ty) = return_type {
+                fields.push(("return_type", write_type(ty)));
+            }
+            fields.push(("body", nodes(body)));
+            fields
+        }
+        ExprKind::Macro { name, params, body } => vec![
+            ("name", json!(name)),
+            ("params", write_params(params)),
+            ("body", nodes(body)),
+        ],
+        ExprKind::BoundedFor {
+            var,
+            start,
+            end,
+            body,
+        } => vec![
+            ("var", json!(var)),
+            ("start", node(start)),
+            ("end", node(end)),
+            ("body", nodes(body)),
+        ],
+        ExprKind::WithCapability { capability, body } => {
+            vec![("capability", node(capability)), ("body", nodes(body))]
+        }
+        ExprKind::EvalCompile(inner) => vec![("expr", node(inner))],
+        ExprKind::Include(path) => vec![("path", json!(path))],
+        ExprKind::For {
+            var,
+            iterable,
+            body,
+        } => vec![
+            ("var", json!(var)),
+            ("iterable", node(iterable)),
+            ("body", nodes(body)),
+        ],
+        ExprKind::While { condition, body } => {
+            vec![("condition", node(condition)), ("body", nodes(body))]
+        }
+        ExprKind::Let { bindings, body } => {
+            let bindings = bindings
+                .iter()
+                .map(|(name, value)| json!({"name": name, "value": node(value)}))
+                .collect();
+            vec![("bindings", Value::Array(bindings)), ("body", nodes(body))]
+        }
+        ExprKind::Set { var, value } => vec![("var", json!(var)), ("value", node(value))],
+        ExprKind::If {
+            condition,
+            then_branch,
+            else_branch,
+        } => vec![
+            ("condition", node(condition)),
+            ("then", node(then_branch)),
+            ("else", node(else_branch)),
+        ],
+        ExprKind::FunctionCall { func, args } => {
+            vec![("function", node(func)), ("args", nodes(args))]
+        }
+        ExprKind::ArrayLiteral { elem_type, size } => {
+            vec![
+                ("element_type", write_type(elem_type)),
+                ("size", json!(size)),
+            ]
+        }
+        ExprKind::ArrayInit {
+            elem_type,
+            elements,
+        } => vec![
+            ("element_type", write_type(elem_type)),
+            ("elements", nodes(elements)),
+        ],
+        ExprKind::ArrayGet { array, index } => {
+            vec![("array", node(array)), ("index", node(index))]
+        }
+        ExprKind::ArraySet {
+            array,
+            index,
+            value,
+        } => vec![
+            ("array", node(array)),
+            ("index", node(index)),
+            ("value", node(value)),
+        ],
+        ExprKind::ArrayLength(array) => vec![("array", node(array))],
+        ExprKind::GpioSet { device, value } => {
+            vec![("device", node(device)), ("value", node(value))]
+        }
+        ExprKind::UartSend { device, data } | ExprKind::NetworkSend { device, data } => {
+            vec![("device", node(device)), ("data", node(data))]
+        }
+        ExprKind::GpioGet(device)
+        | ExprKind::UartRecv(device)
+        | ExprKind::SensorRead(device)
+        | ExprKind::NetworkRecv(device) => vec![("device", node(device))],
+        ExprKind::SleepMs(duration) => vec![("duration", node(duration))],
+        ExprKind::Timestamp => vec![],
+        ExprKind::ResourceBudget { specs } => {
+            let limits = specs
+                .iter()
+                .map(|spec| json!({"resource": spec.kind.to_string(), "amount": spec.amount}))
+                .collect();
+            vec![("limits", Value::Array(limits))]
+        }
+        ExprKind::DefCap {
+            name,
+            params,
+            resource,
+            description,
+        } => {
+            let mut fields = vec![("name", json!(name)), ("params", write_params(params))];
+            if let Some(resource) = resource {
+                fields.push(("resource", json!(resource.to_string())));
+            }
+            fields.push(("description", json!(description)));
+            fields
+        }
+        ExprKind::Program {
+            name,
+            budget,
+            forms,
+        } => vec![
+            ("name", json!(name)),
+            ("budget", node(budget)),
+            ("forms", nodes(forms)),
+        ],
+    };
+
+    let mut object = Map::new();
+    object.insert("kind".to_string(), json!(kind_name(expr)));
+    for (name, value) in fields {
+        object.insert(name.to_string(), value);
+    }
+    if !expr.span.is_dummy() {
+        object.insert("span".to_string(), write_span(expr.span));
+    }
+    if let Some(ty) = &expr.ty {
+        object.insert("type".to_string(), write_type(ty));
+    }
+    Value::Object(object)
+}
+
+fn kind_name(expr: &Expr) -> &'static str {
+    expr.keyword().unwrap_or(match expr.kind {
+        ExprKind::Int(_) => "int",
+        ExprKind::Float(_) => "float",
+        ExprKind::Bool(_) => "bool",
+        ExprKind::String(_) => "string",
+        ExprKind::Ident(_) => "ident",
+        _ => "call",
+    })
+}
+
+fn radix_name(radix: Radix) -> &'static str {
+    RADIXES.iter().find(|(r, _)| *r == radix).unwrap().1
+}
+
+fn write_span(span: Span) -> Value {
+    let mut object = json!({
+        "start": span.start,
+        "end": span.end,
+        "line": span.line,
+        "column": span.column,
+        "file": span.file.0,
+    });
+    if let Some(expansion) = span.expansion {
+        object["expansion"] = json!(expansion.0);
+    }
+    object
+}
+
+fn write_type(ty: &Type) -> Value {
+    match ty {
+        Type::Array { elem_type, size } => {
+            json!({"kind": "array", "element": write_type(elem_type), "size": size})
+        }
+        Type::Capability { resource } => {
+            json!({"kind": "capability", "resource": resource.to_string()})
+        }
+        Type::Function {
+            params,
+            return_type,
+        } => json!({
+            "kind": "function",
+            "params": params.iter().map(write_type).collect::<Vec<_>>(),
+            "return": write_type(return_type),
+        }),
+        simple => json!(simple.to_string()),
+    }
+}
+
+fn write_params(params: &[Parameter]) -> Value {
+    let params = params.iter().map(|param| {
+        let mut object = json!({ "name": param.name });
+        if let Some(ty) = &param.type_annotation {
+            object["type"] = write_type(ty);
+        }
+        if let Some(ty) = &param.inferred_type {
+            object["inferred_type"] = write_type(ty);
+        }
+        if let Some(budget) = param.budget {
+            object["budget"] = json!(budget);
+        }
+        if !param.span.is_dummy() {
+            object["span"] = write_span(param.span);
+        }
+        object
+    });
+    Value::Array(params.collect())
+}
+
+/// An object being read, and its JSON Pointer for error messages
+struct Reader<'a> {
+    object: &'a Map<String, Value>,
+    path: String,
+}
+
+impl<'a> Reader<'a> {
+    fn new(value: &'a Value, path: String) -> Result<Self> {
+        match value {
+            Value::Object(object) => Ok(Self { object, path }),
+            _ => Err(AstJsonError::Invalid {
+                path,
+                message: "expected an object".to_string(),
+            }),
+        }
+    }
+
+    fn path(&self, key: &str) -> String {
+        format!("{}/{}", self.path, key)
+    }
+
+    fn invalid(&self, key: &str, message: impl Into<String>) -> AstJsonError {
+        AstJsonError::Invalid {
+            path: self.path(key),
+            message: message.into(),
+        }
+    }
+
+    fn get(&self, key: &str) -> Result<&'a Value> {
+        self.object
+            .get(key)
+            .ok_or_else(|| self.invalid(key, "missing field"))
+    }
+
+    fn text(&self, key: &str) -> Result<String> {
+        match self.get(key)? {
+            Value::String(s) => Ok(s.clone()),
+            _ => Err(self.invalid(key, "expected a string")),
+        }
+    }
+
+    fn uint(&self, key: &str) -> Result<u64> {
+        self.get(key)?
+            .as_u64()
+            .ok_or_else(|| self.invalid(key, "expected a non-negative integer"))
+    }
+
+    fn size(&self, key: &str) -> Result<usize> {
+        usize::try_from(self.uint(key)?).map_err(|_| self.invalid(key, "size too large"))
+    }
+
+    fn array(&self, key: &str) -> Result<&'a Vec<Value>> {
+        match self.get(key)? {
+            Value::Array(items) => Ok(items),
+            _ => Err(self.invalid(key, "expected an array")),
+        }
+    }
+
+    fn node(&self, key: &str) -> Result<Box<Expr>> {
+        read_node(self.get(key)?, self.path(key)).map(Box::new)
+    }
+
+    fn nodes(&self, key: &str) -> Result<Vec<Expr>> {
+        let items = self.array(key)?;
+        let items = items.iter().enumerate();
+        items
+            .map(|(i, item)| read_node(item, format!("{}/{}", self.path(key), i)))
+            .collect()
+    }
+
+    fn objects(&self, key: &str) -> Result<Vec<Reader<'a>>> {
+        let items = self.array(key)?.iter().enumerate();
+        items
+            .map(|(i, item)| Reader::new(item, format!("{}/{}", self.path(key), i)))
+            .collect()
+    }
+
+    fn ty(&self, key: &str) -> Result<Type> {
+        read_type(self.get(key)?, self.path(key))
+    }
+
+    fn optional_ty(&self, key: &str) -> Result<Option<Type>> {
+        match self.object.get(key) {
+            None | Some(Value::Null) => Ok(None),
+            Some(value) => read_type(value, self.path(key)).map(Some),
+        }
+    }
+
+    fn resource(&self, key: &str) -> Result<ResourceType> {
+        let name = self.text(key)?;
+        RESOURCE_TYPES
+            .iter()
+            .find(|resource| resource.to_string() == name)
+            .cloned()
+            .ok_or_else(|| self.invalid(key, format!("unknown resource `{}`", name)))
+    }
+
+    fn optional_resource(&self, key: &str) -> Result<Option<ResourceType>> {
+        match self.object.get(key) {
+            None | Some(Value::Null) => Ok(None),
+            Some(_) => self.resource(key).map(Some),
+        }
+    }
+
+    fn params(&self, key: &str) -> Result<Vec<Parameter>> {
+        self.objects(key)?
+            .iter()
+            .map(|param| {
+                let mut parameter = Parameter::new(param.text("name")?, param.optional_ty("type")?);
+                parameter.inferred_type = param.optional_ty("inferred_type")?;
+                parameter.budget = match param.object.get("budget") {
+                    None | Some(Value::Null) => None,
+                    Some(_) => Some(param.uint("budget")?),
+                };
+                parameter.span = param.span()?;
+                Ok(parameter)
+            })
+            .collect()
+    }
+
+    fn span(&self) -> Result<Span> {
+        let Some(value) = self.object.get("span") else {
+            return Ok(Span::dummy());
+        };
+        let span = Reader::new(value, self.path("span"))?;
+        let field = |key| Ok::<_, AstJsonError>(span.uint(key)? as usize);
+
+        let mut result = Span::new(
+            field("start")?,
+            field("end")?,
+            field("line")?,
+            field("column")?,
+        );
+        if span.object.contains_key("file") {
+            result.file = FileId(field("file")? as u32);
+        }
+        if span.object.contains_key("expansion") {
+            result.expansion = Some(ExpansionId(field("expansion")? as u32));
+        }
+        Ok(result)
+    }
+}
+
+fn read_node(value: &Value, path: String) -> Result<Expr> {
+    let node = Reader::new(value, path)?;
+
+    let kind = match node.text("kind")?.as_str() {
+        "int" => {
+            let value = node.get("value")?;
+            let value = match (value.as_i64(), value.as_u64()) {
+                (Some(n), _) => i128::from(n),
+                (_, Some(n)) => i128::from(n),
+                _ => return Err(node.invalid("value", "expected an integer")),
+            };
+            let radix = match node.object.get("radix") {
+                None => Radix::Decimal,
+                Some(name) => {
+                    RADIXES
+                        .iter()
+                        .find(|(_, n)| Some(*n) == name.as_str())
+                        .ok_or_else(|| node.invalid("radix", "expected decimal, hex or binary"))?
+                        .0
+                }
+            };
+            ExprKind::Int(IntLiteral {
+                value,
+                radix,
+                suffix: node.optional_ty("suffix")?,
+            })
+        }
+        "float" => ExprKind::Float(
+            node.get("value")?
+                .as_f64()
+                .ok_or_else(|| node.invalid("value", "expected a number"))?,
+        ),
+        "bool" => ExprKind::Bool(
+            node.get("value")?
+                .as_bool()
+                .ok_or_else(|| node.invalid("value", "expected a boolean"))?,
+        ),
+        "string" => ExprKind::String(node.text("value")?),
+        "ident" => ExprKind::Ident(node.text("name")?),
+        "defun-deploy" => ExprKind::DefunDeploy {
+            name: node.text("name")?,
+            params: node.params("params")?,
+            return_type: node.optional_ty("return_type")?,
+            body: node.nodes("body")?,
+        },
+        "defun-compile" => ExprKind::DefunCompile {
+            name: node.text("name")?,
+            params: node.params("params")?,
+            return_type: node.optional_ty("return_type")?,
+            body: node.nodes("body")?,
+        },
+        "macro" => ExprKind::Macro {
+            name: node.text("name")?,
+            params: node.params("params")?,
+            body: node.nodes("body")?,
+        },
+        "bounded-for" => ExprKind::BoundedFor {
+            var: node.text("var")?,
+            start: node.node("start")?,
+            end: node.node("end")?,
+            body: node.nodes("body")?,
+        },
+        "with-capability" => ExprKind::WithCapability {
+            capability: node.node("capability")?,
+            body: node.nodes("body")?,
+        },
+        "eval-compile" => ExprKind::EvalCompile(node.node("expr")?),
+        "include" => ExprKind::Include(node.text("path")?),
+        "for" => ExprKind::For {
+            var: node.text("var")?,
+            iterable: node.node("iterable")?,
+            body: node.nodes("body")?,
+        },
+        "while" => ExprKind::While {
+            condition: node.node("condition")?,
+            body: node.nodes("body")?,
+        },
+        "let" => ExprKind::Let {
+            bindings: node
+                .objects("bindings")?
+                .iter()
+                .map(|binding| Ok((binding.text("name")?, *binding.node("value")?)))
+                .collect::<Result<_>>()?,
+            body: node.nodes("body")?,
+        },
+        "set" => ExprKind::Set {
+            var: node.text("var")?,
+            value: node.node("value")?,
+        },
+        "if" => ExprKind::If {
+            condition: node.node("condition")?,
+            then_branch: node.node("then")?,
+            else_branch: node.node("else")?,
+        },
+        "call" => ExprKind::FunctionCall {
+            func: node.node("function")?,
+            args: node.nodes("args")?,
+        },
+        "array" => ExprKind::ArrayLiteral {
+            elem_type: node.ty("element_type")?,
+            size: node.size("size")?,
+        },
+        "array-init" => ExprKind::ArrayInit {
+            elem_type: node.ty("element_type")?,
+            elements: node.nodes("elements")?,
+        },
+        "array-get" => ExprKind::ArrayGet {
+            array: node.node("array")?,
+            index: node.node("index")?,
+        },
+        "array-set" => ExprKind::ArraySet {
+            array: node.node("array")?,
+            index: node.node("index")?,
+            value: node.node("value")?,
+        },
+        "array-length" => ExprKind::ArrayLength(node.node("array")?),
+        "gpio-set" => ExprKind::GpioSet {
+            device: node.node("device")?,
+            value: node.node("value")?,
+        },
+        "gpio-get" => ExprKind::GpioGet(node.node("device")?),
+        "uart-send" => ExprKind::UartSend {
+            device: node.node("device")?,
+            data: node.node("data")?,
+        },
+        "uart-recv" => ExprKind::UartRecv(node.node("device")?),
+        "sensor-read" => ExprKind::SensorRead(node.node("device")?),
+        "network-send" => ExprKind::NetworkSend {
+            device: node.node("device")?,
+            data: node.node("data")?,
+        },
+        "network-recv" => ExprKind::NetworkRecv(node.node("device")?),
+        "sleep-ms" => ExprKind::SleepMs(node.node("duration")?),
+        "timestamp" => ExprKind::Timestamp,
+        "resource-budget" => ExprKind::ResourceBudget {
+            specs: node
+                .objects("limits")?
+                .iter()
+                .map(|limit| {
+                    let name = limit.text("resource")?;
+                    let kind = RESOURCE_KINDS
+                        .iter()
+                        .find(|kind| kind.to_string() == name)
+                        .ok_or_else(|| {
+                            limit.invalid("resource", format!("unknown resource `{}`", name))
+                        })?;
+                    Ok(ResourceSpec::new(kind.clone(), limit.uint("amount")?))
+                })
+                .collect::<Result<_>>()?,
+        },
+        "defcap" => ExprKind::DefCap {
+            name: node.text("name")?,
+            params: node.params("params")?,
+            resource: node.optional_resource("resource")?,
+            description: node.text("description")?,
+        },
+        "program" => ExprKind::Program {
+            name: node.text("name")?,
+            budget: node.node("budget")?,
+            forms: node.nodes("forms")?,
+        },
+        other => return Err(node.invalid("kind", format!("unknown node kind `{}`", other))),
+    };
+
+    Ok(Expr {
+        kind,
+        span: node.span()?,
+        ty: node.optional_ty("type")?,
+    })
+}
+
+fn read_type(value: &Value, path: String) -> Result<Type> {
+    if let Value::String(name) = value {
+        return SIMPLE_TYPES
+            .iter()
+            .find(|ty| ty.to_string() == *name)
+            .cloned()
+            .ok_or(AstJsonError::Invalid {
+                path,
+                message: format!("unknown type `{}`", name),
+            });
+    }
+
+    let ty = Reader::new(value, path)?;
+    match ty.text("kind")?.as_str() {
+        "array" => Ok(Type::Array {
+            elem_type: Box::new(ty.ty("element")?),
+            size: ty.size("size")?,
+        }),
+        "capability" => Ok(Type::Capability {
+            resource: ty.resource("resource")?,
+        }),
+        "function" => Ok(Type::Function {
+            params: ty
+                .array("params")?
+                .iter()
+                .enumerate()
+                .map(|(i, param)| read_type(param, format!("{}/{}", ty.path("params"), i)))
+                .collect::<Result<_>>()?,
+            return_type: Box::new(ty.ty("return")?),
+        }),
+        other => Err(ty.invalid("kind", format!("unknown type kind `{}`", other))),
+    }
+}
+
+/// JSON Schema (draft 2020-12) of the current version
+pub fn ast_json_schema() -> Value {
+    let node_refs: Vec<Value> = NODE_KINDS
+        .iter()
+        .map(|(kind, _)| json!({ "$ref": format!("#/$defs/{}", kind) }))
+        .collect();
+
+    let mut defs = Map::new();
+    for (kind, fields) in NODE_KINDS {
+        let mut properties = Map::new();
+        properties.insert("kind".to_string(), json!({ "const": kind }));
+        let mut required = vec![json!("kind")];
+        for spec in *fields {
+            properties.insert(spec.name.to_string(), field_schema(spec.field));
+            if spec.required {
+                required.push(json!(spec.name));
+            }
+        }
+        properties.insert("span".to_string(), json!({ "$ref": "#/$defs/span" }));
+        properties.insert("type".to_string(), json!({ "$ref": "#/$defs/type" }));
+
+        defs.insert(
+            kind.to_string(),
+            json!({
+                "type": "object",
+                "properties": properties,
+                "required": required,
+                "additionalProperties": false,
+            }),
+        );
+    }
+
+    let names = |names: Vec<String>| json!({ "enum": names });
+    defs.insert("node".to_string(), json!({ "oneOf": node_refs }));
+    defs.insert(
+        "span".to_string(),
+        json!({
+            "type": "object",
+            "properties": {
+                "start": { "type": "integer", "minimum": 0 },
+                "end": { "type": "integer", "minimum": 0 },
+                "line": { "type": "integer", "minimum": 1 },
+                "column": { "type": "integer", "minimum": 1 },
+                "file": { "type": "integer", "minimum": 0 },
+                "expansion": { "type": "integer", "minimum": 0 },
+            },
+            "required": ["start", "end", "line", "column"],
+            "additionalProperties": false,
+        }),
+    );
+    defs.insert(
+        "type".to_string(),
+        json!({
+            "oneOf": [
+                names(SIMPLE_TYPES.iter().map(Type::to_string).collect()),
+                {
+                    "type": "object",
+                    "properties": {
+                        "kind": { "const": "array" },
+                        "element": { "$ref": "#/$defs/type" },
+                        "size": { "type": "integer", "minimum": 0 },
+                    },
+                    "required": ["kind", "element", "size"],
+                    "additionalProperties": false,
+                },
+                {
+                    "type": "object",
+                    "properties": {
+                        "kind": { "const": "capability" },
+                        "resource": names(RESOURCE_TYPES.iter().map(|r| r.to_string()).collect()),
+                    },
+                    "required": ["kind", "resource"],
+                    "additionalProperties": false,
+                },
+                {
+                    "type": "object",
+                    "properties": {
+                        "kind": { "const": "function" },
+                        "params": { "type": "array", "items": { "$ref": "#/$defs/type" } },
+                        "return": { "$ref": "#/$defs/type" },
+                    },
+                    "required": ["kind", "params", "return"],
+                    "additionalProperties": false,
+                },
+            ],
+        }),
+    );
+    defs.insert(
+        "param".to_string(),
+        json!({
+            "type": "object",
+            "properties": {
+                "name": { "type": "string" },
+                "type": { "$ref": "#/$defs/type" },
+                "inferred_type": { "$ref": "#/$defs/type" },
+                "budget": { "type": "integer", "minimum": 0 },
+                "span": { "$ref": "#/$defs/span" },
+            },
+            "required": ["name"],
+            "additionalProperties": false,
+        }),
+    );
+    defs.insert(
+        "binding".to_string(),
+        json!({
+            "type": "object",
+            "properties": {
+                "name": { "type": "string" },
+                "value": { "$ref": "#/$defs/node" },
+            },
+            "required": ["name", "value"],
+            "additionalProperties": false,
+        }),
+    );
+    defs.insert(
+        "limit".to_string(),
+        json!({
+            "type": "object",
+            "properties": {
+                "resource": names(RESOURCE_KINDS.iter().map(|k| k.to_string()).collect()),
+                "amount": { "type": "integer", "minimum": 0 },
+            },
+            "required": ["resource", "amount"],
+            "additionalProperties": false,
+        }),
+    );
+
+    json!({
+        "$schema": "https://json-schema.org/draft/2020-12/schema",
+        "title": "Oblibeny AST",
+        "type": "object",
+        "properties": {
+            "format": { "const": AST_FORMAT },
+            "version": { "const": AST_FORMAT_VERSION },
+            "forms": { "type": "array", "items": { "$ref": "#/$defs/node" } },
+        },
+        "required": ["format", "version", "forms"],
+        "$defs": defs,
+    })
+}
+
+fn field_schema(field: Field) -> Value {
+    let array = |item: &str| json!({ "type": "array", "items": { "$ref": item } });
+    match field {
+        Field::Node => json!({ "$ref": "#/$defs/node" }),
+        Field::Nodes => array("#/$defs/node"),
+        Field::Text => json!({ "type": "string" }),
+        Field::Integer => json!({ "type": "integer" }),
+        Field::Number => json!({ "type": "number" }),
+        Field::Boolean => json!({ "type": "boolean" }),
+        Field::Radix => json!({ "enum": RADIXES.iter().map(|(_, n)| *n).collect::<Vec<_>>() }),
+        Field::Type => json!({ "$ref": "#/$defs/type" }),
+        Field::Resource => {
+            json!({ "enum": RESOURCE_TYPES.iter().map(|r| r.to_string()).collect::<Vec<_>>() })
+        }
+        Field::Params => array("#/$defs/param"),
+        Field::Bindings => array("#/$defs/binding"),
+        Field::Limits => array("#/$defs/limit"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::arb_expr;
+    use crate::parse_file;
+    use proptest::prelude::*;
+
+    const MONITOR: &str = include_str!("../../../examples/temperature-monitor.obl");
+
+    /// Whether `node` and every node under it has exactly the fields the
+    /// table lists for its kind
+    fn check_fields(node: &Value) -> std::result::Result<(), String> {
+        let object = node.as_object().ok_or("node is not an object")?;
+        let kind = object["kind"].as_str().ok_or("kind is not a string")?;
+        let (_, fields) = NODE_KINDS
+            .iter()
+            .find(|(k, _)| *k == kind)
+            .ok_or(format!("kind `{}` is not in the table", kind))?;
+
+        for spec in *fields {
+            if spec.required && !object.contains_key(spec.name) {
+                return Err(format!("{} lacks `{}`", kind, spec.name));
+            }
+        }
+        for (key, value) in object {
+            if key == "kind" || key == "span" || key == "type" {
+                continue;
+            }
+            let spec = fields
+                .iter()
+                .find(|spec| spec.name == key)
+                .ok_or(format!("{} has unlisted field `{}`", kind, key))?;
+            match spec.field {
+                Field::Node => check_fields(value)?,
+                Field::Nodes => value
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .try_for_each(check_fields)?,
+                Field::Bindings => {
+                    for binding in value.as_array().unwrap() {
+                        check_fields(&binding["value"])?;
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_document_round_trips() {
+        let exprs = parse_file(MONITOR).unwrap();
+        let document = ast_to_json(&exprs);
+        assert_eq!(document["format"], "oblibeny-ast");
+        assert_eq!(document["version"], AST_FORMAT_VERSION);
+        assert_eq!(document["forms"][0]["kind"], "program");
+        assert_eq!(document["forms"][0]["span"]["line"], exprs[0].span.line);
+
+        let back = ast_from_json(&document.to_string()).unwrap();
+        assert_eq!(back, exprs);
+        assert_eq!(back[0].span, exprs[0].span);
+    }
+
+    proptest! {
+        #[test]
+        fn test_writer_matches_schema_table(expr in arb_expr()) {
+            let document = ast_to_json(std::slice::from_ref(&expr));
+            prop_assert_eq!(check_fields(&document["forms"][0]), Ok(()));
+            prop_assert_eq!(ast_from_value(document).unwrap(), vec![expr]);
+        }
+    }
+
+    #[test]
+    fn test_reads_version_zero() {
+        // Before spans, nodes were the bare enum and integers plain numbers
+        let legacy = r#"[{"DefunDeploy": {
+            "name": "f",
+            "params": [{"name": "x", "type_annotation": "Int32"}],
+            "return_type": null,
+            "body": [{"FunctionCall": {
+                "func": {"Ident": "+"},
+                "args": [{"Ident": "x"}, {"Int": 42}]
+            }}, "Timestamp"]
+        }}]"#;
+        let exprs = ast_from_json(legacy).unwrap();
+        assert_eq!(
+            exprs,
+            parse_file("(defun-deploy f ((x int32)) (+ x 42) (timestamp))").unwrap()
+        );
+
+        // The derived encoding with spans that followed
+        let exprs = parse_file(MONITOR).unwrap();
+        let derived = serde_json::to_string(&exprs).unwrap();
+        assert_eq!(ast_from_json(&derived).unwrap(), exprs);
+    }
+
+    #[test]
+    fn test_golden_documents() {
+        let gpio = Type::Capability {
+            resource: ResourceType::Gpio,
+        };
+        let mut led = Parameter::new("led".to_string(), Some(gpio.clone()));
+        led.budget = Some(3);
+        let exprs: Vec<Expr> = vec![
+            ExprKind::DefCap {
+                name: "led".to_string(),
+                params: vec![],
+                resource: Some(ResourceType::Gpio),
+                description: "d".to_string(),
+            }
+            .into(),
+            ExprKind::DefunDeploy {
+                name: "f".to_string(),
+                params: vec![led],
+                return_type: None,
+                body: vec![ExprKind::Ident("led".to_string()).into()],
+            }
+            .into(),
+        ];
+
+        let v3 = json!({
+            "format": "oblibeny-ast",
+            "version": 3,
+            "forms": [
+                {
+                    "kind": "defcap",
+                    "name": "led",
+                    "params": [],
+                    "resource": "gpio",
+                    "description": "d",
+                },
+                {
+                    "kind": "defun-deploy",
+                    "name": "f",
+                    "params": [{
+                        "name": "led",
+                        "type": {"kind": "capability", "resource": "gpio"},
+                        "budget": 3,
+                    }],
+                    "body": [{"kind": "ident", "name": "led"}],
+                },
+            ],
+        });
+        assert_eq!(ast_to_json(&exprs), v3);
+        assert_eq!(ast_from_value(v3.clone()).unwrap(), exprs);
+
+        // Version 2 defcaps have no resource
+        let mut v2 = v3;
+        v2["version"] = json!(2);
+        v2["forms"][0].as_object_mut().unwrap().remove("resource");
+        let decoded = ast_from_value(v2.clone()).unwrap();
+        assert!(matches!(
+            decoded[0].kind,
+            ExprKind::DefCap { resource: None, .. }
+        ));
+        assert_eq!(decoded[1], exprs[1]);
+
+        // Version 1 parameters have no budget either
+        let mut v1 = v2;
+        v1["version"] = json!(1);
+        v1["forms"][1]["params"][0]
+            .as_object_mut()
+            .unwrap()
+            .remove("budget");
+        let ExprKind::DefunDeploy { params, .. } = &ast_from_value(v1).unwrap()[1].kind else {
+            panic!("expected defun-deploy");
+        };
+        assert_eq!(params[0].budget, None);
+        assert_eq!(params[0].type_annotation, Some(gpio));
+    }
+
+    #[test]
+    fn test_rejects_unknown_versions_and_nodes() {
+        let newer = json!({"format": "oblibeny-ast", "version": 4, "forms": []});
+        assert!(matches!(
+            ast_from_value(newer),
+            Err(AstJsonError::UnsupportedVersion { found: 4 })
+        ));
+        assert!(matches!(
+            ast_from_value(json!({"forms": []})),
+            Err(AstJsonError::NotAnAst)
+        ));
+
+        let bad = json!({
+            "format": "oblibeny-ast",
+            "version": 1,
+            "forms": [{"kind": "call", "function": {"kind": "ident", "name": "f"},
+                       "args": [{"kind": "int", "value": "1"}]}],
+        });
+        let err = ast_from_value(bad).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Invalid AST at /forms/0/args/0/value: expected an integer"
+        );
+
+        let schema = ast_json_schema();
+        assert_eq!(
+            schema["$defs"]["node"]["oneOf"].as_array().unwrap().len(),
+            35
+        );
+        assert_eq!(
+            schema["$defs"]["if"]["required"],
+            json!(["kind", "condition", "then", "else"])
+        );
+    }
+}
@@ -0,0 +1,326 @@
+use super::types::Type;
+use serde::{de, Deserialize, Deserializer, Serialize};
+use std::fmt;
+
+/// Base an integer literal was written in, kept so printing round-trips
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Radix {
+    Binary,
+    #[default]
+    Decimal,
+    Hex,
+}
+
+/// An integer literal such as `42`, `0xFF`, `0b1010` or `255u8`
+///
+/// `value` is wide enough for every `int64` and `uint64` value.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(from = "IntLiteralRepr")]
+pub struct IntLiteral {
+    pub value: i128,
+    pub radix: Radix,
+    /// Type named by a suffix like `u8`
+    pub suffix: Option<Type>,
+}
+
+/// Shapes an `IntLiteral` is deserialized from: the literal, or the plain
+/// number written before literals kept their radix and suffix
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum IntLiteralRepr {
+    Literal {
+        #[serde(deserialize_with = "any_integer")]
+        value: i128,
+        #[serde(default)]
+        radix: Radix,
+        #[serde(default)]
+        suffix: Option<Type>,
+    },
+    Value(#[serde(deserialize_with = "any_integer")] i128),
+}
+
+/// Read an `i128` from whatever integer the format has; untagged enums
+/// buffer their input in a form that cannot hold 128-bit integers
+fn any_integer<'de, D: Deserializer<'de>>(deserializer: D) -> Result<i128, D::Error> {
+    struct IntegerVisitor;
+
+    impl de::Visitor<'_> for IntegerVisitor {
+        type Value = i128;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "an integer")
+        }
+
+        fn visit_i64<E>(self, value: i64) -> Result<i128, E> {
+            Ok(value.into())
+        }
+
+        fn visit_u64<E>(self, value: u64) -> Result<i128, E> {
+            Ok(value.into())
+        }
+
+        fn visit_i128<E>(self, value: i128) -> Result<i128, E> {
+            Ok(value)
+        }
+    }
+
+    deserializer.deserialize_any(IntegerVisitor)
+}
+
+impl From<IntLiteralRepr> for IntLiteral {
+    fn from(repr: IntLiteralRepr) -> Self {
+        match repr {
+            IntLiteralRepr::Literal {
+                value,
+                radix,
+                suffix,
+            } => Self {
+                value,
+                radix,
+                suffix,
+            },
+            IntLiteralRepr::Value(value) => Self::new(value),
+        }
+    }
+}
+
+impl IntLiteral {
+    pub fn new(value: i128) -> Self {
+        Self {
+            value,
+            radix: Radix::Decimal,
+            suffix: None,
+        }
+    }
+
+    /// Parse a literal token as accepted by the grammar's `integer` rule.
+    /// Returns `None` if the value does not fit in 64 bits.
+    pub fn parse(text: &str) -> Option<Self> {
+        let (negative, text) = match text.strip_prefix('-') {
+            Some(rest) => (true, rest),
+            None => (false, text),
+        };
+
+        let (radix, text) = if let Some(rest) = text.strip_prefix("0x") {
+            (Radix::Hex, rest)
+        } else if let Some(rest) = text.strip_prefix("0b") {
+            (Radix::Binary, rest)
+        } else {
+            (Radix::Decimal, text)
+        };
+
+        let (digits, suffix) = match text.find(['i', 'u']) {
+            Some(i) => (&text[..i], Some(suffix_type(&text[i..])?)),
+            None => (text, None),
+        };
+
+        let digits: String = digits.chars().filter(|&c| c != '_').collect();
+        let magnitude = i128::from_str_radix(&digits, radix.base()).ok()?;
+        let value = if negative { -magnitude } else { magnitude };
+
+        if value < i64::MIN as i128 || value > u64::MAX as i128 {
+            return None;
+        }
+
+        Some(Self {
+            value,
+            radix,
+            suffix,
+        })
+    }
+
+    /// Whether the value is representable in `ty`; always true for
+    /// non-integer types
+    pub fn fits(&self, ty: &Type) -> bool {
+        match ty.int_range() {
+            Some((min, max)) => (min..=max).contains(&self.value),
+            None => true,
+        }
+    }
+}
+
+impl From<i64> for IntLiteral {
+    fn from(value: i64) -> Self {
+        Self::new(value as i128)
+    }
+}
+
+impl Radix {
+    pub fn base(self) -> u32 {
+        match self {
+            Radix::Binary => 2,
+            Radix::Decimal => 10,
+            Radix::Hex => 16,
+        }
+    }
+}
+
+fn suffix_type(suffix: &str) -> Option<Type> {
+    Some(match suffix {
+        "i8" => Type::Int8,
+        "i16" => Type::Int16,
+        "i32" => Type::Int32,
+        "i64" => Type::Int64,
+        "u8" => Type::Uint8,
+        "u16" => Type::Uint16,
+        "u32" => Type::Uint32,
+        "u64" => Type::Uint64,
+        _ => return None,
+    })
+}
+
+fn suffix_name(ty: &Type) -> &'static str {
+    match ty {
+        Type::Int8 => "i8",
+        Type::Int16 => "i16",
+        Type::Int32 => "i32",
+        Type::Int64 => "i64",
+        Type::Uint8 => "u8",
+        Type::Uint16 => "u16",
+        Type::Uint32 => "u32",
+        Type::Uint64 => "u64",
+        _ => "",
+    }
+}
+
+impl fmt::Display for IntLiteral {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.value < 0 {
+            write!(f, "-")?;
+        }
+        let magnitude = self.value.unsigned_abs();
+        match self.radix {
+            Radix::Binary => write!(f, "0b{:b}", magnitude)?,
+            Radix::Decimal => write!(f, "{}", magnitude)?,
+            Radix::Hex => write!(f, "0x{:X}", magnitude)?,
+        }
+        if let Some(ty) = &self.suffix {
+            write!(f, "{}", suffix_name(ty))?;
+        }
+        Ok(())
+    }
+}
+
+/// An escape sequence outside the set the language defines
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidEscape {
+    /// Byte offset of the backslash within the unquoted text
+    pub offset: usize,
+    /// The offending sequence, backslash included
+    pub sequence: String,
+}
+
+impl fmt::Display for InvalidEscape {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "unknown escape sequence `{}`", self.sequence)
+    }
+}
+
+/// Decode the escapes in the body of a string literal (quotes already
+/// removed). Only `\"`, `\\`, `\n`, `\r` and `\t` are accepted.
+pub fn unescape(raw: &str) -> Result<String, InvalidEscape> {
+    let mut out = String::with_capacity(raw.len());
+    let mut chars = raw.char_indices();
+
+    while let Some((offset, c)) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+
+        match chars.next() {
+            Some((_, '"')) => out.push('"'),
+            Some((_, '\\')) => out.push('\\'),
+            Some((_, 'n')) => out.push('\n'),
+            Some((_, 'r')) => out.push('\r'),
+            Some((_, 't')) => out.push('\t'),
+            other => {
+                let mut sequence = String::from('\\');
+                sequence.extend(other.map(|(_, c)| c));
+                return Err(InvalidEscape { offset, sequence });
+            }
+        }
+    }
+
+    Ok(out)
+}
+
+/// Inverse of `unescape`: the body of a string literal that reads back as `s`
+pub fn escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// `s` as a quoted, escaped string literal
+pub fn quote(s: &str) -> String {
+    format!("\"{}\"", escape(s))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_int_literals() {
+        let lit = IntLiteral::parse("0xFF_FFu16").unwrap();
+        assert_eq!(lit.value, 0xFFFF);
+        assert_eq!(lit.radix, Radix::Hex);
+        assert_eq!(lit.suffix, Some(Type::Uint16));
+
+        assert_eq!(IntLiteral::parse("-0b1000_0000i8").unwrap().value, -128);
+        assert_eq!(IntLiteral::parse("1_000_000").unwrap().value, 1_000_000);
+        assert_eq!(
+            IntLiteral::parse("0xFFFF_FFFF_FFFF_FFFFu64").unwrap().value,
+            u64::MAX as i128
+        );
+        assert_eq!(IntLiteral::parse("0x1_0000_0000_0000_0000"), None);
+    }
+
+    #[test]
+    fn test_int_literal_display_round_trips() {
+        for text in ["42", "-0x80i8", "0b1010", "0xFFu8", "255u8"] {
+            assert_eq!(IntLiteral::parse(text).unwrap().to_string(), text);
+        }
+    }
+
+    #[test]
+    fn test_int_literal_fits() {
+        assert!(IntLiteral::new(255).fits(&Type::Uint8));
+        assert!(!IntLiteral::new(300).fits(&Type::Uint8));
+        assert!(!IntLiteral::new(-1).fits(&Type::Uint32));
+        assert!(IntLiteral::new(-128).fits(&Type::Int8));
+        assert!(IntLiteral::new(300).fits(&Type::Float32));
+    }
+
+    #[test]
+    fn test_unescape_known_sequences() {
+        assert_eq!(
+            unescape(r#"say \"hi\"\n\tpath\\to\r"#).unwrap(),
+            "say \"hi\"\n\tpath\\to\r"
+        );
+    }
+
+    #[test]
+    fn test_unescape_rejects_unknown() {
+        let err = unescape(r"ok \x41").unwrap_err();
+        assert_eq!(err.offset, 3);
+        assert_eq!(err.sequence, r"\x");
+    }
+
+    #[test]
+    fn test_escape_round_trips() {
+        let s = "line\n\"quoted\" \\ tab\t";
+        assert_eq!(unescape(&escape(s)).unwrap(), s);
+        assert_eq!(quote("a\nb"), r#""a\nb""#);
+    }
+}
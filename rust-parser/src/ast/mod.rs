@@ -1,9 +1,25 @@
+#[cfg(any(test, feature = "arbitrary"))]
+pub mod arbitrary;
+pub mod binary;
+pub mod builtins;
 pub mod expr;
+pub mod fold;
+pub mod json;
+pub mod literal;
+pub mod span;
 pub mod types;
 pub mod visitor;
 pub mod pretty_print;
 
+#[cfg(any(test, feature = "arbitrary"))]
+pub use arbitrary::*;
+pub use binary::*;
+pub use builtins::*;
 pub use expr::*;
+pub use fold::*;
+pub use json::*;
+pub use literal::{IntLiteral, Radix};
+pub use span::*;
 pub use types::*;
 pub use visitor::*;
 pub use pretty_print::*;
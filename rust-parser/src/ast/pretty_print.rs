@@ -1,179 +1,386 @@
-use super::expr::Expr;
+//! Source formatter.
+//!
+//! Each expression becomes a `Doc`: a list with a head that stays on its
+//! opening line and arguments that either follow on the same line or, when
+//! the list does not fit the width, go one per line aligned under the
+//! first. Forms with a body (`defun-deploy`, `let`, `bounded-for`...) always
+//! put each body form on its own line, indented by two.
+//!
+//! Comments are not part of the AST, so when formatting a file they are
+//! taken from the source's concrete syntax tree and emitted by position:
+//! before the first node that starts after them, or at the end of the list
+//! that contains them. A comment followed by more code in its list forces
+//! the list to break, and a comment sharing a line with code is kept at the
+//! end of that line, so formatting the output again gives the same text.
+
+use super::expr::{Expr, ExprKind};
+use super::literal;
+use super::span::Span;
+use crate::parser::{SyntaxKind, SyntaxNode, SyntaxToken};
+
+/// Line width used unless another is given
+pub const DEFAULT_WIDTH: usize = 80;
+
+/// How a list lays out its arguments when it does not fit on one line
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Style {
+    /// One argument per line, indented two past the opening parenthesis.
+    /// `always_break` lists are broken whenever they have arguments.
+    Body { always_break: bool },
+    /// First argument after the head, the rest aligned under it
+    Align,
+}
+
+#[derive(Debug, Clone)]
+enum Doc {
+    Atom {
+        text: String,
+        span: Option<Span>,
+    },
+    List {
+        head: Vec<Doc>,
+        args: Vec<Doc>,
+        style: Style,
+        span: Option<Span>,
+    },
+}
+
+impl Doc {
+    fn keyword(text: &str) -> Doc {
+        Doc::Atom {
+            text: text.to_string(),
+            span: None,
+        }
+    }
+
+    fn span(&self) -> Option<Span> {
+        match self {
+            Doc::Atom { span, .. } | Doc::List { span, .. } => *span,
+        }
+    }
+
+    fn flat_width(&self) -> usize {
+        match self {
+            Doc::Atom { text, .. } => text.chars().count(),
+            Doc::List { head, args, .. } => {
+                let items = head.iter().chain(args);
+                2 + items.clone().map(Doc::flat_width).sum::<usize>() + items.count().max(1) - 1
+            }
+        }
+    }
+}
+
+/// A `;` comment in the source
+#[derive(Debug, Clone)]
+struct Comment {
+    start: usize,
+    end: usize,
+    text: String,
+    /// Whether code precedes it on its line
+    trailing: bool,
+    /// Offset of the first code after it that is not a closing parenthesis
+    next_code: usize,
+}
 
 pub struct PrettyPrinter {
+    width: usize,
+    comments: Vec<Comment>,
+    next_comment: usize,
+    /// Offsets of the source lines that hold only whitespace
+    blank_lines: Vec<usize>,
+    /// Source offset up to which everything has been printed
+    last_end: usize,
+    out: String,
+    column: usize,
+    /// Whether nothing but indentation has been written on this line
+    line_empty: bool,
+    /// Indentation of the current line, written with its first token
     indent: usize,
+    /// Indentation for comments that have to go on a line of their own
+    block_indent: usize,
+    /// Comments waiting for the end of the current line
+    pending: Vec<String>,
 }
 
 impl PrettyPrinter {
     pub fn new() -> Self {
-        Self { indent: 0 }
+        Self::with_width(DEFAULT_WIDTH)
     }
 
+    pub fn with_width(width: usize) -> Self {
+        Self {
+            width,
+            comments: Vec::new(),
+            next_comment: 0,
+            blank_lines: Vec::new(),
+            last_end: 0,
+            out: String::new(),
+            column: 0,
+            line_empty: true,
+            indent: 0,
+            block_indent: 0,
+            pending: Vec::new(),
+        }
+    }
+
+    /// Format one expression; comments are not available without the source
     pub fn print(expr: &Expr) -> String {
         let mut printer = Self::new();
         printer.print_expr(expr)
     }
 
-    fn print_expr(&mut self, expr: &Expr) -> String {
-        match expr {
-            Expr::Int(n) => format!("{}", n),
-            Expr::Float(f) => format!("{}", f),
-            Expr::Bool(b) => format!("{}", b),
-            Expr::String(s) => format!("\"{}\"", s),
-            Expr::Ident(i) => i.clone(),
+    pub fn print_expr(&mut self, expr: &Expr) -> String {
+        self.print_doc(&doc(expr), 0);
+        self.flush_pending();
+        std::mem::take(&mut self.out)
+    }
 
-            Expr::DefunDeploy {
-                name,
-                params,
-                return_type,
-                body,
-            } => {
-                let mut result = format!("(defun-deploy {} (", name);
-                for (i, param) in params.iter().enumerate() {
-                    if i > 0 {
-                        result.push_str(" ");
-                    }
-                    result.push_str(&format!("{}", param));
-                }
-                result.push(')');
+    /// Format the forms parsed from `source`, keeping its comments and
+    /// single blank lines between forms
+    pub fn format_file(&mut self, exprs: &[Expr], source: &str) -> String {
+        self.scan(source);
 
-                if let Some(ty) = return_type {
-                    result.push_str(&format!(" : {}", ty));
-                }
+        for (i, expr) in exprs.iter().enumerate() {
+            let doc = doc(expr);
+            if i > 0 {
+                self.break_line(0, &doc);
+            }
+            self.print_doc(&doc, 0);
+        }
+        self.block_indent = 0;
+        self.comments_before(usize::MAX);
+        self.flush_pending();
 
-                self.indent += 2;
-                for expr in body {
-                    result.push('\n');
-                    result.push_str(&self.indent_str());
-                    result.push_str(&self.print_expr(expr));
-                }
-                self.indent -= 2;
+        let mut out = std::mem::take(&mut self.out);
+        if !out.is_empty() {
+            out.push('\n');
+        }
+        out
+    }
 
-                result.push(')');
-                result
-            }
+    /// Record the comments and blank lines of `source`
+    fn scan(&mut self, source: &str) {
+        let tree = SyntaxNode::parse(source);
+        let tokens: Vec<&SyntaxToken> = tree.tokens().collect();
 
-            Expr::BoundedFor {
-                var,
-                start,
-                end,
-                body,
-            } => {
-                let mut result = format!(
-                    "(bounded-for {} {} {}",
-                    var,
-                    self.print_expr(start),
-                    self.print_expr(end)
-                );
-
-                self.indent += 2;
-                for expr in body {
-                    result.push('\n');
-                    result.push_str(&self.indent_str());
-                    result.push_str(&self.print_expr(expr));
+        for (i, token) in tokens.iter().enumerate() {
+            match token.kind {
+                SyntaxKind::Whitespace => {
+                    let start = token.span.start;
+                    if start == 0 && token.text.contains('\n') {
+                        self.blank_lines.push(0);
+                    }
+                    let newlines: Vec<usize> =
+                        token.text.match_indices('\n').map(|(n, _)| start + n + 1).collect();
+                    if let Some((_, blank)) = newlines.split_last() {
+                        self.blank_lines.extend(blank);
+                    }
                 }
-                self.indent -= 2;
-
-                result.push(')');
-                result
+                SyntaxKind::Comment => {
+                    let trailing = match i.checked_sub(1).map(|p| tokens[p]) {
+                        Some(prev) if prev.kind == SyntaxKind::Whitespace => {
+                            !prev.text.contains('\n') && i > 1
+                        }
+                        prev => prev.is_some(),
+                    };
+                    let next_code = tokens[i + 1..]
+                        .iter()
+                        .find(|t| !t.kind.is_trivia() && t.kind != SyntaxKind::RParen)
+                        .map_or(source.len(), |t| t.span.start);
+                    self.comments.push(Comment {
+                        start: token.span.start,
+                        end: token.span.end,
+                        text: token.text.trim_end().to_string(),
+                        trailing,
+                        next_code,
+                    });
+                }
+                _ => {}
             }
+        }
+    }
 
-            Expr::Let { bindings, body } => {
-                let mut result = String::from("(let (");
+    fn print_doc(&mut self, doc: &Doc, trail: usize) {
+        if let Some(span) = doc.span() {
+            self.comments_before(span.start);
+            self.separate(span.start);
+            self.last_end = self.last_end.max(span.start);
+        }
 
-                for (i, (name, expr)) in bindings.iter().enumerate() {
-                    if i > 0 {
-                        result.push(' ');
+        match doc {
+            Doc::Atom { text, .. } => self.write(text),
+            Doc::List {
+                head, args, style, ..
+            } => {
+                let flat =
+                    !self.forced(doc) && self.column + doc.flat_width() + trail <= self.width;
+                let outer = self.block_indent;
+                let column = self.column;
+                self.write("(");
+
+                if flat {
+                    for (i, item) in head.iter().chain(args).enumerate() {
+                        if i > 0 {
+                            self.write(" ");
+                        }
+                        self.print_doc(item, 0);
                     }
-                    result.push_str(&format!("({} {})", name, self.print_expr(expr)));
+                } else {
+                    self.print_broken(head, args, *style, column, trail);
                 }
 
-                result.push(')');
-
-                self.indent += 2;
-                for expr in body {
-                    result.push('\n');
-                    result.push_str(&self.indent_str());
-                    result.push_str(&self.print_expr(expr));
+                if let Some(span) = doc.span() {
+                    self.comments_before(span.end);
                 }
-                self.indent -= 2;
+                self.block_indent = outer;
+                self.write(")");
+            }
+        }
+
+        if let Some(span) = doc.span() {
+            self.last_end = self.last_end.max(span.end);
+        }
+    }
 
-                result.push(')');
-                result
+    fn print_broken(
+        &mut self,
+        head: &[Doc],
+        args: &[Doc],
+        style: Style,
+        column: usize,
+        trail: usize,
+    ) {
+        for (i, item) in head.iter().enumerate() {
+            if i > 0 {
+                self.write(" ");
             }
+            let last = i + 1 == head.len() && args.is_empty();
+            self.block_indent = self.column;
+            self.print_doc(item, if last { trail + 1 } else { 0 });
+        }
+
+        let indent = match style {
+            Style::Body { .. } => column + 2,
+            Style::Align if head.is_empty() => column + 1,
+            Style::Align => self.column + 1,
+        };
+        self.block_indent = indent;
 
-            Expr::If {
-                condition,
-                then_branch,
-                else_branch,
-            } => format!(
-                "(if {} {} {})",
-                self.print_expr(condition),
-                self.print_expr(then_branch),
-                self.print_expr(else_branch)
-            ),
-
-            Expr::FunctionCall { func, args } => {
-                let mut result = format!("({}", self.print_expr(func));
-                for arg in args {
-                    result.push(' ');
-                    result.push_str(&self.print_expr(arg));
+        for (i, arg) in args.iter().enumerate() {
+            let last = i + 1 == args.len();
+            if i == 0 && style == Style::Align {
+                if !head.is_empty() {
+                    self.write(" ");
                 }
-                result.push(')');
-                result
+            } else {
+                self.break_line(indent, arg);
             }
+            self.print_doc(arg, if last { trail + 1 } else { 0 });
+        }
+    }
 
-            Expr::Set { var, value } => {
-                format!("(set {} {})", var, self.print_expr(value))
-            }
+    /// Whether `doc` cannot be printed on one line
+    fn forced(&self, doc: &Doc) -> bool {
+        let Doc::List {
+            head,
+            args,
+            style,
+            span,
+        } = doc
+        else {
+            return false;
+        };
 
-            Expr::ArrayGet { array, index } => {
-                format!(
-                    "(array-get {} {})",
-                    self.print_expr(array),
-                    self.print_expr(index)
-                )
-            }
+        if *style == (Style::Body { always_break: true }) && !args.is_empty() {
+            return true;
+        }
+        if head.iter().chain(args).any(|d| self.forced(d)) {
+            return true;
+        }
+        span.is_some_and(|span| {
+            self.comments[self.next_comment..]
+                .iter()
+                .take_while(|c| c.start < span.end)
+                .any(|c| c.start > span.start && (!c.trailing || c.next_code < span.end))
+        })
+    }
 
-            Expr::ArraySet {
-                array,
-                index,
-                value,
-            } => {
-                format!(
-                    "(array-set {} {} {})",
-                    self.print_expr(array),
-                    self.print_expr(index),
-                    self.print_expr(value)
-                )
+    /// End the line before `next`, keeping comments that share the line
+    fn break_line(&mut self, indent: usize, next: &Doc) {
+        if let Some(span) = next.span() {
+            while let Some(comment) = self.comments.get(self.next_comment) {
+                if comment.start >= span.start || !comment.trailing {
+                    break;
+                }
+                self.pending.push(comment.text.clone());
+                self.last_end = comment.end;
+                self.next_comment += 1;
             }
+        }
+        self.newline(indent);
+    }
 
-            Expr::SleepMs(ms) => {
-                format!("(sleep-ms {})", self.print_expr(ms))
+    /// Print every comment that starts before `offset`
+    fn comments_before(&mut self, offset: usize) {
+        while let Some(comment) = self.comments.get(self.next_comment) {
+            if comment.start >= offset {
+                break;
             }
+            let comment = comment.clone();
+            self.next_comment += 1;
 
-            Expr::Program { name, budget, forms } => {
-                let mut result = format!("(program {}\n", name);
-                result.push_str(&format!("  {}\n", self.print_expr(budget)));
-
-                self.indent += 2;
-                for form in forms {
-                    result.push('\n');
-                    result.push_str(&self.indent_str());
-                    result.push_str(&self.print_expr(form));
+            if comment.trailing && !self.line_empty {
+                self.pending.push(comment.text);
+            } else {
+                if !self.line_empty {
+                    self.newline(self.block_indent);
                 }
-                self.indent -= 2;
-
-                result.push(')');
-                result
+                self.separate(comment.start);
+                self.write(&comment.text);
+                self.newline(self.block_indent);
             }
+            self.last_end = comment.end;
+        }
+    }
 
-            _ => format!("<{:?}>", expr),
+    /// Keep a blank line the source had before `start`
+    fn separate(&mut self, start: usize) {
+        if self.line_empty
+            && !self.out.is_empty()
+            && !self.out.ends_with("\n\n")
+            && self
+                .blank_lines
+                .iter()
+                .any(|&l| l > self.last_end && l < start)
+        {
+            self.out.push('\n');
         }
     }
 
-    fn indent_str(&self) -> String {
-        " ".repeat(self.indent)
+    fn write(&mut self, text: &str) {
+        if self.line_empty {
+            self.out.push_str(&" ".repeat(self.indent));
+            self.line_empty = false;
+        }
+        self.out.push_str(text);
+        self.column += text.chars().count();
+    }
+
+    fn newline(&mut self, indent: usize) {
+        // No separator is left at the end of a line
+        self.out.truncate(self.out.trim_end_matches(' ').len());
+        self.flush_pending();
+        self.out.push('\n');
+        self.indent = indent;
+        self.column = indent;
+        self.line_empty = true;
+    }
+
+    fn flush_pending(&mut self) {
+        for text in std::mem::take(&mut self.pending) {
+            self.out.push(' ');
+            self.out.push_str(&text);
+        }
     }
 }
 
@@ -183,23 +390,405 @@ fn default() -> Self {
     }
 }
 
+fn located(span: Span) -> Option<Span> {
+    (!span.is_dummy()).then_some(span)
+}
+
+fn atom(text: impl Into<String>, span: Span) -> Doc {
+    Doc::Atom {
+        text: text.into(),
+        span: located(span),
+    }
+}
+
+fn list(head: Vec<Doc>, args: Vec<Doc>, style: Style, span: Span) -> Doc {
+    Doc::List {
+        head,
+        args,
+        style,
+        span: located(span),
+    }
+}
+
+fn docs(exprs: &[Expr]) -> Vec<Doc> {
+    exprs.iter().map(doc).collect()
+}
+
+fn params(params: &[super::types::Parameter]) -> Doc {
+    let args: Vec<Doc> = params.iter().map(|p| atom(p.to_string(), p.span)).collect();
+    Doc::List {
+        span: spanning(&args),
+        head: Vec::new(),
+        args,
+        style: Style::Align,
+    }
+}
+
+/// Span from the first to the last of `docs`, for a list whose own span the
+/// AST does not keep, so comments between them still break it
+fn spanning(docs: &[Doc]) -> Option<Span> {
+    let mut spans = docs.iter().filter_map(Doc::span);
+    let first = spans.next()?;
+    Some(spans.fold(first, |all, span| all.merge(&span)))
+}
+
+/// Keyword followed by the given operands, aligned when broken
+fn form(keyword: &str, operands: &[&Expr], span: Span) -> Doc {
+    let args = operands.iter().map(|e| doc(e)).collect();
+    list(vec![Doc::keyword(keyword)], args, Style::Align, span)
+}
+
+/// Print a float so that it reads back as one
+fn float_text(value: f64) -> String {
+    let text = format!("{:?}", value);
+    match text.find('e') {
+        Some(e) if !text[..e].contains('.') => format!("{}.0{}", &text[..e], &text[e..]),
+        _ => text,
+    }
+}
+
+fn doc(expr: &Expr) -> Doc {
+    let span = expr.span;
+    let body = |always_break| Style::Body { always_break };
+
+    match &expr.kind {
+        ExprKind::Int(n) => atom(n.to_string(), span),
+        ExprKind::Float(f) => atom(float_text(*f), span),
+        ExprKind::Bool(b) => atom(b.to_string(), span),
+        ExprKind::String(s) => atom(literal::quote(s), span),
+        ExprKind::Ident(i) => atom(i.clone(), span),
+
+        ExprKind::DefunDeploy {
+            name,
+            params: ps,
+            return_type,
+            body: forms,
+        }
+        | ExprKind::DefunCompile {
+            name,
+            params: ps,
+            return_type,
+            body: forms,
+        } => {
+            let mut head = vec![
+                Doc::keyword(expr.keyword().unwrap_or_default()),
+                Doc::keyword(name),
+                params(ps),
+            ];
+            if let Some(ty) = return_type {
+                head.push(Doc::keyword(":"));
+                head.push(Doc::keyword(&ty.to_string()));
+            }
+            list(head, docs(forms), body(true), span)
+        }
+
+        ExprKind::Macro {
+            name,
+            params: ps,
+            body: forms,
+        } => {
+            let head = vec![Doc::keyword("macro"), Doc::keyword(name), params(ps)];
+            list(head, docs(forms), body(true), span)
+        }
+
+        ExprKind::BoundedFor {
+            var,
+            start,
+            end,
+            body: forms,
+        } => {
+            let head = vec![
+                Doc::keyword("bounded-for"),
+                Doc::keyword(var),
+                doc(start),
+                doc(end),
+            ];
+            list(head, docs(forms), body(true), span)
+        }
+
+        ExprKind::WithCapability {
+            capability,
+            body: forms,
+        } => {
+            let head = vec![Doc::keyword("with-capability"), doc(capability)];
+            list(head, docs(forms), body(true), span)
+        }
+
+        ExprKind::EvalCompile(inner) => form("eval-compile", &[inner], span),
+
+        ExprKind::Include(path) => list(
+            vec![Doc::keyword("include")],
+            vec![Doc::keyword(&literal::quote(path))],
+            Style::Align,
+            span,
+        ),
+
+        ExprKind::For {
+            var,
+            iterable,
+            body: forms,
+        } => {
+            let head = vec![Doc::keyword("for"), Doc::keyword(var), doc(iterable)];
+            list(head, docs(forms), body(true), span)
+        }
+
+        ExprKind::While {
+            condition,
+            body: forms,
+        } => {
+            let head = vec![Doc::keyword("while"), doc(condition)];
+            list(head, docs(forms), body(true), span)
+        }
+
+        ExprKind::Let {
+            bindings,
+            body: forms,
+        } => {
+            let bindings: Vec<Doc> = bindings
+                .iter()
+                .map(|(name, value)| Doc::List {
+                    head: vec![Doc::keyword(name)],
+                    args: vec![doc(value)],
+                    style: Style::Align,
+                    span: located(value.span),
+                })
+                .collect();
+            let bindings = Doc::List {
+                span: spanning(&bindings),
+                head: Vec::new(),
+                args: bindings,
+                style: Style::Align,
+            };
+            list(
+                vec![Doc::keyword("let"), bindings],
+                docs(forms),
+                body(true),
+                span,
+            )
+        }
+
+        ExprKind::Set { var, value } => list(
+            vec![Doc::keyword("set")],
+            vec![Doc::keyword(var), doc(value)],
+            Style::Align,
+            span,
+        ),
+
+        ExprKind::If {
+            condition,
+            then_branch,
+            else_branch,
+        } => form("if", &[condition, then_branch, else_branch], span),
+
+        ExprKind::FunctionCall { func, args } => {
+            list(vec![doc(func)], docs(args), Style::Align, span)
+        }
+
+        ExprKind::ArrayLiteral { elem_type, size } => {
+            atom(format!("(array {} {})", elem_type, size), span)
+        }
+
+        ExprKind::ArrayInit {
+            elem_type,
+            elements,
+        } => list(
+            vec![
+                Doc::keyword("array-init"),
+                Doc::keyword(&elem_type.to_string()),
+            ],
+            docs(elements),
+            Style::Align,
+            span,
+        ),
+
+        ExprKind::ArrayGet { array, index } => form("array-get", &[array, index], span),
+        ExprKind::ArraySet {
+            array,
+            index,
+            value,
+        } => form("array-set", &[array, index, value], span),
+        ExprKind::ArrayLength(array) => form("array-length", &[array], span),
+
+        ExprKind::GpioSet { device, value } => form("gpio-set", &[device, value], span),
+        ExprKind::GpioGet(device) => form("gpio-get", &[device], span),
+        ExprKind::UartSend { device, data } => form("uart-send", &[device, data], span),
+        ExprKind::UartRecv(device) => form("uart-recv", &[device], span),
+        ExprKind::SensorRead(device) => form("sensor-read", &[device], span),
+        ExprKind::NetworkSend { device, data } => form("network-send", &[device, data], span),
+        ExprKind::NetworkRecv(device) => form("network-recv", &[device], span),
+        ExprKind::SleepMs(duration) => form("sleep-ms", &[duration], span),
+        ExprKind::Timestamp => atom("(timestamp)", span),
+
+        ExprKind::ResourceBudget { specs } => list(
+            vec![Doc::keyword("resource-budget")],
+            specs
+                .iter()
+                .map(|s| Doc::keyword(&format!("({} {})", s.kind, s.amount)))
+                .collect(),
+            Style::Align,
+            span,
+        ),
+
+        ExprKind::DefCap {
+            name,
+            params: ps,
+            resource,
+            description,
+        } => {
+            let mut head = vec![Doc::keyword("defcap"), Doc::keyword(name), params(ps)];
+            if let Some(resource) = resource {
+                head.push(Doc::keyword(":"));
+                head.push(Doc::keyword(&format!("(capability {})", resource)));
+            }
+            list(
+                head,
+                vec![Doc::keyword(&literal::quote(description))],
+                body(false),
+                span,
+            )
+        }
+
+        ExprKind::Program {
+            name,
+            budget,
+            forms,
+        } => {
+            let mut args = vec![doc(budget)];
+            args.extend(docs(forms));
+            list(
+                vec![Doc::keyword("program"), Doc::keyword(name)],
+                args,
+                body(true),
+                span,
+            )
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::ast::types::Parameter;
+    use crate::parser::parse_file;
+
+    fn format(source: &str, width: usize) -> String {
+        let exprs = parse_file(source).unwrap();
+        PrettyPrinter::with_width(width).format_file(&exprs, source)
+    }
 
     #[test]
     fn test_pretty_print_simple() {
-        let expr = Expr::Int(42);
+        let expr: Expr = ExprKind::Int(42.into()).into();
         assert_eq!(PrettyPrinter::print(&expr), "42");
     }
 
     #[test]
     fn test_pretty_print_function_call() {
-        let expr = Expr::FunctionCall {
-            func: Box::new(Expr::Ident("+".to_string())),
-            args: vec![Expr::Int(1), Expr::Int(2)],
-        };
+        let expr: Expr = ExprKind::FunctionCall {
+            func: Box::new(ExprKind::Ident("+".to_string()).into()),
+            args: vec![
+                ExprKind::Int(1.into()).into(),
+                ExprKind::Int(2.into()).into(),
+            ],
+        }
+        .into();
         assert_eq!(PrettyPrinter::print(&expr), "(+ 1 2)");
     }
+
+    #[test]
+    fn test_breaks_to_fit_width() {
+        let source = "(defun-deploy f ((x int32)) : int32 (let ((y (array-get buf x)) (z 2.0)) (if (< y 10) (gpio-set led (+ y z)) (sleep-ms 100))))";
+
+        assert_eq!(
+            format(source, 30),
+            "\
+(defun-deploy f ((x int32)) : int32
+  (let ((y (array-get buf x))
+        (z 2.0))
+    (if (< y 10)
+        (gpio-set led (+ y z))
+        (sleep-ms 100))))
+"
+        );
+        assert_eq!(
+            format(source, 80),
+            "\
+(defun-deploy f ((x int32)) : int32
+  (let ((y (array-get buf x)) (z 2.0))
+    (if (< y 10) (gpio-set led (+ y z)) (sleep-ms 100))))
+"
+        );
+    }
+
+    #[test]
+    fn test_keeps_comments_and_is_idempotent() {
+        let source = "\
+; Header comment
+
+(program demo (resource-budget (time-ms 100) (memory-bytes 64))
+  (defcap led (pin) \"Status LED\")
+  (defun-deploy main ((led (capability gpio)))
+        (with-capability led   ; scoped
+    ; blink once
+    (gpio-set led true)
+
+    (sleep-ms 10) ; wait
+    (gpio-set led false))))
+";
+        let once = format(source, 80);
+        assert_eq!(
+            once,
+            "\
+; Header comment
+
+(program demo
+  (resource-budget (time-ms 100) (memory-bytes 64))
+  (defcap led (pin) \"Status LED\")
+  (defun-deploy main ((led (capability gpio)))
+    (with-capability led ; scoped
+      ; blink once
+      (gpio-set led true)
+
+      (sleep-ms 10) ; wait
+      (gpio-set led false))))
+"
+        );
+        assert_eq!(format(&once, 80), once);
+        assert_eq!(parse_file(&once).unwrap(), parse_file(source).unwrap());
+    }
+
+    #[test]
+    fn test_comment_between_bindings_aligns_with_them() {
+        let source = "\
+(defun-deploy f (a
+                 ;; second
+                 b)
+  (let ((x 1)
+        ;; note
+        (y 2))
+    (+ x y)))
+";
+        assert_eq!(format(source, 80), source);
+
+        let squeezed = "(defun-deploy f (a ;; second\n b) (let ((x 1) ;; note\n (y 2)) (+ x y)))";
+        let once = format(squeezed, 80);
+        assert!(once.lines().all(|line| !line.ends_with(' ')), "{}", once);
+        assert_eq!(format(&once, 80), once);
+    }
+
+    #[test]
+    fn test_examples_format_stably() {
+        let sources = [
+            include_str!("../../../examples/crypto-xor.obl"),
+            include_str!("../../../examples/temperature-monitor.obl"),
+        ];
+
+        for source in sources {
+            for width in [40, DEFAULT_WIDTH, 120] {
+                let once = format(source, width);
+                assert_eq!(format(&once, width), once);
+                assert_eq!(parse_file(&once).unwrap(), parse_file(source).unwrap());
+                assert_eq!(once.matches(';').count(), source.matches(';').count());
+            }
+        }
+    }
 }
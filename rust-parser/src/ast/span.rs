@@ -0,0 +1,170 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Identifies one source file of a project; see `SourceMap`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct FileId(pub u32);
+
+/// Identifies one macro expansion; see `Expansions`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct ExpansionId(pub u32);
+
+/// Location of a node in the source text.
+///
+/// `start` and `end` are byte offsets (half-open) into the file `file`,
+/// `line` and `column` are the 1-based position of `start`. Nodes built by
+/// hand rather than by the parser carry the default (dummy) span. Nodes
+/// produced by expanding a macro keep the span they have in the macro
+/// definition and record the expansion they came from in `expansion`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+    pub line: usize,
+    pub column: usize,
+    #[serde(default)]
+    pub file: FileId,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expansion: Option<ExpansionId>,
+}
+
+impl Span {
+    pub fn new(start: usize, end: usize, line: usize, column: usize) -> Self {
+        Self {
+            start,
+            end,
+            line,
+            column,
+            file: FileId::default(),
+            expansion: None,
+        }
+    }
+
+    pub fn in_file(mut self, file: FileId) -> Self {
+        self.file = file;
+        self
+    }
+
+    /// Span used for synthesized nodes that have no source location
+    pub fn dummy() -> Self {
+        Self::default()
+    }
+
+    pub fn is_dummy(&self) -> bool {
+        self.line == 0
+    }
+
+    pub fn len(&self) -> usize {
+        self.end.saturating_sub(self.start)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Smallest span covering both `self` and `other`
+    pub fn merge(&self, other: &Span) -> Span {
+        if self.is_dummy() {
+            return *other;
+        }
+        if other.is_dummy() {
+            return *self;
+        }
+        let first = if self.start <= other.start { self } else { other };
+        Span {
+            end: self.end.max(other.end),
+            ..*first
+        }
+    }
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
+
+/// Maps byte offsets in a source string to line/column positions
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LineIndex {
+    line_starts: Vec<usize>,
+    len: usize,
+    /// File stamped on every span this index builds
+    file: FileId,
+}
+
+impl LineIndex {
+    pub fn new(source: &str) -> Self {
+        Self::for_file(source, FileId::default())
+    }
+
+    pub fn for_file(source: &str, file: FileId) -> Self {
+        let mut line_starts = vec![0];
+        for (i, b) in source.bytes().enumerate() {
+            if b == b'\n' {
+                line_starts.push(i + 1);
+            }
+        }
+        Self {
+            line_starts,
+            len: source.len(),
+            file,
+        }
+    }
+
+    /// 1-based (line, column) of a byte offset; columns count characters
+    pub fn line_col(&self, source: &str, offset: usize) -> (usize, usize) {
+        let offset = offset.min(self.len);
+        let line = match self.line_starts.binary_search(&offset) {
+            Ok(line) => line,
+            Err(next) => next - 1,
+        };
+        let line_start = self.line_starts[line];
+        let column = source
+            .get(line_start..offset)
+            .map(|s| s.chars().count())
+            .unwrap_or(offset - line_start);
+        (line + 1, column + 1)
+    }
+
+    /// Build a span for the byte range `start..end`
+    pub fn span(&self, source: &str, start: usize, end: usize) -> Span {
+        let (line, column) = self.line_col(source, start);
+        Span::new(start, end, line, column).in_file(self.file)
+    }
+
+    pub fn file(&self) -> FileId {
+        self.file
+    }
+
+    /// Byte offset where the given 1-based line starts
+    pub fn line_start(&self, line: usize) -> Option<usize> {
+        self.line_starts.get(line.checked_sub(1)?).copied()
+    }
+
+    pub fn line_count(&self) -> usize {
+        self.line_starts.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_line_col() {
+        let source = "(a\n  (b c))\n";
+        let index = LineIndex::new(source);
+        assert_eq!(index.line_col(source, 0), (1, 1));
+        assert_eq!(index.line_col(source, 5), (2, 3));
+        assert_eq!(index.span(source, 5, 10), Span::new(5, 10, 2, 3));
+    }
+
+    #[test]
+    fn test_merge() {
+        let a = Span::new(4, 8, 1, 5);
+        let b = Span::new(10, 20, 2, 1);
+        assert_eq!(a.merge(&b), Span::new(4, 20, 1, 5));
+        assert_eq!(Span::dummy().merge(&b), b);
+    }
+}
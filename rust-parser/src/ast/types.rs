@@ -1,10 +1,15 @@
+use super::span::Span;
 use serde::{Deserialize, Serialize};
 use std::fmt;
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum Type {
+    Int8,
+    Int16,
     Int32,
     Int64,
+    Uint8,
+    Uint16,
     Uint32,
     Uint64,
     Float32,
@@ -37,11 +42,55 @@ pub enum ResourceType {
     NetworkRecv,
 }
 
+impl Type {
+    /// Smallest and largest value of an integer type
+    pub fn int_range(&self) -> Option<(i128, i128)> {
+        Some(match self {
+            Type::Int8 => (i8::MIN as i128, i8::MAX as i128),
+            Type::Int16 => (i16::MIN as i128, i16::MAX as i128),
+            Type::Int32 => (i32::MIN as i128, i32::MAX as i128),
+            Type::Int64 => (i64::MIN as i128, i64::MAX as i128),
+            Type::Uint8 => (0, u8::MAX as i128),
+            Type::Uint16 => (0, u16::MAX as i128),
+            Type::Uint32 => (0, u32::MAX as i128),
+            Type::Uint64 => (0, u64::MAX as i128),
+            _ => return None,
+        })
+    }
+
+    pub fn is_integer(&self) -> bool {
+        self.int_range().is_some()
+    }
+
+    pub fn is_float(&self) -> bool {
+        matches!(self, Type::Float32 | Type::Float64)
+    }
+
+    /// Bytes one value occupies in device memory; strings and functions are
+    /// counted as a pointer. An array too large to count saturates at
+    /// `u64::MAX`, which no budget allows.
+    pub fn size_bytes(&self) -> u64 {
+        match self {
+            Type::Int8 | Type::Uint8 | Type::Bool => 1,
+            Type::Int16 | Type::Uint16 => 2,
+            Type::Int32 | Type::Uint32 | Type::Float32 => 4,
+            Type::Int64 | Type::Uint64 | Type::Float64 => 8,
+            Type::String | Type::Function { .. } => 8,
+            Type::Void | Type::Capability { .. } => 0,
+            Type::Array { elem_type, size } => elem_type.size_bytes().saturating_mul(*size as u64),
+        }
+    }
+}
+
 impl fmt::Display for Type {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
+            Type::Int8 => write!(f, "int8"),
+            Type::Int16 => write!(f, "int16"),
             Type::Int32 => write!(f, "int32"),
             Type::Int64 => write!(f, "int64"),
+            Type::Uint8 => write!(f, "uint8"),
+            Type::Uint16 => write!(f, "uint16"),
             Type::Uint32 => write!(f, "uint32"),
             Type::Uint64 => write!(f, "uint64"),
             Type::Float32 => write!(f, "float32"),
@@ -50,7 +99,7 @@ fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
             Type::String => write!(f, "string"),
             Type::Void => write!(f, "void"),
             Type::Array { elem_type, size } => write!(f, "(array {} {})", elem_type, size),
-            Type::Capability { resource } => write!(f, "(capability {:?})", resource),
+            Type::Capability { resource } => write!(f, "(capability {})", resource),
             Type::Function { params, return_type } => {
                 write!(f, "(-> ")?;
                 for param in params {
@@ -77,10 +126,19 @@ fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Parameter {
     pub name: String,
     pub type_annotation: Option<Type>,
+    /// Type found by `TypeInference` when there is no annotation
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub inferred_type: Option<Type>,
+    /// Most uses a capability parameter may make, from
+    /// `(capability res budget)`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub budget: Option<u64>,
+    #[serde(default)]
+    pub span: Span,
 }
 
 impl Parameter {
@@ -88,15 +146,47 @@ pub fn new(name: String, type_annotation: Option<Type>) -> Self {
         Self {
             name,
             type_annotation,
+            inferred_type: None,
+            budget: None,
+            span: Span::dummy(),
         }
     }
+
+    pub fn with_budget(mut self, budget: u64) -> Self {
+        self.budget = Some(budget);
+        self
+    }
+
+    pub fn with_span(mut self, span: Span) -> Self {
+        self.span = span;
+        self
+    }
+
+    /// Declared type, or the inferred one if there is no annotation
+    pub fn ty(&self) -> Option<&Type> {
+        self.type_annotation.as_ref().or(self.inferred_type.as_ref())
+    }
 }
 
+// Like `Expr`, parameters compare without regard to their source location.
+impl PartialEq for Parameter {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name
+            && self.type_annotation == other.type_annotation
+            && self.budget == other.budget
+    }
+}
+
+impl Eq for Parameter {}
+
 impl fmt::Display for Parameter {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match &self.type_annotation {
-            Some(ty) => write!(f, "({} {})", self.name, ty),
-            None => write!(f, "{}", self.name),
+        match (&self.type_annotation, self.budget) {
+            (Some(Type::Capability { resource }), Some(budget)) => {
+                write!(f, "({} (capability {} {}))", self.name, resource, budget)
+            }
+            (Some(ty), _) => write!(f, "({} {})", self.name, ty),
+            (None, _) => write!(f, "{}", self.name),
         }
     }
 }
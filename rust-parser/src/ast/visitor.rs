@@ -1,80 +1,894 @@
-use super::expr::Expr;
+//! Traversal of the AST.
+//!
+//! `Visitor` and `MutVisitor` have one hook per `ExprKind` variant. Each
+//! hook's default calls the matching `walk_*` function, which visits the
+//! node's children and nothing else; leaves have no children and default
+//! to doing nothing. An implementation overrides the hooks it cares about
+//! and calls the `walk_*` function itself to keep descending, or leaves it
+//! out to skip the subtree.
 
-pub trait Visitor<T> {
-    fn visit_expr(&mut self, expr: &Expr) -> T;
+use super::expr::{Expr, ExprKind, ResourceSpec};
+use super::literal::IntLiteral;
+use super::span::Span;
+use super::types::{Parameter, ResourceType, Type};
 
-    fn visit_int(&mut self, n: i64) -> T {
-        self.default_visit()
+pub trait Visitor: Sized {
+    fn visit_expr(&mut self, expr: &Expr) {
+        walk_expr(self, expr)
     }
 
-    fn visit_float(&mut self, f: f64) -> T {
-        self.default_visit()
+    fn visit_param(&mut self, _param: &Parameter) {}
+
+    // Literals
+
+    fn visit_int(&mut self, _literal: &IntLiteral, _span: Span) {}
+
+    fn visit_float(&mut self, _value: f64, _span: Span) {}
+
+    fn visit_bool(&mut self, _value: bool, _span: Span) {}
+
+    fn visit_string(&mut self, _value: &str, _span: Span) {}
+
+    fn visit_ident(&mut self, _name: &str, _span: Span) {}
+
+    // Deploy-time constructs
+
+    fn visit_defun_deploy(
+        &mut self,
+        _name: &str,
+        params: &[Parameter],
+        _return_type: Option<&Type>,
+        body: &[Expr],
+        _span: Span,
+    ) {
+        walk_defun_deploy(self, params, body)
     }
 
-    fn visit_bool(&mut self, b: bool) -> T {
-        self.default_visit()
+    fn visit_bounded_for(
+        &mut self,
+        _var: &str,
+        start: &Expr,
+        end: &Expr,
+        body: &[Expr],
+        _span: Span,
+    ) {
+        walk_bounded_for(self, start, end, body)
     }
 
-    fn visit_string(&mut self, s: &str) -> T {
-        self.default_visit()
+    fn visit_with_capability(&mut self, capability: &Expr, body: &[Expr], _span: Span) {
+        walk_with_capability(self, capability, body)
+    }
+
+    // Compile-time constructs
+
+    fn visit_defun_compile(
+        &mut self,
+        _name: &str,
+        params: &[Parameter],
+        _return_type: Option<&Type>,
+        body: &[Expr],
+        _span: Span,
+    ) {
+        walk_defun_compile(self, params, body)
     }
 
-    fn visit_ident(&mut self, i: &str) -> T {
-        self.default_visit()
+    fn visit_macro(&mut self, _name: &str, params: &[Parameter], body: &[Expr], _span: Span) {
+        walk_macro(self, params, body)
     }
 
-    fn visit_defun_deploy(&mut self, name: &str, body: &[Expr]) -> T {
-        for expr in body {
-            self.visit_expr(expr);
-        }
-        self.default_visit()
+    fn visit_eval_compile(&mut self, expr: &Expr, _span: Span) {
+        walk_eval_compile(self, expr)
     }
 
-    fn visit_bounded_for(&mut self, var: &str, start: &Expr, end: &Expr, body: &[Expr]) -> T {
-        self.visit_expr(start);
-        self.visit_expr(end);
-        for expr in body {
-            self.visit_expr(expr);
-        }
-        self.default_visit()
+    fn visit_include(&mut self, _path: &str, _span: Span) {}
+
+    fn visit_for(&mut self, _var: &str, iterable: &Expr, body: &[Expr], _span: Span) {
+        walk_for(self, iterable, body)
     }
 
-    fn visit_function_call(&mut self, func: &Expr, args: &[Expr]) -> T {
-        self.visit_expr(func);
-        for arg in args {
-            self.visit_expr(arg);
+    fn visit_while(&mut self, condition: &Expr, body: &[Expr], _span: Span) {
+        walk_while(self, condition, body)
+    }
+
+    // Common constructs
+
+    fn visit_let(&mut self, bindings: &[(String, Expr)], body: &[Expr], _span: Span) {
+        walk_let(self, bindings, body)
+    }
+
+    fn visit_set(&mut self, _var: &str, value: &Expr, _span: Span) {
+        walk_set(self, value)
+    }
+
+    fn visit_if(&mut self, condition: &Expr, then_branch: &Expr, else_branch: &Expr, _span: Span) {
+        walk_if(self, condition, then_branch, else_branch)
+    }
+
+    fn visit_function_call(&mut self, func: &Expr, args: &[Expr], _span: Span) {
+        walk_function_call(self, func, args)
+    }
+
+    // Array operations
+
+    fn visit_array_literal(&mut self, _elem_type: &Type, _size: usize, _span: Span) {}
+
+    fn visit_array_init(&mut self, _elem_type: &Type, elements: &[Expr], _span: Span) {
+        walk_array_init(self, elements)
+    }
+
+    fn visit_array_get(&mut self, array: &Expr, index: &Expr, _span: Span) {
+        walk_array_get(self, array, index)
+    }
+
+    fn visit_array_set(&mut self, array: &Expr, index: &Expr, value: &Expr, _span: Span) {
+        walk_array_set(self, array, index, value)
+    }
+
+    fn visit_array_length(&mut self, array: &Expr, _span: Span) {
+        walk_array_length(self, array)
+    }
+
+    // I/O operations
+
+    fn visit_gpio_set(&mut self, device: &Expr, value: &Expr, _span: Span) {
+        walk_gpio_set(self, device, value)
+    }
+
+    fn visit_gpio_get(&mut self, device: &Expr, _span: Span) {
+        walk_gpio_get(self, device)
+    }
+
+    fn visit_uart_send(&mut self, device: &Expr, data: &Expr, _span: Span) {
+        walk_uart_send(self, device, data)
+    }
+
+    fn visit_uart_recv(&mut self, device: &Expr, _span: Span) {
+        walk_uart_recv(self, device)
+    }
+
+    fn visit_sensor_read(&mut self, device: &Expr, _span: Span) {
+        walk_sensor_read(self, device)
+    }
+
+    fn visit_network_send(&mut self, device: &Expr, data: &Expr, _span: Span) {
+        walk_network_send(self, device, data)
+    }
+
+    fn visit_network_recv(&mut self, device: &Expr, _span: Span) {
+        walk_network_recv(self, device)
+    }
+
+    fn visit_sleep_ms(&mut self, duration: &Expr, _span: Span) {
+        walk_sleep_ms(self, duration)
+    }
+
+    fn visit_timestamp(&mut self, _span: Span) {}
+
+    // Resource management
+
+    fn visit_resource_budget(&mut self, _specs: &[ResourceSpec], _span: Span) {}
+
+    fn visit_defcap(
+        &mut self,
+        _name: &str,
+        params: &[Parameter],
+        _resource: Option<&ResourceType>,
+        _description: &str,
+        _span: Span,
+    ) {
+        walk_defcap(self, params)
+    }
+
+    // Program structure
+
+    fn visit_program(&mut self, _name: &str, budget: &Expr, forms: &[Expr], _span: Span) {
+        walk_program(self, budget, forms)
+    }
+}
+
+/// Dispatch `expr` to the visitor's hook for its kind
+pub fn walk_expr<V: Visitor>(visitor: &mut V, expr: &Expr) {
+    let span = expr.span;
+    match &expr.kind {
+        ExprKind::Int(literal) => visitor.visit_int(literal, span),
+        ExprKind::Float(value) => visitor.visit_float(*value, span),
+        ExprKind::Bool(value) => visitor.visit_bool(*value, span),
+        ExprKind::String(value) => visitor.visit_string(value, span),
+        ExprKind::Ident(name) => visitor.visit_ident(name, span),
+        ExprKind::DefunDeploy {
+            name,
+            params,
+            return_type,
+            body,
+        } => visitor.visit_defun_deploy(name, params, return_type.as_ref(), body, span),
+        ExprKind::BoundedFor {
+            var,
+            start,
+            end,
+            body,
+        } => visitor.visit_bounded_for(var, start, end, body, span),
+        ExprKind::WithCapability { capability, body } => {
+            visitor.visit_with_capability(capability, body, span)
+        }
+        ExprKind::DefunCompile {
+            name,
+            params,
+            return_type,
+            body,
+        } => visitor.visit_defun_compile(name, params, return_type.as_ref(), body, span),
+        ExprKind::Macro { name, params, body } => visitor.visit_macro(name, params, body, span),
+        ExprKind::EvalCompile(expr) => visitor.visit_eval_compile(expr, span),
+        ExprKind::Include(path) => visitor.visit_include(path, span),
+        ExprKind::For {
+            var,
+            iterable,
+            body,
+        } => visitor.visit_for(var, iterable, body, span),
+        ExprKind::While { condition, body } => visitor.visit_while(condition, body, span),
+        ExprKind::Let { bindings, body } => visitor.visit_let(bindings, body, span),
+        ExprKind::Set { var, value } => visitor.visit_set(var, value, span),
+        ExprKind::If {
+            condition,
+            then_branch,
+            else_branch,
+        } => visitor.visit_if(condition, then_branch, else_branch, span),
+        ExprKind::FunctionCall { func, args } => visitor.visit_function_call(func, args, span),
+        ExprKind::ArrayLiteral { elem_type, size } => {
+            visitor.visit_array_literal(elem_type, *size, span)
         }
-        self.default_visit()
+        ExprKind::ArrayInit {
+            elem_type,
+            elements,
+        } => visitor.visit_array_init(elem_type, elements, span),
+        ExprKind::ArrayGet { array, index } => visitor.visit_array_get(array, index, span),
+        ExprKind::ArraySet {
+            array,
+            index,
+            value,
+        } => visitor.visit_array_set(array, index, value, span),
+        ExprKind::ArrayLength(array) => visitor.visit_array_length(array, span),
+        ExprKind::GpioSet { device, value } => visitor.visit_gpio_set(device, value, span),
+        ExprKind::GpioGet(device) => visitor.visit_gpio_get(device, span),
+        ExprKind::UartSend { device, data } => visitor.visit_uart_send(device, data, span),
+        ExprKind::UartRecv(device) => visitor.visit_uart_recv(device, span),
+        ExprKind::SensorRead(device) => visitor.visit_sensor_read(device, span),
+        ExprKind::NetworkSend { device, data } => visitor.visit_network_send(device, data, span),
+        ExprKind::NetworkRecv(device) => visitor.visit_network_recv(device, span),
+        ExprKind::SleepMs(duration) => visitor.visit_sleep_ms(duration, span),
+        ExprKind::Timestamp => visitor.visit_timestamp(span),
+        ExprKind::ResourceBudget { specs } => visitor.visit_resource_budget(specs, span),
+        ExprKind::DefCap {
+            name,
+            params,
+            resource,
+            description,
+        } => visitor.visit_defcap(name, params, resource.as_ref(), description, span),
+        ExprKind::Program {
+            name,
+            budget,
+            forms,
+        } => visitor.visit_program(name, budget, forms, span),
     }
+}
 
-    fn default_visit(&mut self) -> T;
+pub fn walk_exprs<V: Visitor>(visitor: &mut V, exprs: &[Expr]) {
+    for expr in exprs {
+        visitor.visit_expr(expr);
+    }
 }
 
-/// Mutable visitor for AST traversal and transformation
-pub trait MutVisitor {
-    fn visit_expr_mut(&mut self, expr: &mut Expr);
+pub fn walk_params<V: Visitor>(visitor: &mut V, params: &[Parameter]) {
+    for param in params {
+        visitor.visit_param(param);
+    }
+}
 
-    fn visit_defun_deploy_mut(&mut self, name: &mut String, body: &mut Vec<Expr>) {
-        for expr in body {
-            self.visit_expr_mut(expr);
-        }
+pub fn walk_defun_deploy<V: Visitor>(visitor: &mut V, params: &[Parameter], body: &[Expr]) {
+    walk_params(visitor, params);
+    walk_exprs(visitor, body);
+}
+
+pub fn walk_bounded_for<V: Visitor>(visitor: &mut V, start: &Expr, end: &Expr, body: &[Expr]) {
+    visitor.visit_expr(start);
+    visitor.visit_expr(end);
+    walk_exprs(visitor, body);
+}
+
+pub fn walk_with_capability<V: Visitor>(visitor: &mut V, capability: &Expr, body: &[Expr]) {
+    visitor.visit_expr(capability);
+    walk_exprs(visitor, body);
+}
+
+pub fn walk_defun_compile<V: Visitor>(visitor: &mut V, params: &[Parameter], body: &[Expr]) {
+    walk_params(visitor, params);
+    walk_exprs(visitor, body);
+}
+
+pub fn walk_macro<V: Visitor>(visitor: &mut V, params: &[Parameter], body: &[Expr]) {
+    walk_params(visitor, params);
+    walk_exprs(visitor, body);
+}
+
+pub fn walk_eval_compile<V: Visitor>(visitor: &mut V, expr: &Expr) {
+    visitor.visit_expr(expr);
+}
+
+pub fn walk_for<V: Visitor>(visitor: &mut V, iterable: &Expr, body: &[Expr]) {
+    visitor.visit_expr(iterable);
+    walk_exprs(visitor, body);
+}
+
+pub fn walk_while<V: Visitor>(visitor: &mut V, condition: &Expr, body: &[Expr]) {
+    visitor.visit_expr(condition);
+    walk_exprs(visitor, body);
+}
+
+pub fn walk_let<V: Visitor>(visitor: &mut V, bindings: &[(String, Expr)], body: &[Expr]) {
+    for (_, value) in bindings {
+        visitor.visit_expr(value);
+    }
+    walk_exprs(visitor, body);
+}
+
+pub fn walk_set<V: Visitor>(visitor: &mut V, value: &Expr) {
+    visitor.visit_expr(value);
+}
+
+pub fn walk_if<V: Visitor>(
+    visitor: &mut V,
+    condition: &Expr,
+    then_branch: &Expr,
+    else_branch: &Expr,
+) {
+    visitor.visit_expr(condition);
+    visitor.visit_expr(then_branch);
+    visitor.visit_expr(else_branch);
+}
+
+pub fn walk_function_call<V: Visitor>(visitor: &mut V, func: &Expr, args: &[Expr]) {
+    visitor.visit_expr(func);
+    walk_exprs(visitor, args);
+}
+
+pub fn walk_array_init<V: Visitor>(visitor: &mut V, elements: &[Expr]) {
+    walk_exprs(visitor, elements);
+}
+
+pub fn walk_array_get<V: Visitor>(visitor: &mut V, array: &Expr, index: &Expr) {
+    visitor.visit_expr(array);
+    visitor.visit_expr(index);
+}
+
+pub fn walk_array_set<V: Visitor>(visitor: &mut V, array: &Expr, index: &Expr, value: &Expr) {
+    visitor.visit_expr(array);
+    visitor.visit_expr(index);
+    visitor.visit_expr(value);
+}
+
+pub fn walk_array_length<V: Visitor>(visitor: &mut V, array: &Expr) {
+    visitor.visit_expr(array);
+}
+
+pub fn walk_gpio_set<V: Visitor>(visitor: &mut V, device: &Expr, value: &Expr) {
+    visitor.visit_expr(device);
+    visitor.visit_expr(value);
+}
+
+pub fn walk_gpio_get<V: Visitor>(visitor: &mut V, device: &Expr) {
+    visitor.visit_expr(device);
+}
+
+pub fn walk_uart_send<V: Visitor>(visitor: &mut V, device: &Expr, data: &Expr) {
+    visitor.visit_expr(device);
+    visitor.visit_expr(data);
+}
+
+pub fn walk_uart_recv<V: Visitor>(visitor: &mut V, device: &Expr) {
+    visitor.visit_expr(device);
+}
+
+pub fn walk_sensor_read<V: Visitor>(visitor: &mut V, device: &Expr) {
+    visitor.visit_expr(device);
+}
+
+pub fn walk_network_send<V: Visitor>(visitor: &mut V, device: &Expr, data: &Expr) {
+    visitor.visit_expr(device);
+    visitor.visit_expr(data);
+}
+
+pub fn walk_network_recv<V: Visitor>(visitor: &mut V, device: &Expr) {
+    visitor.visit_expr(device);
+}
+
+pub fn walk_sleep_ms<V: Visitor>(visitor: &mut V, duration: &Expr) {
+    visitor.visit_expr(duration);
+}
+
+pub fn walk_defcap<V: Visitor>(visitor: &mut V, params: &[Parameter]) {
+    walk_params(visitor, params);
+}
+
+pub fn walk_program<V: Visitor>(visitor: &mut V, budget: &Expr, forms: &[Expr]) {
+    visitor.visit_expr(budget);
+    walk_exprs(visitor, forms);
+}
+
+/// Mutable visitor for AST traversal and transformation. To replace a
+/// whole expression, override `visit_expr_mut`.
+pub trait MutVisitor: Sized {
+    fn visit_expr_mut(&mut self, expr: &mut Expr) {
+        walk_expr_mut(self, expr)
+    }
+
+    fn visit_param_mut(&mut self, _param: &mut Parameter) {}
+
+    // Literals
+
+    fn visit_int_mut(&mut self, _literal: &mut IntLiteral, _span: Span) {}
+
+    fn visit_float_mut(&mut self, _value: &mut f64, _span: Span) {}
+
+    fn visit_bool_mut(&mut self, _value: &mut bool, _span: Span) {}
+
+    fn visit_string_mut(&mut self, _value: &mut String, _span: Span) {}
+
+    fn visit_ident_mut(&mut self, _name: &mut String, _span: Span) {}
+
+    // Deploy-time constructs
+
+    fn visit_defun_deploy_mut(
+        &mut self,
+        _name: &mut String,
+        params: &mut Vec<Parameter>,
+        _return_type: &mut Option<Type>,
+        body: &mut Vec<Expr>,
+        _span: Span,
+    ) {
+        walk_defun_deploy_mut(self, params, body)
     }
 
     fn visit_bounded_for_mut(
         &mut self,
-        var: &mut String,
-        start: &mut Box<Expr>,
-        end: &mut Box<Expr>,
+        _var: &mut String,
+        start: &mut Expr,
+        end: &mut Expr,
+        body: &mut Vec<Expr>,
+        _span: Span,
+    ) {
+        walk_bounded_for_mut(self, start, end, body)
+    }
+
+    fn visit_with_capability_mut(
+        &mut self,
+        capability: &mut Expr,
+        body: &mut Vec<Expr>,
+        _span: Span,
+    ) {
+        walk_with_capability_mut(self, capability, body)
+    }
+
+    // Compile-time constructs
+
+    fn visit_defun_compile_mut(
+        &mut self,
+        _name: &mut String,
+        params: &mut Vec<Parameter>,
+        _return_type: &mut Option<Type>,
+        body: &mut Vec<Expr>,
+        _span: Span,
+    ) {
+        walk_defun_compile_mut(self, params, body)
+    }
+
+    fn visit_macro_mut(
+        &mut self,
+        _name: &mut String,
+        params: &mut Vec<Parameter>,
         body: &mut Vec<Expr>,
+        _span: Span,
     ) {
-        self.visit_expr_mut(start);
-        self.visit_expr_mut(end);
-        for expr in body {
-            self.visit_expr_mut(expr);
+        walk_macro_mut(self, params, body)
+    }
+
+    fn visit_eval_compile_mut(&mut self, expr: &mut Expr, _span: Span) {
+        walk_eval_compile_mut(self, expr)
+    }
+
+    fn visit_include_mut(&mut self, _path: &mut String, _span: Span) {}
+
+    fn visit_for_mut(
+        &mut self,
+        _var: &mut String,
+        iterable: &mut Expr,
+        body: &mut Vec<Expr>,
+        _span: Span,
+    ) {
+        walk_for_mut(self, iterable, body)
+    }
+
+    fn visit_while_mut(&mut self, condition: &mut Expr, body: &mut Vec<Expr>, _span: Span) {
+        walk_while_mut(self, condition, body)
+    }
+
+    // Common constructs
+
+    fn visit_let_mut(
+        &mut self,
+        bindings: &mut Vec<(String, Expr)>,
+        body: &mut Vec<Expr>,
+        _span: Span,
+    ) {
+        walk_let_mut(self, bindings, body)
+    }
+
+    fn visit_set_mut(&mut self, _var: &mut String, value: &mut Expr, _span: Span) {
+        walk_set_mut(self, value)
+    }
+
+    fn visit_if_mut(
+        &mut self,
+        condition: &mut Expr,
+        then_branch: &mut Expr,
+        else_branch: &mut Expr,
+        _span: Span,
+    ) {
+        walk_if_mut(self, condition, then_branch, else_branch)
+    }
+
+    fn visit_function_call_mut(&mut self, func: &mut Expr, args: &mut Vec<Expr>, _span: Span) {
+        walk_function_call_mut(self, func, args)
+    }
+
+    // Array operations
+
+    fn visit_array_literal_mut(&mut self, _elem_type: &mut Type, _size: &mut usize, _span: Span) {}
+
+    fn visit_array_init_mut(
+        &mut self,
+        _elem_type: &mut Type,
+        elements: &mut Vec<Expr>,
+        _span: Span,
+    ) {
+        walk_array_init_mut(self, elements)
+    }
+
+    fn visit_array_get_mut(&mut self, array: &mut Expr, index: &mut Expr, _span: Span) {
+        walk_array_get_mut(self, array, index)
+    }
+
+    fn visit_array_set_mut(
+        &mut self,
+        array: &mut Expr,
+        index: &mut Expr,
+        value: &mut Expr,
+        _span: Span,
+    ) {
+        walk_array_set_mut(self, array, index, value)
+    }
+
+    fn visit_array_length_mut(&mut self, array: &mut Expr, _span: Span) {
+        walk_array_length_mut(self, array)
+    }
+
+    // I/O operations
+
+    fn visit_gpio_set_mut(&mut self, device: &mut Expr, value: &mut Expr, _span: Span) {
+        walk_gpio_set_mut(self, device, value)
+    }
+
+    fn visit_gpio_get_mut(&mut self, device: &mut Expr, _span: Span) {
+        walk_gpio_get_mut(self, device)
+    }
+
+    fn visit_uart_send_mut(&mut self, device: &mut Expr, data: &mut Expr, _span: Span) {
+        walk_uart_send_mut(self, device, data)
+    }
+
+    fn visit_uart_recv_mut(&mut self, device: &mut Expr, _span: Span) {
+        walk_uart_recv_mut(self, device)
+    }
+
+    fn visit_sensor_read_mut(&mut self, device: &mut Expr, _span: Span) {
+        walk_sensor_read_mut(self, device)
+    }
+
+    fn visit_network_send_mut(&mut self, device: &mut Expr, data: &mut Expr, _span: Span) {
+        walk_network_send_mut(self, device, data)
+    }
+
+    fn visit_network_recv_mut(&mut self, device: &mut Expr, _span: Span) {
+        walk_network_recv_mut(self, device)
+    }
+
+    fn visit_sleep_ms_mut(&mut self, duration: &mut Expr, _span: Span) {
+        walk_sleep_ms_mut(self, duration)
+    }
+
+    fn visit_timestamp_mut(&mut self, _span: Span) {}
+
+    // Resource management
+
+    fn visit_resource_budget_mut(&mut self, _specs: &mut Vec<ResourceSpec>, _span: Span) {}
+
+    fn visit_defcap_mut(
+        &mut self,
+        _name: &mut String,
+        params: &mut Vec<Parameter>,
+        _resource: &mut Option<ResourceType>,
+        _description: &mut String,
+        _span: Span,
+    ) {
+        walk_defcap_mut(self, params)
+    }
+
+    // Program structure
+
+    fn visit_program_mut(
+        &mut self,
+        _name: &mut String,
+        budget: &mut Expr,
+        forms: &mut Vec<Expr>,
+        _span: Span,
+    ) {
+        walk_program_mut(self, budget, forms)
+    }
+}
+
+/// Dispatch `expr` to the mutable visitor's hook for its kind
+pub fn walk_expr_mut<V: MutVisitor>(visitor: &mut V, expr: &mut Expr) {
+    let span = expr.span;
+    match &mut expr.kind {
+        ExprKind::Int(literal) => visitor.visit_int_mut(literal, span),
+        ExprKind::Float(value) => visitor.visit_float_mut(value, span),
+        ExprKind::Bool(value) => visitor.visit_bool_mut(value, span),
+        ExprKind::String(value) => visitor.visit_string_mut(value, span),
+        ExprKind::Ident(name) => visitor.visit_ident_mut(name, span),
+        ExprKind::DefunDeploy {
+            name,
+            params,
+            return_type,
+            body,
+        } => visitor.visit_defun_deploy_mut(name, params, return_type, body, span),
+        ExprKind::BoundedFor {
+            var,
+            start,
+            end,
+            body,
+        } => visitor.visit_bounded_for_mut(var, start, end, body, span),
+        ExprKind::WithCapability { capability, body } => {
+            visitor.visit_with_capability_mut(capability, body, span)
         }
+        ExprKind::DefunCompile {
+            name,
+            params,
+            return_type,
+            body,
+        } => visitor.visit_defun_compile_mut(name, params, return_type, body, span),
+        ExprKind::Macro { name, params, body } => visitor.visit_macro_mut(name, params, body, span),
+        ExprKind::EvalCompile(expr) => visitor.visit_eval_compile_mut(expr, span),
+        ExprKind::Include(path) => visitor.visit_include_mut(path, span),
+        ExprKind::For {
+            var,
+            iterable,
+            body,
+        } => visitor.visit_for_mut(var, iterable, body, span),
+        ExprKind::While { condition, body } => visitor.visit_while_mut(condition, body, span),
+        ExprKind::Let { bindings, body } => visitor.visit_let_mut(bindings, body, span),
+        ExprKind::Set { var, value } => visitor.visit_set_mut(var, value, span),
+        ExprKind::If {
+            condition,
+            then_branch,
+            else_branch,
+        } => visitor.visit_if_mut(condition, then_branch, else_branch, span),
+        ExprKind::FunctionCall { func, args } => visitor.visit_function_call_mut(func, args, span),
+        ExprKind::ArrayLiteral { elem_type, size } => {
+            visitor.visit_array_literal_mut(elem_type, size, span)
+        }
+        ExprKind::ArrayInit {
+            elem_type,
+            elements,
+        } => visitor.visit_array_init_mut(elem_type, elements, span),
+        ExprKind::ArrayGet { array, index } => visitor.visit_array_get_mut(array, index, span),
+        ExprKind::ArraySet {
+            array,
+            index,
+            value,
+        } => visitor.visit_array_set_mut(array, index, value, span),
+        ExprKind::ArrayLength(array) => visitor.visit_array_length_mut(array, span),
+        ExprKind::GpioSet { device, value } => visitor.visit_gpio_set_mut(device, value, span),
+        ExprKind::GpioGet(device) => visitor.visit_gpio_get_mut(device, span),
+        ExprKind::UartSend { device, data } => visitor.visit_uart_send_mut(device, data, span),
+        ExprKind::UartRecv(device) => visitor.visit_uart_recv_mut(device, span),
+        ExprKind::SensorRead(device) => visitor.visit_sensor_read_mut(device, span),
+        ExprKind::NetworkSend { device, data } => {
+            visitor.visit_network_send_mut(device, data, span)
+        }
+        ExprKind::NetworkRecv(device) => visitor.visit_network_recv_mut(device, span),
+        ExprKind::SleepMs(duration) => visitor.visit_sleep_ms_mut(duration, span),
+        ExprKind::Timestamp => visitor.visit_timestamp_mut(span),
+        ExprKind::ResourceBudget { specs } => visitor.visit_resource_budget_mut(specs, span),
+        ExprKind::DefCap {
+            name,
+            params,
+            resource,
+            description,
+        } => visitor.visit_defcap_mut(name, params, resource, description, span),
+        ExprKind::Program {
+            name,
+            budget,
+            forms,
+        } => visitor.visit_program_mut(name, budget, forms, span),
+    }
+}
+
+pub fn walk_exprs_mut<V: MutVisitor>(visitor: &mut V, exprs: &mut [Expr]) {
+    for expr in exprs {
+        visitor.visit_expr_mut(expr);
     }
 }
 
+pub fn walk_params_mut<V: MutVisitor>(visitor: &mut V, params: &mut [Parameter]) {
+    for param in params {
+        visitor.visit_param_mut(param);
+    }
+}
+
+pub fn walk_defun_deploy_mut<V: MutVisitor>(
+    visitor: &mut V,
+    params: &mut [Parameter],
+    body: &mut [Expr],
+) {
+    walk_params_mut(visitor, params);
+    walk_exprs_mut(visitor, body);
+}
+
+pub fn walk_bounded_for_mut<V: MutVisitor>(
+    visitor: &mut V,
+    start: &mut Expr,
+    end: &mut Expr,
+    body: &mut [Expr],
+) {
+    visitor.visit_expr_mut(start);
+    visitor.visit_expr_mut(end);
+    walk_exprs_mut(visitor, body);
+}
+
+pub fn walk_with_capability_mut<V: MutVisitor>(
+    visitor: &mut V,
+    capability: &mut Expr,
+    body: &mut [Expr],
+) {
+    visitor.visit_expr_mut(capability);
+    walk_exprs_mut(visitor, body);
+}
+
+pub fn walk_defun_compile_mut<V: MutVisitor>(
+    visitor: &mut V,
+    params: &mut [Parameter],
+    body: &mut [Expr],
+) {
+    walk_params_mut(visitor, params);
+    walk_exprs_mut(visitor, body);
+}
+
+pub fn walk_macro_mut<V: MutVisitor>(visitor: &mut V, params: &mut [Parameter], body: &mut [Expr]) {
+    walk_params_mut(visitor, params);
+    walk_exprs_mut(visitor, body);
+}
+
+pub fn walk_eval_compile_mut<V: MutVisitor>(visitor: &mut V, expr: &mut Expr) {
+    visitor.visit_expr_mut(expr);
+}
+
+pub fn walk_for_mut<V: MutVisitor>(visitor: &mut V, iterable: &mut Expr, body: &mut [Expr]) {
+    visitor.visit_expr_mut(iterable);
+    walk_exprs_mut(visitor, body);
+}
+
+pub fn walk_while_mut<V: MutVisitor>(visitor: &mut V, condition: &mut Expr, body: &mut [Expr]) {
+    visitor.visit_expr_mut(condition);
+    walk_exprs_mut(visitor, body);
+}
+
+pub fn walk_let_mut<V: MutVisitor>(
+    visitor: &mut V,
+    bindings: &mut [(String, Expr)],
+    body: &mut [Expr],
+) {
+    for (_, value) in bindings {
+        visitor.visit_expr_mut(value);
+    }
+    walk_exprs_mut(visitor, body);
+}
+
+pub fn walk_set_mut<V: MutVisitor>(visitor: &mut V, value: &mut Expr) {
+    visitor.visit_expr_mut(value);
+}
+
+pub fn walk_if_mut<V: MutVisitor>(
+    visitor: &mut V,
+    condition: &mut Expr,
+    then_branch: &mut Expr,
+    else_branch: &mut Expr,
+) {
+    visitor.visit_expr_mut(condition);
+    visitor.visit_expr_mut(then_branch);
+    visitor.visit_expr_mut(else_branch);
+}
+
+pub fn walk_function_call_mut<V: MutVisitor>(visitor: &mut V, func: &mut Expr, args: &mut [Expr]) {
+    visitor.visit_expr_mut(func);
+    walk_exprs_mut(visitor, args);
+}
+
+pub fn walk_array_init_mut<V: MutVisitor>(visitor: &mut V, elements: &mut [Expr]) {
+    walk_exprs_mut(visitor, elements);
+}
+
+pub fn walk_array_get_mut<V: MutVisitor>(visitor: &mut V, array: &mut Expr, index: &mut Expr) {
+    visitor.visit_expr_mut(array);
+    visitor.visit_expr_mut(index);
+}
+
+pub fn walk_array_set_mut<V: MutVisitor>(
+    visitor: &mut V,
+    array: &mut Expr,
+    index: &mut Expr,
+    value: &mut Expr,
+) {
+    visitor.visit_expr_mut(array);
+    visitor.visit_expr_mut(index);
+    visitor.visit_expr_mut(value);
+}
+
+pub fn walk_array_length_mut<V: MutVisitor>(visitor: &mut V, array: &mut Expr) {
+    visitor.visit_expr_mut(array);
+}
+
+pub fn walk_gpio_set_mut<V: MutVisitor>(visitor: &mut V, device: &mut Expr, value: &mut Expr) {
+    visitor.visit_expr_mut(device);
+    visitor.visit_expr_mut(value);
+}
+
+pub fn walk_gpio_get_mut<V: MutVisitor>(visitor: &mut V, device: &mut Expr) {
+    visitor.visit_expr_mut(device);
+}
+
+pub fn walk_uart_send_mut<V: MutVisitor>(visitor: &mut V, device: &mut Expr, data: &mut Expr) {
+    visitor.visit_expr_mut(device);
+    visitor.visit_expr_mut(data);
+}
+
+pub fn walk_uart_recv_mut<V: MutVisitor>(visitor: &mut V, device: &mut Expr) {
+    visitor.visit_expr_mut(device);
+}
+
+pub fn walk_sensor_read_mut<V: MutVisitor>(visitor: &mut V, device: &mut Expr) {
+    visitor.visit_expr_mut(device);
+}
+
+pub fn walk_network_send_mut<V: MutVisitor>(visitor: &mut V, device: &mut Expr, data: &mut Expr) {
+    visitor.visit_expr_mut(device);
+    visitor.visit_expr_mut(data);
+}
+
+pub fn walk_network_recv_mut<V: MutVisitor>(visitor: &mut V, device: &mut Expr) {
+    visitor.visit_expr_mut(device);
+}
+
+pub fn walk_sleep_ms_mut<V: MutVisitor>(visitor: &mut V, duration: &mut Expr) {
+    visitor.visit_expr_mut(duration);
+}
+
+pub fn walk_defcap_mut<V: MutVisitor>(visitor: &mut V, params: &mut [Parameter]) {
+    walk_params_mut(visitor, params);
+}
+
+pub fn walk_program_mut<V: MutVisitor>(visitor: &mut V, budget: &mut Expr, forms: &mut [Expr]) {
+    visitor.visit_expr_mut(budget);
+    walk_exprs_mut(visitor, forms);
+}
+
 /// Collect all identifiers in an expression
 pub struct IdentCollector {
     pub idents: Vec<String>,
@@ -92,46 +906,108 @@ pub fn collect(expr: &Expr) -> Vec<String> {
     }
 }
 
-impl Visitor<()> for IdentCollector {
-    fn visit_expr(&mut self, expr: &Expr) -> () {
-        match expr {
-            Expr::Ident(i) => self.visit_ident(i),
-            Expr::DefunDeploy { body, .. } => {
-                for e in body {
-                    self.visit_expr(e);
-                }
-            }
-            Expr::BoundedFor {
-                var,
-                start,
-                end,
-                body,
-            } => self.visit_bounded_for(var, start, end, body),
-            Expr::FunctionCall { func, args } => self.visit_function_call(func, args),
-            Expr::Let { bindings, body } => {
-                for (_, expr) in bindings {
-                    self.visit_expr(expr);
-                }
-                for expr in body {
-                    self.visit_expr(expr);
-                }
-            }
-            Expr::If {
-                condition,
-                then_branch,
-                else_branch,
-            } => {
-                self.visit_expr(condition);
-                self.visit_expr(then_branch);
-                self.visit_expr(else_branch);
-            }
-            _ => {}
+impl Default for IdentCollector {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Visitor for IdentCollector {
+    fn visit_ident(&mut self, name: &str, _span: Span) {
+        self.idents.push(name.to_string());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_file;
+
+    const SOURCE: &str = r#"
+(program p (resource-budget (time-ms 10))
+  (defcap led (pin) "LED")
+  (defun-deploy f ((cap (capability gpio)) n)
+    (let ((buf (array-init uint8 a b)))
+      (set n (array-length buf))
+      (with-capability cap
+        (gpio-set cap (array-get buf c))
+        (network-send net (uart-recv uart))
+        (sleep-ms (if d 1 2))
+        (array-set buf 0 (timestamp)))
+      (bounded-for i 0 n (sensor-read e))))
+  (defun-compile g () (while (h) (for x xs (eval-compile k)))))
+"#;
+
+    /// Counts every expression node it is dispatched
+    #[derive(Default)]
+    struct Counter {
+        nodes: usize,
+    }
+
+    impl Visitor for Counter {
+        fn visit_expr(&mut self, expr: &Expr) {
+            self.nodes += 1;
+            walk_expr(self, expr);
         }
     }
 
-    fn visit_ident(&mut self, i: &str) -> () {
-        self.idents.push(i.to_string());
+    fn count_children(expr: &Expr) -> usize {
+        1 + expr
+            .children()
+            .into_iter()
+            .map(count_children)
+            .sum::<usize>()
+    }
+
+    #[test]
+    fn test_walk_reaches_every_node() {
+        let exprs = parse_file(SOURCE).unwrap();
+        let mut counter = Counter::default();
+        walk_exprs(&mut counter, &exprs);
+        assert_eq!(
+            counter.nodes,
+            exprs.iter().map(count_children).sum::<usize>()
+        );
     }
 
-    fn default_visit(&mut self) -> () {}
+    #[test]
+    fn test_ident_collector_sees_all_nodes() {
+        let exprs = parse_file(SOURCE).unwrap();
+        let idents = IdentCollector::collect(&exprs[0]);
+        for name in [
+            "a", "b", "buf", "cap", "c", "net", "uart", "d", "e", "h", "xs", "k",
+        ] {
+            assert!(idents.iter().any(|i| i == name), "missing {}", name);
+        }
+    }
+
+    #[test]
+    fn test_mut_visitor_rewrites_in_place() {
+        struct Rename;
+
+        impl MutVisitor for Rename {
+            fn visit_ident_mut(&mut self, name: &mut String, _span: Span) {
+                name.make_ascii_uppercase();
+            }
+
+            fn visit_param_mut(&mut self, param: &mut Parameter) {
+                param.name.make_ascii_uppercase();
+            }
+        }
+
+        let mut exprs = parse_file(SOURCE).unwrap();
+        walk_exprs_mut(&mut Rename, &mut exprs);
+        let idents = IdentCollector::collect(&exprs[0]);
+        assert!(idents
+            .iter()
+            .all(|i| i.chars().all(|c| !c.is_ascii_lowercase())));
+
+        let ExprKind::Program { forms, .. } = &exprs[0].kind else {
+            panic!("expected program");
+        };
+        let ExprKind::DefunDeploy { params, .. } = &forms[1].kind else {
+            panic!("expected defun-deploy");
+        };
+        assert_eq!(params[1].name, "N");
+    }
 }
@@ -1,7 +1,8 @@
 use clap::{Parser, Subcommand};
 use oblibeny_parser::*;
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::process;
 
 #[derive(Parser)]
 #[command(name = "oblibeny")]
@@ -19,7 +20,7 @@ enum Commands {
         #[arg(short, long)]
         input: PathBuf,
 
-        /// Output AST as JSON
+        /// Output AST as versioned JSON (see `oblibeny schema`)
         #[arg(short, long)]
         json: bool,
 
@@ -37,6 +38,55 @@ enum Commands {
         /// Verbose output
         #[arg(short, long)]
         verbose: bool,
+
+        /// Output the analyzed AST, with inferred types, as JSON
+        #[arg(short, long)]
+        json: bool,
+    },
+
+    /// Format an Oblibeny source file
+    Fmt {
+        /// Input file path
+        #[arg(short, long)]
+        input: PathBuf,
+
+        /// Report whether the file is formatted instead of printing it
+        #[arg(long, conflicts_with = "write")]
+        check: bool,
+
+        /// Rewrite the file in place
+        #[arg(short, long)]
+        write: bool,
+
+        /// Maximum line width
+        #[arg(long, default_value_t = DEFAULT_WIDTH)]
+        width: usize,
+    },
+
+    /// Encode a source file as a binary AST work unit payload
+    Encode {
+        /// Input file path
+        #[arg(short, long)]
+        input: PathBuf,
+
+        /// Output file path (defaults to the input with an `.oblb` extension)
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+
+        /// Leave source spans out of the payload
+        #[arg(long)]
+        strip_spans: bool,
+    },
+
+    /// Validate a binary AST payload and print it as source
+    Decode {
+        /// Input file path
+        #[arg(short, long)]
+        input: PathBuf,
+
+        /// Output AST as versioned JSON instead of source
+        #[arg(short, long)]
+        json: bool,
     },
 
     /// Check phase separation
@@ -60,6 +110,20 @@ enum Commands {
         input: PathBuf,
     },
 
+    /// List the symbols a program defines and how often each is used
+    Symbols {
+        /// Input file path
+        #[arg(short, long)]
+        input: PathBuf,
+    },
+
+    /// List each program's declared capabilities and where they are used
+    Capabilities {
+        /// Input file path
+        #[arg(short, long)]
+        input: PathBuf,
+    },
+
     /// Generate call graph
     CallGraph {
         /// Input file path
@@ -70,6 +134,26 @@ enum Commands {
         #[arg(short, long, default_value = "text")]
         format: String,
     },
+
+    /// Print the JSON Schema of the AST interchange format
+    Schema,
+}
+
+/// Read a source file, keeping its path for diagnostics
+fn read_source(input: &Path) -> anyhow::Result<SourceFile> {
+    let source = fs::read_to_string(input)?;
+    Ok(SourceFile::new(input.display().to_string(), source))
+}
+
+/// Parse a source file, rendering the syntax error and exiting on failure
+fn parse_or_exit(file: &SourceFile) -> Vec<Expr> {
+    match parse_file(&file.source) {
+        Ok(exprs) => exprs,
+        Err(diagnostic) => {
+            eprint!("{}", render(&diagnostic, file));
+            process::exit(1);
+        }
+    }
 }
 
 fn main() -> anyhow::Result<()> {
@@ -81,11 +165,11 @@ fn main() -> anyhow::Result<()> {
             json,
             pretty,
         } => {
-            let source = fs::read_to_string(&input)?;
-            let exprs = parse_file(&source)?;
+            let file = read_source(&input)?;
+            let exprs = parse_or_exit(&file);
 
             if json {
-                println!("{}", serde_json::to_string_pretty(&exprs)?);
+                println!("{}", serde_json::to_string_pretty(&ast_to_json(&exprs))?);
             } else if pretty {
                 for expr in &exprs {
                     println!("{}", PrettyPrinter::print(expr));
@@ -98,12 +182,86 @@ fn main() -> anyhow::Result<()> {
             }
         }
 
-        Commands::Analyze { input, verbose } => {
-            let source = fs::read_to_string(&input)?;
-            let analysis = ProgramAnalysis::analyze(&source)?;
+        Commands::Analyze {
+            input,
+            verbose,
+            json,
+        } => {
+            let analysis = ProgramAnalysis::load(&FsLoader, &input);
+            if json {
+                println!("{}", analysis.to_json()?);
+                return Ok(());
+            }
+
+            let sources = &analysis.sources;
+            let expansions = &analysis.expansions;
+
+            let mut load_errors = analysis.syntax_errors.clone();
+            load_errors.extend(analysis.include_errors.iter().map(|e| e.to_diagnostic()));
+            if !load_errors.is_empty() {
+                eprintln!("{}", render_all_in(&load_errors, sources));
+            }
 
             println!("=== Oblibeny Program Analysis ===\n");
 
+            println!("Files: {}", sources.len());
+            println!("Syntax: {}",
+                if load_errors.is_empty() {
+                    "✓ PASS".to_string()
+                } else {
+                    format!("✗ {} error(s)", load_errors.len())
+                }
+            );
+
+            println!("Macros: {}",
+                if analysis.macro_errors.is_empty() {
+                    format!("✓ PASS ({} expansion(s))", expansions.len())
+                } else {
+                    "✗ FAIL".to_string()
+                }
+            );
+
+            for e in &analysis.macro_errors {
+                println!("\n{}", render_in(&expansions.annotate(e.to_diagnostic()), sources));
+            }
+
+            println!("Compile-time Evaluation: {}",
+                if analysis.comptime_errors.is_empty() {
+                    "✓ PASS"
+                } else {
+                    "✗ FAIL"
+                }
+            );
+
+            for e in &analysis.comptime_errors {
+                println!("\n{}", render_in(&expansions.annotate(e.to_diagnostic()), sources));
+            }
+
+            let resolve_failed = analysis.resolve_errors.iter().any(|e| !e.is_warning());
+            println!("Name Resolution: {}",
+                if resolve_failed {
+                    "✗ FAIL"
+                } else {
+                    "✓ PASS"
+                }
+            );
+
+            for e in &analysis.resolve_errors {
+                println!("\n{}", render_in(&expansions.annotate(e.to_diagnostic()), sources));
+            }
+
+            println!("Type Inference: {}",
+                if analysis.inference_errors.is_empty() {
+                    "✓ PASS".to_string()
+                } else {
+                    format!("✓ PASS ({} ambiguous)", analysis.inference_errors.len())
+                }
+            );
+
+            for e in &analysis.inference_errors {
+                println!("\n{}", render_in(&expansions.annotate(e.to_diagnostic()), sources));
+            }
+
             println!("Phase Check: {}",
                 if analysis.phase_check.is_ok() {
                     "✓ PASS"
@@ -113,7 +271,80 @@ fn main() -> anyhow::Result<()> {
             );
 
             if let Err(e) = &analysis.phase_check {
-                println!("  Error: {}", e);
+                println!("\n{}", render_in(&expansions.annotate(e.to_diagnostic()), sources));
+            }
+
+            println!("\nType Check: {}",
+                if analysis.type_check.is_ok() {
+                    "✓ PASS"
+                } else {
+                    "✗ FAIL"
+                }
+            );
+
+            if let Err(errors) = &analysis.type_check {
+                for e in errors {
+                    println!("\n{}", render_in(&expansions.annotate(e.to_diagnostic()), sources));
+                }
+            }
+
+            println!("\nCapabilities: {}",
+                if analysis.capability_errors.is_empty() {
+                    "✓ PASS"
+                } else {
+                    "✗ FAIL"
+                }
+            );
+
+            for e in &analysis.capability_errors {
+                println!("\n{}", render_in(&expansions.annotate(e.to_diagnostic()), sources));
+            }
+
+            println!("Linearity: {}",
+                if analysis.linearity_errors.is_empty() {
+                    "✓ PASS"
+                } else {
+                    "✗ FAIL"
+                }
+            );
+
+            for e in &analysis.linearity_errors {
+                println!("\n{}", render_in(&expansions.annotate(e.to_diagnostic()), sources));
+            }
+
+            println!("\nCapability Budgets:");
+            for capability in &analysis.capability_budgets.capabilities {
+                let uses = match capability.uses {
+                    Some(uses) => format!("{} {}", uses, capability.unit()),
+                    None => "unbounded".to_string(),
+                };
+                let verdict = match (capability.budget, capability.within_budget()) {
+                    (Some(budget), Some(true)) => format!(" (budget {}) ✓", budget),
+                    (Some(budget), _) => format!(" (budget {}) ✗", budget),
+                    (None, _) => String::new(),
+                };
+                println!(
+                    "  {}: {} ({}): {}{}",
+                    capability.function, capability.name, capability.resource, uses, verdict
+                );
+            }
+
+            for e in &analysis.capability_budgets.errors() {
+                println!("\n{}", render_in(&expansions.annotate(e.to_diagnostic()), sources));
+            }
+
+            let manifests = &analysis.capability_manifests;
+            let manifest_failed = manifests.errors.iter().any(|e| !e.is_warning());
+            println!("\nCapability Declarations: {}",
+                if manifest_failed {
+                    "✗ FAIL"
+                } else {
+                    "✓ PASS"
+                }
+            );
+
+            for e in &manifests.errors {
+                println!("\n{}", render_in(&expansions.annotate(e.to_diagnostic()), sources));
             }
 
             println!("\nTermination Check: {}",
@@ -125,7 +356,34 @@ fn main() -> anyhow::Result<()> {
             );
 
             if let Err(e) = &analysis.termination_check {
-                println!("  Error: {}", e);
+                println!("\n{}", render_in(&expansions.annotate(e.to_diagnostic()), sources));
+            }
+
+            let warnings = analysis.bounds_errors.iter().filter(|e| e.is_warning()).count();
+            println!("\nArray Bounds: {}",
+                if warnings < analysis.bounds_errors.len() {
+                    "✗ FAIL".to_string()
+                } else if warnings > 0 {
+                    format!("✓ PASS ({} unproven)", warnings)
+                } else {
+                    "✓ PASS".to_string()
+                }
+            );
+
+            for e in &analysis.bounds_errors {
+                println!("\n{}", render_in(&expansions.annotate(e.to_diagnostic()), sources));
+            }
+
+            println!("\nLiteral Ranges: {}",
+                if analysis.literal_errors.is_empty() {
+                    "✓ PASS"
+                } else {
+                    "✗ FAIL"
+                }
+            );
+
+            for e in &analysis.literal_errors {
+                println!("\n{}", render_in(&expansions.annotate(e.to_diagnostic()), sources));
             }
 
             println!("\nResource Bounds (WCET):");
@@ -133,6 +391,9 @@ fn main() -> anyhow::Result<()> {
             println!("  Memory: {} bytes", analysis.resource_bounds.memory_bytes);
             println!("  Network: {} bytes", analysis.resource_bounds.network_bytes);
             println!("  Storage: {} bytes", analysis.resource_bounds.storage_bytes);
+            if analysis.resource_bounds.unbounded {
+                println!("  Unbounded: a loop trip count is not a constant");
+            }
 
             println!("\nCall Graph:");
             println!("  Functions: {}", analysis.call_graph.function_count());
@@ -158,50 +419,131 @@ fn main() -> anyhow::Result<()> {
             }
         }
 
+        Commands::Fmt {
+            input,
+            check,
+            write,
+            width,
+        } => {
+            let file = read_source(&input)?;
+            let exprs = parse_or_exit(&file);
+            let formatted = PrettyPrinter::with_width(width).format_file(&exprs, &file.source);
+
+            if check {
+                if formatted != file.source {
+                    let (old, new) = (file.source.lines(), formatted.lines());
+                    let line = old
+                        .clone()
+                        .zip(new.clone())
+                        .position(|(a, b)| a != b)
+                        .unwrap_or_else(|| old.count().min(new.count()));
+                    println!(
+                        "✗ {}: not formatted (first difference at line {})",
+                        input.display(),
+                        line + 1
+                    );
+                    process::exit(1);
+                }
+                println!("✓ {}: formatted", input.display());
+            } else if write {
+                if formatted != file.source {
+                    fs::write(&input, formatted)?;
+                }
+            } else {
+                print!("{}", formatted);
+            }
+        }
+
+        Commands::Encode {
+            input,
+            output,
+            strip_spans,
+        } => {
+            let file = read_source(&input)?;
+            let exprs = parse_or_exit(&file);
+            let encoder = if strip_spans {
+                BinaryEncoder::new().without_spans()
+            } else {
+                BinaryEncoder::new()
+            };
+            let bytes = encoder.encode(&exprs);
+
+            let output = output.unwrap_or_else(|| input.with_extension("oblb"));
+            fs::write(&output, &bytes)?;
+            println!(
+                "✓ {}: {} bytes ({} bytes of source)",
+                output.display(),
+                bytes.len(),
+                file.source.len()
+            );
+        }
+
+        Commands::Decode { input, json } => {
+            let exprs = match decode_binary(&fs::read(&input)?) {
+                Ok(exprs) => exprs,
+                Err(e) => {
+                    eprintln!("✗ {}: {}", input.display(), e);
+                    process::exit(1);
+                }
+            };
+
+            if json {
+                println!("{}", serde_json::to_string_pretty(&ast_to_json(&exprs))?);
+            } else {
+                for expr in &exprs {
+                    println!("{}", PrettyPrinter::print(expr));
+                    println!();
+                }
+            }
+        }
+
         Commands::CheckPhases { input } => {
-            let source = fs::read_to_string(&input)?;
-            let exprs = parse_file(&source)?;
+            let file = read_source(&input)?;
+            let exprs = parse_or_exit(&file);
             let separator = PhaseSeparator::new();
 
             match separator.validate_deploy_phase(&exprs) {
                 Ok(()) => println!("✓ Phase separation: PASS"),
                 Err(e) => {
-                    println!("✗ Phase separation: FAIL");
-                    println!("  {}", e);
-                    std::process::exit(1);
+                    println!("✗ Phase separation: FAIL\n");
+                    print!("{}", render(&e.to_diagnostic(), &file));
+                    process::exit(1);
                 }
             }
         }
 
         Commands::CheckTermination { input } => {
-            let source = fs::read_to_string(&input)?;
-            let exprs = parse_file(&source)?;
+            let file = read_source(&input)?;
+            let exprs = parse_or_exit(&file);
             let checker = TerminationChecker::new(&exprs);
 
             match checker.check_terminates(&exprs) {
                 Ok(()) => println!("✓ Termination: GUARANTEED"),
                 Err(e) => {
-                    println!("✗ Termination: CANNOT PROVE");
-                    println!("  {}", e);
-                    std::process::exit(1);
+                    println!("✗ Termination: CANNOT PROVE\n");
+                    print!("{}", render(&e.to_diagnostic(), &file));
+                    process::exit(1);
                 }
             }
         }
 
         Commands::Resources { input } => {
-            let source = fs::read_to_string(&input)?;
-            let exprs = parse_file(&source)?;
+            let file = read_source(&input)?;
+            let exprs = parse_or_exit(&file);
             let analyzer = ResourceAnalyzer::new();
 
             println!("=== Resource Analysis ===\n");
 
-            for expr in &exprs {
-                if let Expr::DefunDeploy { name, .. } = expr {
-                    let bounds = analyzer.analyze(expr);
+            for function in PhaseSeparator::new().extract_deploy_functions(&exprs) {
+                if let ExprKind::DefunDeploy { name, .. } = &function.kind {
+                    let bounds = analyzer.analyze(function);
                     println!("Function: {}", name);
                     println!("  Time: {} ms", bounds.time_ms);
                     println!("  Memory: {} bytes", bounds.memory_bytes);
                     println!("  Network: {} bytes", bounds.network_bytes);
+                    if bounds.unbounded {
+                        println!("  Unbounded: a loop trip count is not a constant");
+                    }
                     println!();
                 }
             }
@@ -214,9 +556,73 @@ fn main() -> anyhow::Result<()> {
             }
         }
 
+        Commands::Symbols { input } => {
+            let analysis = ProgramAnalysis::load(&FsLoader, &input);
+            let table = &analysis.symbols;
+
+            // Source order puts each function's locals right after it
+            let mut symbols: Vec<(SymbolId, &Symbol)> = table
+                .symbols()
+                .iter()
+                .enumerate()
+                .map(|(i, symbol)| (SymbolId(i as u32), symbol))
+                .collect();
+            symbols.sort_by_key(|(_, s)| (s.span.file.0, s.span.start));
+
+            for (id, symbol) in symbols {
+                let file = analysis
+                    .sources
+                    .file_of(symbol.span)
+                    .map_or("<unknown>", |f| f.name.as_str());
+                println!(
+                    "{}{} [{}] at {}:{}, {} reference(s)",
+                    "  ".repeat(table.depth(symbol.scope)),
+                    symbol.name,
+                    symbol.kind,
+                    file,
+                    symbol.span,
+                    table.references_to(id).len()
+                );
+            }
+        }
+
+        Commands::Capabilities { input } => {
+            let analysis = ProgramAnalysis::load(&FsLoader, &input);
+
+            for manifest in &analysis.capability_manifests.manifests {
+                match &manifest.program {
+                    Some(name) => println!("Program {}:", name),
+                    None => println!("Top level:"),
+                }
+                for capability in &manifest.capabilities {
+                    let resource = capability
+                        .resource
+                        .as_ref()
+                        .map_or("no resource".to_string(), |r| r.to_string());
+                    println!(
+                        "  {} ({}) at {}: {}",
+                        capability.name, resource, capability.span, capability.description
+                    );
+                    for site in &capability.uses {
+                        let kind = match &site.kind {
+                            UseKind::Parameter(name) => format!("parameter `{}`", name),
+                            UseKind::Scope => "with-capability".to_string(),
+                            UseKind::Call => "call".to_string(),
+                        };
+                        println!("    {} in {} at {}", kind, site.function, site.span);
+                    }
+                }
+            }
+
+            for e in &analysis.capability_manifests.errors {
+                let diagnostic = analysis.expansions.annotate(e.to_diagnostic());
+                eprintln!("\n{}", render_in(&diagnostic, &analysis.sources));
+            }
+        }
+
         Commands::CallGraph { input, format } => {
-            let source = fs::read_to_string(&input)?;
-            let exprs = parse_file(&source)?;
+            let file = read_source(&input)?;
+            let exprs = parse_or_exit(&file);
             let cg = CallGraph::build(&exprs);
 
             match format.as_str() {
@@ -243,10 +649,14 @@ fn main() -> anyhow::Result<()> {
                 }
                 _ => {
                     eprintln!("Unknown format: {}", format);
-                    std::process::exit(1);
+                    process::exit(1);
                 }
             }
         }
+
+        Commands::Schema => {
+            println!("{}", serde_json::to_string_pretty(&ast_json_schema())?);
+        }
     }
 
     Ok(())
@@ -0,0 +1,148 @@
+//! Stable diagnostic codes.
+//!
+//! Codes are grouped by the pass that emits them and are never reused once
+//! published, since CI scripts and editor integrations match on them:
+//!
+//! | Range        | Pass                  |
+//! |--------------|-----------------------|
+//! | OBL0001–0099 | parsing and lowering  |
+//! | OBL0100–0199 | phase separation      |
+//! | OBL0200–0299 | termination checking  |
+//! | OBL0300–0399 | type checking         |
+//! | OBL0400–0499 | expansion             |
+//! | OBL0500–0599 | name resolution       |
+//! | OBL0600–0699 | capability checking   |
+//! | OBL0700–0799 | bounds checking       |
+
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct Code(pub u16);
+
+impl fmt::Display for Code {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "OBL{:04}", self.0)
+    }
+}
+
+// === PARSING ===
+
+/// Source text does not match the grammar
+pub const SYNTAX_ERROR: Code = Code(1);
+/// Literal is well-formed but cannot be represented
+pub const INVALID_LITERAL: Code = Code(2);
+/// Type name not known to the compiler
+pub const UNKNOWN_TYPE: Code = Code(3);
+/// Resource or budget kind not known to the compiler
+pub const UNKNOWN_RESOURCE: Code = Code(4);
+/// String literal contains an escape the language does not define
+pub const INVALID_ESCAPE: Code = Code(5);
+
+// === PHASE SEPARATION ===
+
+/// Compile-time construct used in deploy-time code
+pub const COMPILE_IN_DEPLOY: Code = Code(101);
+/// Expression mixes compile-time and deploy-time evaluation
+pub const MIXED_PHASE: Code = Code(102);
+/// Recursion found while separating phases
+pub const RECURSION_IN_DEPLOY: Code = Code(103);
+
+// === TERMINATION ===
+
+/// Deploy-time call graph contains a cycle
+pub const RECURSION: Code = Code(201);
+/// Loop without a static bound in deploy-time code
+pub const UNBOUNDED_LOOP: Code = Code(202);
+/// `bounded-for` whose bounds are not provably finite
+pub const UNKNOWN_BOUNDS: Code = Code(203);
+/// Deployment declares no finite resource budget
+pub const INFINITE_RESOURCES: Code = Code(204);
+
+// === TYPE CHECKING ===
+
+/// Integer literal outside the range of the type its context expects
+pub const LITERAL_OUT_OF_RANGE: Code = Code(301);
+/// Expression has a different type than its context requires
+pub const TYPE_MISMATCH: Code = Code(302);
+/// Array operation applied to something that is not an array
+pub const NOT_AN_ARRAY: Code = Code(303);
+/// Operator applied to a type it is not defined for
+pub const INVALID_OPERAND: Code = Code(304);
+/// Inference could not decide the type of a parameter or binding
+pub const AMBIGUOUS_TYPE: Code = Code(305);
+
+// === EXPANSION ===
+
+/// Included file cannot be read
+pub const INCLUDE_NOT_FOUND: Code = Code(401);
+/// File includes itself, directly or through other files
+pub const INCLUDE_CYCLE: Code = Code(402);
+/// Macro called with the wrong number of arguments
+pub const MACRO_ARITY: Code = Code(403);
+/// Macro expansion nested deeper than the expansion limit
+pub const MACRO_DEPTH: Code = Code(404);
+/// Compile-time evaluation did not finish within its fuel
+pub const COMPTIME_FUEL: Code = Code(405);
+/// Compile-time evaluation failed
+pub const COMPTIME_ERROR: Code = Code(406);
+/// Compile-time result has no literal form to splice into the code
+pub const NOT_SPLICEABLE: Code = Code(407);
+/// Macro expansion produced more code than the expansion limit
+pub const MACRO_SIZE: Code = Code(408);
+
+// === NAME RESOLUTION ===
+
+/// Variable or `set` target not bound in any enclosing scope
+pub const UNDEFINED_VARIABLE: Code = Code(501);
+/// Call to a name that is neither defined nor built in
+pub const UNDEFINED_FUNCTION: Code = Code(502);
+/// Call with the wrong number of arguments
+pub const ARITY_MISMATCH: Code = Code(503);
+/// Binding hides another binding of the same name
+pub const SHADOWED_NAME: Code = Code(504);
+/// Name defined twice in the same scope
+pub const DUPLICATE_DEFINITION: Code = Code(505);
+
+// === CAPABILITIES ===
+
+/// I/O operation outside of every `with-capability` block
+pub const IO_OUTSIDE_CAPABILITY: Code = Code(601);
+/// I/O operation under a capability for a different resource
+pub const WRONG_CAPABILITY: Code = Code(602);
+/// `with-capability` applied to a value that is not a capability
+pub const NOT_A_CAPABILITY: Code = Code(603);
+/// Capability held in two places at once
+pub const DUPLICATED_CAPABILITY: Code = Code(604);
+/// Capability copied into a binding, data structure or result
+pub const LEAKED_CAPABILITY: Code = Code(605);
+/// Capability parameter the function never uses
+pub const UNUSED_CAPABILITY: Code = Code(606);
+/// Capability may be used more than its declared budget allows
+pub const CAPABILITY_OVER_BUDGET: Code = Code(607);
+/// Capability with a budget whose uses have no static bound
+pub const UNBOUNDED_CAPABILITY: Code = Code(608);
+/// Capability for a resource no `defcap` in the program declares
+pub const UNDECLARED_CAPABILITY: Code = Code(609);
+/// Capability for a resource several `defcap`s declare
+pub const AMBIGUOUS_CAPABILITY: Code = Code(610);
+/// `defcap` that nothing in the program uses
+pub const UNUSED_DEFCAP: Code = Code(611);
+
+// === BOUNDS CHECKING ===
+
+/// Array access whose index is out of bounds on every path
+pub const INDEX_OUT_OF_BOUNDS: Code = Code(701);
+/// Array access whose index cannot be shown to be in bounds
+pub const INDEX_MAY_BE_OUT_OF_BOUNDS: Code = Code(702);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_code_format() {
+        assert_eq!(SYNTAX_ERROR.to_string(), "OBL0001");
+        assert_eq!(UNKNOWN_BOUNDS.to_string(), "OBL0203");
+    }
+}
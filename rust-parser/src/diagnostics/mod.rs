@@ -0,0 +1,9 @@
+pub mod codes;
+pub mod diagnostic;
+pub mod render;
+pub mod source;
+
+pub use codes::Code;
+pub use diagnostic::*;
+pub use render::*;
+pub use source::*;
@@ -0,0 +1,160 @@
+use super::diagnostic::{Diagnostic, Label};
+use super::source::{SourceFile, SourceMap};
+
+/// Render a diagnostic with the offending source lines and carets
+///
+/// ```text
+/// error[OBL0202]: unbounded loop in deploy-time code
+///  --> monitor.obl:4:5
+///   |
+/// 4 |     (while running
+///   |     ^^^^^^^^^^^^^^ `while` loop has no static bound
+///   |
+///   = help: use `bounded-for` with a static iteration count
+/// ```
+pub fn render(diagnostic: &Diagnostic, file: &SourceFile) -> String {
+    let mut out = format!(
+        "{}[{}]: {}\n",
+        diagnostic.severity, diagnostic.code, diagnostic.message
+    );
+
+    let mut labels: Vec<(&Label, bool)> = vec![(&diagnostic.primary, true)];
+    labels.extend(diagnostic.secondary.iter().map(|l| (l, false)));
+    labels.retain(|(l, _)| !l.span.is_dummy() && l.span.file == file.id());
+
+    let gutter = labels
+        .iter()
+        .map(|(l, _)| l.span.line.to_string().len())
+        .max()
+        .unwrap_or(0);
+    let pad = " ".repeat(gutter);
+
+    if let Some((primary, _)) = labels.first() {
+        out.push_str(&format!("{}--> {}\n", pad, file.location(primary.span)));
+        out.push_str(&format!("{} |\n", pad));
+
+        let mut lines: Vec<usize> = labels.iter().map(|(l, _)| l.span.line).collect();
+        lines.sort_unstable();
+        lines.dedup();
+
+        for line in lines {
+            let text = file.line_text(line).unwrap_or("");
+            out.push_str(&format!("{:>width$} | {}\n", line, text, width = gutter));
+
+            for (label, is_primary) in labels.iter().filter(|(l, _)| l.span.line == line) {
+                let marker = if *is_primary { '^' } else { '-' };
+                out.push_str(&format!(
+                    "{} | {}\n",
+                    pad,
+                    underline(file, text, label, marker).trim_end()
+                ));
+            }
+        }
+    }
+
+    if !diagnostic.notes.is_empty() || diagnostic.help.is_some() {
+        out.push_str(&format!("{} |\n", pad));
+    }
+    for note in &diagnostic.notes {
+        out.push_str(&format!("{} = note: {}\n", pad, note));
+    }
+    if let Some(help) = &diagnostic.help {
+        out.push_str(&format!("{} = help: {}\n", pad, help));
+    }
+
+    out
+}
+
+/// Render several diagnostics separated by blank lines
+pub fn render_all(diagnostics: &[Diagnostic], file: &SourceFile) -> String {
+    diagnostics
+        .iter()
+        .map(|d| render(d, file))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Render a diagnostic against whichever file of the project it points into
+pub fn render_in(diagnostic: &Diagnostic, sources: &SourceMap) -> String {
+    match sources.file_of(diagnostic.span()) {
+        Some(file) => render(diagnostic, file),
+        None => render(diagnostic, &SourceFile::new("<unknown>", "")),
+    }
+}
+
+/// `render_all` for diagnostics spread over several files
+pub fn render_all_in(diagnostics: &[Diagnostic], sources: &SourceMap) -> String {
+    diagnostics
+        .iter()
+        .map(|d| render_in(d, sources))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Marker row for one label; spans running past the line are cut at its end
+fn underline(file: &SourceFile, text: &str, label: &Label, marker: char) -> String {
+    let column = label.span.column.saturating_sub(1);
+
+    // Keep tabs so the carets line up with the source line above
+    let mut row: String = text
+        .chars()
+        .take(column)
+        .map(|c| if c == '\t' { '\t' } else { ' ' })
+        .collect();
+
+    let line_end = file
+        .index()
+        .line_start(label.span.line)
+        .map(|start| start + text.len())
+        .unwrap_or(label.span.end);
+    let end = label.span.end.min(line_end).max(label.span.start);
+    let width = file
+        .source
+        .get(label.span.start..end)
+        .map(|s| s.chars().count())
+        .unwrap_or(0)
+        .max(1);
+
+    row.extend(std::iter::repeat_n(marker, width));
+    if !label.message.is_empty() {
+        row.push(' ');
+        row.push_str(&label.message);
+    }
+    row
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::Span;
+    use crate::diagnostics::codes;
+
+    #[test]
+    fn test_render_snippet() {
+        let file = SourceFile::new("loop.obl", "(defun-deploy f ()\n  (spin 1))\n");
+        let diag = Diagnostic::error(codes::RECURSION, "recursion", Span::new(21, 29, 2, 3))
+            .with_label("calls itself")
+            .with_secondary(Span::new(0, 30, 1, 1), "in this function")
+            .with_help("unroll it");
+
+        let expected = "\
+error[OBL0201]: recursion
+ --> loop.obl:2:3
+  |
+1 | (defun-deploy f ()
+  | ------------------ in this function
+2 |   (spin 1))
+  |   ^^^^^^^^ calls itself
+  |
+  = help: unroll it
+";
+        assert_eq!(render(&diag, &file), expected);
+    }
+
+    #[test]
+    fn test_render_without_span() {
+        let file = SourceFile::new("a.obl", "");
+        let diag = Diagnostic::error(codes::INFINITE_RESOURCES, "no budget", Span::dummy());
+        assert_eq!(render(&diag, &file), "error[OBL0204]: no budget\n");
+    }
+}
@@ -0,0 +1,91 @@
+use crate::ast::{FileId, LineIndex, Span};
+
+/// A named source text that diagnostics can point into
+#[derive(Debug, Clone)]
+pub struct SourceFile {
+    pub name: String,
+    pub source: String,
+    index: LineIndex,
+}
+
+impl SourceFile {
+    pub fn new(name: impl Into<String>, source: impl Into<String>) -> Self {
+        Self::with_id(FileId::default(), name, source)
+    }
+
+    fn with_id(id: FileId, name: impl Into<String>, source: impl Into<String>) -> Self {
+        let source = source.into();
+        let index = LineIndex::for_file(&source, id);
+        Self {
+            name: name.into(),
+            source,
+            index,
+        }
+    }
+
+    pub fn id(&self) -> FileId {
+        self.index.file()
+    }
+
+    pub fn index(&self) -> &LineIndex {
+        &self.index
+    }
+
+    /// Text of a 1-based line, without its line terminator
+    pub fn line_text(&self, line: usize) -> Option<&str> {
+        let start = self.index.line_start(line)?;
+        let end = self
+            .index
+            .line_start(line + 1)
+            .unwrap_or(self.source.len());
+        Some(self.source[start..end].trim_end_matches(['\n', '\r']))
+    }
+
+    /// `name:line:column` for a span in this file
+    pub fn location(&self, span: Span) -> String {
+        if span.is_dummy() {
+            self.name.clone()
+        } else {
+            format!("{}:{}", self.name, span)
+        }
+    }
+}
+
+/// Every file of a project, addressed by the `FileId` in their spans
+#[derive(Debug, Clone, Default)]
+pub struct SourceMap {
+    files: Vec<SourceFile>,
+}
+
+impl SourceMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&mut self, name: impl Into<String>, source: impl Into<String>) -> FileId {
+        let id = FileId(self.files.len() as u32);
+        self.files.push(SourceFile::with_id(id, name, source));
+        id
+    }
+
+    pub fn get(&self, id: FileId) -> Option<&SourceFile> {
+        self.files.get(id.0 as usize)
+    }
+
+    /// The file a span points into
+    pub fn file_of(&self, span: Span) -> Option<&SourceFile> {
+        self.get(span.file)
+    }
+
+    pub fn files(&self) -> &[SourceFile] {
+        &self.files
+    }
+
+    pub fn len(&self) -> usize {
+        self.files.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+}
@@ -0,0 +1,854 @@
+use crate::ast::{is_builtin, Expr, ExprKind, IntLiteral, Span, Type};
+use crate::diagnostics::{codes, Diagnostic, ToDiagnostic};
+use std::collections::HashMap;
+use std::rc::Rc;
+use thiserror::Error;
+
+/// Steps a single `eval-compile` may take before it is abandoned
+pub const DEFAULT_FUEL: u64 = 1_000_000;
+
+/// Nested function calls a compile-time evaluation may make
+const MAX_CALL_DEPTH: usize = 256;
+
+/// A value computed at compile time
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Int(i128),
+    Float(f64),
+    Bool(bool),
+    String(String),
+    Array {
+        elem_type: Type,
+        elements: Vec<Value>,
+    },
+    Void,
+}
+
+impl Value {
+    pub fn type_name(&self) -> String {
+        match self {
+            Value::Int(_) => "integer".to_string(),
+            Value::Float(_) => "float".to_string(),
+            Value::Bool(_) => "bool".to_string(),
+            Value::String(_) => "string".to_string(),
+            Value::Array {
+                elem_type,
+                elements,
+            } => Type::Array {
+                elem_type: Box::new(elem_type.clone()),
+                size: elements.len(),
+            }
+            .to_string(),
+            Value::Void => "void".to_string(),
+        }
+    }
+
+    /// The literal form that evaluates to this value, if there is one
+    pub fn to_expr(&self, span: Span) -> Option<Expr> {
+        let kind = match self {
+            Value::Int(n) => {
+                if *n < i64::MIN as i128 || *n > u64::MAX as i128 {
+                    return None;
+                }
+                ExprKind::Int(IntLiteral::new(*n))
+            }
+            Value::Float(f) => ExprKind::Float(*f),
+            Value::Bool(b) => ExprKind::Bool(*b),
+            Value::String(s) => ExprKind::String(s.clone()),
+            Value::Array {
+                elem_type,
+                elements,
+            } => ExprKind::ArrayInit {
+                elem_type: elem_type.clone(),
+                elements: elements
+                    .iter()
+                    .map(|e| e.to_expr(span))
+                    .collect::<Option<_>>()?,
+            },
+            Value::Void => return None,
+        };
+        Some(Expr::new(kind, span))
+    }
+}
+
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum EvalError {
+    #[error("Compile-time evaluation ran out of fuel after {fuel} steps at {span}")]
+    OutOfFuel { fuel: u64, span: Span },
+
+    #[error("Compile-time evaluation failed: {message} at {span}")]
+    Failed {
+        message: String,
+        span: Span,
+        /// The `eval-compile` form being evaluated
+        site: Span,
+    },
+
+    #[error("Compile-time result of type {ty} cannot be spliced at {span}")]
+    NotSpliceable { ty: String, span: Span },
+}
+
+impl EvalError {
+    pub fn span(&self) -> Span {
+        match self {
+            EvalError::OutOfFuel { span, .. }
+            | EvalError::Failed { span, .. }
+            | EvalError::NotSpliceable { span, .. } => *span,
+        }
+    }
+}
+
+impl ToDiagnostic for EvalError {
+    fn to_diagnostic(&self) -> Diagnostic {
+        match self {
+            EvalError::OutOfFuel { fuel, span } => Diagnostic::error(
+                codes::COMPTIME_FUEL,
+                format!("compile-time evaluation did not finish in {} steps", fuel),
+                *span,
+            )
+            .with_label("evaluated here")
+            .with_help("check for a `for` or `while` loop that never ends"),
+            EvalError::Failed {
+                message,
+                span,
+                site,
+            } => {
+                let diagnostic = Diagnostic::error(codes::COMPTIME_ERROR, message.clone(), *span);
+                if site == span {
+                    diagnostic
+                } else {
+                    diagnostic.with_secondary(*site, "while evaluating this")
+                }
+            }
+            EvalError::NotSpliceable { ty, span } => Diagnostic::error(
+                codes::NOT_SPLICEABLE,
+                "compile-time result cannot be used as a literal",
+                *span,
+            )
+            .with_label(format!("this evaluates to {}", ty))
+            .with_note(
+                "only integers, floats, booleans, strings and arrays of them can be spliced",
+            ),
+        }
+    }
+}
+
+struct Function {
+    params: Vec<String>,
+    body: Vec<Expr>,
+}
+
+/// Interpreter for the compile phase.
+///
+/// Unlike deploy code, compile-time code may loop with `for` and `while`,
+/// so every evaluation runs on a fuel allowance and is abandoned when it
+/// runs out. Both `defun-compile` and `defun-deploy` functions can be
+/// called; I/O and capabilities are not available.
+pub struct Evaluator {
+    functions: HashMap<String, Rc<Function>>,
+    fuel: u64,
+}
+
+impl Evaluator {
+    /// Make the functions defined at the top level of `exprs`, and inside
+    /// `program` forms, callable
+    pub fn new(exprs: &[Expr]) -> Self {
+        let mut evaluator = Self {
+            functions: HashMap::new(),
+            fuel: DEFAULT_FUEL,
+        };
+        for expr in exprs {
+            evaluator.collect(expr);
+        }
+        evaluator
+    }
+
+    pub fn with_fuel(mut self, fuel: u64) -> Self {
+        self.fuel = fuel;
+        self
+    }
+
+    /// Evaluate `expr` with a fresh fuel allowance
+    pub fn eval(&self, expr: &Expr) -> Result<Value, EvalError> {
+        self.machine(expr.span).eval(expr)
+    }
+
+    /// Replace every `eval-compile` form outside compile-time definitions
+    /// by the literal it evaluates to. Forms that fail to evaluate are left
+    /// in place.
+    pub fn splice(&self, exprs: &mut [Expr]) -> Vec<EvalError> {
+        let mut errors = Vec::new();
+        for expr in exprs {
+            self.splice_expr(expr, &mut errors);
+        }
+        errors
+    }
+
+    fn splice_expr(&self, expr: &mut Expr, errors: &mut Vec<EvalError>) {
+        match &expr.kind {
+            // Evaluated whenever the definition itself runs
+            ExprKind::DefunCompile { .. } | ExprKind::Macro { .. } => return,
+            ExprKind::EvalCompile(inner) => {
+                let span = expr.span;
+                match self.machine(span).eval(inner) {
+                    Ok(value) => match value.to_expr(span) {
+                        Some(literal) => *expr = literal,
+                        None => errors.push(EvalError::NotSpliceable {
+                            ty: value.type_name(),
+                            span,
+                        }),
+                    },
+                    Err(e) => errors.push(e),
+                }
+                return;
+            }
+            _ => {}
+        }
+
+        for child in expr.children_mut() {
+            self.splice_expr(child, errors);
+        }
+    }
+
+    /// A fresh evaluation on behalf of the form at `site`
+    fn machine(&self, site: Span) -> Machine<'_> {
+        Machine {
+            functions: &self.functions,
+            fuel: self.fuel,
+            limit: self.fuel,
+            site,
+            frames: vec![Vec::new()],
+        }
+    }
+
+    fn collect(&mut self, expr: &Expr) {
+        match &expr.kind {
+            ExprKind::DefunDeploy {
+                name, params, body, ..
+            }
+            | ExprKind::DefunCompile {
+                name, params, body, ..
+            } => {
+                let function = Function {
+                    params: params.iter().map(|p| p.name.clone()).collect(),
+                    body: body.clone(),
+                };
+                self.functions.insert(name.clone(), Rc::new(function));
+            }
+            ExprKind::Program { forms, .. } => {
+                for form in forms {
+                    self.collect(form);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// State of one evaluation
+struct Machine<'e> {
+    functions: &'e HashMap<String, Rc<Function>>,
+    fuel: u64,
+    limit: u64,
+    site: Span,
+    /// Local variables of each active call, innermost binding last
+    frames: Vec<Vec<(String, Value)>>,
+}
+
+type Eval<T> = Result<T, EvalError>;
+
+impl Machine<'_> {
+    fn eval(&mut self, expr: &Expr) -> Eval<Value> {
+        self.spend(1)?;
+
+        match &expr.kind {
+            ExprKind::Int(literal) => Ok(Value::Int(literal.value)),
+            ExprKind::Float(f) => Ok(Value::Float(*f)),
+            ExprKind::Bool(b) => Ok(Value::Bool(*b)),
+            ExprKind::String(s) => Ok(Value::String(s.clone())),
+
+            ExprKind::Ident(name) => match self.lookup(name) {
+                Some(value) => Ok(value.clone()),
+                None => self.fail(
+                    expr.span,
+                    format!("`{}` has no value at compile time", name),
+                ),
+            },
+
+            // Bindings are evaluated in the enclosing scope, as in T-Let
+            ExprKind::Let { bindings, body } => {
+                let mut values = Vec::with_capacity(bindings.len());
+                for (name, value) in bindings {
+                    values.push((name.clone(), self.eval(value)?));
+                }
+                self.scoped(values, |m| m.eval_body(body))
+            }
+
+            ExprKind::If {
+                condition,
+                then_branch,
+                else_branch,
+            } => {
+                if self.bool(condition)? {
+                    self.eval(then_branch)
+                } else {
+                    self.eval(else_branch)
+                }
+            }
+
+            ExprKind::Set { var, value } => {
+                let value = self.eval(value)?;
+                match self.lookup_mut(var) {
+                    Some(slot) => {
+                        *slot = value;
+                        Ok(Value::Void)
+                    }
+                    None => self.fail(expr.span, format!("`{}` is not a variable", var)),
+                }
+            }
+
+            ExprKind::BoundedFor {
+                var,
+                start,
+                end,
+                body,
+            } => {
+                let (start, end) = (self.int(start)?, self.int(end)?);
+                for i in start..end {
+                    self.spend(1)?;
+                    self.scoped(vec![(var.clone(), Value::Int(i))], |m| m.eval_body(body))?;
+                }
+                Ok(Value::Void)
+            }
+
+            ExprKind::For {
+                var,
+                iterable,
+                body,
+            } => {
+                let elements = match self.eval(iterable)? {
+                    Value::Array { elements, .. } => elements,
+                    other => return self.expected("an array", &other, iterable.span),
+                };
+                for element in elements {
+                    self.spend(1)?;
+                    self.scoped(vec![(var.clone(), element)], |m| m.eval_body(body))?;
+                }
+                Ok(Value::Void)
+            }
+
+            ExprKind::While { condition, body } => {
+                while self.bool(condition)? {
+                    self.eval_body(body)?;
+                }
+                Ok(Value::Void)
+            }
+
+            ExprKind::ArrayLiteral { elem_type, size } => {
+                // Nested arrays are filled too, so every element costs fuel
+                self.spend(element_count(elem_type, *size).unwrap_or(u64::MAX))?;
+                match zero(elem_type) {
+                    Some(zero) => Ok(Value::Array {
+                        elem_type: elem_type.clone(),
+                        elements: vec![zero; *size],
+                    }),
+                    None => self.fail(
+                        expr.span,
+                        format!("arrays of `{}` cannot be built at compile time", elem_type),
+                    ),
+                }
+            }
+
+            ExprKind::ArrayInit {
+                elem_type,
+                elements,
+            } => {
+                let mut values = Vec::with_capacity(elements.len());
+                for element in elements {
+                    let value = self.eval(element)?;
+                    if let Err(message) = check_element(&value, elem_type) {
+                        return self.fail(element.span, message);
+                    }
+                    values.push(value);
+                }
+                Ok(Value::Array {
+                    elem_type: elem_type.clone(),
+                    elements: values,
+                })
+            }
+
+            ExprKind::ArrayGet { array, index } => {
+                // A variable is indexed in place rather than copied whole
+                if let ExprKind::Ident(name) = &array.kind {
+                    self.spend(1)?;
+                    let i = self.int(index)?;
+                    return match self.lookup(name) {
+                        Some(Value::Array { elements, .. }) => {
+                            let i = self.index(i, elements.len(), index.span)?;
+                            Ok(elements[i].clone())
+                        }
+                        Some(other) => self.expected("an array", other, array.span),
+                        None => self.fail(
+                            array.span,
+                            format!("`{}` has no value at compile time", name),
+                        ),
+                    };
+                }
+                let mut elements = match self.eval(array)? {
+                    Value::Array { elements, .. } => elements,
+                    other => return self.expected("an array", &other, array.span),
+                };
+                let i = self.int(index)?;
+                let i = self.index(i, elements.len(), index.span)?;
+                Ok(elements.swap_remove(i))
+            }
+
+            ExprKind::ArraySet {
+                array,
+                index,
+                value,
+            } => {
+                let ExprKind::Ident(name) = &array.kind else {
+                    return self.fail(array.span, "only arrays held in variables can be modified");
+                };
+                let i = self.int(index)?;
+                let new_value = self.eval(value)?;
+
+                let (elem_type, len) = match self.lookup(name) {
+                    Some(Value::Array {
+                        elem_type,
+                        elements,
+                    }) => (elem_type.clone(), elements.len()),
+                    Some(other) => {
+                        let other = other.clone();
+                        return self.expected("an array", &other, array.span);
+                    }
+                    None => return self.fail(array.span, format!("`{}` is not a variable", name)),
+                };
+                let i = self.index(i, len, index.span)?;
+                if let Err(message) = check_element(&new_value, &elem_type) {
+                    return self.fail(value.span, message);
+                }
+                if let Some(Value::Array { elements, .. }) = self.lookup_mut(name) {
+                    elements[i] = new_value;
+                }
+                Ok(Value::Void)
+            }
+
+            ExprKind::ArrayLength(array) => match self.eval(array)? {
+                Value::Array { elements, .. } => Ok(Value::Int(elements.len() as i128)),
+                other => self.expected("an array", &other, array.span),
+            },
+
+            ExprKind::EvalCompile(inner) => self.eval(inner),
+
+            ExprKind::FunctionCall { func, args } => {
+                let ExprKind::Ident(name) = &func.kind else {
+                    return self.fail(
+                        func.span,
+                        "only named functions can be called at compile time",
+                    );
+                };
+                let mut values = Vec::with_capacity(args.len());
+                for arg in args {
+                    values.push(self.eval(arg)?);
+                }
+
+                if let Some(function) = self.functions.get(name).cloned() {
+                    return self.call(name, &function, values, expr.span);
+                }
+                match builtin(name, &values) {
+                    Some(Ok(value)) => Ok(value),
+                    Some(Err(message)) => self.fail(expr.span, message),
+                    None => self.fail(func.span, format!("unknown function `{}`", name)),
+                }
+            }
+
+            _ => self.fail(
+                expr.span,
+                format!(
+                    "`{}` cannot run at compile time",
+                    expr.keyword().unwrap_or_default()
+                ),
+            ),
+        }
+    }
+
+    fn eval_body(&mut self, body: &[Expr]) -> Eval<Value> {
+        let mut value = Value::Void;
+        for expr in body {
+            value = self.eval(expr)?;
+        }
+        Ok(value)
+    }
+
+    fn call(
+        &mut self,
+        name: &str,
+        function: &Function,
+        args: Vec<Value>,
+        span: Span,
+    ) -> Eval<Value> {
+        if args.len() != function.params.len() {
+            return self.fail(
+                span,
+                format!(
+                    "`{}` takes {} argument(s) but {} were given",
+                    name,
+                    function.params.len(),
+                    args.len()
+                ),
+            );
+        }
+        if self.frames.len() > MAX_CALL_DEPTH {
+            return self.fail(
+                span,
+                format!("calls nested more than {} deep", MAX_CALL_DEPTH),
+            );
+        }
+
+        self.frames
+            .push(function.params.iter().cloned().zip(args).collect());
+        let result = self.eval_body(&function.body);
+        self.frames.pop();
+        result
+    }
+
+    /// Run `f` with `bindings` added to the current frame
+    fn scoped<T>(
+        &mut self,
+        bindings: Vec<(String, Value)>,
+        f: impl FnOnce(&mut Self) -> Eval<T>,
+    ) -> Eval<T> {
+        let mark = self.frame().len();
+        self.frame().extend(bindings);
+        let result = f(self);
+        self.frame().truncate(mark);
+        result
+    }
+
+    fn frame(&mut self) -> &mut Vec<(String, Value)> {
+        self.frames.last_mut().expect("evaluation has a frame")
+    }
+
+    fn lookup(&self, name: &str) -> Option<&Value> {
+        let frame = self.frames.last()?;
+        frame.iter().rev().find(|(n, _)| n == name).map(|(_, v)| v)
+    }
+
+    fn lookup_mut(&mut self, name: &str) -> Option<&mut Value> {
+        let frame = self.frames.last_mut()?;
+        frame
+            .iter_mut()
+            .rev()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v)
+    }
+
+    fn int(&mut self, expr: &Expr) -> Eval<i128> {
+        match self.eval(expr)? {
+            Value::Int(n) => Ok(n),
+            other => self.expected("an integer", &other, expr.span),
+        }
+    }
+
+    fn bool(&mut self, expr: &Expr) -> Eval<bool> {
+        match self.eval(expr)? {
+            Value::Bool(b) => Ok(b),
+            other => self.expected("a bool", &other, expr.span),
+        }
+    }
+
+    fn index(&self, index: i128, len: usize, span: Span) -> Eval<usize> {
+        match usize::try_from(index) {
+            Ok(i) if i < len => Ok(i),
+            _ => self.fail(
+                span,
+                format!(
+                    "index {} is out of bounds for an array of length {}",
+                    index, len
+                ),
+            ),
+        }
+    }
+
+    fn spend(&mut self, steps: u64) -> Eval<()> {
+        match self.fuel.checked_sub(steps) {
+            Some(rest) => {
+                self.fuel = rest;
+                Ok(())
+            }
+            None => Err(EvalError::OutOfFuel {
+                fuel: self.limit,
+                span: self.site,
+            }),
+        }
+    }
+
+    fn expected<T>(&self, what: &str, found: &Value, span: Span) -> Eval<T> {
+        self.fail(
+            span,
+            format!("expected {}, found {}", what, found.type_name()),
+        )
+    }
+
+    fn fail<T>(&self, span: Span, message: impl Into<String>) -> Eval<T> {
+        Err(EvalError::Failed {
+            message: message.into(),
+            span,
+            site: self.site,
+        })
+    }
+}
+
+/// Elements of a fresh `(array T n)`, counting those of nested arrays;
+/// `None` if there are more than fit in a `u64`
+fn element_count(elem_type: &Type, size: usize) -> Option<u64> {
+    let per_element = match elem_type {
+        Type::Array { elem_type, size } => element_count(elem_type, *size)?.checked_add(1)?,
+        _ => 1,
+    };
+    (size as u64).checked_mul(per_element)
+}
+
+/// Value a fresh `(array T n)` is filled with
+fn zero(ty: &Type) -> Option<Value> {
+    Some(match ty {
+        Type::Float32 | Type::Float64 => Value::Float(0.0),
+        Type::Bool => Value::Bool(false),
+        Type::String => Value::String(String::new()),
+        Type::Array { elem_type, size } => Value::Array {
+            elem_type: (**elem_type).clone(),
+            elements: vec![zero(elem_type)?; *size],
+        },
+        ty if ty.is_integer() => Value::Int(0),
+        _ => return None,
+    })
+}
+
+/// Whether `value` can be stored in an array of `ty`
+fn check_element(value: &Value, ty: &Type) -> Result<(), String> {
+    let matches = match (value, ty) {
+        (Value::Int(n), ty) if ty.is_integer() => {
+            if !IntLiteral::new(*n).fits(ty) {
+                return Err(format!("`{}` does not fit in `{}`", n, ty));
+            }
+            true
+        }
+        (Value::Float(_), Type::Float32 | Type::Float64)
+        | (Value::Bool(_), Type::Bool)
+        | (Value::String(_), Type::String) => true,
+        (
+            Value::Array {
+                elem_type,
+                elements,
+            },
+            Type::Array {
+                elem_type: expected,
+                size,
+            },
+        ) => **expected == *elem_type && elements.len() == *size,
+        _ => false,
+    };
+    if matches {
+        Ok(())
+    } else {
+        Err(format!("expected `{}`, found {}", ty, value.type_name()))
+    }
+}
+
+/// Apply a built-in operator; `None` if `name` is not one
+fn builtin(name: &str, args: &[Value]) -> Option<Result<Value, String>> {
+    use Value::{Bool, Float, Int};
+
+    if let ("<" | "<=" | ">" | ">=", [a, b]) = (name, args) {
+        let ordering = match (a, b) {
+            (Int(a), Int(b)) => a.partial_cmp(b),
+            (Float(a), Float(b)) => a.partial_cmp(b),
+            _ => None,
+        };
+        if let Some(ordering) = ordering {
+            return Some(Ok(Bool(match name {
+                "<" => ordering.is_lt(),
+                "<=" => ordering.is_le(),
+                ">" => ordering.is_gt(),
+                _ => ordering.is_ge(),
+            })));
+        }
+    }
+
+    let result = match (name, args) {
+        ("/" | "mod", [Int(_), Int(0)]) => return Some(Err("division by zero".to_string())),
+        ("+", [Int(a), Int(b)]) => a.checked_add(*b).map(Int),
+        ("-", [Int(a), Int(b)]) => a.checked_sub(*b).map(Int),
+        ("-", [Int(a)]) => a.checked_neg().map(Int),
+        ("*", [Int(a), Int(b)]) => a.checked_mul(*b).map(Int),
+        ("/", [Int(a), Int(b)]) => a.checked_div(*b).map(Int),
+        ("mod", [Int(a), Int(b)]) => a.checked_rem(*b).map(Int),
+        ("+", [Float(a), Float(b)]) => Some(Float(a + b)),
+        ("-", [Float(a), Float(b)]) => Some(Float(a - b)),
+        ("-", [Float(a)]) => Some(Float(-a)),
+        ("*", [Float(a), Float(b)]) => Some(Float(a * b)),
+        ("/", [Float(a), Float(b)]) => Some(Float(a / b)),
+        ("=", [a, b]) => Some(Bool(a == b)),
+        ("!=", [a, b]) => Some(Bool(a != b)),
+        ("and", [Bool(a), Bool(b)]) => Some(Bool(*a && *b)),
+        ("or", [Bool(a), Bool(b)]) => Some(Bool(*a || *b)),
+        ("not", [Bool(a)]) => Some(Bool(!a)),
+        ("bitwise-and", [Int(a), Int(b)]) => Some(Int(a & b)),
+        ("bitwise-or", [Int(a), Int(b)]) => Some(Int(a | b)),
+        ("bitwise-xor", [Int(a), Int(b)]) => Some(Int(a ^ b)),
+        ("bitwise-not", [Int(a)]) => Some(Int(!a)),
+        ("bit-shift-left", [Int(a), Int(b)]) => u32::try_from(*b)
+            .ok()
+            .and_then(|b| a.checked_shl(b))
+            .map(Int),
+        ("bit-shift-right", [Int(a), Int(b)]) => u32::try_from(*b)
+            .ok()
+            .and_then(|b| a.checked_shr(b))
+            .map(Int),
+        _ if is_builtin(name) => {
+            let types: Vec<String> = args.iter().map(Value::type_name).collect();
+            return Some(Err(format!(
+                "`{}` cannot be applied to ({})",
+                name,
+                types.join(", ")
+            )));
+        }
+        _ => return None,
+    };
+
+    Some(result.ok_or_else(|| format!("integer overflow in `{}`", name)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::PrettyPrinter;
+    use crate::parser::parse_file;
+
+    fn splice(source: &str, fuel: u64) -> (Vec<Expr>, Vec<EvalError>) {
+        let mut exprs = parse_file(source).unwrap();
+        let errors = Evaluator::new(&exprs).with_fuel(fuel).splice(&mut exprs);
+        (exprs, errors)
+    }
+
+    #[test]
+    fn test_lookup_table_spliced() {
+        let source = r#"
+(defun-compile squares (n) : (array uint8 4)
+  (let ((table (array uint8 4)))
+    (bounded-for i 0 n
+      (array-set table i (* i i)))
+    table))
+(defun-deploy lookup (i) : uint8
+  (array-get (eval-compile (squares 4)) i))
+"#;
+        let (exprs, errors) = splice(source, DEFAULT_FUEL);
+        assert!(errors.is_empty(), "{:?}", errors);
+
+        let ExprKind::DefunDeploy { body, .. } = &exprs[1].kind else {
+            panic!("expected defun-deploy");
+        };
+        assert_eq!(
+            PrettyPrinter::print(&body[0]),
+            "(array-get (array-init uint8 0 1 4 9) i)"
+        );
+    }
+
+    #[test]
+    fn test_loops_and_strings() {
+        let source = r#"
+(defun-compile sum (xs)
+  (let ((total 0) (i 0))
+    (for x xs (set total (+ total x)))
+    (while (< i 3) (set i (+ i 1)) (set total (bitwise-xor total 1)))
+    total))
+(defun-deploy f ()
+  (eval-compile (sum (array-init int32 1 2 3)))
+  (eval-compile (if (= 1 1) "yes" "no")))
+"#;
+        let (exprs, errors) = splice(source, DEFAULT_FUEL);
+        assert!(errors.is_empty(), "{:?}", errors);
+
+        let ExprKind::DefunDeploy { body, .. } = &exprs[1].kind else {
+            panic!("expected defun-deploy");
+        };
+        assert_eq!(body[0].kind, ExprKind::Int(7.into()));
+        assert_eq!(body[1].kind, ExprKind::String("yes".to_string()));
+    }
+
+    #[test]
+    fn test_runaway_loop_runs_out_of_fuel() {
+        let source = r#"
+(defun-compile spin () (while true 0))
+(defun-deploy f () (eval-compile (spin)))
+"#;
+        let (exprs, errors) = splice(source, 1000);
+        assert_eq!(errors.len(), 1);
+        assert!(matches!(errors[0], EvalError::OutOfFuel { fuel: 1000, .. }));
+        assert_eq!(errors[0].span().column, 20);
+
+        // The form is left for the phase checker to reject
+        let ExprKind::DefunDeploy { body, .. } = &exprs[1].kind else {
+            panic!("expected defun-deploy");
+        };
+        assert_eq!(body[0].keyword(), Some("eval-compile"));
+    }
+
+    #[test]
+    fn test_nested_arrays_cost_every_element() {
+        let source = r#"
+(defun-compile big () (array (array uint64 100000000) 100))
+(defun-compile huge () (array (array (array uint8 4294967296) 4294967296) 4294967296))
+(defun-compile grid () : int32
+  (let ((g (array (array uint8 3) 4)))
+    (array-length (array-get g 3))))
+(defun-deploy f ()
+  (eval-compile (big))
+  (eval-compile (huge))
+  (eval-compile (grid)))
+"#;
+        let (exprs, errors) = splice(source, DEFAULT_FUEL);
+        assert_eq!(errors.len(), 2);
+        assert!(errors
+            .iter()
+            .all(|e| matches!(e, EvalError::OutOfFuel { .. })));
+
+        let ExprKind::DefunDeploy { body, .. } = &exprs[3].kind else {
+            panic!("expected defun-deploy");
+        };
+        assert_eq!(body[2].kind, ExprKind::Int(3.into()));
+    }
+
+    #[test]
+    fn test_evaluation_errors() {
+        let source = r#"
+(defun-compile fill () : (array uint8 2)
+  (let ((t (array uint8 2)))
+    (array-set t 1 (/ 512 2))
+    t))
+(defun-deploy f (x)
+  (eval-compile (fill))
+  (eval-compile (+ x 1))
+  (eval-compile (bounded-for i 0 2 i)))
+"#;
+        let (_, errors) = splice(source, DEFAULT_FUEL);
+        let diagnostics: Vec<Diagnostic> = errors.iter().map(|e| e.to_diagnostic()).collect();
+        let messages: Vec<&str> = diagnostics.iter().map(|d| d.message.as_str()).collect();
+        assert_eq!(
+            messages,
+            vec![
+                "`256` does not fit in `uint8`",
+                "`x` has no value at compile time",
+                "compile-time result cannot be used as a literal",
+            ]
+        );
+
+        // Failures inside a function also point at the `eval-compile`
+        assert_eq!(diagnostics[0].span().line, 4);
+        assert_eq!(diagnostics[0].secondary[0].span.line, 7);
+        assert_eq!(diagnostics[2].code, codes::NOT_SPLICEABLE);
+        assert_eq!(diagnostics[2].primary.message, "this evaluates to void");
+    }
+}
@@ -0,0 +1,326 @@
+use crate::ast::{Expr, ExprKind, Span};
+use crate::diagnostics::{codes, Diagnostic, SourceMap, ToDiagnostic};
+use crate::parser::parse_source;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use thiserror::Error;
+
+/// Where source files come from
+pub trait SourceLoader {
+    fn load(&self, path: &Path) -> io::Result<String>;
+}
+
+/// Loads source files from disk
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FsLoader;
+
+impl SourceLoader for FsLoader {
+    fn load(&self, path: &Path) -> io::Result<String> {
+        fs::read_to_string(path)
+    }
+}
+
+/// Serves source files from memory, for tests and editor buffers
+#[derive(Debug, Clone, Default)]
+pub struct MemoryLoader {
+    files: HashMap<PathBuf, String>,
+}
+
+impl MemoryLoader {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_file(mut self, path: impl AsRef<Path>, source: impl Into<String>) -> Self {
+        self.insert(path, source);
+        self
+    }
+
+    pub fn insert(&mut self, path: impl AsRef<Path>, source: impl Into<String>) {
+        self.files.insert(normalize(path.as_ref()), source.into());
+    }
+}
+
+impl SourceLoader for MemoryLoader {
+    fn load(&self, path: &Path) -> io::Result<String> {
+        self.files
+            .get(&normalize(path))
+            .cloned()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no such file"))
+    }
+}
+
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum IncludeError {
+    #[error("Cannot read {}: {reason}", path.display())]
+    NotFound {
+        path: PathBuf,
+        reason: String,
+        span: Span,
+    },
+
+    #[error("Include cycle: {}", display_chain(chain))]
+    Cycle { chain: Vec<PathBuf>, span: Span },
+}
+
+impl IncludeError {
+    pub fn span(&self) -> Span {
+        match self {
+            IncludeError::NotFound { span, .. } | IncludeError::Cycle { span, .. } => *span,
+        }
+    }
+}
+
+impl ToDiagnostic for IncludeError {
+    fn to_diagnostic(&self) -> Diagnostic {
+        match self {
+            IncludeError::NotFound { path, reason, span } => Diagnostic::error(
+                codes::INCLUDE_NOT_FOUND,
+                format!("cannot read `{}`", path.display()),
+                *span,
+            )
+            .with_label(reason.clone()),
+            IncludeError::Cycle { chain, span } => {
+                Diagnostic::error(codes::INCLUDE_CYCLE, "include cycle", *span)
+                    .with_label("this include leads back to a file being included")
+                    .with_note(display_chain(chain))
+            }
+        }
+    }
+}
+
+fn display_chain(chain: &[PathBuf]) -> String {
+    chain
+        .iter()
+        .map(|p| p.display().to_string())
+        .collect::<Vec<_>>()
+        .join(" -> ")
+}
+
+/// A root file with its top-level includes expanded
+#[derive(Debug, Clone, Default)]
+pub struct Project {
+    /// Every file read, root first; spans carry the id of their file
+    pub sources: SourceMap,
+    pub exprs: Vec<Expr>,
+    pub syntax_errors: Vec<Diagnostic>,
+    pub include_errors: Vec<IncludeError>,
+}
+
+impl Project {
+    /// Load `root` and everything it includes
+    pub fn load(loader: &dyn SourceLoader, root: impl AsRef<Path>) -> Self {
+        IncludeExpander::new(loader).load(root.as_ref())
+    }
+
+    /// A project made of a single in-memory file
+    pub fn single(name: &str, source: &str) -> Self {
+        Self::load(&MemoryLoader::new().with_file(name, source), name)
+    }
+
+    pub fn diagnostics(&self) -> Vec<Diagnostic> {
+        let mut diagnostics = self.syntax_errors.clone();
+        diagnostics.extend(self.include_errors.iter().map(|e| e.to_diagnostic()));
+        diagnostics
+    }
+}
+
+/// Splices `(include "path")` forms at the top level of a file with the
+/// forms of the named file, resolved relative to the including file.
+///
+/// Each file is expanded at most once, so a file included from several
+/// places contributes its definitions a single time.
+pub struct IncludeExpander<'l> {
+    loader: &'l dyn SourceLoader,
+    project: Project,
+    /// Files currently being expanded, outermost first
+    stack: Vec<PathBuf>,
+    expanded: HashSet<PathBuf>,
+}
+
+impl<'l> IncludeExpander<'l> {
+    pub fn new(loader: &'l dyn SourceLoader) -> Self {
+        Self {
+            loader,
+            project: Project::default(),
+            stack: Vec::new(),
+            expanded: HashSet::new(),
+        }
+    }
+
+    pub fn load(mut self, root: &Path) -> Project {
+        let root = normalize(root);
+        match self.loader.load(&root) {
+            Ok(source) => self.project.exprs = self.expand_file(root, source),
+            Err(e) => self.project.include_errors.push(IncludeError::NotFound {
+                path: root,
+                reason: e.to_string(),
+                span: Span::dummy(),
+            }),
+        }
+        self.project
+    }
+
+    fn expand_file(&mut self, path: PathBuf, source: String) -> Vec<Expr> {
+        let id = self.project.sources.add(path.display().to_string(), source);
+        let outcome = parse_source(self.project.sources.get(id).unwrap());
+        self.project.syntax_errors.extend(outcome.diagnostics);
+
+        self.stack.push(path.clone());
+        self.expanded.insert(path.clone());
+
+        let mut exprs = Vec::new();
+        for expr in outcome.exprs {
+            match &expr.kind {
+                ExprKind::Include(target) => {
+                    exprs.extend(self.include(&path, target, expr.span));
+                }
+                _ => exprs.push(expr),
+            }
+        }
+
+        self.stack.pop();
+        exprs
+    }
+
+    fn include(&mut self, from: &Path, target: &str, span: Span) -> Vec<Expr> {
+        let path = resolve_include(from, target);
+
+        if let Some(start) = self.stack.iter().position(|p| *p == path) {
+            let mut chain = self.stack[start..].to_vec();
+            chain.push(path);
+            self.project
+                .include_errors
+                .push(IncludeError::Cycle { chain, span });
+            return Vec::new();
+        }
+        if self.expanded.contains(&path) {
+            return Vec::new();
+        }
+
+        match self.loader.load(&path) {
+            Ok(source) => self.expand_file(path, source),
+            Err(e) => {
+                self.project.include_errors.push(IncludeError::NotFound {
+                    path,
+                    reason: e.to_string(),
+                    span,
+                });
+                Vec::new()
+            }
+        }
+    }
+}
+
+/// Path named by `(include "target")` inside the file `from`
+pub fn resolve_include(from: &Path, target: &str) -> PathBuf {
+    let base = from.parent().unwrap_or_else(|| Path::new(""));
+    normalize(&base.join(target))
+}
+
+/// Remove `.` and resolve `..` components without touching the filesystem
+fn normalize(path: &Path) -> PathBuf {
+    let mut out = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => match out.components().next_back() {
+                Some(Component::Normal(_)) => {
+                    out.pop();
+                }
+                _ => out.push(".."),
+            },
+            other => out.push(other),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::FileId;
+
+    #[test]
+    fn test_includes_are_spliced_with_file_spans() {
+        let loader = MemoryLoader::new()
+            .with_file(
+                "app/main.obl",
+                "(include \"lib/util.obl\")\n(defun-deploy main () (helper))",
+            )
+            .with_file(
+                "app/lib/util.obl",
+                "(include \"../common.obl\")\n(defun-deploy helper () 1)",
+            )
+            .with_file("app/common.obl", "\n(defcap tx () \"uart\")");
+
+        let project = Project::load(&loader, "app/main.obl");
+        assert!(project.diagnostics().is_empty());
+
+        let names: Vec<&str> = project
+            .exprs
+            .iter()
+            .filter_map(|e| match &e.kind {
+                ExprKind::DefunDeploy { name, .. } | ExprKind::DefCap { name, .. } => {
+                    Some(name.as_str())
+                }
+                _ => None,
+            })
+            .collect();
+        assert_eq!(names, vec!["tx", "helper", "main"]);
+
+        let files: Vec<&str> = project
+            .sources
+            .files()
+            .iter()
+            .map(|f| f.name.as_str())
+            .collect();
+        assert_eq!(
+            files,
+            vec!["app/main.obl", "app/lib/util.obl", "app/common.obl"]
+        );
+
+        let defcap = &project.exprs[0];
+        assert_eq!(defcap.span.file, FileId(2));
+        assert_eq!(defcap.span.line, 2);
+    }
+
+    #[test]
+    fn test_include_cycle_reported() {
+        let loader = MemoryLoader::new()
+            .with_file("a.obl", "(include \"b.obl\")\n(defun-deploy a () 1)")
+            .with_file("b.obl", "(include \"a.obl\")\n(defun-deploy b () 2)");
+
+        let project = Project::load(&loader, "a.obl");
+        assert_eq!(project.exprs.len(), 2);
+        assert_eq!(project.include_errors.len(), 1);
+
+        let IncludeError::Cycle { chain, span } = &project.include_errors[0] else {
+            panic!("expected cycle");
+        };
+        assert_eq!(display_chain(chain), "a.obl -> b.obl -> a.obl");
+        assert_eq!(project.sources.file_of(*span).unwrap().name, "b.obl");
+    }
+
+    #[test]
+    fn test_missing_include_and_shared_file() {
+        let loader = MemoryLoader::new()
+            .with_file(
+                "main.obl",
+                "(include \"x.obl\") (include \"y.obl\") (include \"gone.obl\")",
+            )
+            .with_file("x.obl", "(include \"shared.obl\")")
+            .with_file("y.obl", "(include \"shared.obl\")")
+            .with_file("shared.obl", "(defun-deploy shared () 0)");
+
+        let project = Project::load(&loader, "main.obl");
+        assert_eq!(project.exprs.len(), 1);
+
+        let diagnostics = project.diagnostics();
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].code, codes::INCLUDE_NOT_FOUND);
+        assert_eq!(diagnostics[0].span().column, 37);
+    }
+}
@@ -0,0 +1,597 @@
+use crate::ast::{ExpansionId, Expr, ExprKind, Span};
+use crate::diagnostics::{codes, Diagnostic, ToDiagnostic};
+use std::collections::HashMap;
+use std::rc::Rc;
+use thiserror::Error;
+
+/// Nesting depth at which expansion gives up, assuming a runaway macro
+pub const DEFAULT_DEPTH_LIMIT: usize = 64;
+
+/// Total number of nodes all expansions may produce, so that macros which
+/// call themselves more than once cannot blow up within the depth limit
+pub const DEFAULT_SIZE_LIMIT: usize = 100_000;
+
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum MacroError {
+    #[error("Macro {name} takes {expected} argument(s) but {found} were given at {span}")]
+    Arity {
+        name: String,
+        expected: usize,
+        found: usize,
+        span: Span,
+        definition: Span,
+    },
+
+    #[error("Expansion of macro {name} exceeds the depth limit of {limit} at {span}")]
+    DepthExceeded {
+        name: String,
+        limit: usize,
+        span: Span,
+    },
+
+    #[error("Expansion of macro {name} exceeds the size limit of {limit} nodes at {span}")]
+    SizeExceeded {
+        name: String,
+        limit: usize,
+        span: Span,
+    },
+}
+
+impl MacroError {
+    pub fn span(&self) -> Span {
+        match self {
+            MacroError::Arity { span, .. }
+            | MacroError::DepthExceeded { span, .. }
+            | MacroError::SizeExceeded { span, .. } => *span,
+        }
+    }
+}
+
+impl ToDiagnostic for MacroError {
+    fn to_diagnostic(&self) -> Diagnostic {
+        match self {
+            MacroError::Arity {
+                name,
+                expected,
+                found,
+                span,
+                definition,
+            } => Diagnostic::error(
+                codes::MACRO_ARITY,
+                format!(
+                    "macro `{}` takes {} argument(s) but {} were given",
+                    name, expected, found
+                ),
+                *span,
+            )
+            .with_label(format!("expected {} argument(s)", expected))
+            .with_secondary(*definition, "macro defined here"),
+            MacroError::DepthExceeded { name, limit, span } => Diagnostic::error(
+                codes::MACRO_DEPTH,
+                format!("expansion of macro `{}` is nested too deeply", name),
+                *span,
+            )
+            .with_label(format!("more than {} nested expansions", limit))
+            .with_help("a macro whose expansion calls itself never terminates"),
+            MacroError::SizeExceeded { name, limit, span } => Diagnostic::error(
+                codes::MACRO_SIZE,
+                format!("expansion of macro `{}` produces too much code", name),
+                *span,
+            )
+            .with_label(format!("more than {} expanded nodes", limit))
+            .with_help("a macro that calls itself more than once grows exponentially"),
+        }
+    }
+}
+
+/// One macro call that was replaced by the macro's body
+#[derive(Debug, Clone, PartialEq)]
+pub struct Expansion {
+    pub name: String,
+    /// The call that was expanded; itself inside an expansion when macros
+    /// call other macros
+    pub call_site: Span,
+    /// The `macro` form
+    pub definition: Span,
+}
+
+/// Every expansion performed, indexed by the `ExpansionId` on expanded spans
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Expansions {
+    entries: Vec<Expansion>,
+}
+
+impl Expansions {
+    pub fn get(&self, id: ExpansionId) -> Option<&Expansion> {
+        self.entries.get(id.0 as usize)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// The expansions `span` was produced by, innermost first
+    pub fn backtrace(&self, span: Span) -> Vec<&Expansion> {
+        let mut trace = Vec::new();
+        let mut next = span.expansion;
+        while let Some(expansion) = next.and_then(|id| self.get(id)) {
+            trace.push(expansion);
+            next = expansion.call_site.expansion;
+        }
+        trace
+    }
+
+    /// Label every call site that `diagnostic`'s primary span was expanded
+    /// from, so errors in macro bodies also point at the code that used them
+    pub fn annotate(&self, mut diagnostic: Diagnostic) -> Diagnostic {
+        for expansion in self.backtrace(diagnostic.span()) {
+            diagnostic = diagnostic.with_secondary(
+                expansion.call_site,
+                format!("in this expansion of `{}`", expansion.name),
+            );
+        }
+        diagnostic
+    }
+
+    fn push(&mut self, expansion: Expansion) -> ExpansionId {
+        self.entries.push(expansion);
+        ExpansionId(self.entries.len() as u32 - 1)
+    }
+}
+
+/// Forms after macro expansion
+#[derive(Debug, Clone, Default)]
+pub struct MacroExpansion {
+    pub exprs: Vec<Expr>,
+    pub expansions: Expansions,
+    pub errors: Vec<MacroError>,
+}
+
+struct MacroDef {
+    params: Vec<String>,
+    body: Vec<Expr>,
+    span: Span,
+}
+
+/// Names visible while substituting a macro body
+#[derive(Clone, Default)]
+struct Env {
+    /// Macro parameters and the argument passed for each
+    args: HashMap<String, Expr>,
+    /// Names bound inside the body and the fresh names they were given
+    renames: HashMap<String, String>,
+}
+
+/// Replaces calls to `macro`-defined names with the macro body, the
+/// parameters substituted by the (unevaluated) argument forms.
+///
+/// Expansion is hygienic: names bound by `let` and loops inside a macro
+/// body are renamed to `name#N`, which no source identifier can spell, so
+/// they neither capture nor shadow variables at the call site. Expanded
+/// nodes keep their span in the macro definition, tagged with an
+/// `ExpansionId` that leads back to the call site.
+pub struct MacroExpander {
+    macros: HashMap<String, Rc<MacroDef>>,
+    depth_limit: usize,
+    size_limit: usize,
+    /// Nodes produced by expansion so far
+    size: usize,
+    expansions: Expansions,
+    errors: Vec<MacroError>,
+    next_fresh: usize,
+}
+
+impl MacroExpander {
+    /// Collect the macros defined at the top level of `exprs` and inside
+    /// `program` forms
+    pub fn new(exprs: &[Expr]) -> Self {
+        let mut expander = Self {
+            macros: HashMap::new(),
+            depth_limit: DEFAULT_DEPTH_LIMIT,
+            size_limit: DEFAULT_SIZE_LIMIT,
+            size: 0,
+            expansions: Expansions::default(),
+            errors: Vec::new(),
+            next_fresh: 0,
+        };
+        for expr in exprs {
+            expander.collect(expr);
+        }
+        expander
+    }
+
+    pub fn with_depth_limit(mut self, limit: usize) -> Self {
+        self.depth_limit = limit;
+        self
+    }
+
+    pub fn with_size_limit(mut self, limit: usize) -> Self {
+        self.size_limit = limit;
+        self
+    }
+
+    pub fn expand(mut self, mut exprs: Vec<Expr>) -> MacroExpansion {
+        for expr in &mut exprs {
+            self.expand_expr(expr, 0);
+        }
+        MacroExpansion {
+            exprs,
+            expansions: self.expansions,
+            errors: self.errors,
+        }
+    }
+
+    fn collect(&mut self, expr: &Expr) {
+        match &expr.kind {
+            ExprKind::Macro { name, params, body } => {
+                let def = MacroDef {
+                    params: params.iter().map(|p| p.name.clone()).collect(),
+                    body: body.clone(),
+                    span: expr.span,
+                };
+                self.macros.insert(name.clone(), Rc::new(def));
+            }
+            ExprKind::Program { forms, .. } => {
+                for form in forms {
+                    self.collect(form);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn expand_expr(&mut self, expr: &mut Expr, depth: usize) {
+        match &expr.kind {
+            // Bodies are expanded where they are used
+            ExprKind::Macro { .. } => return,
+            ExprKind::FunctionCall { func, .. } => {
+                if let ExprKind::Ident(name) = &func.kind {
+                    if let Some(def) = self.macros.get(name).cloned() {
+                        let name = name.clone();
+                        return self.expand_call(expr, &name, &def, depth);
+                    }
+                }
+            }
+            _ => {}
+        }
+
+        for child in expr.children_mut() {
+            self.expand_expr(child, depth);
+        }
+    }
+
+    fn expand_call(&mut self, call: &mut Expr, name: &str, def: &MacroDef, depth: usize) {
+        let ExprKind::FunctionCall { args, .. } = &mut call.kind else {
+            return;
+        };
+
+        if args.len() != def.params.len() {
+            self.errors.push(MacroError::Arity {
+                name: name.to_string(),
+                expected: def.params.len(),
+                found: args.len(),
+                span: call.span,
+                definition: def.span,
+            });
+            return;
+        }
+        // Once over the size limit, or once the call the user wrote has hit
+        // a limit, further calls are left unexpanded
+        let site = self.user_call_site(call.span);
+        if self.size > self.size_limit || self.gave_up(site) {
+            return;
+        }
+        if depth >= self.depth_limit {
+            self.errors.push(MacroError::DepthExceeded {
+                name: name.to_string(),
+                limit: self.depth_limit,
+                span: site,
+            });
+            return;
+        }
+
+        // Arguments are expanded once, before being copied into the body
+        for arg in args.iter_mut() {
+            self.expand_expr(arg, depth);
+        }
+
+        let arg_sizes: HashMap<&str, usize> = def
+            .params
+            .iter()
+            .zip(args.iter())
+            .map(|(param, arg)| (param.as_str(), node_count(arg, &HashMap::new())))
+            .collect();
+        let size = def.body.iter().fold(0usize, |n, expr| {
+            n.saturating_add(node_count(expr, &arg_sizes))
+        });
+        self.size = self.size.saturating_add(size);
+        if self.size > self.size_limit {
+            self.errors.push(MacroError::SizeExceeded {
+                name: name.to_string(),
+                limit: self.size_limit,
+                span: site,
+            });
+            return;
+        }
+
+        let id = self.expansions.push(Expansion {
+            name: name.to_string(),
+            call_site: call.span,
+            definition: def.span,
+        });
+        let env = Env {
+            args: def.params.iter().cloned().zip(args.drain(..)).collect(),
+            renames: HashMap::new(),
+        };
+
+        let mut body = def.body.clone();
+        for expr in &mut body {
+            self.substitute(expr, &env, id);
+        }
+        *call = match body.len() {
+            1 => body.remove(0),
+            _ => Expr::new(
+                ExprKind::Let {
+                    bindings: Vec::new(),
+                    body,
+                },
+                Span {
+                    expansion: Some(id),
+                    ..def.span
+                },
+            ),
+        };
+
+        self.expand_expr(call, depth + 1);
+    }
+
+    /// The call the user wrote that `span` was expanded from, so errors are
+    /// not reported deep inside a chain of expansions
+    fn user_call_site(&self, span: Span) -> Span {
+        self.expansions
+            .backtrace(span)
+            .last()
+            .map_or(span, |e| e.call_site)
+    }
+
+    fn gave_up(&self, site: Span) -> bool {
+        self.errors.iter().any(|e| {
+            matches!(
+                e,
+                MacroError::DepthExceeded { .. } | MacroError::SizeExceeded { .. }
+            ) && e.span() == site
+        })
+    }
+
+    fn substitute(&mut self, expr: &mut Expr, env: &Env, id: ExpansionId) {
+        expr.span.expansion = Some(id);
+
+        match &mut expr.kind {
+            ExprKind::Ident(name) => {
+                if let Some(fresh) = env.renames.get(name) {
+                    *name = fresh.clone();
+                } else if let Some(arg) = env.args.get(name) {
+                    *expr = arg.clone();
+                }
+            }
+
+            ExprKind::Set { var, value } => {
+                self.substitute(value, env, id);
+                if let Some(fresh) = env.renames.get(var) {
+                    *var = fresh.clone();
+                } else if let Some(ExprKind::Ident(target)) = env.args.get(var).map(|a| &a.kind) {
+                    *var = target.clone();
+                }
+            }
+
+            // Bindings are evaluated in the enclosing scope, as in T-Let
+            ExprKind::Let { bindings, body } => {
+                let mut inner = env.clone();
+                for (name, value) in bindings.iter_mut() {
+                    self.substitute(value, env, id);
+                    *name = self.bind(name, &mut inner);
+                }
+                for expr in body {
+                    self.substitute(expr, &inner, id);
+                }
+            }
+
+            ExprKind::BoundedFor {
+                var,
+                start,
+                end,
+                body,
+            } => {
+                self.substitute(start, env, id);
+                self.substitute(end, env, id);
+                let mut inner = env.clone();
+                *var = self.bind(var, &mut inner);
+                for expr in body {
+                    self.substitute(expr, &inner, id);
+                }
+            }
+
+            ExprKind::For {
+                var,
+                iterable,
+                body,
+            } => {
+                self.substitute(iterable, env, id);
+                let mut inner = env.clone();
+                *var = self.bind(var, &mut inner);
+                for expr in body {
+                    self.substitute(expr, &inner, id);
+                }
+            }
+
+            _ => {
+                for child in expr.children_mut() {
+                    self.substitute(child, env, id);
+                }
+            }
+        }
+    }
+
+    /// Give a name bound inside a macro body a fresh name, in scope for `env`
+    fn bind(&mut self, name: &str, env: &mut Env) -> String {
+        let fresh = format!("{}#{}", name, self.next_fresh);
+        self.next_fresh += 1;
+        env.renames.insert(name.to_string(), fresh.clone());
+        fresh
+    }
+}
+
+/// Nodes in `expr` once every parameter in `args` is replaced by an
+/// argument of the given size
+fn node_count(expr: &Expr, args: &HashMap<&str, usize>) -> usize {
+    if let ExprKind::Ident(name) = &expr.kind {
+        if let Some(&size) = args.get(name.as_str()) {
+            return size;
+        }
+    }
+    expr.children()
+        .into_iter()
+        .fold(1usize, |n, child| n.saturating_add(node_count(child, args)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::PrettyPrinter;
+    use crate::parser::parse_file;
+
+    fn expand(source: &str) -> MacroExpansion {
+        let exprs = parse_file(source).unwrap();
+        MacroExpander::new(&exprs).expand(exprs)
+    }
+
+    /// The body of the last function in the expanded source, printed
+    fn expanded_body(expansion: &MacroExpansion) -> Vec<String> {
+        let ExprKind::DefunDeploy { body, .. } = &expansion.exprs.last().unwrap().kind else {
+            panic!("expected defun-deploy");
+        };
+        body.iter().map(PrettyPrinter::print).collect()
+    }
+
+    #[test]
+    fn test_arguments_substituted() {
+        let expansion = expand(
+            r#"
+(macro square (x) (* x x))
+(macro twice (e) (square (+ e e)))
+(defun-deploy f (y) (twice y) (square 3))
+"#,
+        );
+        assert!(expansion.errors.is_empty());
+        assert_eq!(
+            expanded_body(&expansion),
+            vec!["(* (+ y y) (+ y y))", "(* 3 3)"]
+        );
+        assert_eq!(expansion.expansions.len(), 3);
+    }
+
+    #[test]
+    fn test_let_bound_names_are_hygienic() {
+        let expansion = expand(
+            r#"
+(macro swap! (a b)
+  (let ((tmp a))
+    (set a b)
+    (set b tmp)))
+(defun-deploy f (tmp x) (swap! tmp x))
+"#,
+        );
+        assert_eq!(
+            expanded_body(&expansion),
+            vec!["(let ((tmp#0 tmp))\n  (set tmp x)\n  (set x tmp#0))"]
+        );
+    }
+
+    #[test]
+    fn test_depth_limit_and_arity() {
+        let expansion = expand(
+            r#"
+(macro forever (x) (forever (+ x 1)))
+(macro pair (a b) (+ a b))
+(defun-deploy f () (forever 0) (pair 1))
+"#,
+        );
+        let errors: Vec<String> = expansion
+            .errors
+            .iter()
+            .map(|e| format!("{} {}", e.to_diagnostic().code, e.span()))
+            .collect();
+        assert_eq!(errors, vec!["OBL0404 4:20", "OBL0403 4:32"]);
+    }
+
+    #[test]
+    fn test_branching_recursion_stops_at_first_limit() {
+        let expansion = expand(
+            r#"
+(macro b (x) (+ (b x) (b x)))
+(defun-deploy f () (b 1))
+"#,
+        );
+        let errors: Vec<String> = expansion
+            .errors
+            .iter()
+            .map(|e| format!("{} {}", e.to_diagnostic().code, e.span()))
+            .collect();
+        assert_eq!(errors, vec!["OBL0404 3:20"]);
+        assert!(expansion.expansions.len() <= DEFAULT_DEPTH_LIMIT);
+    }
+
+    #[test]
+    fn test_size_limit() {
+        let source = r#"
+(macro d1 (x) (+ x x))
+(macro d2 (x) (d1 (d1 x)))
+(macro d3 (x) (d2 (d2 x)))
+(defun-deploy f (y) (d3 y))
+"#;
+        let exprs = parse_file(source).unwrap();
+        let expansion = MacroExpander::new(&exprs)
+            .with_size_limit(100)
+            .expand(exprs);
+        let errors: Vec<String> = expansion
+            .errors
+            .iter()
+            .map(|e| format!("{} {}", e.to_diagnostic().code, e.span()))
+            .collect();
+        assert_eq!(errors, vec!["OBL0408 5:21"]);
+
+        let exprs = parse_file(source).unwrap();
+        assert!(MacroExpander::new(&exprs).expand(exprs).errors.is_empty());
+    }
+
+    #[test]
+    fn test_expanded_spans_lead_to_call_site() {
+        let source = "(macro inc (x) (+ x 1))\n(defun-deploy f (n) (inc n))";
+        let expansion = expand(source);
+        let ExprKind::DefunDeploy { body, .. } = &expansion.exprs[1].kind else {
+            panic!("expected defun-deploy");
+        };
+
+        // `(+ n 1)` sits in the macro; `n` came from the call
+        assert_eq!((body[0].span.line, body[0].span.column), (1, 16));
+        let ExprKind::FunctionCall { args, .. } = &body[0].kind else {
+            panic!("expected call");
+        };
+        assert_eq!(args[0].span.line, 2);
+        assert_eq!(args[0].span.expansion, None);
+
+        let diagnostic = Diagnostic::error(codes::SYNTAX_ERROR, "test", body[0].span);
+        let diagnostic = expansion.expansions.annotate(diagnostic);
+        assert_eq!(diagnostic.secondary.len(), 1);
+        assert_eq!(diagnostic.secondary[0].span.line, 2);
+        assert_eq!(diagnostic.secondary[0].span.column, 21);
+        assert_eq!(
+            diagnostic.secondary[0].message,
+            "in this expansion of `inc`"
+        );
+    }
+}
@@ -0,0 +1,7 @@
+pub mod comptime;
+pub mod include;
+pub mod macros;
+
+pub use comptime::*;
+pub use include::*;
+pub use macros::*;
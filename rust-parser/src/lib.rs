@@ -1,66 +1,218 @@
+// Diagnostics are large, but only built on the error path
+#![allow(clippy::result_large_err)]
+
 pub mod ast;
+pub mod diagnostics;
+pub mod expand;
 pub mod parser;
 pub mod phases;
 pub mod analyzer;
 
 pub use ast::*;
+pub use diagnostics::*;
+pub use expand::*;
 pub use parser::*;
 pub use phases::*;
 pub use analyzer::*;
 
 use anyhow::Result;
+use std::path::Path;
 
 /// Complete analysis of an Oblibeny program
 pub struct ProgramAnalysis {
+    /// Every file the program was loaded from; spans index into these
+    pub sources: SourceMap,
     pub exprs: Vec<Expr>,
+    /// Syntax errors; the passes below only see the forms that parsed
+    pub syntax_errors: Vec<Diagnostic>,
+    pub include_errors: Vec<IncludeError>,
+    /// Macro calls expanded before the passes below ran
+    pub expansions: Expansions,
+    pub macro_errors: Vec<MacroError>,
+    /// Failed `eval-compile` forms; the rest were replaced by their values
+    pub comptime_errors: Vec<EvalError>,
+    pub symbols: SymbolTable,
+    /// Name resolution problems, including shadowing warnings
+    pub resolve_errors: Vec<ResolveError>,
+    /// Parameters and bindings whose type inference could not decide
+    pub inference_errors: Vec<InferenceError>,
     pub phase_check: Result<(), PhaseError>,
+    pub type_check: Result<(), Vec<TypeError>>,
+    /// I/O operations not covered by a capability for their resource
+    pub capability_errors: Vec<CapabilityError>,
+    /// Capabilities duplicated, leaked or never used
+    pub linearity_errors: Vec<LinearityError>,
+    /// Worst-case I/O under each capability, against declared budgets
+    pub capability_budgets: CapabilityBudgets,
+    /// Each program's `defcap`s linked to their use sites, including
+    /// warnings for declarations nothing uses
+    pub capability_manifests: CapabilityManifests,
     pub termination_check: Result<(), TerminationError>,
+    /// Array accesses out of bounds, with warnings for those that may be
+    pub bounds_errors: Vec<BoundsError>,
+    pub literal_errors: Vec<LiteralError>,
     pub resource_bounds: ResourceBounds,
     pub call_graph: CallGraph,
 }
 
 impl ProgramAnalysis {
-    pub fn analyze(source: &str) -> Result<Self> {
-        // Parse
-        let exprs = parse_file(source)?;
+    /// Analyze a single source text; includes are not resolved
+    pub fn analyze(source: &str) -> Self {
+        Self::analyze_project(Project::single("<input>", source))
+    }
+
+    /// Analyze `root` together with every file it includes
+    pub fn load(loader: &dyn SourceLoader, root: impl AsRef<Path>) -> Self {
+        Self::analyze_project(Project::load(loader, root))
+    }
+
+    pub fn analyze_project(project: Project) -> Self {
+        Self::analyze_project_with(project, &mut Pipeline::new())
+    }
+
+    /// Analyze a project after rewriting it with `passes`, which run once
+    /// macros are expanded and compile-time code is evaluated, and before
+    /// any of the analyzers
+    pub fn analyze_project_with(project: Project, passes: &mut Pipeline) -> Self {
+        // Parsing and include expansion keep whatever forms survive errors
+        let Project {
+            sources,
+            exprs,
+            syntax_errors,
+            include_errors,
+        } = project;
+
+        // Macro expansion; the passes below see the expanded forms
+        let MacroExpansion {
+            mut exprs,
+            expansions,
+            errors: macro_errors,
+        } = MacroExpander::new(&exprs).expand(exprs);
+
+        // Compile-time evaluation, so deploy code only sees the results
+        let comptime_errors = Evaluator::new(&exprs).splice(&mut exprs);
+
+        // Source-to-source passes
+        let mut exprs = passes.run(exprs);
+
+        // Name resolution
+        let Resolution {
+            symbols,
+            errors: resolve_errors,
+        } = NameResolver::new(&exprs).resolve(&exprs);
+
+        // Type inference; fills in the types the checker below relies on
+        let inference_errors = TypeInference::new(&exprs).infer(&mut exprs);
 
         // Phase separation
         let separator = PhaseSeparator::new();
         let phase_check = separator.validate_deploy_phase(&exprs);
 
+        // Type checking
+        let type_check = TypeChecker::new(&exprs).check(&exprs);
+
+        // Capability scopes
+        let capability_errors = CapabilityChecker::new().check(&exprs);
+        let linearity_errors = LinearityChecker::new().check(&exprs);
+        let capability_budgets = CapabilityBudgets::analyze(&exprs);
+        let capability_manifests = CapabilityManifests::link(&exprs, &symbols);
+
         // Termination checking
         let term_checker = TerminationChecker::new(&exprs);
         let termination_check = term_checker.check_terminates(&exprs);
 
+        // Array bounds
+        let bounds_errors = BoundsChecker::new().check(&exprs);
+
+        // Literal ranges
+        let literal_errors = LiteralChecker::new(&exprs).check(&exprs);
+
         // Resource analysis
         let resource_analyzer = ResourceAnalyzer::new();
         let mut resource_bounds = ResourceBounds::new();
 
-        for expr in &exprs {
-            if let Expr::DefunDeploy { .. } = expr {
-                let bounds = resource_analyzer.analyze(expr);
-                resource_bounds.add(&bounds);
-            }
+        for function in PhaseSeparator::new().extract_deploy_functions(&exprs) {
+            resource_bounds.add(&resource_analyzer.analyze(function));
         }
 
         // Call graph
         let call_graph = CallGraph::build(&exprs);
 
-        Ok(Self {
+        Self {
+            sources,
             exprs,
+            syntax_errors,
+            include_errors,
+            expansions,
+            macro_errors,
+            comptime_errors,
+            symbols,
+            resolve_errors,
+            inference_errors,
             phase_check,
+            type_check,
+            capability_errors,
+            linearity_errors,
+            capability_budgets,
+            capability_manifests,
             termination_check,
+            bounds_errors,
+            literal_errors,
             resource_bounds,
             call_graph,
-        })
+        }
     }
 
     pub fn is_valid(&self) -> bool {
-        self.phase_check.is_ok() && self.termination_check.is_ok()
+        self.syntax_errors.is_empty()
+            && self.include_errors.is_empty()
+            && self.macro_errors.is_empty()
+            && self.comptime_errors.is_empty()
+            && self.resolve_errors.iter().all(|e| e.is_warning())
+            && self.phase_check.is_ok()
+            && self.type_check.is_ok()
+            && self.capability_errors.is_empty()
+            && self.linearity_errors.is_empty()
+            && self.capability_budgets.errors().is_empty()
+            && self.capability_manifests.errors.iter().all(|e| e.is_warning())
+            && self.termination_check.is_ok()
+            && self.bounds_errors.iter().all(|e| e.is_warning())
+            && self.literal_errors.is_empty()
+    }
+
+    /// All problems found by the analysis passes, as diagnostics. Problems
+    /// inside macro expansions also point at the macro's call sites.
+    pub fn diagnostics(&self) -> Vec<Diagnostic> {
+        let mut diagnostics = self.syntax_errors.clone();
+        diagnostics.extend(self.include_errors.iter().map(|e| e.to_diagnostic()));
+        diagnostics.extend(self.macro_errors.iter().map(|e| e.to_diagnostic()));
+        diagnostics.extend(self.comptime_errors.iter().map(|e| e.to_diagnostic()));
+        diagnostics.extend(self.resolve_errors.iter().map(|e| e.to_diagnostic()));
+        diagnostics.extend(self.inference_errors.iter().map(|e| e.to_diagnostic()));
+        if let Err(e) = &self.phase_check {
+            diagnostics.push(e.to_diagnostic());
+        }
+        if let Err(errors) = &self.type_check {
+            diagnostics.extend(errors.iter().map(|e| e.to_diagnostic()));
+        }
+        diagnostics.extend(self.capability_errors.iter().map(|e| e.to_diagnostic()));
+        diagnostics.extend(self.linearity_errors.iter().map(|e| e.to_diagnostic()));
+        diagnostics.extend(self.capability_budgets.errors().iter().map(|e| e.to_diagnostic()));
+        diagnostics.extend(self.capability_manifests.errors.iter().map(|e| e.to_diagnostic()));
+        if let Err(e) = &self.termination_check {
+            diagnostics.push(e.to_diagnostic());
+        }
+        diagnostics.extend(self.bounds_errors.iter().map(|e| e.to_diagnostic()));
+        diagnostics.extend(self.literal_errors.iter().map(|e| e.to_diagnostic()));
+        diagnostics
+            .into_iter()
+            .map(|d| self.expansions.annotate(d))
+            .collect()
     }
 
+    /// The analyzed AST in the versioned interchange format
     pub fn to_json(&self) -> Result<String> {
-        Ok(serde_json::to_string_pretty(&self.exprs)?)
+        Ok(serde_json::to_string_pretty(&ast_to_json(&self.exprs))?)
     }
 }
 
@@ -75,7 +227,7 @@ fn test_simple_program() {
   (+ a b))
 "#;
 
-        let analysis = ProgramAnalysis::analyze(source).unwrap();
+        let analysis = ProgramAnalysis::analyze(source);
         assert!(analysis.is_valid());
     }
 
@@ -89,7 +241,210 @@ fn test_bounded_loop() {
     total))
 "#;
 
-        let analysis = ProgramAnalysis::analyze(source).unwrap();
+        let analysis = ProgramAnalysis::analyze(source);
+        assert!(analysis.is_valid());
+    }
+
+    #[test]
+    fn test_diagnostics_carry_codes() {
+        let source = r#"
+(defun-deploy ping () : int32
+  (ping))
+"#;
+
+        let analysis = ProgramAnalysis::analyze(source);
+        let codes: Vec<String> = analysis
+            .diagnostics()
+            .iter()
+            .map(|d| d.code.to_string())
+            .collect();
+        assert_eq!(codes, vec!["OBL0201"]);
+    }
+
+    #[test]
+    fn test_analysis_continues_past_syntax_errors() {
+        let source = r#"
+(defun-deploy broken (x) : x)
+(defun-deploy ping () : int32
+  (ping))
+"#;
+
+        let analysis = ProgramAnalysis::analyze(source);
+        let codes: Vec<String> = analysis
+            .diagnostics()
+            .iter()
+            .map(|d| d.code.to_string())
+            .collect();
+        assert_eq!(codes, vec!["OBL0001", "OBL0201"]);
+        assert!(!analysis.is_valid());
+    }
+
+    #[test]
+    fn test_while_in_deploy_is_rejected() {
+        let source = r#"
+(defun-deploy poll () : int32
+  (while (ready)
+    (step))
+  0)
+(defun-deploy ready () : bool true)
+(defun-deploy step () 0)
+"#;
+
+        let analysis = ProgramAnalysis::analyze(source);
+        assert!(!analysis.is_valid());
+        let codes: Vec<String> = analysis
+            .diagnostics()
+            .iter()
+            .map(|d| d.code.to_string())
+            .collect();
+        assert_eq!(codes, vec!["OBL0101", "OBL0202"]);
+    }
+
+    #[test]
+    fn test_passes_run_before_analyzers() {
+        struct Desugar;
+
+        impl Fold for Desugar {
+            fn fold_function_call(
+                &mut self,
+                func: Box<Expr>,
+                args: Vec<Expr>,
+                _span: Span,
+            ) -> ExprKind {
+                match (&func.kind, args.as_slice()) {
+                    (ExprKind::Ident(name), [ms]) if name == "legacy-sleep" => {
+                        ExprKind::SleepMs(Box::new(self.fold_expr(ms.clone())))
+                    }
+                    _ => fold_function_call(self, func, args),
+                }
+            }
+        }
+
+        let source = "(defun-deploy wait () (legacy-sleep 5))";
+        assert!(!ProgramAnalysis::analyze(source).is_valid());
+
+        let project = Project::single("<input>", source);
+        let mut passes = Pipeline::new().then(Desugar);
+        let analysis = ProgramAnalysis::analyze_project_with(project, &mut passes);
         assert!(analysis.is_valid());
+        assert_eq!(analysis.resource_bounds.time_ms, 5);
+    }
+
+    #[test]
+    fn test_small_integer_types() {
+        let source = include_str!("../../examples/crypto-xor.obl");
+        let analysis = ProgramAnalysis::analyze(source);
+        assert!(analysis.syntax_errors.is_empty());
+
+        let ExprKind::DefunCompile { return_type, .. } = &analysis.exprs[1].kind else {
+            panic!("expected defun-compile");
+        };
+        let ty = return_type.as_ref().unwrap();
+        assert_eq!(ty.to_string(), "(array uint8 128)");
+
+        let json = serde_json::to_string(ty).unwrap();
+        assert_eq!(serde_json::from_str::<Type>(&json).unwrap(), *ty);
+    }
+
+    #[test]
+    fn test_test_vectors_computed_at_compile_time() {
+        let source = include_str!("../../examples/crypto-xor.obl");
+        let analysis = ProgramAnalysis::analyze(source);
+        assert!(analysis.comptime_errors.is_empty());
+        assert!(analysis.phase_check.is_ok());
+        assert!(analysis.type_check.is_ok());
+
+        let ExprKind::Program { forms, .. } = &analysis.exprs[0].kind else {
+            panic!("expected program");
+        };
+        let self_test = forms.iter().find(|f| {
+            matches!(&f.kind, ExprKind::DefunDeploy { name, .. } if name == "self-test")
+        });
+        let Some(ExprKind::DefunDeploy { body, .. }) = self_test.map(|f| &f.kind) else {
+            panic!("expected self-test");
+        };
+        let ExprKind::FunctionCall { args, .. } = &body[0].kind else {
+            panic!("expected call");
+        };
+        let ExprKind::ArrayInit { elem_type, elements } = &args[0].kind else {
+            panic!("expected spliced array, found {:?}", args[0].kind);
+        };
+        assert_eq!(*elem_type, Type::Uint8);
+        assert_eq!(elements.len(), 128);
+        assert_eq!(elements[2].kind, ExprKind::Int(85.into()));
+    }
+
+    #[test]
+    fn test_crypto_xor_is_valid() {
+        let source = include_str!("../../examples/crypto-xor.obl");
+        let analysis = ProgramAnalysis::analyze(source);
+        assert!(analysis.is_valid(), "{:?}", analysis.diagnostics());
+        assert_eq!(analysis.inference_errors, vec![]);
+    }
+
+    #[test]
+    fn test_temperature_monitor_is_valid() {
+        let source = include_str!("../../examples/temperature-monitor.obl");
+        let analysis = ProgramAnalysis::analyze(source);
+        assert!(analysis.is_valid(), "{:?}", analysis.diagnostics());
+        assert_eq!(analysis.capability_manifests.errors, vec![]);
+    }
+
+    #[test]
+    fn test_return_type_mismatch_is_invalid() {
+        let source = r#"
+(defun-deploy ready () : bool
+  (+ 1 2))
+"#;
+
+        let analysis = ProgramAnalysis::analyze(source);
+        assert!(!analysis.is_valid());
+        let diagnostic = analysis.diagnostics().remove(0);
+        assert_eq!(diagnostic.code, codes::TYPE_MISMATCH);
+        assert_eq!(diagnostic.primary.message, "expected `bool`, found `int32`");
+    }
+
+    #[test]
+    fn test_project_analysis_spans_files() {
+        let loader = MemoryLoader::new()
+            .with_file("src/main.obl", "(include \"net.obl\")\n(defun-deploy main () (poll))")
+            .with_file(
+                "src/net.obl",
+                "(defun-deploy poll ()\n  (while (ready) (step)))\n\
+                 (defun-deploy ready () true)\n\
+                 (defun-deploy step () 0)",
+            );
+
+        let analysis = ProgramAnalysis::load(&loader, "src/main.obl");
+        assert!(analysis.include_errors.is_empty());
+        assert_eq!(analysis.exprs.len(), 4);
+
+        let diagnostic = analysis.diagnostics().remove(0);
+        assert_eq!(diagnostic.code, codes::COMPILE_IN_DEPLOY);
+        let rendered = render_in(&diagnostic, &analysis.sources);
+        assert!(rendered.contains("--> src/net.obl:2:3"), "{}", rendered);
+    }
+
+    #[test]
+    fn test_errors_in_macros_point_at_call_site() {
+        let source = r#"
+(macro spin (cond) (while cond (step)))
+(defun-deploy poll () : int32
+  (spin (ready))
+  0)
+(defun-deploy ready () : bool true)
+(defun-deploy step () 0)
+"#;
+
+        let analysis = ProgramAnalysis::analyze(source);
+        assert!(analysis.macro_errors.is_empty());
+        assert_eq!(analysis.expansions.len(), 1);
+
+        let diagnostic = analysis.diagnostics().remove(0);
+        assert_eq!(diagnostic.code, codes::COMPILE_IN_DEPLOY);
+        assert_eq!(diagnostic.span().line, 2);
+        let call_site = diagnostic.secondary.last().unwrap();
+        assert_eq!((call_site.span.line, call_site.span.column), (4, 3));
+        assert_eq!(call_site.message, "in this expansion of `spin`");
     }
 }
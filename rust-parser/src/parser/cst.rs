@@ -0,0 +1,439 @@
+//! Lossless concrete syntax tree.
+//!
+//! The grammar treats whitespace and comments as silent, so the AST cannot
+//! say where they were. The CST keeps every byte of the source as a token
+//! (parentheses, atoms, strings, comments and whitespace runs) grouped into
+//! one node per parenthesized list, and concatenating its tokens gives back
+//! the source exactly, even when the source does not parse.
+//!
+//! A list node and the `Expr` parsed from it cover the same bytes, so the
+//! two map onto each other through their spans.
+
+use super::parser::parse_file;
+use crate::ast::{Expr, LineIndex, Span};
+use crate::diagnostics::Diagnostic;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SyntaxKind {
+    /// The whole file
+    Root,
+    /// A parenthesized list, from `(` to its `)` if it has one
+    List,
+    LParen,
+    RParen,
+    /// Identifier, keyword, number, boolean, type name or `:`
+    Atom,
+    String,
+    /// `;` up to, not including, the end of the line
+    Comment,
+    Whitespace,
+}
+
+impl SyntaxKind {
+    pub fn is_trivia(self) -> bool {
+        matches!(self, SyntaxKind::Comment | SyntaxKind::Whitespace)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SyntaxToken {
+    pub kind: SyntaxKind,
+    pub text: String,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SyntaxElement {
+    Node(SyntaxNode),
+    Token(SyntaxToken),
+}
+
+impl SyntaxElement {
+    pub fn kind(&self) -> SyntaxKind {
+        match self {
+            SyntaxElement::Node(node) => node.kind,
+            SyntaxElement::Token(token) => token.kind,
+        }
+    }
+
+    pub fn span(&self) -> Span {
+        match self {
+            SyntaxElement::Node(node) => node.span,
+            SyntaxElement::Token(token) => token.span,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SyntaxNode {
+    pub kind: SyntaxKind,
+    pub span: Span,
+    pub children: Vec<SyntaxElement>,
+}
+
+impl SyntaxNode {
+    /// Parse `source` into a tree whose root covers all of it
+    pub fn parse(source: &str) -> SyntaxNode {
+        Builder::new(source).build()
+    }
+
+    /// The exact source text this node was built from
+    pub fn text(&self) -> String {
+        self.tokens().map(|t| t.text.as_str()).collect()
+    }
+
+    /// Every token under this node, in source order
+    pub fn tokens(&self) -> impl Iterator<Item = &SyntaxToken> {
+        let mut tokens = Vec::new();
+        self.collect_tokens(&mut tokens);
+        tokens.into_iter()
+    }
+
+    fn collect_tokens<'a>(&'a self, tokens: &mut Vec<&'a SyntaxToken>) {
+        for child in &self.children {
+            match child {
+                SyntaxElement::Node(node) => node.collect_tokens(tokens),
+                SyntaxElement::Token(token) => tokens.push(token),
+            }
+        }
+    }
+
+    /// Child lists
+    pub fn lists(&self) -> impl Iterator<Item = &SyntaxNode> {
+        self.children.iter().filter_map(|child| match child {
+            SyntaxElement::Node(node) => Some(node),
+            SyntaxElement::Token(_) => None,
+        })
+    }
+
+    /// Children other than trivia and the list's own parentheses
+    pub fn items(&self) -> impl Iterator<Item = &SyntaxElement> {
+        self.children.iter().filter(|child| {
+            !child.kind().is_trivia()
+                && !matches!(child.kind(), SyntaxKind::LParen | SyntaxKind::RParen)
+        })
+    }
+
+    /// Whether a list has its closing parenthesis
+    pub fn is_closed(&self) -> bool {
+        self.kind == SyntaxKind::List
+            && matches!(self.children.last(), Some(e) if e.kind() == SyntaxKind::RParen)
+    }
+
+    /// Lower the tree to the AST by parsing its text with the grammar, which
+    /// gives each expression the span of its list node or atom token
+    pub fn lower(&self) -> Result<Vec<Expr>, Diagnostic> {
+        parse_file(&self.text())
+    }
+
+    /// The list node an expression was parsed from
+    pub fn find(&self, span: Span) -> Option<&SyntaxNode> {
+        self.path_to(span).and_then(|path| path.last().copied())
+    }
+
+    /// Comments on their own lines directly above the node at `span`, with
+    /// no blank line in between; how declarations are documented
+    pub fn leading_comments(&self, span: Span) -> Vec<&SyntaxToken> {
+        let Some((parent, index)) = self.position_of(span) else {
+            return Vec::new();
+        };
+
+        let mut comments = Vec::new();
+        let mut before = parent.children[..index].iter().rev().peekable();
+        while let Some(element) = before.next() {
+            match element {
+                SyntaxElement::Token(t) if t.kind == SyntaxKind::Whitespace => {
+                    if t.text.matches('\n').count() > 1 {
+                        break;
+                    }
+                }
+                SyntaxElement::Token(t) if t.kind == SyntaxKind::Comment => {
+                    // A comment after code on the same line belongs to that code
+                    let own_line = match before.peek() {
+                        Some(SyntaxElement::Token(w)) if w.kind == SyntaxKind::Whitespace => {
+                            w.text.contains('\n')
+                        }
+                        Some(e) => e.kind() == SyntaxKind::LParen,
+                        None => true,
+                    };
+                    if !own_line {
+                        break;
+                    }
+                    comments.push(t);
+                }
+                _ => break,
+            }
+        }
+        comments.reverse();
+        comments
+    }
+
+    /// Comment at the end of the line the node at `span` ends on
+    pub fn trailing_comment(&self, span: Span) -> Option<&SyntaxToken> {
+        let (parent, index) = self.position_of(span)?;
+        let mut after = parent.children[index + 1..].iter();
+        match after.next()? {
+            SyntaxElement::Token(t) if t.kind == SyntaxKind::Comment => Some(t),
+            SyntaxElement::Token(t)
+                if t.kind == SyntaxKind::Whitespace && !t.text.contains('\n') =>
+            {
+                match after.next()? {
+                    SyntaxElement::Token(c) if c.kind == SyntaxKind::Comment => Some(c),
+                    _ => None,
+                }
+            }
+            _ => None,
+        }
+    }
+
+    /// Nodes from the root down to the node covering exactly `span`
+    fn path_to(&self, span: Span) -> Option<Vec<&SyntaxNode>> {
+        let mut path = vec![self];
+        let mut node = self;
+        loop {
+            if node.kind == SyntaxKind::List
+                && node.span.start == span.start
+                && node.span.end == span.end
+            {
+                return Some(path);
+            }
+            node = node
+                .lists()
+                .find(|n| n.span.start <= span.start && span.end <= n.span.end)?;
+            path.push(node);
+        }
+    }
+
+    /// Parent of the node at `span` and the node's index among its children
+    fn position_of(&self, span: Span) -> Option<(&SyntaxNode, usize)> {
+        let path = self.path_to(span)?;
+        let parent = path.get(path.len().checked_sub(2)?)?;
+        let index = parent.children.iter().position(
+            |child| matches!(child, SyntaxElement::Node(n) if n.span.start == span.start),
+        )?;
+        Some((parent, index))
+    }
+}
+
+struct Builder<'s> {
+    source: &'s str,
+    index: LineIndex,
+    pos: usize,
+    /// Lists opened but not yet closed, innermost last
+    stack: Vec<SyntaxNode>,
+}
+
+impl<'s> Builder<'s> {
+    fn new(source: &'s str) -> Self {
+        Self {
+            source,
+            index: LineIndex::new(source),
+            pos: 0,
+            stack: vec![SyntaxNode {
+                kind: SyntaxKind::Root,
+                span: Span::new(0, source.len(), 1, 1),
+                children: Vec::new(),
+            }],
+        }
+    }
+
+    fn build(mut self) -> SyntaxNode {
+        while let Some(c) = self.source[self.pos..].chars().next() {
+            let start = self.pos;
+            let rest = &self.source[start..];
+            let (kind, len) = match c {
+                '(' => (SyntaxKind::LParen, 1),
+                ')' => (SyntaxKind::RParen, 1),
+                ';' => (SyntaxKind::Comment, rest.find('\n').unwrap_or(rest.len())),
+                '"' => (SyntaxKind::String, string_len(rest)),
+                c if c.is_whitespace() => (
+                    SyntaxKind::Whitespace,
+                    rest.find(|c: char| !c.is_whitespace())
+                        .unwrap_or(rest.len()),
+                ),
+                _ => (
+                    SyntaxKind::Atom,
+                    rest.find(|c: char| c.is_whitespace() || "();\"".contains(c))
+                        .unwrap_or(rest.len()),
+                ),
+            };
+            self.pos += len;
+
+            let token = SyntaxToken {
+                kind,
+                text: rest[..len].to_string(),
+                span: self.index.span(self.source, start, self.pos),
+            };
+            match kind {
+                SyntaxKind::LParen => self.stack.push(SyntaxNode {
+                    kind: SyntaxKind::List,
+                    span: token.span,
+                    children: vec![SyntaxElement::Token(token)],
+                }),
+                // A `)` with nothing open stays a stray token of the root
+                SyntaxKind::RParen if self.stack.len() > 1 => {
+                    self.push(SyntaxElement::Token(token));
+                    self.close();
+                }
+                _ => self.push(SyntaxElement::Token(token)),
+            }
+        }
+
+        while self.stack.len() > 1 {
+            self.close();
+        }
+        self.stack.pop().unwrap()
+    }
+
+    fn push(&mut self, element: SyntaxElement) {
+        let node = self.stack.last_mut().unwrap();
+        if node.kind == SyntaxKind::List {
+            node.span.end = element.span().end;
+        }
+        node.children.push(element);
+    }
+
+    fn close(&mut self) {
+        let node = self.stack.pop().unwrap();
+        self.push(SyntaxElement::Node(node));
+    }
+}
+
+/// Length of the string literal at the start of `rest`, up to the end of
+/// input if it is unterminated
+fn string_len(rest: &str) -> usize {
+    let mut escaped = false;
+    for (i, c) in rest.char_indices().skip(1) {
+        match c {
+            '\\' if !escaped => escaped = true,
+            '"' if !escaped => return i + 1,
+            _ => escaped = false,
+        }
+    }
+    rest.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::{arb_expr, PrettyPrinter};
+    use proptest::prelude::*;
+    use std::collections::HashMap;
+
+    const MONITOR: &str = include_str!("../../../examples/temperature-monitor.obl");
+
+    /// Whether every expression the grammar parses from `source` has a CST
+    /// element at the same position: a list node for a form, a token for an
+    /// atom
+    fn check_spans(source: &str) -> Result<(), String> {
+        let tree = SyntaxNode::parse(source);
+        let exprs = tree.lower().map_err(|d| format!("{:?}", d))?;
+        let tokens: HashMap<usize, &SyntaxToken> = tree
+            .tokens()
+            .filter(|t| !t.kind.is_trivia())
+            .map(|t| (t.span.start, t))
+            .collect();
+        let position = |span: Span| (span.start, span.end, span.line, span.column);
+
+        let mut stack: Vec<&Expr> = exprs.iter().collect();
+        while let Some(expr) = stack.pop() {
+            let found = if source[expr.span.start..].starts_with('(') {
+                tree.find(expr.span).map(|node| node.span)
+            } else {
+                tokens.get(&expr.span.start).map(|token| token.span)
+            };
+            if found.map(position) != Some(position(expr.span)) {
+                return Err(format!(
+                    "`{}` at {:?}, CST has {:?}",
+                    &source[expr.span.start..expr.span.end],
+                    expr.span,
+                    found
+                ));
+            }
+            stack.extend(expr.children());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_round_trips_every_byte() {
+        let sources = [
+            MONITOR,
+            include_str!("../../../examples/crypto-xor.obl"),
+            "(a \"str ) ; \\\" still\" ; c)\n  ",
+            "(unclosed (list\n; trailing",
+            "stray ) (x) \"unterminated",
+        ];
+
+        for source in sources {
+            let tree = SyntaxNode::parse(source);
+            assert_eq!(tree.text(), source);
+            assert_eq!(tree.span.end, source.len());
+        }
+
+        let tree = SyntaxNode::parse("(unclosed (list) ; c\n");
+        let list = tree.lists().next().unwrap();
+        assert!(!list.is_closed());
+        assert!(list.lists().next().unwrap().is_closed());
+        assert_eq!(list.items().count(), 2);
+    }
+
+    #[test]
+    fn test_lists_map_to_expressions() {
+        let tree = SyntaxNode::parse(MONITOR);
+        let exprs = tree.lower().unwrap();
+        assert_eq!(exprs, crate::parse_file(MONITOR).unwrap());
+
+        let mut stack: Vec<&Expr> = exprs.iter().collect();
+        while let Some(expr) = stack.pop() {
+            if expr.keyword().is_some() || expr.children().len() > 1 {
+                let node = tree.find(expr.span).expect("every form has a list node");
+                assert_eq!(node.text(), &MONITOR[expr.span.start..expr.span.end]);
+            }
+            stack.extend(expr.children());
+        }
+    }
+
+    #[test]
+    fn test_examples_agree_with_parser_on_spans() {
+        for source in [MONITOR, include_str!("../../../examples/crypto-xor.obl")] {
+            assert_eq!(check_spans(source), Ok(()));
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn test_printed_trees_agree_with_parser_on_spans(expr in arb_expr()) {
+            // Narrow, so that spans start on many lines and columns
+            let printed = PrettyPrinter::with_width(10).print_expr(&expr);
+            prop_assert_eq!(check_spans(&printed), Ok(()), "{}", printed);
+        }
+    }
+
+    #[test]
+    fn test_leading_and_trailing_comments() {
+        let source = "\
+(program p (resource-budget (time-ms 1))
+  ; Unrelated
+
+  ;; Blink the status LED
+  ;; `times` times.
+  (defun-deploy blink (times) 0) ; done
+  (defun-deploy idle () 0))";
+        let tree = SyntaxNode::parse(source);
+        let exprs = tree.lower().unwrap();
+        let crate::ExprKind::Program { forms, .. } = &exprs[0].kind else {
+            panic!("expected program");
+        };
+
+        let docs: Vec<&str> = tree
+            .leading_comments(forms[0].span)
+            .iter()
+            .map(|t| t.text.as_str())
+            .collect();
+        assert_eq!(docs, vec![";; Blink the status LED", ";; `times` times."]);
+        assert_eq!(tree.trailing_comment(forms[0].span).unwrap().text, "; done");
+        assert!(tree.leading_comments(forms[1].span).is_empty());
+        assert!(tree.trailing_comment(forms[1].span).is_none());
+    }
+}
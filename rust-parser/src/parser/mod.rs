@@ -1,3 +1,8 @@
+pub mod cst;
+#[allow(clippy::module_inception)]
 pub mod parser;
+pub mod recovery;
 
+pub use cst::*;
 pub use parser::*;
+pub use recovery::*;
@@ -1,21 +1,34 @@
 use pest::Parser;
 use pest_derive::Parser;
 
-use crate::ast::{Expr, Parameter, ResourceKind, ResourceSpec, ResourceType, Type};
-use anyhow::{anyhow, Result};
+use crate::ast::{
+    literal, Expr, ExprKind, IntLiteral, LineIndex, Parameter, ResourceKind, ResourceSpec,
+    ResourceType, Span, Type,
+};
+use crate::diagnostics::{codes, Code, Diagnostic};
+use std::str::FromStr;
+
+type Result<T> = std::result::Result<T, Diagnostic>;
 
 #[derive(Parser)]
 #[grammar = "parser/grammar.pest"]
 pub struct OblibenyParser;
 
+type Pair<'i> = pest::iterators::Pair<'i, Rule>;
+
 pub fn parse_file(input: &str) -> Result<Vec<Expr>> {
-    let pairs = OblibenyParser::parse(Rule::file, input)
-        .map_err(|e| anyhow!("Parse error: {}", e))?;
+    let index = LineIndex::new(input);
+    let file = OblibenyParser::parse(Rule::file, input)
+        .map_err(|e| syntax_error(e, input, &index, 0))?
+        .next()
+        .unwrap();
+
+    let lowerer = Lowerer::new(input, &index);
 
     let mut exprs = Vec::new();
-    for pair in pairs {
+    for pair in file.into_inner() {
         if pair.as_rule() == Rule::form {
-            exprs.push(parse_form(pair)?);
+            exprs.push(lowerer.parse_form(pair)?);
         } else if pair.as_rule() == Rule::EOI {
             break;
         }
@@ -24,406 +37,919 @@ pub fn parse_file(input: &str) -> Result<Vec<Expr>> {
     Ok(exprs)
 }
 
-fn parse_form(pair: pest::iterators::Pair<Rule>) -> Result<Expr> {
-    let inner = pair.into_inner().next().unwrap();
-
-    match inner.as_rule() {
-        Rule::integer => Ok(Expr::Int(inner.as_str().parse()?)),
-        Rule::float => Ok(Expr::Float(inner.as_str().parse()?)),
-        Rule::boolean => Ok(Expr::Bool(inner.as_str() == "true")),
-        Rule::string => {
-            let s = inner.as_str();
-            let unquoted = &s[1..s.len() - 1]; // Remove quotes
-            Ok(Expr::String(unquoted.to_string()))
+/// Lowers pest pairs into `Expr` nodes, attaching source spans as it goes
+pub(super) struct Lowerer<'s> {
+    source: &'s str,
+    index: &'s LineIndex,
+    /// Byte offset of the parsed text within `source`
+    offset: usize,
+}
+
+impl<'s> Lowerer<'s> {
+    pub(super) fn new(source: &'s str, index: &'s LineIndex) -> Self {
+        Self::at_offset(source, index, 0)
+    }
+
+    /// Lowerer for a slice of `source` starting at `offset`
+    pub(super) fn at_offset(source: &'s str, index: &'s LineIndex, offset: usize) -> Self {
+        Self {
+            source,
+            index,
+            offset,
         }
-        Rule::ident => Ok(Expr::Ident(inner.as_str().to_string())),
-        Rule::list => parse_list(inner),
-        _ => Err(anyhow!("Unexpected rule: {:?}", inner.as_rule())),
     }
-}
 
-fn parse_list(pair: pest::iterators::Pair<Rule>) -> Result<Expr> {
-    let inner = pair.into_inner().next();
-    if inner.is_none() {
-        return Ok(Expr::FunctionCall {
-            func: Box::new(Expr::Ident("nil".to_string())),
-            args: vec![],
-        });
-    }
-
-    let inner = inner.unwrap();
-
-    match inner.as_rule() {
-        Rule::defun_deploy => parse_defun_deploy(inner),
-        Rule::defun_compile => parse_defun_compile(inner),
-        Rule::bounded_for => parse_bounded_for(inner),
-        Rule::with_capability => parse_with_capability(inner),
-        Rule::let_binding => parse_let(inner),
-        Rule::if_expr => parse_if(inner),
-        Rule::set_var => parse_set(inner),
-        Rule::array_get => parse_array_get(inner),
-        Rule::array_set => parse_array_set(inner),
-        Rule::array_length => parse_array_length(inner),
-        Rule::array_literal => parse_array_literal(inner),
-        Rule::sleep_ms => parse_sleep_ms(inner),
-        Rule::gpio_set => parse_gpio_set(inner),
-        Rule::gpio_get => parse_gpio_get(inner),
-        Rule::sensor_read => parse_sensor_read(inner),
-        Rule::network_send => parse_network_send(inner),
-        Rule::program => parse_program(inner),
-        Rule::resource_budget => parse_resource_budget(inner),
-        Rule::defcap => parse_defcap(inner),
-        Rule::function_call => parse_function_call(inner),
-        _ => {
-            // Default to function call
-            parse_function_call(inner)
+    fn span(&self, pair: &Pair) -> Span {
+        let span = pair.as_span();
+        self.index.span(
+            self.source,
+            self.offset + span.start(),
+            self.offset + span.end(),
+        )
+    }
+
+    fn error(&self, code: Code, message: impl Into<String>, pair: &Pair) -> Diagnostic {
+        Diagnostic::error(code, message, self.span(pair))
+    }
+
+    /// Parse a numeric token, reporting out-of-range values at the token
+    fn number<T: FromStr>(&self, pair: &Pair) -> Result<T> {
+        pair.as_str()
+            .parse()
+            .map_err(|_| self.out_of_range(pair))
+    }
+
+    fn int_literal(&self, pair: &Pair) -> Result<IntLiteral> {
+        IntLiteral::parse(pair.as_str()).ok_or_else(|| self.out_of_range(pair))
+    }
+
+    /// An integer token used as a size or amount rather than an expression
+    fn integer<T: TryFrom<i128>>(&self, pair: &Pair) -> Result<T> {
+        T::try_from(self.int_literal(pair)?.value).map_err(|_| self.out_of_range(pair))
+    }
+
+    fn out_of_range(&self, pair: &Pair) -> Diagnostic {
+        self.error(
+            codes::INVALID_LITERAL,
+            format!("numeric literal `{}` is out of range", pair.as_str()),
+            pair,
+        )
+    }
+
+    /// Decode a string literal token, reporting unknown escapes at the escape
+    fn string(&self, pair: &Pair) -> Result<String> {
+        let s = pair.as_str();
+        literal::unescape(&s[1..s.len() - 1]).map_err(|e| {
+            // Skip the opening quote
+            let start = self.offset + pair.as_span().start() + 1 + e.offset;
+            Diagnostic::error(
+                codes::INVALID_ESCAPE,
+                e.to_string(),
+                self.index.span(self.source, start, start + e.sequence.len()),
+            )
+            .with_label("not a valid escape")
+            .with_help(r#"the valid escapes are `\"`, `\\`, `\n`, `\r` and `\t`"#)
+        })
+    }
+
+    pub(super) fn parse_form(&self, pair: Pair) -> Result<Expr> {
+        let span = self.span(&pair);
+        let mut inner = pair.into_inner().next().unwrap();
+        if inner.as_rule() == Rule::atom {
+            inner = inner.into_inner().next().unwrap();
         }
+
+        let kind = match inner.as_rule() {
+            Rule::integer => ExprKind::Int(self.int_literal(&inner)?),
+            Rule::float => ExprKind::Float(self.number(&inner)?),
+            Rule::boolean => ExprKind::Bool(inner.as_str() == "true"),
+            Rule::string => ExprKind::String(self.string(&inner)?),
+            Rule::ident => ExprKind::Ident(inner.as_str().to_string()),
+            Rule::list => return self.parse_list(inner),
+            rule => {
+                return Err(self.error(
+                    codes::SYNTAX_ERROR,
+                    format!("unexpected {}", describe_rule(&rule)),
+                    &inner,
+                ))
+            }
+        };
+
+        Ok(Expr::new(kind, span))
     }
-}
 
-fn parse_defun_deploy(pair: pest::iterators::Pair<Rule>) -> Result<Expr> {
-    let mut inner = pair.into_inner();
+    fn parse_list(&self, pair: Pair) -> Result<Expr> {
+        let span = self.span(&pair);
+        let inner = pair.into_inner().next();
+        if inner.is_none() {
+            return Ok(Expr::new(
+                ExprKind::FunctionCall {
+                    func: Box::new(Expr::new(ExprKind::Ident("nil".to_string()), span)),
+                    args: vec![],
+                },
+                span,
+            ));
+        }
 
-    let name = inner.next().unwrap().as_str().to_string();
-    let params = parse_param_list(inner.next().unwrap())?;
+        self.parse_construct(inner.unwrap())
+    }
+
+    /// Lower one of the keyword constructs that can appear inside a list
+    pub(super) fn parse_construct(&self, inner: Pair) -> Result<Expr> {
+        match inner.as_rule() {
+            Rule::defun_deploy => self.parse_defun_deploy(inner),
+            Rule::defun_compile => self.parse_defun_compile(inner),
+            Rule::macro_def => self.parse_macro(inner),
+            Rule::eval_compile => self.parse_eval_compile(inner),
+            Rule::include_file => self.parse_include(inner),
+            Rule::for_loop => self.parse_for(inner),
+            Rule::while_loop => self.parse_while(inner),
+            Rule::bounded_for => self.parse_bounded_for(inner),
+            Rule::with_capability => self.parse_with_capability(inner),
+            Rule::let_binding => self.parse_let(inner),
+            Rule::if_expr => self.parse_if(inner),
+            Rule::set_var => self.parse_set(inner),
+            Rule::array_get => self.parse_array_get(inner),
+            Rule::array_set => self.parse_array_set(inner),
+            Rule::array_length => self.parse_array_length(inner),
+            Rule::array_literal => self.parse_array_literal(inner),
+            Rule::array_init => self.parse_array_init(inner),
+            Rule::sleep_ms => self.parse_sleep_ms(inner),
+            Rule::gpio_set => self.parse_gpio_set(inner),
+            Rule::gpio_get => self.parse_gpio_get(inner),
+            Rule::uart_send => self.parse_uart_send(inner),
+            Rule::uart_recv => self.parse_uart_recv(inner),
+            Rule::sensor_read => self.parse_sensor_read(inner),
+            Rule::network_send => self.parse_network_send(inner),
+            Rule::network_recv => self.parse_network_recv(inner),
+            Rule::timestamp => Ok(Expr::new(ExprKind::Timestamp, self.span(&inner))),
+            Rule::program => self.parse_program(inner),
+            Rule::resource_budget => self.parse_resource_budget(inner),
+            Rule::defcap => self.parse_defcap(inner),
+            Rule::function_call => self.parse_function_call(inner),
+            rule => Err(self.error(
+                codes::SYNTAX_ERROR,
+                format!("unexpected {}", describe_rule(&rule)),
+                &inner,
+            )),
+        }
+    }
 
-    let mut return_type = None;
-    let mut body = Vec::new();
+    fn parse_defun_deploy(&self, pair: Pair) -> Result<Expr> {
+        let span = self.span(&pair);
+        let mut inner = pair.into_inner();
 
-    for pair in inner {
-        match pair.as_rule() {
-            Rule::type_annotation => {
-                return_type = Some(parse_type(pair.into_inner().next().unwrap())?);
+        let name = inner.next().unwrap().as_str().to_string();
+        let params = self.parse_param_list(inner.next().unwrap())?;
+
+        let mut return_type = None;
+        let mut body = Vec::new();
+
+        for pair in inner {
+            match pair.as_rule() {
+                Rule::type_annotation => {
+                    return_type = Some(self.parse_type(pair.into_inner().next().unwrap())?);
+                }
+                Rule::form => {
+                    body.push(self.parse_form(pair)?);
+                }
+                _ => {}
             }
-            Rule::form => {
-                body.push(parse_form(pair)?);
+        }
+
+        Ok(Expr::new(
+            ExprKind::DefunDeploy {
+                name,
+                params,
+                return_type,
+                body,
+            },
+            span,
+        ))
+    }
+
+    fn parse_defun_compile(&self, pair: Pair) -> Result<Expr> {
+        let span = self.span(&pair);
+        let mut inner = pair.into_inner();
+
+        let name = inner.next().unwrap().as_str().to_string();
+        let params = self.parse_param_list(inner.next().unwrap())?;
+
+        let mut return_type = None;
+        let mut body = Vec::new();
+
+        for pair in inner {
+            match pair.as_rule() {
+                Rule::type_annotation => {
+                    return_type = Some(self.parse_type(pair.into_inner().next().unwrap())?);
+                }
+                Rule::form => {
+                    body.push(self.parse_form(pair)?);
+                }
+                _ => {}
             }
-            _ => {}
         }
+
+        Ok(Expr::new(
+            ExprKind::DefunCompile {
+                name,
+                params,
+                return_type,
+                body,
+            },
+            span,
+        ))
     }
 
-    Ok(Expr::DefunDeploy {
-        name,
-        params,
-        return_type,
-        body,
-    })
-}
+    fn parse_macro(&self, pair: Pair) -> Result<Expr> {
+        let span = self.span(&pair);
+        let mut inner = pair.into_inner();
 
-fn parse_defun_compile(pair: pest::iterators::Pair<Rule>) -> Result<Expr> {
-    let mut inner = pair.into_inner();
+        let name = inner.next().unwrap().as_str().to_string();
+        let params = self.parse_param_list(inner.next().unwrap())?;
 
-    let name = inner.next().unwrap().as_str().to_string();
-    let params = parse_param_list(inner.next().unwrap())?;
+        let mut body = Vec::new();
+        for pair in inner {
+            body.push(self.parse_form(pair)?);
+        }
 
-    let mut return_type = None;
-    let mut body = Vec::new();
+        Ok(Expr::new(ExprKind::Macro { name, params, body }, span))
+    }
 
-    for pair in inner {
-        match pair.as_rule() {
-            Rule::type_annotation => {
-                return_type = Some(parse_type(pair.into_inner().next().unwrap())?);
-            }
-            Rule::form => {
-                body.push(parse_form(pair)?);
-            }
-            _ => {}
+    fn parse_eval_compile(&self, pair: Pair) -> Result<Expr> {
+        let span = self.span(&pair);
+        let inner = pair.into_inner().next().unwrap();
+        Ok(Expr::new(
+            ExprKind::EvalCompile(Box::new(self.parse_form(inner)?)),
+            span,
+        ))
+    }
+
+    fn parse_include(&self, pair: Pair) -> Result<Expr> {
+        let span = self.span(&pair);
+        let path = self.string(&pair.into_inner().next().unwrap())?;
+
+        Ok(Expr::new(ExprKind::Include(path), span))
+    }
+
+    fn parse_for(&self, pair: Pair) -> Result<Expr> {
+        let span = self.span(&pair);
+        let mut inner = pair.into_inner();
+
+        let var = inner.next().unwrap().as_str().to_string();
+        let iterable = Box::new(self.parse_form(inner.next().unwrap())?);
+
+        let mut body = Vec::new();
+        for pair in inner {
+            body.push(self.parse_form(pair)?);
         }
+
+        Ok(Expr::new(
+            ExprKind::For {
+                var,
+                iterable,
+                body,
+            },
+            span,
+        ))
     }
 
-    Ok(Expr::DefunCompile {
-        name,
-        params,
-        return_type,
-        body,
-    })
-}
+    fn parse_while(&self, pair: Pair) -> Result<Expr> {
+        let span = self.span(&pair);
+        let mut inner = pair.into_inner();
 
-fn parse_bounded_for(pair: pest::iterators::Pair<Rule>) -> Result<Expr> {
-    let mut inner = pair.into_inner();
+        let condition = Box::new(self.parse_form(inner.next().unwrap())?);
 
-    let var = inner.next().unwrap().as_str().to_string();
-    let start = Box::new(parse_form(inner.next().unwrap())?);
-    let end = Box::new(parse_form(inner.next().unwrap())?);
+        let mut body = Vec::new();
+        for pair in inner {
+            body.push(self.parse_form(pair)?);
+        }
 
-    let mut body = Vec::new();
-    for pair in inner {
-        body.push(parse_form(pair)?);
+        Ok(Expr::new(ExprKind::While { condition, body }, span))
     }
 
-    Ok(Expr::BoundedFor {
-        var,
-        start,
-        end,
-        body,
-    })
-}
+    fn parse_bounded_for(&self, pair: Pair) -> Result<Expr> {
+        let span = self.span(&pair);
+        let mut inner = pair.into_inner();
 
-fn parse_with_capability(pair: pest::iterators::Pair<Rule>) -> Result<Expr> {
-    let mut inner = pair.into_inner();
+        let var = inner.next().unwrap().as_str().to_string();
+        let start = Box::new(self.parse_form(inner.next().unwrap())?);
+        let end = Box::new(self.parse_form(inner.next().unwrap())?);
 
-    let capability = Box::new(parse_form(inner.next().unwrap())?);
+        let mut body = Vec::new();
+        for pair in inner {
+            body.push(self.parse_form(pair)?);
+        }
 
-    let mut body = Vec::new();
-    for pair in inner {
-        body.push(parse_form(pair)?);
+        Ok(Expr::new(
+            ExprKind::BoundedFor {
+                var,
+                start,
+                end,
+                body,
+            },
+            span,
+        ))
     }
 
-    Ok(Expr::WithCapability { capability, body })
-}
+    fn parse_with_capability(&self, pair: Pair) -> Result<Expr> {
+        let span = self.span(&pair);
+        let mut inner = pair.into_inner();
 
-fn parse_let(pair: pest::iterators::Pair<Rule>) -> Result<Expr> {
-    let mut inner = pair.into_inner();
+        let capability = Box::new(self.parse_form(inner.next().unwrap())?);
 
-    let bindings_pair = inner.next().unwrap();
-    let bindings = parse_bindings(bindings_pair)?;
+        let mut body = Vec::new();
+        for pair in inner {
+            body.push(self.parse_form(pair)?);
+        }
 
-    let mut body = Vec::new();
-    for pair in inner {
-        body.push(parse_form(pair)?);
+        Ok(Expr::new(ExprKind::WithCapability { capability, body }, span))
     }
 
-    Ok(Expr::Let { bindings, body })
-}
+    fn parse_let(&self, pair: Pair) -> Result<Expr> {
+        let span = self.span(&pair);
+        let mut inner = pair.into_inner();
 
-fn parse_bindings(pair: pest::iterators::Pair<Rule>) -> Result<Vec<(String, Expr)>> {
-    let mut bindings = Vec::new();
+        let bindings_pair = inner.next().unwrap();
+        let bindings = self.parse_bindings(bindings_pair)?;
 
-    for binding in pair.into_inner() {
-        let mut inner = binding.into_inner();
-        let name = inner.next().unwrap().as_str().to_string();
-        let expr = parse_form(inner.next().unwrap())?;
-        bindings.push((name, expr));
+        let mut body = Vec::new();
+        for pair in inner {
+            body.push(self.parse_form(pair)?);
+        }
+
+        Ok(Expr::new(ExprKind::Let { bindings, body }, span))
     }
 
-    Ok(bindings)
-}
+    fn parse_bindings(&self, pair: Pair) -> Result<Vec<(String, Expr)>> {
+        let mut bindings = Vec::new();
 
-fn parse_if(pair: pest::iterators::Pair<Rule>) -> Result<Expr> {
-    let mut inner = pair.into_inner();
+        for binding in pair.into_inner() {
+            let mut inner = binding.into_inner();
+            let name = inner.next().unwrap().as_str().to_string();
+            let expr = self.parse_form(inner.next().unwrap())?;
+            bindings.push((name, expr));
+        }
 
-    let condition = Box::new(parse_form(inner.next().unwrap())?);
-    let then_branch = Box::new(parse_form(inner.next().unwrap())?);
-    let else_branch = Box::new(parse_form(inner.next().unwrap())?);
+        Ok(bindings)
+    }
 
-    Ok(Expr::If {
-        condition,
-        then_branch,
-        else_branch,
-    })
-}
+    fn parse_if(&self, pair: Pair) -> Result<Expr> {
+        let span = self.span(&pair);
+        let mut inner = pair.into_inner();
+
+        let condition = Box::new(self.parse_form(inner.next().unwrap())?);
+        let then_branch = Box::new(self.parse_form(inner.next().unwrap())?);
+        let else_branch = Box::new(self.parse_form(inner.next().unwrap())?);
+
+        Ok(Expr::new(
+            ExprKind::If {
+                condition,
+                then_branch,
+                else_branch,
+            },
+            span,
+        ))
+    }
 
-fn parse_set(pair: pest::iterators::Pair<Rule>) -> Result<Expr> {
-    let mut inner = pair.into_inner();
+    fn parse_set(&self, pair: Pair) -> Result<Expr> {
+        let span = self.span(&pair);
+        let mut inner = pair.into_inner();
 
-    let var = inner.next().unwrap().as_str().to_string();
-    let value = Box::new(parse_form(inner.next().unwrap())?);
+        let var = inner.next().unwrap().as_str().to_string();
+        let value = Box::new(self.parse_form(inner.next().unwrap())?);
 
-    Ok(Expr::Set { var, value })
-}
+        Ok(Expr::new(ExprKind::Set { var, value }, span))
+    }
 
-fn parse_array_get(pair: pest::iterators::Pair<Rule>) -> Result<Expr> {
-    let mut inner = pair.into_inner();
+    fn parse_array_get(&self, pair: Pair) -> Result<Expr> {
+        let span = self.span(&pair);
+        let mut inner = pair.into_inner();
 
-    let array = Box::new(parse_form(inner.next().unwrap())?);
-    let index = Box::new(parse_form(inner.next().unwrap())?);
+        let array = Box::new(self.parse_form(inner.next().unwrap())?);
+        let index = Box::new(self.parse_form(inner.next().unwrap())?);
 
-    Ok(Expr::ArrayGet { array, index })
-}
+        Ok(Expr::new(ExprKind::ArrayGet { array, index }, span))
+    }
 
-fn parse_array_set(pair: pest::iterators::Pair<Rule>) -> Result<Expr> {
-    let mut inner = pair.into_inner();
+    fn parse_array_set(&self, pair: Pair) -> Result<Expr> {
+        let span = self.span(&pair);
+        let mut inner = pair.into_inner();
+
+        let array = Box::new(self.parse_form(inner.next().unwrap())?);
+        let index = Box::new(self.parse_form(inner.next().unwrap())?);
+        let value = Box::new(self.parse_form(inner.next().unwrap())?);
+
+        Ok(Expr::new(
+            ExprKind::ArraySet {
+                array,
+                index,
+                value,
+            },
+            span,
+        ))
+    }
 
-    let array = Box::new(parse_form(inner.next().unwrap())?);
-    let index = Box::new(parse_form(inner.next().unwrap())?);
-    let value = Box::new(parse_form(inner.next().unwrap())?);
+    fn parse_array_length(&self, pair: Pair) -> Result<Expr> {
+        let span = self.span(&pair);
+        let inner = pair.into_inner().next().unwrap();
+        Ok(Expr::new(
+            ExprKind::ArrayLength(Box::new(self.parse_form(inner)?)),
+            span,
+        ))
+    }
 
-    Ok(Expr::ArraySet {
-        array,
-        index,
-        value,
-    })
-}
+    fn parse_array_literal(&self, pair: Pair) -> Result<Expr> {
+        let span = self.span(&pair);
+        let mut inner = pair.into_inner();
 
-fn parse_array_length(pair: pest::iterators::Pair<Rule>) -> Result<Expr> {
-    let inner = pair.into_inner().next().unwrap();
-    Ok(Expr::ArrayLength(Box::new(parse_form(inner)?)))
-}
+        let elem_type = self.parse_type(inner.next().unwrap())?;
+        let size = self.integer(&inner.next().unwrap())?;
 
-fn parse_array_literal(pair: pest::iterators::Pair<Rule>) -> Result<Expr> {
-    let mut inner = pair.into_inner();
+        Ok(Expr::new(ExprKind::ArrayLiteral { elem_type, size }, span))
+    }
 
-    let elem_type = parse_type(inner.next().unwrap())?;
-    let size = inner.next().unwrap().as_str().parse()?;
+    fn parse_array_init(&self, pair: Pair) -> Result<Expr> {
+        let span = self.span(&pair);
+        let mut inner = pair.into_inner();
 
-    Ok(Expr::ArrayLiteral { elem_type, size })
-}
+        let elem_type = self.parse_type(inner.next().unwrap())?;
+        let elements = inner
+            .map(|p| self.parse_form(p))
+            .collect::<Result<Vec<_>>>()?;
 
-fn parse_sleep_ms(pair: pest::iterators::Pair<Rule>) -> Result<Expr> {
-    let inner = pair.into_inner().next().unwrap();
-    Ok(Expr::SleepMs(Box::new(parse_form(inner)?)))
-}
+        Ok(Expr::new(ExprKind::ArrayInit { elem_type, elements }, span))
+    }
 
-fn parse_gpio_set(pair: pest::iterators::Pair<Rule>) -> Result<Expr> {
-    let mut inner = pair.into_inner();
+    fn parse_sleep_ms(&self, pair: Pair) -> Result<Expr> {
+        let span = self.span(&pair);
+        let inner = pair.into_inner().next().unwrap();
+        Ok(Expr::new(
+            ExprKind::SleepMs(Box::new(self.parse_form(inner)?)),
+            span,
+        ))
+    }
 
-    let device = Box::new(parse_form(inner.next().unwrap())?);
-    let value = Box::new(parse_form(inner.next().unwrap())?);
+    fn parse_gpio_set(&self, pair: Pair) -> Result<Expr> {
+        let span = self.span(&pair);
+        let mut inner = pair.into_inner();
 
-    Ok(Expr::GpioSet { device, value })
-}
+        let device = Box::new(self.parse_form(inner.next().unwrap())?);
+        let value = Box::new(self.parse_form(inner.next().unwrap())?);
 
-fn parse_gpio_get(pair: pest::iterators::Pair<Rule>) -> Result<Expr> {
-    let inner = pair.into_inner().next().unwrap();
-    Ok(Expr::GpioGet(Box::new(parse_form(inner)?)))
-}
+        Ok(Expr::new(ExprKind::GpioSet { device, value }, span))
+    }
 
-fn parse_sensor_read(pair: pest::iterators::Pair<Rule>) -> Result<Expr> {
-    let inner = pair.into_inner().next().unwrap();
-    Ok(Expr::SensorRead(Box::new(parse_form(inner)?)))
-}
+    fn parse_gpio_get(&self, pair: Pair) -> Result<Expr> {
+        let span = self.span(&pair);
+        let inner = pair.into_inner().next().unwrap();
+        Ok(Expr::new(
+            ExprKind::GpioGet(Box::new(self.parse_form(inner)?)),
+            span,
+        ))
+    }
 
-fn parse_network_send(pair: pest::iterators::Pair<Rule>) -> Result<Expr> {
-    let mut inner = pair.into_inner();
+    fn parse_uart_send(&self, pair: Pair) -> Result<Expr> {
+        let span = self.span(&pair);
+        let mut inner = pair.into_inner();
 
-    let device = Box::new(parse_form(inner.next().unwrap())?);
-    let data = Box::new(parse_form(inner.next().unwrap())?);
+        let device = Box::new(self.parse_form(inner.next().unwrap())?);
+        let data = Box::new(self.parse_form(inner.next().unwrap())?);
 
-    Ok(Expr::NetworkSend { device, data })
-}
+        Ok(Expr::new(ExprKind::UartSend { device, data }, span))
+    }
 
-fn parse_program(pair: pest::iterators::Pair<Rule>) -> Result<Expr> {
-    let mut inner = pair.into_inner();
+    fn parse_uart_recv(&self, pair: Pair) -> Result<Expr> {
+        let span = self.span(&pair);
+        let inner = pair.into_inner().next().unwrap();
+        Ok(Expr::new(
+            ExprKind::UartRecv(Box::new(self.parse_form(inner)?)),
+            span,
+        ))
+    }
+
+    fn parse_sensor_read(&self, pair: Pair) -> Result<Expr> {
+        let span = self.span(&pair);
+        let inner = pair.into_inner().next().unwrap();
+        Ok(Expr::new(
+            ExprKind::SensorRead(Box::new(self.parse_form(inner)?)),
+            span,
+        ))
+    }
 
-    let name = inner.next().unwrap().as_str().to_string();
-    let budget = Box::new(parse_resource_budget(inner.next().unwrap())?);
+    fn parse_network_send(&self, pair: Pair) -> Result<Expr> {
+        let span = self.span(&pair);
+        let mut inner = pair.into_inner();
 
-    let mut forms = Vec::new();
-    for pair in inner {
-        forms.push(parse_form(pair)?);
+        let device = Box::new(self.parse_form(inner.next().unwrap())?);
+        let data = Box::new(self.parse_form(inner.next().unwrap())?);
+
+        Ok(Expr::new(ExprKind::NetworkSend { device, data }, span))
     }
 
-    Ok(Expr::Program {
-        name,
-        budget,
-        forms,
-    })
-}
+    fn parse_network_recv(&self, pair: Pair) -> Result<Expr> {
+        let span = self.span(&pair);
+        let inner = pair.into_inner().next().unwrap();
+        Ok(Expr::new(
+            ExprKind::NetworkRecv(Box::new(self.parse_form(inner)?)),
+            span,
+        ))
+    }
 
-fn parse_resource_budget(pair: pest::iterators::Pair<Rule>) -> Result<Expr> {
-    let mut specs = Vec::new();
+    fn parse_program(&self, pair: Pair) -> Result<Expr> {
+        let span = self.span(&pair);
+        let mut inner = pair.into_inner();
 
-    for spec_pair in pair.into_inner() {
-        let mut inner = spec_pair.into_inner();
-        let kind_str = inner.next().unwrap().as_str();
-        let amount: u64 = inner.next().unwrap().as_str().parse()?;
+        let name = inner.next().unwrap().as_str().to_string();
+        let budget = Box::new(self.parse_resource_budget(inner.next().unwrap())?);
 
-        let kind = match kind_str {
-            "time-ms" => ResourceKind::TimeMs,
-            "memory-bytes" => ResourceKind::MemoryBytes,
-            "network-bytes" => ResourceKind::NetworkBytes,
-            "storage-bytes" => ResourceKind::StorageBytes,
-            _ => return Err(anyhow!("Unknown resource kind: {}", kind_str)),
-        };
+        let mut forms = Vec::new();
+        for pair in inner {
+            // Each toplevel_form wraps exactly one construct
+            forms.push(self.parse_construct(pair.into_inner().next().unwrap())?);
+        }
 
-        specs.push(ResourceSpec::new(kind, amount));
+        Ok(Expr::new(
+            ExprKind::Program {
+                name,
+                budget,
+                forms,
+            },
+            span,
+        ))
     }
 
-    Ok(Expr::ResourceBudget { specs })
-}
+    pub(super) fn parse_resource_budget(&self, pair: Pair) -> Result<Expr> {
+        let span = self.span(&pair);
+        let mut specs = Vec::new();
+
+        for spec_pair in pair.into_inner() {
+            let mut inner = spec_pair.into_inner();
+            let kind_pair = inner.next().unwrap();
+            let amount: u64 = self.integer(&inner.next().unwrap())?;
+
+            let kind = match kind_pair.as_str() {
+                "time-ms" => ResourceKind::TimeMs,
+                "memory-bytes" => ResourceKind::MemoryBytes,
+                "network-bytes" => ResourceKind::NetworkBytes,
+                "storage-bytes" => ResourceKind::StorageBytes,
+                other => {
+                    return Err(self.error(
+                        codes::UNKNOWN_RESOURCE,
+                        format!("unknown resource kind `{}`", other),
+                        &kind_pair,
+                    ))
+                }
+            };
 
-fn parse_defcap(pair: pest::iterators::Pair<Rule>) -> Result<Expr> {
-    let mut inner = pair.into_inner();
+            specs.push(ResourceSpec::new(kind, amount));
+        }
 
-    let name = inner.next().unwrap().as_str().to_string();
-    let params = parse_param_list(inner.next().unwrap())?;
-    let description_pair = inner.next().unwrap();
-    let desc_str = description_pair.as_str();
-    let description = desc_str[1..desc_str.len() - 1].to_string(); // Remove quotes
+        Ok(Expr::new(ExprKind::ResourceBudget { specs }, span))
+    }
 
-    Ok(Expr::DefCap {
-        name,
-        params,
-        description,
-    })
-}
+    fn parse_defcap(&self, pair: Pair) -> Result<Expr> {
+        let span = self.span(&pair);
+        let mut inner = pair.into_inner();
+
+        let name = inner.next().unwrap().as_str().to_string();
+        let params = self.parse_param_list(inner.next().unwrap())?;
+        let mut next = inner.next().unwrap();
+        let resource = if next.as_rule() == Rule::capability_type {
+            let resource = self.parse_resource(next.into_inner().next().unwrap())?;
+            next = inner.next().unwrap();
+            Some(resource)
+        } else {
+            None
+        };
+        let description = self.string(&next)?;
+
+        Ok(Expr::new(
+            ExprKind::DefCap {
+                name,
+                params,
+                resource,
+                description,
+            },
+            span,
+        ))
+    }
 
-fn parse_function_call(pair: pest::iterators::Pair<Rule>) -> Result<Expr> {
-    let mut inner = pair.into_inner();
+    fn parse_function_call(&self, pair: Pair) -> Result<Expr> {
+        let span = self.span(&pair);
+        let mut inner = pair.into_inner();
 
-    let func = Box::new(parse_form(inner.next().unwrap())?);
+        let func = Box::new(self.parse_form(inner.next().unwrap())?);
 
-    let mut args = Vec::new();
-    for pair in inner {
-        args.push(parse_form(pair)?);
+        let mut args = Vec::new();
+        for pair in inner {
+            args.push(self.parse_form(pair)?);
+        }
+
+        Ok(Expr::new(ExprKind::FunctionCall { func, args }, span))
     }
 
-    Ok(Expr::FunctionCall { func, args })
-}
+    fn parse_param_list(&self, pair: Pair) -> Result<Vec<Parameter>> {
+        let mut params = Vec::new();
 
-fn parse_param_list(pair: pest::iterators::Pair<Rule>) -> Result<Vec<Parameter>> {
-    let mut params = Vec::new();
+        for param_pair in pair.into_inner() {
+            let param = self.parse_parameter(param_pair)?;
+            params.push(param);
+        }
 
-    for param_pair in pair.into_inner() {
-        let param = parse_parameter(param_pair)?;
-        params.push(param);
+        Ok(params)
     }
 
-    Ok(params)
-}
+    fn parse_parameter(&self, pair: Pair) -> Result<Parameter> {
+        let span = self.span(&pair);
+        let mut parts = pair.into_inner();
+        let name = parts.next().unwrap().as_str().to_string();
+
+        // Typed parameter: (name type), or (name (capability res budget))
+        match parts.next() {
+            Some(ty) if ty.as_rule() == Rule::budgeted_capability => {
+                let mut parts = ty.into_inner();
+                let resource = self.parse_resource(parts.next().unwrap())?;
+                let budget = self.integer(&parts.next().unwrap())?;
+                Ok(Parameter::new(name, Some(Type::Capability { resource }))
+                    .with_budget(budget)
+                    .with_span(span))
+            }
+            Some(ty) => Ok(Parameter::new(name, Some(self.parse_type(ty)?)).with_span(span)),
+            None => Ok(Parameter::new(name, None).with_span(span)),
+        }
+    }
 
-fn parse_parameter(pair: pest::iterators::Pair<Rule>) -> Result<Parameter> {
-    let inner = pair.into_inner().next().unwrap();
-
-    match inner.as_rule() {
-        Rule::ident => Ok(Parameter::new(inner.as_str().to_string(), None)),
-        _ => {
-            // Typed parameter: (name type)
-            let mut parts = inner.into_inner();
-            let name = parts.next().unwrap().as_str().to_string();
-            let ty = parse_type(parts.next().unwrap())?;
-            Ok(Parameter::new(name, Some(ty)))
+    fn parse_type(&self, pair: Pair) -> Result<Type> {
+        let inner = pair.into_inner().next().unwrap();
+
+        match inner.as_rule() {
+            Rule::simple_type => Ok(match inner.as_str() {
+                "int8" => Type::Int8,
+                "int16" => Type::Int16,
+                "int32" => Type::Int32,
+                "int64" => Type::Int64,
+                "uint8" => Type::Uint8,
+                "uint16" => Type::Uint16,
+                "uint32" => Type::Uint32,
+                "uint64" => Type::Uint64,
+                "float32" => Type::Float32,
+                "float64" => Type::Float64,
+                "bool" => Type::Bool,
+                "string" => Type::String,
+                "void" => Type::Void,
+                other => {
+                    return Err(self.error(
+                        codes::UNKNOWN_TYPE,
+                        format!("unknown type `{}`", other),
+                        &inner,
+                    ))
+                }
+            }),
+            Rule::array_type => {
+                let mut parts = inner.into_inner();
+                let elem_type = Box::new(self.parse_type(parts.next().unwrap())?);
+                let size = self.integer(&parts.next().unwrap())?;
+                Ok(Type::Array { elem_type, size })
+            }
+            Rule::capability_type => {
+                let resource = self.parse_resource(inner.into_inner().next().unwrap())?;
+                Ok(Type::Capability { resource })
+            }
+            rule => Err(self.error(
+                codes::UNKNOWN_TYPE,
+                format!("unexpected {} in type position", describe_rule(&rule)),
+                &inner,
+            )),
         }
     }
+
+    fn parse_resource(&self, pair: Pair) -> Result<ResourceType> {
+        Ok(match pair.as_str() {
+            "uart-tx" => ResourceType::UartTx,
+            "uart-rx" => ResourceType::UartRx,
+            "gpio" => ResourceType::Gpio,
+            "i2c" => ResourceType::I2c,
+            "spi" => ResourceType::Spi,
+            "sensor-read" => ResourceType::SensorRead,
+            "network-send" => ResourceType::NetworkSend,
+            "network-recv" => ResourceType::NetworkRecv,
+            other => {
+                return Err(self.error(
+                    codes::UNKNOWN_RESOURCE,
+                    format!("unknown resource type `{}`", other),
+                    &pair,
+                ))
+            }
+        })
+    }
+}
+
+/// Convert a pest failure into a syntax diagnostic; `offset` is where the
+/// parsed text starts within `source`
+pub(super) fn syntax_error(
+    error: pest::error::Error<Rule>,
+    source: &str,
+    index: &LineIndex,
+    offset: usize,
+) -> Diagnostic {
+    let (start, end) = match error.location {
+        pest::error::InputLocation::Pos(pos) => (offset + pos, offset + pos),
+        pest::error::InputLocation::Span((start, end)) => (offset + start, offset + end),
+    };
+    let error = error.renamed_rules(describe_rule);
+
+    Diagnostic::error(
+        codes::SYNTAX_ERROR,
+        error.variant.message().to_string(),
+        index.span(source, start, end),
+    )
 }
 
-fn parse_type(pair: pest::iterators::Pair<Rule>) -> Result<Type> {
-    let inner = pair.into_inner().next().unwrap();
-
-    match inner.as_rule() {
-        Rule::simple_type => Ok(match inner.as_str() {
-            "int32" => Type::Int32,
-            "int64" => Type::Int64,
-            "uint32" => Type::Uint32,
-            "uint64" => Type::Uint64,
-            "float32" => Type::Float32,
-            "float64" => Type::Float64,
-            "bool" => Type::Bool,
-            "string" => Type::String,
-            "void" => Type::Void,
-            _ => return Err(anyhow!("Unknown type: {}", inner.as_str())),
-        }),
-        Rule::array_type => {
-            let mut parts = inner.into_inner();
-            let elem_type = Box::new(parse_type(parts.next().unwrap())?);
-            let size = parts.next().unwrap().as_str().parse()?;
-            Ok(Type::Array { elem_type, size })
+/// Human-readable name of a grammar rule for error messages
+fn describe_rule(rule: &Rule) -> String {
+    match rule {
+        Rule::EOI => "end of input".to_string(),
+        Rule::form | Rule::list | Rule::atom => "expression".to_string(),
+        Rule::ident => "identifier".to_string(),
+        Rule::keyword => "keyword".to_string(),
+        Rule::type_expr | Rule::simple_type => "type".to_string(),
+        Rule::param_list => "parameter list".to_string(),
+        Rule::binding_list => "binding list".to_string(),
+        rule => format!("{:?}", rule).replace('_', " "),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::Radix;
+
+    #[test]
+    fn test_spans_attached() {
+        let source = "(defun-deploy add (a b) : int32\n  (+ a b))\n";
+        let exprs = parse_file(source).unwrap();
+
+        let defun = &exprs[0];
+        assert_eq!(defun.span, Span::new(0, source.len() - 1, 1, 1));
+
+        let ExprKind::DefunDeploy { params, body, .. } = &defun.kind else {
+            panic!("expected defun-deploy");
+        };
+        assert_eq!(params[1].span, Span::new(21, 22, 1, 22));
+
+        let call = &body[0];
+        assert_eq!(call.span, Span::new(34, 41, 2, 3));
+        let ExprKind::FunctionCall { args, .. } = &call.kind else {
+            panic!("expected call");
+        };
+        assert_eq!(args[1].span, Span::new(39, 40, 2, 8));
+    }
+
+    #[test]
+    fn test_spans_survive_json() {
+        let exprs = parse_file("(sleep-ms 10)").unwrap();
+        let json = serde_json::to_string(&exprs).unwrap();
+        let back: Vec<Expr> = serde_json::from_str(&json).unwrap();
+        assert_eq!(back[0].span, exprs[0].span);
+        assert_eq!(back[0].span, Span::new(0, 13, 1, 1));
+    }
+
+    #[test]
+    fn test_syntax_error_diagnostic() {
+        let err = parse_file("(defun-deploy f (x) : (array uint128 4)\n  x)").unwrap_err();
+        assert_eq!(err.code, codes::SYNTAX_ERROR);
+        assert_eq!(err.span().line, 1);
+        assert_eq!(err.span().column, 30);
+        assert!(err.message.starts_with("expected"));
+    }
+
+    fn parse_one(source: &str) -> Expr {
+        let mut exprs = parse_file(source).unwrap();
+        assert_eq!(exprs.len(), 1, "{}", source);
+        exprs.remove(0)
+    }
+
+    #[test]
+    fn test_lowers_every_construct() {
+        let cases = [
+            ("(uart-send tx \"hi\")", "uart-send"),
+            ("(uart-recv rx)", "uart-recv"),
+            ("(network-recv net)", "network-recv"),
+            ("(timestamp)", "timestamp"),
+            ("(macro twice (x) (+ x x))", "macro"),
+            ("(eval-compile (table 8))", "eval-compile"),
+            ("(include \"lib.obl\")", "include"),
+            ("(for x items (emit x))", "for"),
+            ("(while running (step))", "while"),
+            ("(array-init uint8 1 2 3)", "array-init"),
+        ];
+
+        for (source, keyword) in cases {
+            let expr = parse_one(source);
+            assert_eq!(expr.keyword(), Some(keyword), "{}", source);
+            assert_eq!(expr.span, Span::new(0, source.len(), 1, 1));
         }
-        Rule::capability_type => {
-            let resource_pair = inner.into_inner().next().unwrap();
-            let resource = match resource_pair.as_str() {
-                "uart-tx" => ResourceType::UartTx,
-                "uart-rx" => ResourceType::UartRx,
-                "gpio" => ResourceType::Gpio,
-                "i2c" => ResourceType::I2c,
-                "spi" => ResourceType::Spi,
-                "sensor-read" => ResourceType::SensorRead,
-                "network-send" => ResourceType::NetworkSend,
-                "network-recv" => ResourceType::NetworkRecv,
-                _ => return Err(anyhow!("Unknown resource type: {}", resource_pair.as_str())),
-            };
-            Ok(Type::Capability { resource })
+    }
+
+    #[test]
+    fn test_constructs_round_trip() {
+        let sources = [
+            "(uart-send tx \"hi\")",
+            "(uart-recv rx)",
+            "(network-recv net)",
+            "(timestamp)",
+            "(macro twice (x)\n  (+ x x))",
+            "(eval-compile (table 8))",
+            "(include \"lib.obl\")",
+            "(for x items\n  (emit x))",
+            "(while running\n  (step))",
+            "(array-init uint8 0x1 0x2 0x3)",
+            "(array-init int32)",
+        ];
+
+        for source in sources {
+            let expr = parse_one(source);
+            let printed = crate::ast::PrettyPrinter::print(&expr);
+            assert_eq!(printed, source);
+            assert_eq!(parse_one(&printed), expr);
         }
-        _ => Err(anyhow!("Unknown type rule: {:?}", inner.as_rule())),
+    }
+
+    #[test]
+    fn test_keyword_must_be_whole_word() {
+        let call = parse_one("(format x)");
+        assert!(matches!(call.kind, ExprKind::FunctionCall { .. }));
+
+        let call = parse_one("(settle x 1)");
+        assert!(matches!(call.kind, ExprKind::FunctionCall { .. }));
+    }
+
+    #[test]
+    fn test_malformed_special_form_is_syntax_error() {
+        for source in ["(while)", "(let x 1)", "(bounded-for i 0)"] {
+            let err = parse_file(source).unwrap_err();
+            assert_eq!(err.code, codes::SYNTAX_ERROR, "{}", source);
+        }
+    }
+
+    #[test]
+    fn test_string_escapes_decoded() {
+        let expr = parse_one(r#"(uart-send tx "ok\r\n\"q\"\t\\")"#);
+        let ExprKind::UartSend { data, .. } = &expr.kind else {
+            panic!("expected uart-send");
+        };
+        assert_eq!(data.kind, ExprKind::String("ok\r\n\"q\"\t\\".to_string()));
+
+        let printed = crate::ast::PrettyPrinter::print(&expr);
+        assert_eq!(printed, r#"(uart-send tx "ok\r\n\"q\"\t\\")"#);
+        assert_eq!(parse_one(&printed), expr);
+    }
+
+    #[test]
+    fn test_unknown_escape_rejected() {
+        let err = parse_file("(defcap tx () \"send \\x41\")").unwrap_err();
+        assert_eq!(err.code, codes::INVALID_ESCAPE);
+        assert_eq!(err.message, "unknown escape sequence `\\x`");
+        assert_eq!(err.span(), Span::new(20, 22, 1, 21));
+        assert_eq!(
+            err.help.as_deref(),
+            Some(r#"the valid escapes are `\"`, `\\`, `\n`, `\r` and `\t`"#)
+        );
+    }
+
+    #[test]
+    fn test_numeric_literal_forms() {
+        let source = "(uart-send 0x1F (f 0b1010 1_000 255u8 -7 1.5 trueish))";
+        let expr = parse_one(source);
+        let ExprKind::UartSend { device, data } = &expr.kind else {
+            panic!("expected uart-send");
+        };
+        let ExprKind::FunctionCall { args, .. } = &data.kind else {
+            panic!("expected call");
+        };
+        let int = |e: &Expr| match &e.kind {
+            ExprKind::Int(literal) => (literal.value, literal.radix, literal.suffix.clone()),
+            other => panic!("expected integer, got {:?}", other),
+        };
+
+        assert_eq!(int(device), (0x1F, Radix::Hex, None));
+        assert_eq!(int(&args[0]), (10, Radix::Binary, None));
+        assert_eq!(int(&args[1]), (1000, Radix::Decimal, None));
+        assert_eq!(int(&args[2]), (255, Radix::Decimal, Some(Type::Uint8)));
+        assert_eq!(int(&args[3]), (-7, Radix::Decimal, None));
+        assert_eq!(args[4].kind, ExprKind::Float(1.5));
+        assert_eq!(args[5].kind, ExprKind::Ident("trueish".to_string()));
+
+        let printed = crate::ast::PrettyPrinter::print(&expr);
+        assert_eq!(printed, "(uart-send 0x1F (f 0b1010 1000 255u8 -7 1.5 trueish))");
+        assert_eq!(parse_one(&printed), expr);
+    }
+
+    #[test]
+    fn test_oversized_literal_rejected() {
+        let err = parse_file("(f 0x1_0000_0000_0000_0000)").unwrap_err();
+        assert_eq!(err.code, codes::INVALID_LITERAL);
+        assert_eq!(err.span().column, 4);
     }
 }
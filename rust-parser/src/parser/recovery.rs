@@ -0,0 +1,449 @@
+use pest::Parser;
+
+use super::parser::{syntax_error, Lowerer, OblibenyParser, Rule};
+use crate::ast::{Expr, ExprKind, LineIndex};
+use crate::diagnostics::{codes, Diagnostic, SourceFile};
+
+/// Result of a recovering parse: every form that could be parsed, plus
+/// every syntax error found along the way
+#[derive(Debug, Clone, Default)]
+pub struct ParseOutcome {
+    pub exprs: Vec<Expr>,
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+impl ParseOutcome {
+    pub fn is_ok(&self) -> bool {
+        self.diagnostics.is_empty()
+    }
+}
+
+/// Parse a file without stopping at the first syntax error.
+///
+/// The source is split into balanced top-level forms and each is parsed on
+/// its own, so one malformed form does not hide errors in the rest. Forms
+/// inside a `(program ...)` wrapper are recovered the same way. A form left
+/// unclosed is cut off at the next line that starts with `(` no further
+/// right than the form itself.
+pub fn parse_file_recovering(input: &str) -> ParseOutcome {
+    recover(input, &LineIndex::new(input))
+}
+
+/// Recovering parse of one file of a project; spans carry the file's id
+pub fn parse_source(file: &SourceFile) -> ParseOutcome {
+    recover(&file.source, file.index())
+}
+
+fn recover(input: &str, index: &LineIndex) -> ParseOutcome {
+    let recovery = Recovery {
+        source: input,
+        index,
+    };
+
+    let mut outcome = ParseOutcome::default();
+    for chunk in recovery.split(0, input.len(), false, &mut outcome.diagnostics) {
+        if let Some(expr) = recovery.parse_toplevel(chunk, &mut outcome.diagnostics) {
+            outcome.exprs.push(expr);
+        }
+    }
+    outcome
+}
+
+/// Byte range of one form; `unclosed` forms ran out of input (or were cut
+/// at a resync point) before their closing parenthesis
+#[derive(Debug, Clone, Copy)]
+struct Chunk {
+    start: usize,
+    end: usize,
+    unclosed: bool,
+}
+
+struct Recovery<'s> {
+    source: &'s str,
+    index: &'s LineIndex,
+}
+
+impl<'s> Recovery<'s> {
+    /// Split `start..end` into forms, skipping whitespace, comments and
+    /// stray closing parentheses. With `strict`, a form is cut as soon as a
+    /// sibling appears at its indentation, even if parentheses would balance
+    /// further on.
+    fn split(
+        &self,
+        start: usize,
+        end: usize,
+        strict: bool,
+        diagnostics: &mut Vec<Diagnostic>,
+    ) -> Vec<Chunk> {
+        let bytes = self.source.as_bytes();
+        let mut chunks = Vec::new();
+        let mut pos = start;
+
+        while pos < end {
+            match bytes[pos] {
+                b' ' | b'\t' | b'\r' | b'\n' => pos += 1,
+                b';' => pos = self.skip_comment(pos, end),
+                b')' => {
+                    diagnostics.push(
+                        Diagnostic::error(
+                            codes::SYNTAX_ERROR,
+                            "unexpected `)`",
+                            self.index.span(self.source, pos, pos + 1),
+                        )
+                        .with_label("no matching `(`"),
+                    );
+                    pos += 1;
+                }
+                b'(' => {
+                    let chunk = self.balanced(pos, end, strict);
+                    pos = chunk.end;
+                    chunks.push(chunk);
+                }
+                b'"' => {
+                    let close = self.skip_string(pos, end);
+                    chunks.push(Chunk {
+                        start: pos,
+                        end: close,
+                        unclosed: false,
+                    });
+                    pos = close;
+                }
+                _ => {
+                    let atom_end = self.atom_end(pos, end);
+                    chunks.push(Chunk {
+                        start: pos,
+                        end: atom_end,
+                        unclosed: false,
+                    });
+                    pos = atom_end;
+                }
+            }
+        }
+
+        chunks
+    }
+
+    /// The form opened by the `(` at `open`. A form still open at `end` is
+    /// cut off before the first line that starts with `(` at or left of the
+    /// opening column, which is where the next sibling form most likely is.
+    fn balanced(&self, open: usize, end: usize, strict: bool) -> Chunk {
+        let bytes = self.source.as_bytes();
+        let open_column = open - self.line_start(open);
+        let mut depth = 0usize;
+        let mut resync = None;
+        let mut pos = open;
+
+        while pos < end {
+            match bytes[pos] {
+                b';' => {
+                    pos = self.skip_comment(pos, end);
+                    continue;
+                }
+                b'"' => {
+                    pos = self.skip_string(pos, end);
+                    continue;
+                }
+                b'(' => {
+                    let line_start = self.line_start(pos);
+                    let starts_line = self.source[line_start..pos].trim().is_empty();
+                    if pos > open
+                        && resync.is_none()
+                        && starts_line
+                        && pos - line_start <= open_column
+                    {
+                        if strict {
+                            break;
+                        }
+                        resync = Some(pos);
+                    }
+                    depth += 1;
+                }
+                b')' => {
+                    depth -= 1;
+                    if depth == 0 {
+                        return Chunk {
+                            start: open,
+                            end: pos + 1,
+                            unclosed: false,
+                        };
+                    }
+                }
+                _ => {}
+            }
+            pos += 1;
+        }
+
+        Chunk {
+            start: open,
+            end: resync.unwrap_or(pos),
+            unclosed: true,
+        }
+    }
+
+    fn line_start(&self, pos: usize) -> usize {
+        self.source[..pos].rfind('\n').map(|i| i + 1).unwrap_or(0)
+    }
+
+    fn skip_comment(&self, pos: usize, end: usize) -> usize {
+        self.source[pos..end]
+            .find('\n')
+            .map(|i| pos + i + 1)
+            .unwrap_or(end)
+    }
+
+    /// Position just past the string literal opened at `pos`
+    fn skip_string(&self, pos: usize, end: usize) -> usize {
+        let bytes = self.source.as_bytes();
+        let mut i = pos + 1;
+        while i < end {
+            match bytes[i] {
+                b'\\' => i += 2,
+                b'"' => return i + 1,
+                _ => i += 1,
+            }
+        }
+        end
+    }
+
+    fn atom_end(&self, pos: usize, end: usize) -> usize {
+        self.source[pos..end]
+            .find(|c: char| c.is_whitespace() || matches!(c, '(' | ')' | ';' | '"'))
+            .map(|i| pos + i)
+            .unwrap_or(end)
+    }
+
+    fn unclosed(&self, chunk: Chunk) -> Diagnostic {
+        Diagnostic::error(
+            codes::SYNTAX_ERROR,
+            "unclosed `(`",
+            self.index.span(self.source, chunk.start, chunk.start + 1),
+        )
+        .with_label("this form is never closed")
+    }
+
+    fn parse_toplevel(&self, chunk: Chunk, diagnostics: &mut Vec<Diagnostic>) -> Option<Expr> {
+        let result = if chunk.unclosed {
+            Err(self.unclosed(chunk))
+        } else {
+            self.parse_chunk(Rule::form_input, chunk)
+        };
+
+        match result {
+            Ok(expr) => Some(expr),
+            Err(error) => {
+                if self.is_program(chunk) {
+                    if let Some(program) = self.recover_program(chunk, diagnostics) {
+                        return Some(program);
+                    }
+                }
+                diagnostics.push(error);
+                None
+            }
+        }
+    }
+
+    fn is_program(&self, chunk: Chunk) -> bool {
+        let text = &self.source[chunk.start..chunk.end];
+        text.strip_prefix('(')
+            .map(str::trim_start)
+            .and_then(|inner| inner.strip_prefix("program"))
+            .is_some_and(|rest| rest.starts_with(char::is_whitespace))
+    }
+
+    /// Rebuild a `(program name budget forms...)` from whichever of its
+    /// members parse. Returns `None` if every member is fine, in which case
+    /// the error lies in the wrapper itself and is reported as such.
+    ///
+    /// When the program itself is unclosed, one of its members has most
+    /// likely swallowed the closing parenthesis, so members are split by
+    /// indentation and the final `)` is taken to close the program.
+    fn recover_program(&self, chunk: Chunk, diagnostics: &mut Vec<Diagnostic>) -> Option<Expr> {
+        let text = self.source[chunk.start..chunk.end].trim_end();
+        let inner_end = if text.ends_with(')') {
+            chunk.start + text.len() - 1
+        } else {
+            chunk.end
+        };
+        let keyword = self.source[chunk.start..].find("program").unwrap() + chunk.start;
+
+        let mut member_errors = Vec::new();
+        let mut members = self
+            .split(
+                keyword + "program".len(),
+                inner_end,
+                chunk.unclosed,
+                &mut member_errors,
+            )
+            .into_iter();
+
+        let name = members
+            .next()
+            .map(|c| self.source[c.start..c.end].to_string())
+            .unwrap_or_default();
+
+        let empty_budget = || Expr::from(ExprKind::ResourceBudget { specs: vec![] });
+        let budget = match members.next() {
+            Some(c) => match self.parse_member(Rule::budget_input, c) {
+                Ok(budget) => budget,
+                Err(e) => {
+                    member_errors.push(e);
+                    empty_budget()
+                }
+            },
+            None => empty_budget(),
+        };
+
+        let mut forms = Vec::new();
+        for member in members {
+            match self.parse_member(Rule::toplevel_input, member) {
+                Ok(form) => forms.push(form),
+                Err(e) => member_errors.push(e),
+            }
+        }
+
+        if member_errors.is_empty() {
+            return None;
+        }
+        diagnostics.extend(member_errors);
+
+        let span = self.index.span(self.source, chunk.start, chunk.end);
+        Some(Expr::new(
+            ExprKind::Program {
+                name,
+                budget: Box::new(budget),
+                forms,
+            },
+            span,
+        ))
+    }
+
+    fn parse_member(&self, rule: Rule, chunk: Chunk) -> Result<Expr, Diagnostic> {
+        if chunk.unclosed {
+            return Err(self.unclosed(chunk));
+        }
+        self.parse_chunk(rule, chunk)
+    }
+
+    /// Parse one chunk with the given entry rule and lower it
+    fn parse_chunk(&self, rule: Rule, chunk: Chunk) -> Result<Expr, Diagnostic> {
+        let text = &self.source[chunk.start..chunk.end];
+        let pair = OblibenyParser::parse(rule, text)
+            .map_err(|e| syntax_error(e, self.source, self.index, chunk.start))?
+            .next()
+            .unwrap();
+
+        let lowerer = Lowerer::at_offset(self.source, self.index, chunk.start);
+        match rule {
+            Rule::toplevel_input => lowerer.parse_construct(pair.into_inner().next().unwrap()),
+            Rule::budget_input => lowerer.parse_resource_budget(pair),
+            _ => lowerer.parse_form(pair),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_file;
+
+    #[test]
+    fn test_clean_file_matches_strict_parse() {
+        let source = "(defun-deploy a () 1)\n;; comment\n(defun-deploy b (x) (+ x 1))\n";
+        let outcome = parse_file_recovering(source);
+        assert!(outcome.is_ok());
+        assert_eq!(outcome.exprs, parse_file(source).unwrap());
+        assert_eq!(outcome.exprs[1].span.line, 3);
+    }
+
+    #[test]
+    fn test_reports_every_broken_form() {
+        let source = "\
+(defun-deploy ok1 () 1)
+(defun-deploy bad1 (x) : (array int32 x) x)
+(defun-deploy ok2 () 2)
+(set x : 1)
+(defun-deploy ok3 () 3)
+";
+        let outcome = parse_file_recovering(source);
+        assert_eq!(outcome.exprs.len(), 3);
+        let lines: Vec<usize> = outcome.diagnostics.iter().map(|d| d.span().line).collect();
+        assert_eq!(lines, vec![2, 4]);
+    }
+
+    #[test]
+    fn test_resyncs_after_unclosed_form() {
+        let source = ")\n(defun-deploy broken ()\n  (+ 1 2)\n(defun-deploy fine () 1)\n";
+        let outcome = parse_file_recovering(source);
+
+        assert_eq!(outcome.exprs.len(), 1);
+        assert!(matches!(
+            &outcome.exprs[0].kind,
+            ExprKind::DefunDeploy { name, .. } if name == "fine"
+        ));
+        let messages: Vec<&str> = outcome
+            .diagnostics
+            .iter()
+            .map(|d| d.message.as_str())
+            .collect();
+        assert!(messages.contains(&"unclosed `(`"));
+        assert!(messages.contains(&"unexpected `)`"));
+    }
+
+    #[test]
+    fn test_non_ascii_toplevel_is_reported() {
+        let outcome = parse_file_recovering("é");
+        assert!(outcome.exprs.is_empty());
+        assert_eq!(outcome.diagnostics.len(), 1);
+
+        let outcome = parse_file_recovering("(defun-deploy ok () 1)\nλ (défun x)\n");
+        assert_eq!(outcome.exprs.len(), 1);
+        assert!(!outcome.is_ok());
+    }
+
+    #[test]
+    fn test_unindented_body_is_not_split() {
+        let source = "(defun-deploy f ()\n(+ 1 2))\n";
+        let outcome = parse_file_recovering(source);
+        assert!(outcome.is_ok());
+        assert_eq!(outcome.exprs.len(), 1);
+    }
+
+    #[test]
+    fn test_recovers_unclosed_program_member() {
+        let source = "\
+(program demo
+  (resource-budget (time-ms 10))
+  (defun-deploy broken ()
+    (+ 1 2)
+  (defun-deploy fine () 1))
+";
+        let outcome = parse_file_recovering(source);
+        assert_eq!(outcome.diagnostics.len(), 1);
+        assert_eq!(outcome.diagnostics[0].span().line, 3);
+
+        let ExprKind::Program { forms, .. } = &outcome.exprs[0].kind else {
+            panic!("expected program");
+        };
+        assert_eq!(forms.len(), 1);
+    }
+
+    #[test]
+    fn test_recovers_inside_program() {
+        let source = "\
+(program demo
+  (resource-budget (time-ms 10))
+  (defun-deploy ok () 1)
+  (defun-deploy bad (x) : (array int32 x) x)
+  (defun-deploy also-ok () 2))
+";
+        let outcome = parse_file_recovering(source);
+        assert_eq!(outcome.diagnostics.len(), 1);
+        assert_eq!(outcome.diagnostics[0].span().line, 4);
+
+        let ExprKind::Program { name, forms, .. } = &outcome.exprs[0].kind else {
+            panic!("expected program");
+        };
+        assert_eq!(name, "demo");
+        assert_eq!(forms.len(), 2);
+    }
+}
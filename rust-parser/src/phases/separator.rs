@@ -1,17 +1,67 @@
-use crate::ast::{Expr, Phase};
+use crate::ast::{
+    walk_bounded_for, walk_defun_deploy, walk_expr, walk_with_capability, Expr, ExprKind,
+    Parameter, Phase, Span, Type, Visitor,
+};
+use crate::diagnostics::{codes, Diagnostic, ToDiagnostic};
 use std::collections::HashSet;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
 pub enum PhaseError {
-    #[error("Compile-time construct found in deploy-time code: {0}")]
-    CompileInDeploy(String),
+    #[error("Compile-time construct found in deploy-time code: {context} at {span}")]
+    CompileInDeploy {
+        construct: String,
+        context: String,
+        span: Span,
+        enclosing: Span,
+    },
 
-    #[error("Mixed phase in expression (compile and deploy mixed)")]
-    MixedPhase,
+    #[error("Mixed phase in expression (compile and deploy mixed) at {span}")]
+    MixedPhase { span: Span },
 
-    #[error("Recursion detected in deploy-time code")]
-    RecursionInDeploy,
+    #[error("Recursion detected in deploy-time code at {span}")]
+    RecursionInDeploy { span: Span },
+}
+
+impl PhaseError {
+    /// Source location of the offending construct
+    pub fn span(&self) -> Span {
+        match self {
+            PhaseError::CompileInDeploy { span, .. }
+            | PhaseError::MixedPhase { span }
+            | PhaseError::RecursionInDeploy { span } => *span,
+        }
+    }
+}
+
+impl ToDiagnostic for PhaseError {
+    fn to_diagnostic(&self) -> Diagnostic {
+        match self {
+            PhaseError::CompileInDeploy {
+                construct,
+                context,
+                span,
+                enclosing,
+            } => Diagnostic::error(
+                codes::COMPILE_IN_DEPLOY,
+                "compile-time construct in deploy-time code",
+                *span,
+            )
+            .with_label(format!("`{}` only runs at compile time", construct))
+            .with_secondary(*enclosing, context.clone())
+            .with_help("move it into a `defun-compile` or `eval-compile` form"),
+            PhaseError::MixedPhase { span } => Diagnostic::error(
+                codes::MIXED_PHASE,
+                "expression mixes compile-time and deploy-time code",
+                *span,
+            ),
+            PhaseError::RecursionInDeploy { span } => Diagnostic::error(
+                codes::RECURSION_IN_DEPLOY,
+                "recursion in deploy-time code",
+                *span,
+            ),
+        }
+    }
 }
 
 pub struct PhaseSeparator {
@@ -23,7 +73,6 @@ pub fn new() -> Self {
         let mut compile_only_constructs = HashSet::new();
         compile_only_constructs.insert("defun-compile".to_string());
         compile_only_constructs.insert("macro".to_string());
-        compile_only_constructs.insert("eval-compile".to_string());
         compile_only_constructs.insert("include".to_string());
         compile_only_constructs.insert("for".to_string());
         compile_only_constructs.insert("while".to_string());
@@ -33,135 +82,148 @@ pub fn new() -> Self {
         }
     }
 
-    /// Analyze an expression and determine its phase
+    /// Analyze an expression and determine its phase. Deploy-time code
+    /// is searched at any depth for compile-only constructs; the bodies of
+    /// compile-time forms are not, and an `eval-compile` is allowed since
+    /// only its result is deployed.
     pub fn analyze(&self, expr: &Expr) -> Result<Phase, PhaseError> {
-        match expr {
-            Expr::DefunDeploy { body, name, .. } => {
-                // Ensure no compile-time constructs in body
-                for e in body {
-                    if self.is_compile_only(e) {
-                        return Err(PhaseError::CompileInDeploy(format!(
-                            "in function {}",
-                            name
-                        )));
-                    }
-                    // Recursively check
-                    self.analyze(e)?;
-                }
-                Ok(Phase::Deploy)
-            }
-
-            Expr::DefunCompile { .. }
-            | Expr::Macro { .. }
-            | Expr::EvalCompile(_)
-            | Expr::Include(_)
-            | Expr::For { .. }
-            | Expr::While { .. } => Ok(Phase::Compile),
-
-            Expr::BoundedFor { body, .. } => {
-                // Ensure body is deploy-safe
-                for e in body {
-                    if self.is_compile_only(e) {
-                        return Err(PhaseError::CompileInDeploy(
-                            "in bounded-for loop".to_string(),
-                        ));
-                    }
-                    self.analyze(e)?;
-                }
-                Ok(Phase::Deploy)
-            }
-
-            Expr::WithCapability { body, .. } => {
-                for e in body {
-                    if self.is_compile_only(e) {
-                        return Err(PhaseError::CompileInDeploy(
-                            "in with-capability block".to_string(),
-                        ));
-                    }
-                    self.analyze(e)?;
-                }
-                Ok(Phase::Deploy)
-            }
-
-            Expr::Let { bindings, body } => {
-                for (_, expr) in bindings {
-                    self.analyze(expr)?;
-                }
-                for expr in body {
-                    self.analyze(expr)?;
-                }
-                // Infer phase from body
-                let phases: Vec<_> = body.iter().map(|e| e.phase()).collect();
-                if phases.contains(&Phase::Compile) {
-                    Ok(Phase::Compile)
-                } else {
-                    Ok(Phase::Deploy)
-                }
-            }
-
-            Expr::If {
-                condition,
-                then_branch,
-                else_branch,
-            } => {
-                self.analyze(condition)?;
-                self.analyze(then_branch)?;
-                self.analyze(else_branch)?;
-                Ok(Phase::Deploy)
-            }
-
-            Expr::FunctionCall { func, args } => {
-                self.analyze(func)?;
-                for arg in args {
-                    self.analyze(arg)?;
-                }
-                Ok(Phase::Deploy)
-            }
-
-            // Literals are phase-neutral (can be used in both)
-            Expr::Int(_)
-            | Expr::Float(_)
-            | Expr::Bool(_)
-            | Expr::String(_)
-            | Expr::Ident(_) => Ok(Phase::Deploy),
+        let mut checker = PhaseChecker {
+            separator: self,
+            context: Vec::new(),
+            error: None,
+        };
+        checker.visit_expr(expr);
 
-            // Other constructs default to deploy
-            _ => Ok(Phase::Deploy),
+        match checker.error {
+            Some(error) => Err(error),
+            None if expr.phase() == Phase::Compile => Ok(Phase::Compile),
+            None => Ok(Phase::Deploy),
         }
     }
 
     /// Check if an expression is compile-only
     fn is_compile_only(&self, expr: &Expr) -> bool {
-        expr.is_compile_only()
+        expr.keyword()
+            .is_some_and(|k| self.compile_only_constructs.contains(k))
     }
 
     /// Extract all deploy-time functions from a program
-    pub fn extract_deploy_functions(&self, exprs: &[Expr]) -> Vec<&Expr> {
-        exprs
-            .iter()
-            .filter(|e| matches!(e, Expr::DefunDeploy { .. }))
+    pub fn extract_deploy_functions<'a>(&self, exprs: &'a [Expr]) -> Vec<&'a Expr> {
+        top_level_forms(exprs)
+            .filter(|e| matches!(e.kind, ExprKind::DefunDeploy { .. }))
             .collect()
     }
 
     /// Extract all compile-time functions from a program
-    pub fn extract_compile_functions(&self, exprs: &[Expr]) -> Vec<&Expr> {
-        exprs
-            .iter()
-            .filter(|e| matches!(e, Expr::DefunCompile { .. } | Expr::Macro { .. }))
+    pub fn extract_compile_functions<'a>(&self, exprs: &'a [Expr]) -> Vec<&'a Expr> {
+        top_level_forms(exprs)
+            .filter(|e| matches!(e.kind, ExprKind::DefunCompile { .. } | ExprKind::Macro { .. }))
             .collect()
     }
 
     /// Validate that all deploy functions are phase-correct
     pub fn validate_deploy_phase(&self, exprs: &[Expr]) -> Result<(), PhaseError> {
-        for expr in exprs {
-            if let Expr::DefunDeploy { .. } = expr {
-                self.analyze(expr)?;
-            }
+        for expr in self.extract_deploy_functions(exprs) {
+            self.analyze(expr)?;
         }
         Ok(())
     }
 }
 
+/// Top-level forms, with the forms of any `program` in place of the program
+fn top_level_forms(exprs: &[Expr]) -> impl Iterator<Item = &Expr> {
+    exprs.iter().flat_map(|expr| match &expr.kind {
+        ExprKind::Program { forms, .. } => forms.iter().collect::<Vec<_>>(),
+        _ => vec![expr],
+    })
+}
+
+/// Finds the first compile-only construct inside deploy-time code, keeping
+/// the innermost enclosing deploy-time construct for the error
+struct PhaseChecker<'a> {
+    separator: &'a PhaseSeparator,
+    context: Vec<(String, Span)>,
+    error: Option<PhaseError>,
+}
+
+impl PhaseChecker<'_> {
+    fn within(&mut self, context: String, span: Span, walk: impl FnOnce(&mut Self)) {
+        self.context.push((context, span));
+        walk(self);
+        self.context.pop();
+    }
+}
+
+impl Visitor for PhaseChecker<'_> {
+    fn visit_expr(&mut self, expr: &Expr) {
+        if self.error.is_some() {
+            return;
+        }
+        match self.context.last() {
+            Some((context, enclosing)) if self.separator.is_compile_only(expr) => {
+                self.error = Some(PhaseError::CompileInDeploy {
+                    construct: expr.keyword().unwrap_or_default().to_string(),
+                    context: context.clone(),
+                    span: expr.span,
+                    enclosing: *enclosing,
+                });
+            }
+            _ => walk_expr(self, expr),
+        }
+    }
+
+    fn visit_defun_deploy(
+        &mut self,
+        name: &str,
+        params: &[Parameter],
+        _return_type: Option<&Type>,
+        body: &[Expr],
+        span: Span,
+    ) {
+        self.within(format!("in function {}", name), span, |this| {
+            walk_defun_deploy(this, params, body)
+        });
+    }
+
+    fn visit_bounded_for(
+        &mut self,
+        _var: &str,
+        start: &Expr,
+        end: &Expr,
+        body: &[Expr],
+        span: Span,
+    ) {
+        self.within("in bounded-for loop".to_string(), span, |this| {
+            walk_bounded_for(this, start, end, body)
+        });
+    }
+
+    fn visit_with_capability(&mut self, capability: &Expr, body: &[Expr], span: Span) {
+        self.within("in with-capability block".to_string(), span, |this| {
+            walk_with_capability(this, capability, body)
+        });
+    }
+
+    // The bodies of compile-time forms run in the compiler
+    fn visit_defun_compile(
+        &mut self,
+        _name: &str,
+        _params: &[Parameter],
+        _return_type: Option<&Type>,
+        _body: &[Expr],
+        _span: Span,
+    ) {
+    }
+
+    fn visit_macro(&mut self, _name: &str, _params: &[Parameter], _body: &[Expr], _span: Span) {}
+
+    fn visit_eval_compile(&mut self, _expr: &Expr, _span: Span) {}
+
+    fn visit_for(&mut self, _var: &str, _iterable: &Expr, _body: &[Expr], _span: Span) {}
+
+    fn visit_while(&mut self, _condition: &Expr, _body: &[Expr], _span: Span) {}
+}
+
 impl Default for PhaseSeparator {
     fn default() -> Self {
         Self::new()
@@ -171,26 +233,27 @@ fn default() -> Self {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::ast::types::Parameter;
 
     #[test]
     fn test_deploy_function_valid() {
         let separator = PhaseSeparator::new();
 
-        let expr = Expr::DefunDeploy {
+        let expr: Expr = ExprKind::DefunDeploy {
             name: "test".to_string(),
             params: vec![],
             return_type: None,
             body: vec![
-                Expr::Int(42),
-                Expr::BoundedFor {
+                ExprKind::Int(42.into()).into(),
+                ExprKind::BoundedFor {
                     var: "i".to_string(),
-                    start: Box::new(Expr::Int(0)),
-                    end: Box::new(Expr::Int(10)),
-                    body: vec![Expr::Int(1)],
-                },
+                    start: Box::new(ExprKind::Int(0.into()).into()),
+                    end: Box::new(ExprKind::Int(10.into()).into()),
+                    body: vec![ExprKind::Int(1.into()).into()],
+                }
+                .into(),
             ],
-        };
+        }
+        .into();
 
         assert!(separator.analyze(&expr).is_ok());
     }
@@ -199,19 +262,61 @@ fn test_deploy_function_valid() {
     fn test_deploy_function_invalid() {
         let separator = PhaseSeparator::new();
 
-        let expr = Expr::DefunDeploy {
+        let expr: Expr = ExprKind::DefunDeploy {
             name: "test".to_string(),
             params: vec![],
             return_type: None,
             body: vec![
-                Expr::Int(42),
-                Expr::While {
-                    condition: Box::new(Expr::Bool(true)),
-                    body: vec![Expr::Int(1)],
-                },
+                ExprKind::Int(42.into()).into(),
+                ExprKind::While {
+                    condition: Box::new(ExprKind::Bool(true).into()),
+                    body: vec![ExprKind::Int(1.into()).into()],
+                }
+                .into(),
             ],
-        };
+        }
+        .into();
 
         assert!(separator.analyze(&expr).is_err());
     }
+
+    #[test]
+    fn test_error_carries_span() {
+        let separator = PhaseSeparator::new();
+        let loop_span = Span::new(20, 38, 2, 3);
+
+        let expr: Expr = ExprKind::DefunDeploy {
+            name: "test".to_string(),
+            params: vec![],
+            return_type: None,
+            body: vec![Expr::new(
+                ExprKind::While {
+                    condition: Box::new(ExprKind::Bool(true).into()),
+                    body: vec![],
+                },
+                loop_span,
+            )],
+        }
+        .into();
+
+        let err = separator.analyze(&expr).unwrap_err();
+        assert_eq!(err.span(), loop_span);
+        assert!(err.to_string().contains("in function test at 2:3"));
+
+        let diag = err.to_diagnostic();
+        assert_eq!(diag.code, codes::COMPILE_IN_DEPLOY);
+        assert_eq!(diag.primary.message, "`while` only runs at compile time");
+    }
+
+    #[test]
+    fn test_nested_construct_reports_innermost_context() {
+        let separator = PhaseSeparator::new();
+        let source = "(program p (resource-budget (time-ms 10))\n  (defun-deploy f (x)\n    (let ((y (eval-compile 1)))\n      (bounded-for i 0 y\n        (set x (if x (while x 1) 0))))))";
+        let exprs = crate::parse_file(source).unwrap();
+
+        let err = separator.validate_deploy_phase(&exprs).unwrap_err();
+        assert_eq!(err.span().line, 5);
+        assert!(err.to_string().contains("in bounded-for loop"));
+        assert_eq!(separator.extract_deploy_functions(&exprs).len(), 1);
+    }
 }